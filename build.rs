@@ -0,0 +1,9 @@
+// Generates the gRPC service code for `sayit grpc-serve` from
+// proto/sayit.proto, with a bundled protoc so no system install is needed
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/sayit.proto");
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/sayit.proto")?;
+    Ok(())
+}
@@ -0,0 +1,24 @@
+/* Calling sayit from C:
+ *
+ *   cargo build --release --lib --features ffi
+ *   cc examples/speak.c -Iinclude -Ltarget/release -lsayit -o speak
+ *   LD_LIBRARY_PATH=target/release ./speak "Hello from C" hello.mp3
+ */
+#include <stdio.h>
+
+#include "sayit.h"
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s TEXT OUTPUT_FILE\n", argv[0]);
+        return 2;
+    }
+
+    int status = sayit_speak(argv[1], "nova", 1.0f, argv[2]);
+    if (status != SAYIT_OK) {
+        fprintf(stderr, "sayit_speak failed with code %d\n", status);
+        return 1;
+    }
+    printf("Wrote %s\n", argv[2]);
+    return 0;
+}
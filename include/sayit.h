@@ -0,0 +1,32 @@
+/* C interface to sayit's text to speech.
+ *
+ * Build the library with `cargo build --release --lib --features ffi`, which
+ * produces target/release/libsayit.so (libsayit.dylib on macOS, sayit.dll
+ * on Windows). The OpenAI API key is read from OPENAI_API_KEY.
+ */
+#ifndef SAYIT_H
+#define SAYIT_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SAYIT_OK 0
+#define SAYIT_INVALID_ARGUMENT 1
+#define SAYIT_NO_API_KEY 2
+#define SAYIT_REQUEST_FAILED 3
+#define SAYIT_WRITE_FAILED 4
+
+/* Synthesize `text` (UTF-8) with `voice` (alloy, echo, fable, onyx, nova or
+ * shimmer) at `speed` (0.25 - 4.0, or 0 for the default of 1.0) and save it
+ * to `output_path`. The extension picks the format: .flac, .aac,
+ * .opus/.ogg, .wav or .pcm, and mp3 otherwise; .m4a and .m4b are refused
+ * with SAYIT_INVALID_ARGUMENT. Blocks until the file has been written and
+ * returns one of the SAYIT_* codes above. */
+int sayit_speak(const char *text, const char *voice, float speed, const char *output_path);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SAYIT_H */
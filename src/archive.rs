@@ -0,0 +1,133 @@
+// Packing everything a run writes into one zip file (--archive). The files
+// are generated in a staging directory next to the archive, laid out as they
+// appear inside it (audio under audio/, sidecars such as the manifest and
+// playlist at the top), then copied into the zip one at a time from disk.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+// Where audio goes within the archive
+pub const AUDIO_DIR: &str = "audio";
+
+// A hidden directory beside the archive, unique to this run
+pub fn staging_dir(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .map_or("archive".into(), |name| name.to_string_lossy());
+    archive.with_file_name(format!(".{}.{}.staging", name, std::process::id()))
+}
+
+// `path` moved into `dir` of the staging directory, keeping its file name
+pub fn staged(staging: &Path, dir: &str, path: &str) -> String {
+    let name = Path::new(path)
+        .file_name()
+        .map_or_else(|| path.into(), |name| name.to_string_lossy());
+    staging
+        .join(dir)
+        .join(name.as_ref())
+        .to_string_lossy()
+        .into_owned()
+}
+
+// A staged file's path inside the archive, always with forward slashes
+pub fn internal_path(staging: &Path, path: &str) -> String {
+    match Path::new(path).strip_prefix(staging) {
+        Ok(relative) => relative
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        Err(_) => path.to_string(),
+    }
+}
+
+fn staged_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            staged_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Audio is already compressed, so only text sidecars are deflated
+fn compression(path: &Path) -> CompressionMethod {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    match ext.to_lowercase().as_str() {
+        "json" | "m3u" | "m3u8" | "txt" | "srt" | "vtt" | "tsv" => CompressionMethod::Deflated,
+        _ => CompressionMethod::Stored,
+    }
+}
+
+// Zip every file in the staging directory into `archive`, through
+// `<archive>.part` so a failed run never leaves half an archive. Returns the
+// number of files archived.
+pub fn write(archive: &Path, staging: &Path) -> io::Result<usize> {
+    let mut files = Vec::new();
+    staged_files(staging, &mut files)?;
+
+    let mut part = archive.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let written = (|| {
+        let mut zip = ZipWriter::new(File::create(&part)?);
+        for path in &files {
+            let name = internal_path(staging, &path.to_string_lossy());
+            let options = SimpleFileOptions::default()
+                .compression_method(compression(path))
+                .large_file(std::fs::metadata(path)?.len() >= u32::MAX as u64);
+            zip.start_file(name, options)?;
+            io::copy(&mut File::open(path)?, &mut zip)?;
+        }
+        zip.finish()?.sync_all()
+    })();
+    match written {
+        Ok(()) => std::fs::rename(&part, archive).map(|_| files.len()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&part);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn staged_files_are_zipped_at_their_internal_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("book.zip");
+        let staging = staging_dir(&archive);
+        let audio = staged(&staging, AUDIO_DIR, "out/book.mp3");
+        let manifest = staged(&staging, "", "book.json");
+        std::fs::create_dir_all(staging.join(AUDIO_DIR)).unwrap();
+        std::fs::write(&audio, b"ID3 audio").unwrap();
+        std::fs::write(&manifest, b"{}").unwrap();
+        assert_eq!(internal_path(&staging, &audio), "audio/book.mp3");
+
+        assert_eq!(write(&archive, &staging).unwrap(), 2);
+        let mut zip = zip::ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        let mut contents = String::new();
+        let mut entry = zip.by_name("audio/book.mp3").unwrap();
+        assert_eq!(entry.compression(), CompressionMethod::Stored);
+        entry.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "ID3 audio");
+        drop(entry);
+        assert!(zip.by_name("book.json").is_ok());
+        assert!(!dir.path().join("book.zip.part").exists());
+    }
+}
@@ -0,0 +1,105 @@
+// Giving up on a run the API keeps failing (--max-failure-rate). Once more
+// than the given share of the last WINDOW chunks to finish have failed, the
+// breaker trips: chunks still waiting or retrying are cancelled rather than
+// each grinding through its own retries during an outage.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::watch;
+
+pub const DEFAULT_MAX_FAILURE_RATE: u8 = 50;
+
+// How many of the most recent chunks the failure rate is taken over
+pub const WINDOW: usize = 10;
+
+static BREAKER: OnceLock<Breaker> = OnceLock::new();
+
+// Set the failure rate (a percentage) for the run; only the first call has
+// any effect
+pub fn init(max_failure_rate: u8) {
+    let _ = BREAKER.set(Breaker::new(max_failure_rate));
+}
+
+fn breaker() -> &'static Breaker {
+    BREAKER.get_or_init(|| Breaker::new(DEFAULT_MAX_FAILURE_RATE))
+}
+
+// Note how a chunk ended
+pub fn record(succeeded: bool) {
+    breaker().record(succeeded);
+}
+
+pub fn is_tripped() -> bool {
+    *breaker().tripped.borrow()
+}
+
+// Wait until the breaker trips, which may be never
+pub async fn tripped() {
+    let mut tripped = breaker().tripped.subscribe();
+    // The sender lives in a static, so this only ends by tripping
+    let _ = tripped.wait_for(|tripped| *tripped).await;
+}
+
+pub struct Breaker {
+    max_failure_rate: u8,
+    recent: Mutex<VecDeque<bool>>,
+    tripped: watch::Sender<bool>,
+}
+
+impl Breaker {
+    pub fn new(max_failure_rate: u8) -> Breaker {
+        Breaker {
+            max_failure_rate: max_failure_rate.min(100),
+            recent: Mutex::new(VecDeque::with_capacity(WINDOW)),
+            tripped: watch::Sender::new(false),
+        }
+    }
+
+    pub fn record(&self, succeeded: bool) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(succeeded);
+        let failures = recent.iter().filter(|succeeded| !**succeeded).count();
+        if recent.len() < WINDOW
+            || failures * 100 <= usize::from(self.max_failure_rate) * WINDOW
+            || *self.tripped.borrow()
+        {
+            return;
+        }
+        log::error!(
+            "{} of the last {} chunks failed; giving up on the rest of the run \
+             (see --max-failure-rate)",
+            failures,
+            WINDOW
+        );
+        self.tripped.send_replace(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trips_once_too_many_recent_chunks_fail() {
+        let breaker = Breaker::new(50);
+        for _ in 0..WINDOW {
+            breaker.record(true);
+        }
+        // Half of the last ten failing isn't more than half
+        for _ in 0..5 {
+            breaker.record(false);
+        }
+        assert!(!*breaker.tripped.borrow());
+        breaker.record(false);
+        assert!(*breaker.tripped.borrow());
+
+        let never = Breaker::new(100);
+        for _ in 0..WINDOW * 2 {
+            never.record(false);
+        }
+        assert!(!*never.tripped.borrow());
+    }
+}
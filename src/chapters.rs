@@ -0,0 +1,95 @@
+// Splitting input into sections at heading lines, for --chapter-announce.
+
+use regex::Regex;
+
+// Markdown headings (`## Title`) and numbered sections (`3. Title`)
+pub const DEFAULT_CHAPTER_PATTERN: &str = r"^(?:#{1,6}|\d+\.)\s+(?P<title>.*\S)";
+
+// A run of text, introduced by a heading unless it precedes the first one
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    pub title: Option<String>,
+    pub body: String,
+}
+
+// Break the text at every line matching `pattern`. The heading's title is the
+// `title` capture group if the pattern has one, otherwise the whole line.
+pub fn split_sections(text: &str, pattern: &Regex) -> Vec<Section> {
+    let mut sections = vec![Section {
+        title: None,
+        body: String::new(),
+    }];
+
+    for line in text.lines() {
+        if let Some(captures) = pattern.captures(line) {
+            let title = captures
+                .name("title")
+                .map_or(line, |title| title.as_str())
+                .trim()
+                .to_string();
+            sections.push(Section {
+                title: Some(title),
+                body: String::new(),
+            });
+        } else {
+            let body = &mut sections.last_mut().unwrap().body;
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+
+    if sections[0].body.trim().is_empty() {
+        sections.remove(0);
+    }
+    sections
+}
+
+// The spoken announcement for the `number`th chapter
+pub fn announcement(number: usize, title: &str) -> String {
+    if title.is_empty() {
+        format!("Chapter {}.", number)
+    } else {
+        format!("Chapter {}: {}.", number, title.trim_end_matches('.'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_sections_at_markdown_and_numbered_headings() {
+        let pattern = Regex::new(DEFAULT_CHAPTER_PATTERN).unwrap();
+        let text = "Preface text.\n# Beginnings\nOnce upon a time.\n2. The Middle\nThen.\n";
+
+        let sections = split_sections(text, &pattern);
+
+        assert_eq!(
+            sections,
+            vec![
+                Section {
+                    title: None,
+                    body: "Preface text.\n".to_string()
+                },
+                Section {
+                    title: Some("Beginnings".to_string()),
+                    body: "Once upon a time.\n".to_string()
+                },
+                Section {
+                    title: Some("The Middle".to_string()),
+                    body: "Then.\n".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_pattern_without_title_group_uses_the_whole_line() {
+        let pattern = Regex::new(r"^CHAPTER ").unwrap();
+        let sections = split_sections("CHAPTER ONE\nBody.", &pattern);
+
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].title.as_deref(), Some("CHAPTER ONE"));
+        assert_eq!(announcement(1, "Beginnings."), "Chapter 1: Beginnings.");
+    }
+}
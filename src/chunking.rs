@@ -0,0 +1,49 @@
+// Splitting input text into pieces short enough for one speech request.
+// Shared by the command line tool and the C interface (see lib.rs).
+
+// Helper to split input text into manageable chunks of at most `max_length`
+// bytes, breaking between words where possible
+pub fn split_input(input_text: &str, max_length: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current_chunk = String::new();
+
+    for word in input_text.split_whitespace() {
+        for piece in split_oversized_word(word, max_length) {
+            if !current_chunk.is_empty() && current_chunk.len() + 1 + piece.len() > max_length {
+                chunks.push(std::mem::take(&mut current_chunk));
+            }
+            if !current_chunk.is_empty() {
+                current_chunk.push(' ');
+            }
+            current_chunk.push_str(piece);
+        }
+    }
+
+    if !current_chunk.is_empty() {
+        chunks.push(current_chunk);
+    }
+
+    chunks
+}
+
+// Break a word longer than `max_length` bytes into pieces on char boundaries
+fn split_oversized_word(word: &str, max_length: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = word;
+
+    while rest.len() > max_length {
+        let mut end = max_length;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // A single character wider than the limit still has to go somewhere
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    pieces.push(rest);
+    pieces
+}
@@ -0,0 +1,127 @@
+// Reading the clipboard as a particular MIME type (--read-clipboard-format).
+// The clipboard crate only ever asks for plain text, so on X11 the selection
+// is requested directly.
+
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum ClipboardFormat {
+    #[value(name = "text/plain")]
+    Plain,
+    #[value(name = "text/html")]
+    Html,
+    #[value(name = "text/uri-list")]
+    UriList,
+}
+
+impl ClipboardFormat {
+    fn mime_type(self) -> &'static str {
+        match self {
+            ClipboardFormat::Plain => "text/plain",
+            ClipboardFormat::Html => "text/html",
+            ClipboardFormat::UriList => "text/uri-list",
+        }
+    }
+}
+
+// The clipboard's contents as `format`, turned into readable text
+pub fn read(format: ClipboardFormat) -> Result<String, String> {
+    let bytes = load(format.mime_type())?;
+    let text = String::from_utf8_lossy(&bytes);
+    Ok(match format {
+        ClipboardFormat::Plain => text.into_owned(),
+        ClipboardFormat::Html => crate::preprocess::strip_html(&text),
+        ClipboardFormat::UriList => uri_list_paths(&text),
+    })
+}
+
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "android"))))]
+fn load(mime_type: &str) -> Result<Vec<u8>, String> {
+    use std::time::Duration;
+
+    let clipboard = x11_clipboard::Clipboard::new().map_err(|e| e.to_string())?;
+    let atoms = &clipboard.getter.atoms;
+    let target = clipboard
+        .getter
+        .get_atom(mime_type)
+        .map_err(|e| e.to_string())?;
+    let bytes = clipboard
+        .load(
+            atoms.clipboard,
+            target,
+            atoms.property,
+            Duration::from_secs(3),
+        )
+        .map_err(|e| e.to_string())?;
+    if bytes.is_empty() {
+        return Err(format!("the clipboard has no {} content", mime_type));
+    }
+    Ok(bytes)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "macos", target_os = "android")))))]
+fn load(mime_type: &str) -> Result<Vec<u8>, String> {
+    use clipboard::{ClipboardContext, ClipboardProvider};
+
+    if mime_type != "text/plain" {
+        return Err(format!("reading {} is only supported on X11", mime_type));
+    }
+    let mut ctx: ClipboardContext = ClipboardProvider::new().map_err(|e| e.to_string())?;
+    ctx.get_contents()
+        .map(String::into_bytes)
+        .map_err(|e| e.to_string())
+}
+
+// One line per entry of a URI list, with file:// URIs read as plain paths
+fn uri_list_paths(list: &str) -> String {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|uri| match uri.strip_prefix("file://") {
+            // Drop the (usually empty) host part
+            Some(rest) => percent_decode(&rest[rest.find('/').unwrap_or(0)..]),
+            None => uri.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri_lists_read_as_paths() {
+        let list = "# copied from the file manager\r\n\
+                    file:///home/me/Quarterly%20Report.pdf\r\n\
+                    file://laptop/tmp/notes.txt\r\n\
+                    https://example.com/a%20b\r\n";
+        assert_eq!(
+            uri_list_paths(list),
+            "/home/me/Quarterly Report.pdf\n/tmp/notes.txt\nhttps://example.com/a%20b"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+// Shell completion scripts (`sayit completions SHELL`). Flag names and the
+// values of enum flags such as --voice and --format come from the command
+// definition; the config file's profile names are read when the script is
+// generated, so it needs regenerating after profiles are added.
+
+use crate::config;
+use clap::builder::PossibleValuesParser;
+use clap_complete::Shell;
+use std::io::Write;
+
+pub const INSTALL: &str = "\
+Installing the script:
+  bash:        sayit completions bash > ~/.local/share/bash-completion/completions/sayit
+  zsh:         sayit completions zsh > ~/.zfunc/_sayit
+               (with fpath+=~/.zfunc before compinit in ~/.zshrc)
+  fish:        sayit completions fish > ~/.config/fish/completions/sayit.fish
+  powershell:  sayit completions powershell >> $PROFILE
+  elvish:      sayit completions elvish >> ~/.config/elvish/rc.elv
+
+Profile names are taken from the config file as it is now; run it again after adding some.";
+
+// Write the completion script for `shell`, offering `profiles` for --profile
+pub fn write(shell: Shell, profiles: Vec<String>, out: &mut dyn Write) {
+    let mut command = crate::cli_command();
+    if !profiles.is_empty() {
+        command = command.mut_arg("profile", |arg| {
+            arg.value_parser(PossibleValuesParser::new(profiles))
+        });
+    }
+    clap_complete::generate(shell, &mut command, "sayit", out);
+}
+
+pub fn print(shell: Shell) {
+    let mut script = Vec::new();
+    write(shell, config::known_profiles(), &mut script);
+    // Generating straight to stdout panics when it's piped into `head`
+    let _ = std::io::stdout().write_all(&script);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_offer_voices_formats_and_profiles() {
+        let mut script = Vec::new();
+        write(Shell::Zsh, vec!["audiobook".to_string()], &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("--wav-bits"));
+        for value in ["onyx", "flac", "audiobook"] {
+            assert!(script.contains(value), "{} is missing", value);
+        }
+    }
+}
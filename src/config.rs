@@ -0,0 +1,1055 @@
+// Persistent defaults from `$XDG_CONFIG_HOME/sayit/config.toml` (falling back
+// to `~/.config/sayit/config.toml`), or the file given with --config. Besides
+// the settings below, any flag can be given a default by its long name
+// (`concurrency = 4`, `hd = true`, `replace = ["a", "b"]`). A
+// `[profile.NAME]` section holds more of the same, used over the rest of the
+// file with --profile NAME. Command line flags always win, then SAYIT_*
+// environment variables (SAYIT_CONCURRENCY for --concurrency, and so on),
+// then the file.
+
+use crate::{Cli, ResponseFormat, Voice};
+use clap::builder::{BoolishValueParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, Id, ValueEnum};
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::{Confirm, Input, Select};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use toml::{Spanned, Value};
+
+pub const MIN_SPEED: f32 = 0.25;
+pub const MAX_SPEED: f32 = 4.0;
+
+pub const MODELS: [&str; 2] = ["tts-1", "tts-1-hd"];
+
+// What a model costs per million characters, in dollars, for stating what a
+// run of API requests comes to
+pub fn price_per_million(model: &str) -> f64 {
+    match model {
+        "tts-1-hd" => 30.0,
+        _ => 15.0,
+    }
+}
+
+// The model to request: tts-1-hd with --hd, otherwise --model's or the
+// config file's choice, falling back to tts-1
+pub fn tts_model(hd: bool, model: Option<&str>, config: &Config) -> &'static str {
+    if hd {
+        return "tts-1-hd";
+    }
+    model
+        .and_then(|model| MODELS.into_iter().find(|known| *known == model))
+        .or(config.model())
+        .unwrap_or("tts-1")
+}
+
+// Keys read into Config rather than standing in for a flag
+pub const SETTINGS: [&str; 5] = ["voice", "speed", "format", "model", "save_text"];
+
+// Flags about the config file itself
+pub const NOT_CONFIGURABLE: [&str; 3] = ["config", "no_config", "profile"];
+
+// Which file defaults come from, as chosen on the command line
+enum Choice {
+    Default,
+    File(PathBuf),
+    Disabled,
+}
+
+static CHOICE: OnceLock<Choice> = OnceLock::new();
+
+// The --profile in use
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+fn profile() -> Option<&'static str> {
+    PROFILE.get().map(String::as_str)
+}
+
+// The `[profile.NAME]` sections, each read like the top of the file
+#[derive(Debug, Default, Deserialize)]
+struct Profiles<T> {
+    #[serde(default = "BTreeMap::new")]
+    profile: BTreeMap<String, T>,
+}
+
+// A config file's keys with where each one is, for reporting. Values can't
+// carry their own spans: tables made with `[profile.NAME]` headers have none.
+type Spans = BTreeMap<Spanned<String>, Value>;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    pub voice: Option<Voice>,
+    pub speed: Option<f32>,
+    pub format: Option<ResponseFormat>,
+    pub model: Option<String>,
+    // Save the synthesized text next to --output-file (as .txt) without
+    // needing --save-text
+    pub save_text: Option<bool>,
+}
+
+impl Config {
+    // These settings, with any left out taken from `base`
+    fn or(self, base: Config) -> Config {
+        Config {
+            voice: self.voice.or(base.voice),
+            speed: self.speed.or(base.speed),
+            format: self.format.or(base.format),
+            model: self.model.or(base.model),
+            save_text: self.save_text.or(base.save_text),
+        }
+    }
+
+    // The configured model as one of MODELS
+    pub fn model(&self) -> Option<&'static str> {
+        let model = self.model.as_deref()?;
+        MODELS.into_iter().find(|known| *known == model)
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("sayit").join("config.toml"))
+}
+
+pub fn check_speed(speed: f32) -> Result<f32, String> {
+    if (MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        Ok(speed)
+    } else {
+        Err(format!(
+            "speed {} is outside the supported range {:?} - {:?}",
+            speed, MIN_SPEED, MAX_SPEED
+        ))
+    }
+}
+
+// The config file in use, if any, and whether it has to exist
+fn location() -> Option<(PathBuf, bool)> {
+    match CHOICE.get().unwrap_or(&Choice::Default) {
+        Choice::Default => default_path().map(|path| (path, false)),
+        Choice::File(path) => Some((path.clone(), true)),
+        Choice::Disabled => None,
+    }
+}
+
+// The config file's path and contents; a missing default file means no
+// defaults, but a missing --config file is an error
+fn read() -> Result<Option<(PathBuf, String)>, String> {
+    let Some((path, required)) = location() else {
+        return Ok(None);
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Ok(Some((path, contents))),
+        Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+// Load the config file if there is one
+pub fn load() -> Result<Config, String> {
+    match read()? {
+        Some((path, contents)) => {
+            parse(&contents, profile()).map_err(|e| format!("{}: {}", path.display(), e))
+        }
+        None => Ok(Config::default()),
+    }
+}
+
+fn invalid(e: String) -> ! {
+    eprintln!("Invalid config file {}", e);
+    std::process::exit(1);
+}
+
+// The environment variables standing in for a flag: SAYIT_ and its long
+// name, shouted, then the same for each of its aliases
+pub fn env_names(arg: &Arg) -> Vec<String> {
+    let takes_pairs = arg
+        .get_num_args()
+        .is_some_and(|range| range.max_values() > 1);
+    if arg.get_env().is_some() || takes_pairs {
+        return Vec::new();
+    }
+    let aliases = arg.get_all_aliases().unwrap_or_default();
+    arg.get_long()
+        .into_iter()
+        .chain(aliases)
+        .map(|long| format!("SAYIT_{}", long.replace('-', "_").to_uppercase()))
+        .collect()
+}
+
+// The flags to read from their environment variables, as (flag, variable):
+// those whose variable is set and that the command line neither gives nor
+// conflicts with. Switches take 1/0, true/false, yes/no or on/off, and are
+// only read when on. A value the flag won't take is an error naming the
+// variable, which clap's own message wouldn't.
+fn env_flags(given: &ArgMatches) -> Result<Vec<(Id, String)>, String> {
+    let mut command = Cli::command();
+    command.build();
+    let flags = flags(&command);
+    let given = given_flags(&flags, Some(given));
+    let mut env = Vec::new();
+    for arg in flags {
+        let set = env_names(arg).into_iter().find_map(|name| {
+            let value = std::env::var_os(&name).filter(|value| !value.is_empty())?;
+            Some((name, value))
+        });
+        let Some((name, value)) = set else {
+            continue;
+        };
+        if given
+            .iter()
+            .any(|other| other.get_id() == arg.get_id() || conflict(&command, arg, other))
+        {
+            continue;
+        }
+        let long = arg.get_long().unwrap_or_default();
+        if arg.get_action().takes_values() {
+            check(&[format!("--{}={}", long, value.to_string_lossy())])
+                .map_err(|e| format!("{}: {}", name, e))?;
+        } else {
+            let on = BoolishValueParser::new()
+                .parse_ref(&command, None, &value)
+                .map_err(|_| {
+                    format!(
+                        "{}: invalid value '{}' for '--{}': expected 1/0, true/false, yes/no or on/off",
+                        name,
+                        value.to_string_lossy(),
+                        long
+                    )
+                })?;
+            if !on {
+                continue;
+            }
+        }
+        env.push((arg.get_id().clone(), name));
+    }
+    Ok(env)
+}
+
+// The command line with `env` flags read from their variables
+fn command(env: &[(Id, String)]) -> Command {
+    Cli::command().mut_args(|arg| {
+        let Some((_, name)) = env.iter().find(|(id, _)| id == arg.get_id()) else {
+            return arg;
+        };
+        if arg.get_action().takes_values() {
+            arg.env(name)
+        } else {
+            arg.env(name).value_parser(BoolishValueParser::new())
+        }
+    })
+}
+
+// Parse the command line, with SAYIT_* environment variables and then the
+// config file's defaults filled in for every flag it leaves out. Also
+// returns warnings to log once logging is up.
+pub fn parse_cli() -> (Cli, Vec<String>) {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let run = take_run_subcommand(&mut args);
+    let (mut cli, warnings) = parse_args(args);
+    if run.is_some() {
+        cli.command = run;
+    }
+    (cli, warnings)
+}
+
+// `sayit speak ...` and `sayit save ...` are read as the bare command line
+// without the subcommand, so the environment and config file fill them in
+// the same way; this takes it out of `args`, to be put back once parsed
+fn take_run_subcommand(args: &mut Vec<OsString>) -> Option<crate::Command> {
+    let matches = crate::cli_command().get_matches_from(&*args);
+    let name = matches.subcommand_name()?;
+    let run = match name {
+        "speak" => crate::Command::Speak,
+        "save" => crate::Command::Save,
+        _ => return None,
+    };
+    // The subcommand is the first token equal to its name that clap reads as
+    // one, rather than as the value of an option before it
+    let at = (1..args.len()).find(|&at| {
+        args[at] == name
+            && crate::cli_command()
+                .ignore_errors(true)
+                .try_get_matches_from(&args[..=at])
+                .is_ok_and(|prefix| prefix.subcommand_name() == Some(name))
+    });
+    if let Some(at) = at {
+        args.remove(at);
+    }
+    Some(run)
+}
+
+fn parse_args(args: Vec<OsString>) -> (Cli, Vec<String>) {
+    let env = env_flags(&Cli::command().get_matches_from(&args)).unwrap_or_else(|e| {
+        eprintln!("Invalid environment variable {}", e);
+        std::process::exit(1);
+    });
+    let matches = command(&env).get_matches_from(&args);
+    if let Some(name) = matches.get_one::<String>("profile") {
+        let _ = PROFILE.set(name.clone());
+    }
+    let choice = match matches.get_one::<String>("config") {
+        Some(path) => Choice::File(PathBuf::from(path)),
+        None if matches.get_flag("no_config") => Choice::Disabled,
+        None => Choice::Default,
+    };
+    let _ = CHOICE.set(choice);
+
+    let Some((path, contents)) = read().unwrap_or_else(|e| invalid(e)) else {
+        if let Some(name) = profile() {
+            eprintln!("Unknown profile `{}`: there's no config file", name);
+            std::process::exit(1);
+        }
+        let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        return (cli, Vec::new());
+    };
+    if let Some(name) = profile() {
+        let names = profile_names(&contents)
+            .unwrap_or_else(|e| invalid(format!("{}: {}", path.display(), e)));
+        if !names.iter().any(|known| known == name) {
+            eprintln!("Unknown profile `{}`; {}", name, available(&names));
+            std::process::exit(1);
+        }
+    }
+    let defaults = flag_defaults(&contents, Some(&matches), profile())
+        .unwrap_or_else(|e| invalid(format!("{}: {}", path.display(), e)));
+    // The defaults go first, since after a subcommand they'd be taken as
+    // its arguments
+    let args = args[..1]
+        .iter()
+        .cloned()
+        .chain(defaults.args.into_iter().map(OsString::from))
+        .chain(args[1..].iter().cloned());
+    let parsed = command(&env)
+        .try_get_matches_from(args)
+        .and_then(|matches| Cli::from_arg_matches(&matches));
+    match parsed {
+        Ok(cli) => (cli, defaults.warnings),
+        Err(e) => {
+            eprintln!("With the defaults from {}:", path.display());
+            e.exit()
+        }
+    }
+}
+
+// The flags a config file stands for, and what in it was ignored
+#[derive(Debug, Default)]
+struct FlagDefaults {
+    args: Vec<String>,
+    warnings: Vec<String>,
+}
+
+fn profile_names(contents: &str) -> Result<Vec<String>, String> {
+    let profiles: Profiles<toml::Table> = toml::from_str(contents).map_err(|e| e.to_string())?;
+    Ok(profiles.profile.into_keys().collect())
+}
+
+fn available(names: &[String]) -> String {
+    if names.is_empty() {
+        "the config file has no [profile.NAME] sections".to_string()
+    } else {
+        format!("the config file has {}", names.join(", "))
+    }
+}
+
+// Turn every key naming a flag into that flag, skipping flags `given` on the
+// command line (or in the environment) and flags that conflict with them.
+// The keys of `profile` replace those at the top of the file.
+fn flag_defaults(
+    contents: &str,
+    given: Option<&ArgMatches>,
+    profile: Option<&str>,
+) -> Result<FlagDefaults, String> {
+    let mut table: Spans = toml::from_str(contents).map_err(|e| e.to_string())?;
+    table.retain(|key, _| key.get_ref() != "profile");
+    if let Some(name) = profile {
+        let mut profiles: Profiles<Spans> = toml::from_str(contents).map_err(|e| e.to_string())?;
+        let keys = profiles.profile.remove(name).unwrap_or_default();
+        for (key, value) in keys {
+            // Spanned keys compare by name, so the top-level one is replaced
+            table.remove(&key);
+            table.insert(key, value);
+        }
+    }
+    let mut command = Cli::command();
+    command.build();
+    let flags = flags(&command);
+    let given = given_flags(&flags, given);
+
+    let mut defaults = FlagDefaults::default();
+    for (spanned_key, value) in &table {
+        let key = spanned_key.get_ref().as_str();
+        if SETTINGS.contains(&key) {
+            continue;
+        }
+        let long = key.replace('_', "-");
+        let Some(arg) = flags
+            .iter()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+        else {
+            defaults
+                .warnings
+                .push(format!("Ignoring unknown key `{}` in the config file", key));
+            continue;
+        };
+        if NOT_CONFIGURABLE.contains(&arg.get_id().as_str()) {
+            defaults
+                .warnings
+                .push(format!("`{}` can only be given on the command line", key));
+            continue;
+        }
+        if given
+            .iter()
+            .any(|other| other.get_id() == arg.get_id() || conflict(&command, arg, other))
+        {
+            continue;
+        }
+        let args = flag_args(arg, value)
+            .and_then(|args| check(&args).map(|_| args))
+            .map_err(|e| {
+                format!(
+                    "{}: {}: {}",
+                    position(contents, spanned_key.span().start),
+                    key,
+                    e
+                )
+            })?;
+        defaults.args.extend(args);
+    }
+    Ok(defaults)
+}
+
+// The flags, leaving out the positional input, hidden ones and --help/--version
+pub fn flags(command: &Command) -> Vec<&Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| {
+            arg.get_long().is_some()
+                && !arg.is_hide_set()
+                && !["help", "version"].contains(&arg.get_id().as_str())
+        })
+        .collect()
+}
+
+// Those of `flags` given in `matches`
+fn given_flags<'a>(flags: &[&'a Arg], matches: Option<&ArgMatches>) -> Vec<&'a Arg> {
+    flags
+        .iter()
+        .copied()
+        .filter(|arg| {
+            matches.is_some_and(|matches| {
+                matches
+                    .value_source(arg.get_id().as_str())
+                    .is_some_and(|source| source != ValueSource::DefaultValue)
+            })
+        })
+        .collect()
+}
+
+// Whether `a` and `b` can't be given together, either way round or as
+// members of the same one-of group
+fn conflict(command: &clap::Command, a: &Arg, b: &Arg) -> bool {
+    let conflicts_with = |arg: &Arg, other: &Arg| {
+        command
+            .get_arg_conflicts_with(arg)
+            .iter()
+            .any(|conflict| conflict.get_id() == other.get_id())
+    };
+    conflicts_with(a, b)
+        || conflicts_with(b, a)
+        || command.get_groups().any(|group| {
+            // Derive puts every field in a group named after the struct that
+            // allows any number of them
+            let exclusive = !group.clone().is_multiple();
+            let is_member = |arg: &Arg| group.get_args().any(|id| id == arg.get_id());
+            exclusive && is_member(a) && is_member(b)
+        })
+}
+
+// The command line arguments for `arg` set to `value`. Flags that take
+// several values at once (--replace) take a list, or a list of lists when
+// repeated.
+fn flag_args(arg: &Arg, value: &Value) -> Result<Vec<String>, String> {
+    let long = arg.get_long().unwrap_or_default();
+    if !arg.get_action().takes_values() {
+        return match value {
+            Value::Boolean(true) => Ok(vec![format!("--{}", long)]),
+            Value::Boolean(false) => Ok(Vec::new()),
+            _ => Err("expected true or false".to_string()),
+        };
+    }
+    let text = |value: &Value| match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Integer(_) | Value::Float(_) | Value::Datetime(_) => Ok(value.to_string()),
+        Value::Boolean(_) => Err("expected a value, not true or false".to_string()),
+        Value::Array(_) | Value::Table(_) => Err("expected a single value".to_string()),
+    };
+    let per_use = arg.get_num_args().map_or(1, |range| range.max_values());
+    let one_use = |value: &Value| match value {
+        // --copy-path on its own
+        Value::Boolean(true)
+            if arg
+                .get_num_args()
+                .is_some_and(|range| range.min_values() == 0) =>
+        {
+            Ok(vec![format!("--{}", long)])
+        }
+        Value::Array(values) if per_use > 1 => std::iter::once(Ok(format!("--{}", long)))
+            .chain(values.iter().map(text))
+            .collect(),
+        _ if per_use > 1 => Err(format!("expected a list of {} values", per_use)),
+        value => Ok(vec![format!("--{}={}", long, text(value)?)]),
+    };
+    match value {
+        Value::Array(uses)
+            if matches!(arg.get_action(), ArgAction::Append)
+                && (per_use == 1 || uses.iter().all(Value::is_array)) =>
+        {
+            let uses: Result<Vec<Vec<String>>, String> = uses.iter().map(one_use).collect();
+            Ok(uses?.concat())
+        }
+        value => one_use(value),
+    }
+}
+
+// Run `args` through the flag's own parser, so a bad value is reported
+// against the config file rather than the command line
+fn check(args: &[String]) -> Result<(), String> {
+    let Err(e) = Cli::command()
+        .try_get_matches_from(std::iter::once("sayit").chain(args.iter().map(String::as_str)))
+    else {
+        return Ok(());
+    };
+    if !matches!(
+        e.kind(),
+        ErrorKind::InvalidValue
+            | ErrorKind::ValueValidation
+            | ErrorKind::InvalidUtf8
+            | ErrorKind::WrongNumberOfValues
+            | ErrorKind::TooFewValues
+            | ErrorKind::TooManyValues
+    ) {
+        return Ok(());
+    }
+    let message = e.to_string();
+    let first_line = message.lines().next().unwrap_or_default();
+    Err(first_line.trim_start_matches("error: ").to_string())
+}
+
+// `line L, column C` of a byte offset
+fn position(contents: &str, offset: usize) -> String {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    format!("line {}, column {}", line, column)
+}
+
+// The settings at the top of the file, under those of `profile`
+fn settings(contents: &str, profile: Option<&str>) -> Result<Config, String> {
+    let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+    let Some(name) = profile else {
+        return Ok(config);
+    };
+    let mut profiles: Profiles<Config> = toml::from_str(contents).map_err(|e| e.to_string())?;
+    Ok(profiles.profile.remove(name).unwrap_or_default().or(config))
+}
+
+fn parse(contents: &str, profile: Option<&str>) -> Result<Config, String> {
+    let config = settings(contents, profile)?;
+    match problems(&config).into_iter().next() {
+        Some(problem) => Err(problem),
+        None => Ok(config),
+    }
+}
+
+// Values that parse but are out of range
+fn problems(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(speed) = config.speed {
+        if let Err(e) = check_speed(speed) {
+            problems.push(e);
+        }
+    }
+    if let Some(model) = &config.model {
+        if config.model().is_none() {
+            problems.push(format!(
+                "unknown model {:?} (expected one of {})",
+                model,
+                MODELS.join(", ")
+            ));
+        }
+    }
+    problems
+}
+
+// `sayit config validate`: check the config file and the environment, and
+// print the settings a run would use. The API key is only needed without
+// --no-auth. Returns whether everything is valid.
+pub fn validate(no_auth: bool) -> bool {
+    let mut errors = Vec::new();
+    let mut config = Config::default();
+    let mut flags = FlagDefaults::default();
+    match (location(), read()) {
+        (None, _) if matches!(CHOICE.get(), Some(Choice::Disabled)) => {
+            println!("Config file: none (--no-config)")
+        }
+        (None, _) => errors.push("can't find the config file: HOME isn't set".to_string()),
+        (_, Err(e)) => errors.push(e),
+        (Some((path, _)), Ok(None)) => println!("Config file: {} (not found)", path.display()),
+        (_, Ok(Some((path, contents)))) => {
+            println!("Config file: {}", path.display());
+            if let Some(name) = profile() {
+                println!("Profile: {}", name);
+            }
+            match settings(&contents, profile()) {
+                Ok(parsed) => {
+                    errors.extend(problems(&parsed));
+                    config = parsed;
+                }
+                Err(e) => errors.push(e),
+            }
+            match flag_defaults(&contents, None, profile()) {
+                Ok(defaults) => flags = defaults,
+                // Already reported if the file doesn't parse at all
+                Err(e) if !errors.contains(&e) => errors.push(e),
+                Err(_) => {}
+            }
+        }
+    }
+    if !no_auth && std::env::var_os("OPENAI_API_KEY").is_none_or(|key| key.is_empty()) {
+        errors.push("OPENAI_API_KEY isn't set".to_string());
+    }
+
+    print_settings(&config, &flags.args, "");
+
+    for error in &errors {
+        eprintln!("error: {}", error);
+    }
+    errors.is_empty()
+}
+
+// What a run would use, `indent`ed
+fn print_settings(config: &Config, flags: &[String], indent: &str) {
+    let setting = |value: Option<String>, default: &str| match value {
+        Some(value) => value,
+        None => format!("{} (default)", default),
+    };
+    println!(
+        "{}voice  = {}",
+        indent,
+        setting(config.voice.map(|v| value_name(&v)), "alloy")
+    );
+    println!(
+        "{}speed  = {}",
+        indent,
+        setting(config.speed.map(|s| s.to_string()), "1")
+    );
+    println!(
+        "{}format = {}",
+        indent,
+        setting(config.format.map(|f| value_name(&f)), "mp3")
+    );
+    println!(
+        "{}model  = {}",
+        indent,
+        setting(config.model.clone(), "tts-1")
+    );
+    println!(
+        "{}save_text = {}",
+        indent,
+        setting(config.save_text.map(|save| save.to_string()), "false")
+    );
+    if !flags.is_empty() {
+        println!("{}flags  = {}", indent, flags.join(" "));
+    }
+}
+
+// The config file's profile names, or none if it can't be read
+pub fn known_profiles() -> Vec<String> {
+    match read() {
+        Ok(Some((_, contents))) => profile_names(&contents).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+// `sayit profiles`: every profile in the config file, with the settings a
+// run using it gets before any flags are given
+pub fn list_profiles() -> Result<(), String> {
+    let Some((path, contents)) = read()? else {
+        println!("No config file, so no profiles");
+        return Ok(());
+    };
+    let in_file = |e: String| format!("{}: {}", path.display(), e);
+    let names = profile_names(&contents).map_err(in_file)?;
+    if names.is_empty() {
+        println!("No profiles in {}", path.display());
+        return Ok(());
+    }
+    for (number, name) in names.iter().enumerate() {
+        let config = parse(&contents, Some(name)).map_err(in_file)?;
+        let flags = flag_defaults(&contents, None, Some(name)).map_err(in_file)?;
+        if number > 0 {
+            println!();
+        }
+        println!("{}", name);
+        print_settings(&config, &flags.args, "  ");
+    }
+    Ok(())
+}
+
+fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+fn render(
+    voice: Voice,
+    speed: f32,
+    format: ResponseFormat,
+    model: &str,
+    save_text: Option<bool>,
+    flags: &toml::Table,
+) -> String {
+    let mut contents = format!(
+        "# Defaults for sayit; command line flags always win\n\
+         voice = \"{}\"\n\
+         speed = {:?}\n\
+         format = \"{}\"\n\
+         model = \"{}\"\n",
+        value_name(&voice),
+        speed,
+        value_name(&format),
+        model
+    );
+    // Not asked for, but kept if it was already set
+    if let Some(save_text) = save_text {
+        contents.push_str(&format!("save_text = {}\n", save_text));
+    }
+    // So are flag defaults, though not checked again
+    if !flags.is_empty() {
+        contents.push_str(&toml::to_string(flags).unwrap_or_default());
+    }
+    contents
+}
+
+// `sayit config init`: ask for each default, offering the current ones, and
+// write them to the default config path
+pub fn init() -> Result<(), String> {
+    let (path, _) = location().ok_or(match CHOICE.get() {
+        Some(Choice::Disabled) => "there's no config file to write with --no-config",
+        _ => "can't tell where the config file goes: HOME isn't set",
+    })?;
+    // A broken file is about to be replaced, so its values aren't needed
+    let current = load().unwrap_or_default();
+    let mut flags: toml::Table = read()
+        .ok()
+        .flatten()
+        .and_then(|(_, contents)| toml::from_str(&contents).ok())
+        .unwrap_or_default();
+    flags.retain(|key, _| !SETTINGS.contains(&key));
+    let theme = ColorfulTheme::default();
+    let prompt_error = |e: dialoguer::Error| e.to_string();
+
+    let voices = Voice::value_variants();
+    let voice_names: Vec<String> = voices.iter().map(value_name).collect();
+    let voice = Select::with_theme(&theme)
+        .with_prompt("Voice")
+        .items(&voice_names)
+        .default(
+            current
+                .voice
+                .and_then(|voice| voices.iter().position(|known| *known == voice))
+                .unwrap_or(0),
+        )
+        .interact()
+        .map_err(prompt_error)?;
+
+    let speed: f32 = Input::with_theme(&theme)
+        .with_prompt(format!("Speed ({} - {})", MIN_SPEED, MAX_SPEED))
+        .default(current.speed.unwrap_or(1.0))
+        .validate_with(|speed: &f32| check_speed(*speed).map(drop))
+        .interact_text()
+        .map_err(prompt_error)?;
+
+    let formats = ResponseFormat::value_variants();
+    let format_names: Vec<String> = formats.iter().map(value_name).collect();
+    let format = Select::with_theme(&theme)
+        .with_prompt("Format")
+        .items(&format_names)
+        .default(
+            formats
+                .iter()
+                .position(|known| *known == current.format.unwrap_or(ResponseFormat::Mp3))
+                .unwrap_or(0),
+        )
+        .interact()
+        .map_err(prompt_error)?;
+
+    let model = Select::with_theme(&theme)
+        .with_prompt("Model")
+        .items(&MODELS)
+        .default(
+            current
+                .model()
+                .and_then(|model| MODELS.iter().position(|known| *known == model))
+                .unwrap_or(0),
+        )
+        .interact()
+        .map_err(prompt_error)?;
+
+    if path.exists() {
+        let overwrite = Confirm::with_theme(&theme)
+            .with_prompt(format!("{} already exists. Overwrite it?", path.display()))
+            .default(false)
+            .interact()
+            .map_err(prompt_error)?;
+        if !overwrite {
+            println!("Left {} unchanged", path.display());
+            return Ok(());
+        }
+    }
+
+    let contents = render(
+        voices[voice],
+        speed,
+        formats[format],
+        MODELS[model],
+        current.save_text,
+        &flags,
+    );
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    }
+    std::fs::write(&path, contents)
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_is_validated_at_load_time() {
+        assert_eq!(parse("speed = 1.15", None).unwrap().speed, Some(1.15));
+        assert_eq!(parse("", None).unwrap().speed, None);
+        assert!(parse("speed = 4.5", None).unwrap_err().contains("outside"));
+        assert!(parse("speed = \"fast\"", None).is_err());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config: Config = toml::from_str("speed = 9.0\nmodel = \"tts-2\"").unwrap();
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("outside"));
+        assert!(problems[1].contains("unknown model"));
+    }
+
+    #[test]
+    fn keys_become_flags_the_command_line_leaves_out() {
+        let contents = "hd = true\n\
+                        no_tags = false\n\
+                        concurrency = 3\n\
+                        replace = [[\"a\", \"b\"], [\"c\", \"d\"]]\n\
+                        play = true\n\
+                        colour = \"blue\"\n";
+        let defaults = flag_defaults(contents, None, None).unwrap();
+        assert_eq!(
+            defaults.args,
+            [
+                "--concurrency=3",
+                "--hd",
+                "--play",
+                "--replace",
+                "a",
+                "b",
+                "--replace",
+                "c",
+                "d"
+            ]
+        );
+        assert_eq!(defaults.warnings.len(), 1);
+        assert!(defaults.warnings[0].contains("`colour`"));
+
+        // Given flags win, and flags that conflict with them are dropped
+        let matches = Cli::command().get_matches_from(["sayit", "--concurrency", "5", "--no-play"]);
+        let defaults = flag_defaults(contents, Some(&matches), None).unwrap();
+        assert_eq!(
+            defaults.args,
+            ["--hd", "--replace", "a", "b", "--replace", "c", "d"]
+        );
+    }
+
+    #[test]
+    fn bad_flag_values_are_reported_where_they_are() {
+        let error = flag_defaults("hd = true\nconcurrency = 0\n", None, None).unwrap_err();
+        assert!(
+            error.starts_with("line 2, column 1: concurrency:"),
+            "{}",
+            error
+        );
+        let error = flag_defaults("hd = \"yes\"", None, None).unwrap_err();
+        assert_eq!(error, "line 1, column 1: hd: expected true or false");
+        assert!(flag_defaults("hd = ", None, None)
+            .unwrap_err()
+            .contains("line 1, column 6"));
+    }
+
+    #[test]
+    fn a_profile_is_used_over_the_rest_of_the_file() {
+        let contents = "voice = \"alloy\"\n\
+                        concurrency = 4\n\
+                        hd = true\n\
+                        [profile.audiobook]\n\
+                        voice = \"onyx\"\n\
+                        format = \"flac\"\n\
+                        concurrency = 2\n\
+                        no_play = true\n";
+        let config = parse(contents, Some("audiobook")).unwrap();
+        assert_eq!(config.voice, Some(Voice::Onyx));
+        assert_eq!(config.format, Some(ResponseFormat::Flac));
+        assert_eq!(parse(contents, None).unwrap().format, None);
+
+        let defaults = flag_defaults(contents, None, Some("audiobook")).unwrap();
+        assert_eq!(defaults.args, ["--concurrency=2", "--hd", "--no-play"]);
+        let defaults = flag_defaults(contents, None, None).unwrap();
+        assert_eq!(defaults.args, ["--concurrency=4", "--hd"]);
+        assert_eq!(profile_names(contents).unwrap(), ["audiobook"]);
+    }
+
+    #[test]
+    fn speak_and_save_are_read_as_the_bare_command_line() {
+        let mut args: Vec<OsString> = [
+            "sayit",
+            "--voice",
+            "onyx",
+            "save",
+            "notes.txt",
+            "-o",
+            "notes.mp3",
+        ]
+        .map(OsString::from)
+        .to_vec();
+        assert!(matches!(
+            take_run_subcommand(&mut args),
+            Some(crate::Command::Save)
+        ));
+        assert_eq!(
+            args,
+            ["sayit", "--voice", "onyx", "notes.txt", "-o", "notes.mp3"]
+        );
+
+        // An option's value that happens to be a subcommand's name stays put
+        let mut args: Vec<OsString> = ["sayit", "--prefix", "save", "-f", "flac", "save", "x.txt"]
+            .map(OsString::from)
+            .to_vec();
+        assert!(matches!(
+            take_run_subcommand(&mut args),
+            Some(crate::Command::Save)
+        ));
+        assert_eq!(args, ["sayit", "--prefix", "save", "-f", "flac", "x.txt"]);
+
+        let mut bare: Vec<OsString> = ["sayit", "notes.txt"].map(OsString::from).to_vec();
+        assert!(take_run_subcommand(&mut bare).is_none());
+        assert_eq!(bare, ["sayit", "notes.txt"]);
+    }
+
+    #[test]
+    fn environment_variables_come_between_the_file_and_the_command_line() {
+        let parse = |args: &[&str]| {
+            let env = env_flags(&Cli::command().get_matches_from(args))?;
+            let matches = command(&env).get_matches_from(args);
+            let defaults = flag_defaults("concurrency = 3\nhd = true\n", Some(&matches), None)?;
+            let args = [args[0]]
+                .into_iter()
+                .chain(defaults.args.iter().map(String::as_str))
+                .chain(args[1..].iter().copied());
+            Ok::<Cli, String>(Cli::from_arg_matches(&command(&env).get_matches_from(args)).unwrap())
+        };
+        std::env::set_var("SAYIT_CONCURRENCY", "7");
+        std::env::set_var("SAYIT_NO_PLAY", "yes");
+        std::env::set_var("SAYIT_PLAY", "0");
+
+        let cli = parse(&["sayit", "-o", "out.mp3"]).unwrap();
+        assert_eq!(
+            (cli.concurrency, cli.hd, cli.no_play, cli.play),
+            (7, true, true, false)
+        );
+        // Given flags win, and variables that conflict with them are dropped
+        let cli = parse(&["sayit", "--concurrency", "5", "--play"]).unwrap();
+        assert_eq!((cli.concurrency, cli.no_play, cli.play), (5, false, true));
+
+        std::env::set_var("SAYIT_CONCURRENCY", "lots");
+        let error = parse(&["sayit"]).err().unwrap();
+        assert!(
+            error.starts_with("SAYIT_CONCURRENCY: invalid value 'lots'"),
+            "{}",
+            error
+        );
+        for name in ["SAYIT_CONCURRENCY", "SAYIT_NO_PLAY", "SAYIT_PLAY"] {
+            std::env::remove_var(name);
+        }
+
+        // Aliases are read too, and SAYIT_MODEL goes over the file's model
+        std::env::set_var("SAYIT_JOBS", "2");
+        std::env::set_var("SAYIT_NO_PLAYBACK", "1");
+        std::env::set_var("SAYIT_MODEL", "tts-1");
+        let cli = parse(&["sayit", "-o", "out.mp3"]).unwrap();
+        assert_eq!(
+            (cli.concurrency, cli.no_play, cli.model.as_deref(), cli.hd),
+            (2, true, Some("tts-1"), false)
+        );
+        let config = super::parse("model = \"tts-1-hd\"\n", None).unwrap();
+        assert_eq!(tts_model(cli.hd, cli.model.as_deref(), &config), "tts-1");
+
+        std::env::set_var("SAYIT_MODEL", "tts-2");
+        let error = parse(&["sayit"]).err().unwrap();
+        assert!(
+            error.starts_with("SAYIT_MODEL: invalid value 'tts-2'"),
+            "{}",
+            error
+        );
+        for name in ["SAYIT_JOBS", "SAYIT_NO_PLAYBACK", "SAYIT_MODEL"] {
+            std::env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn generated_config_loads_back() {
+        let rendered = render(
+            Voice::Nova,
+            1.0,
+            ResponseFormat::Flac,
+            "tts-1-hd",
+            Some(true),
+            &toml::Table::new(),
+        );
+        let config = parse(&rendered, None).unwrap();
+        assert_eq!(config.voice, Some(Voice::Nova));
+        assert_eq!(config.speed, Some(1.0));
+        assert_eq!(config.format, Some(ResponseFormat::Flac));
+        assert_eq!(config.model(), Some("tts-1-hd"));
+        assert_eq!(config.save_text, Some(true));
+
+        assert!(parse("model = \"tts-2\"", None)
+            .unwrap_err()
+            .contains("unknown model"));
+        assert!(parse("voice = \"robot\"", None).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+// Putting the paths of the files a run produced on the clipboard
+// (--copy-path), ready to paste into a chat or another command. Nothing here
+// fails the run: the audio is already written.
+
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+// What to copy when a run writes more than one file
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum CopyPath {
+    // Every path, one per line
+    List,
+    // The directory holding them all
+    Dir,
+}
+
+// The text to copy for `paths`, made absolute
+pub fn text(paths: &[String], mode: CopyPath) -> Option<String> {
+    let paths: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path)))
+        .collect();
+    let text = match paths.as_slice() {
+        [] => return None,
+        [path] => path.to_string_lossy().into_owned(),
+        _ if mode == CopyPath::Dir => common_dir(&paths).to_string_lossy().into_owned(),
+        _ => paths
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+    Some(text)
+}
+
+// The deepest directory every path is inside
+pub fn common_dir(paths: &[PathBuf]) -> PathBuf {
+    let mut dir = paths[0].parent().unwrap_or(Path::new("/")).to_path_buf();
+    while !paths.iter().all(|path| path.starts_with(&dir)) {
+        if !dir.pop() {
+            break;
+        }
+    }
+    dir
+}
+
+pub fn copy(text: &str) {
+    if let Err(e) = set_clipboard(text) {
+        log::warn!("Couldn't copy the output path to the clipboard: {}", e);
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "android"))))]
+fn display_server() -> bool {
+    ["DISPLAY", "WAYLAND_DISPLAY"]
+        .iter()
+        .any(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()))
+}
+
+#[cfg(not(all(unix, not(any(target_os = "macos", target_os = "android")))))]
+fn display_server() -> bool {
+    true
+}
+
+fn set_clipboard(text: &str) -> Result<(), String> {
+    use clipboard::{ClipboardContext, ClipboardProvider};
+
+    if !display_server() {
+        return Err("there's no display server".to_string());
+    }
+    let mut ctx: ClipboardContext = ClipboardProvider::new().map_err(|e| e.to_string())?;
+    ctx.set_contents(text.to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn several_files_are_listed_or_reduced_to_their_directory() {
+        let paths = [
+            "/books/dune/part1.mp3".to_string(),
+            "/books/dune/part2.mp3".to_string(),
+        ];
+        assert_eq!(
+            text(&paths, CopyPath::List).unwrap(),
+            "/books/dune/part1.mp3\n/books/dune/part2.mp3"
+        );
+        assert_eq!(text(&paths, CopyPath::Dir).unwrap(), "/books/dune");
+        assert_eq!(
+            text(&paths[..1], CopyPath::Dir).unwrap(),
+            "/books/dune/part1.mp3"
+        );
+        let spread = ["/books/a/1.mp3".to_string(), "/books/b/2.mp3".to_string()];
+        assert_eq!(text(&spread, CopyPath::Dir).unwrap(), "/books");
+        assert!(text(&[], CopyPath::List).is_none());
+    }
+}
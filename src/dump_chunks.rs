@@ -0,0 +1,53 @@
+// Keeping each chunk's response exactly as the API sent it
+// (--dump-chunks-dir), before any conversion, merging or decoding, so a
+// corrupt output can be traced to the API or to sayit
+
+use crate::AudioChunk;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn init(dir: &str) {
+    let _ = DIR.set(PathBuf::from(dir));
+}
+
+// Where chunk `index` of `format` is dumped in `dir`
+fn path(dir: &Path, index: usize, format: &str) -> PathBuf {
+    dir.join(format!("chunk_{:03}.{}", index, format))
+}
+
+// Write `chunk` if dumping is on; a failure is only a warning, since the
+// run itself doesn't depend on it
+pub fn write(chunk: &AudioChunk, format: &str) {
+    let Some(dir) = DIR.get() else {
+        return;
+    };
+    let path = path(dir, chunk.index, format);
+    match std::fs::write(&path, &chunk.bytes) {
+        Ok(()) => log::debug!("Dumped chunk {} to {}", chunk.index, path.display()),
+        Err(e) => log::warn!(
+            "Failed to dump chunk {} to {}: {}",
+            chunk.index,
+            path.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_numbered_with_the_response_format() {
+        assert_eq!(
+            path(Path::new("raw"), 7, "opus"),
+            Path::new("raw/chunk_007.opus")
+        );
+        assert_eq!(
+            path(Path::new("raw"), 1234, "pcm"),
+            Path::new("raw/chunk_1234.pcm")
+        );
+    }
+}
@@ -0,0 +1,346 @@
+// Reading an EPUB (--epub, or a .epub input file) as chapters of prose in
+// reading order. The package's spine gives the order, the table of contents
+// (EPUB 3 nav or EPUB 2 NCX) the chapter titles, and each XHTML document is
+// read with strip_html; images, stylesheets and other media are skipped.
+
+use crate::chapters::Section;
+use crate::clipboard_format::percent_decode;
+use crate::preprocess::strip_html;
+use regex::Regex;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+// The heading line each chapter is introduced by in the extracted text
+pub const HEADING_PATTERN: &str = r"^# (?P<title>.*\S)";
+
+struct Item {
+    path: String,
+    media_type: String,
+    properties: String,
+}
+
+fn read_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, path: &str) -> Result<String, String> {
+    let mut entry = archive
+        .by_name(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let mut contents = String::new();
+    entry
+        .read_to_string(&mut contents)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(contents)
+}
+
+fn parse_xml<'a>(path: &str, contents: &'a str) -> Result<roxmltree::Document<'a>, String> {
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    roxmltree::Document::parse_with_options(contents, options)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+// `href` (relative to the directory `base`, and percent-encoded) as a path
+// within the archive
+fn resolve(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut parts: Vec<String> = base
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(str::to_string)
+        .collect();
+    for part in percent_decode(href).split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part.to_string()),
+        }
+    }
+    parts.join("/")
+}
+
+fn parent(path: &str) -> &str {
+    path.rfind('/').map_or("", |slash| &path[..slash])
+}
+
+// Chapter titles by document path, from the first table of contents entry
+// pointing into each document
+fn toc_titles(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    items: &HashMap<String, Item>,
+    ncx_id: Option<&str>,
+) -> HashMap<String, String> {
+    let mut titles = HashMap::new();
+    let mut add = |path: String, title: String| {
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !title.is_empty() {
+            titles.entry(path).or_insert(title);
+        }
+    };
+
+    let nav = items
+        .values()
+        .find(|item| item.properties.split_whitespace().any(|p| p == "nav"));
+    if let Some(nav) = nav {
+        if let Ok(contents) = read_entry(archive, &nav.path) {
+            match parse_xml(&nav.path, &contents) {
+                Ok(doc) => {
+                    let toc = doc.descendants().find(|node| {
+                        node.has_tag_name("nav")
+                            && node
+                                .attributes()
+                                .any(|a| a.name() == "type" && a.value() == "toc")
+                    });
+                    for link in toc.iter().flat_map(|toc| toc.descendants()) {
+                        if let (true, Some(href)) = (link.has_tag_name("a"), link.attribute("href"))
+                        {
+                            let text: String = link
+                                .descendants()
+                                .filter(|node| node.is_text())
+                                .filter_map(|node| node.text())
+                                .collect();
+                            add(resolve(parent(&nav.path), href), text);
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Ignoring the EPUB's table of contents: {}", e),
+            }
+        }
+    }
+
+    if let Some(ncx) = ncx_id.and_then(|id| items.get(id)) {
+        if let Ok(contents) = read_entry(archive, &ncx.path) {
+            match parse_xml(&ncx.path, &contents) {
+                Ok(doc) => {
+                    for point in doc.descendants().filter(|n| n.has_tag_name("navPoint")) {
+                        let label = point
+                            .children()
+                            .find(|n| n.has_tag_name("navLabel"))
+                            .and_then(|label| label.descendants().find(|n| n.has_tag_name("text")))
+                            .and_then(|text| text.text());
+                        let src = point
+                            .children()
+                            .find(|n| n.has_tag_name("content"))
+                            .and_then(|content| content.attribute("src"));
+                        if let (Some(label), Some(src)) = (label, src) {
+                            add(resolve(parent(&ncx.path), src), label.to_string());
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Ignoring the EPUB's table of contents: {}", e),
+            }
+        }
+    }
+    titles
+}
+
+// The first heading of a document, for chapters missing from the table of
+// contents
+fn first_heading(html: &str) -> Option<String> {
+    let heading = Regex::new(r"(?is)<h[1-3]\b[^>]*>(.*?)</h[1-3]\s*>").unwrap();
+    let title = strip_html(heading.captures(html)?.get(1)?.as_str());
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    Some(title).filter(|title| !title.is_empty())
+}
+
+// The book's chapters in reading order. Documents without a title of their
+// own (a chapter split over several files) continue the chapter before them.
+pub fn read_chapters(bytes: &[u8]) -> Result<Vec<Section>, String> {
+    let mut archive =
+        ZipArchive::new(Cursor::new(bytes)).map_err(|e| format!("not an EPUB: {}", e))?;
+
+    let container = read_entry(&mut archive, "META-INF/container.xml")?;
+    let container_doc = parse_xml("META-INF/container.xml", &container)?;
+    let package_path = container_doc
+        .descendants()
+        .find(|node| node.has_tag_name("rootfile"))
+        .and_then(|rootfile| rootfile.attribute("full-path"))
+        .ok_or("META-INF/container.xml names no package document")?
+        .to_string();
+
+    let package = read_entry(&mut archive, &package_path)?;
+    let package_doc = parse_xml(&package_path, &package)?;
+    let base = parent(&package_path);
+    let items: HashMap<String, Item> = package_doc
+        .descendants()
+        .filter(|node| node.has_tag_name("item"))
+        .filter_map(|item| {
+            let id = item.attribute("id")?;
+            Some((
+                id.to_string(),
+                Item {
+                    path: resolve(base, item.attribute("href")?),
+                    media_type: item.attribute("media-type").unwrap_or_default().to_string(),
+                    properties: item.attribute("properties").unwrap_or_default().to_string(),
+                },
+            ))
+        })
+        .collect();
+    let spine = package_doc
+        .descendants()
+        .find(|node| node.has_tag_name("spine"))
+        .ok_or_else(|| format!("{} has no spine", package_path))?;
+    let documents: Vec<&Item> = spine
+        .children()
+        .filter(|node| node.has_tag_name("itemref"))
+        .filter(|itemref| itemref.attribute("linear") != Some("no"))
+        .filter_map(|itemref| items.get(itemref.attribute("idref")?))
+        .filter(|item| {
+            matches!(
+                item.media_type.as_str(),
+                "application/xhtml+xml" | "text/html"
+            )
+        })
+        .collect();
+
+    let titles = toc_titles(&mut archive, &items, spine.attribute("toc"));
+    let mut sections: Vec<Section> = Vec::new();
+    for document in documents {
+        let html = match read_entry(&mut archive, &document.path) {
+            Ok(html) => html,
+            Err(e) => {
+                log::warn!("Skipping part of the EPUB: {}", e);
+                continue;
+            }
+        };
+        let text = strip_html(&html);
+        if text.is_empty() {
+            // A cover or other page of nothing but images
+            continue;
+        }
+        let title = titles
+            .get(&document.path)
+            .cloned()
+            .or_else(|| first_heading(&html));
+        match (title, sections.last_mut()) {
+            (None, Some(previous)) => {
+                previous.body.push_str("\n\n");
+                previous.body.push_str(&text);
+            }
+            (title, _) => sections.push(Section { title, body: text }),
+        }
+    }
+    if sections.is_empty() {
+        return Err("the EPUB has no readable text".to_string());
+    }
+    Ok(sections)
+}
+
+// The chapters as plain text, each introduced by a HEADING_PATTERN line
+pub fn to_text(sections: &[Section]) -> String {
+    sections
+        .iter()
+        .map(|section| match &section.title {
+            Some(title) => format!("# {}\n\n{}\n", title, section.body),
+            None => format!("{}\n", section.body),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    fn epub(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container" version="1.0">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+
+    const PACKAGE: &str = r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>
+    <item id="cover-image" href="images/cover.jpg" media-type="image/jpeg"/>
+    <item id="one" href="text/chapter%201.xhtml" media-type="application/xhtml+xml"/>
+    <item id="one-b" href="text/chapter1b.xhtml" media-type="application/xhtml+xml"/>
+    <item id="two" href="text/two.xhtml" media-type="application/xhtml+xml"/>
+    <item id="notes" href="notes.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine toc="ncx">
+    <itemref idref="cover"/>
+    <itemref idref="one"/>
+    <itemref idref="one-b"/>
+    <itemref idref="two"/>
+    <itemref idref="notes" linear="no"/>
+  </spine>
+</package>"#;
+
+    const NCX: &str = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <navMap>
+    <navPoint id="p1"><navLabel><text>The Beginning</text></navLabel><content src="text/chapter%201.xhtml#start"/></navPoint>
+  </navMap>
+</ncx>"#;
+
+    #[test]
+    fn chapters_follow_the_spine_with_toc_titles() {
+        let book = epub(&[
+            ("mimetype", "application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/toc.ncx", NCX),
+            ("OEBPS/cover.xhtml", "<html><body><img src=\"images/cover.jpg\"/></body></html>"),
+            ("OEBPS/text/chapter 1.xhtml", "<html><body><p>It began.</p></body></html>"),
+            ("OEBPS/text/chapter1b.xhtml", "<html><body><p>And went on.</p></body></html>"),
+            (
+                "OEBPS/text/two.xhtml",
+                "<html><head><title>Book</title></head><body><h2>Second &amp; Last</h2><p>The end.</p></body></html>",
+            ),
+            ("OEBPS/notes.xhtml", "<html><body><p>Footnotes.</p></body></html>"),
+        ]);
+
+        let sections = read_chapters(&book).unwrap();
+        let titles: Vec<Option<&str>> = sections.iter().map(|s| s.title.as_deref()).collect();
+        assert_eq!(titles, [Some("The Beginning"), Some("Second & Last")]);
+        assert_eq!(sections[0].body, "It began.\n\nAnd went on.");
+
+        let text = to_text(&sections);
+        assert!(text.starts_with("# The Beginning\n\nIt began."));
+        assert!(!text.contains("Footnotes"));
+        let pattern = Regex::new(HEADING_PATTERN).unwrap();
+        assert_eq!(crate::chapters::split_sections(&text, &pattern).len(), 2);
+    }
+
+    #[test]
+    fn epub3_nav_titles_are_read() {
+        let package = r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <manifest>
+    <item id="nav" href="nav.xhtml" properties="nav" media-type="application/xhtml+xml"/>
+    <item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine><itemref idref="a"/></spine>
+</package>"#;
+        let nav = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<body><nav epub:type="toc"><ol><li><a href="a.xhtml"><span>Alpha</span> Centauri</a></li></ol></nav></body></html>"#;
+        let book = epub(&[
+            ("META-INF/container.xml", &CONTAINER.replace("OEBPS/", "")),
+            ("content.opf", package),
+            ("nav.xhtml", nav),
+            ("a.xhtml", "<p>Far away.</p>"),
+        ]);
+        let sections = read_chapters(&book).unwrap();
+        assert_eq!(sections[0].title.as_deref(), Some("Alpha Centauri"));
+    }
+
+    #[test]
+    fn paths_resolve_against_the_package() {
+        assert_eq!(resolve("OEBPS", "../Images/a%20b.png#x"), "Images/a b.png");
+        assert_eq!(resolve("", "./text/one.xhtml"), "text/one.xhtml");
+        assert!(read_chapters(b"plain text").is_err());
+    }
+}
@@ -0,0 +1,181 @@
+// The C interface: `sayit_speak` as declared in include/sayit.h.
+
+use crate::{chunking, merge, SPEECH_ENDPOINT, VOICES};
+use std::ffi::{c_char, c_int, CStr};
+use std::path::Path;
+
+pub const SAYIT_OK: c_int = 0;
+pub const SAYIT_INVALID_ARGUMENT: c_int = 1;
+pub const SAYIT_NO_API_KEY: c_int = 2;
+pub const SAYIT_REQUEST_FAILED: c_int = 3;
+pub const SAYIT_WRITE_FAILED: c_int = 4;
+
+// A UTF-8 string argument, or None for a null or invalid one
+unsafe fn str_arg<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        return None;
+    }
+    CStr::from_ptr(ptr).to_str().ok()
+}
+
+// The API response format for an output path, and whether it's PCM to be
+// wrapped in a WAV header. MP4 files (.m4a, .m4b) would need the AAC remuxed,
+// which the command line tool does but the library doesn't, so they're
+// refused.
+fn format_for_path(path: &str) -> Option<(&'static str, bool)> {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase);
+    match ext.as_deref() {
+        Some("flac") => Some(("flac", false)),
+        Some("aac") => Some(("aac", false)),
+        Some("m4a" | "m4b") => None,
+        Some("opus" | "ogg") => Some(("opus", false)),
+        Some("wav") => Some(("pcm", true)),
+        Some("pcm" | "raw") => Some(("pcm", false)),
+        _ => Some(("mp3", false)),
+    }
+}
+
+async fn fetch(
+    client: &reqwest::Client,
+    api_key: &str,
+    text: &str,
+    voice: &str,
+    speed: f32,
+    audio_format: &str,
+) -> Option<Vec<u8>> {
+    let response = client
+        .post(SPEECH_ENDPOINT)
+        .bearer_auth(api_key)
+        .json(&serde_json::json!({
+            "model": "tts-1",
+            "voice": voice,
+            "input": text,
+            "response_format": audio_format,
+            "speed": speed,
+        }))
+        .send()
+        .await
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    Some(response.bytes().await.ok()?.to_vec())
+}
+
+fn speak(text: &str, voice: &str, speed: f32, output_path: &str) -> c_int {
+    let speed = if speed == 0.0 { 1.0 } else { speed };
+    if !VOICES.contains(&voice) || !(0.25..=4.0).contains(&speed) || output_path.is_empty() {
+        return SAYIT_INVALID_ARGUMENT;
+    }
+    let Some((audio_format, wrap_pcm_in_wav)) = format_for_path(output_path) else {
+        return SAYIT_INVALID_ARGUMENT;
+    };
+    let chunks = chunking::split_input(text, 4096);
+    if chunks.is_empty() {
+        return SAYIT_INVALID_ARGUMENT;
+    }
+    let api_key = match std::env::var("OPENAI_API_KEY") {
+        Ok(key) if !key.is_empty() => key,
+        _ => return SAYIT_NO_API_KEY,
+    };
+
+    let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    else {
+        return SAYIT_REQUEST_FAILED;
+    };
+    let client = reqwest::Client::new();
+    let fetched = runtime.block_on(async {
+        let mut fetched = Vec::with_capacity(chunks.len());
+        for chunk in &chunks {
+            fetched.push(fetch(&client, &api_key, chunk, voice, speed, audio_format).await?);
+        }
+        Some(fetched)
+    });
+    let Some(fetched) = fetched else {
+        return SAYIT_REQUEST_FAILED;
+    };
+
+    let audio = if wrap_pcm_in_wav {
+        let data = fetched.concat();
+        if merge::check_pcm(&data).is_err() {
+            return SAYIT_REQUEST_FAILED;
+        }
+        let Ok(data_len) = u32::try_from(data.len()) else {
+            return SAYIT_WRITE_FAILED;
+        };
+        [merge::wav_header(merge::API_PCM_FORMAT, data_len), data].concat()
+    } else {
+        match merge::merge_chunks(audio_format, &fetched) {
+            Ok(audio) => audio,
+            Err(_) => return SAYIT_REQUEST_FAILED,
+        }
+    };
+    match std::fs::write(output_path, audio) {
+        Ok(()) => SAYIT_OK,
+        Err(_) => SAYIT_WRITE_FAILED,
+    }
+}
+
+/// Synthesize `text` with `voice` at `speed` (0 for the default of 1.0) and
+/// save it to `output_path`, whose extension picks the format (mp3 unless
+/// it's .flac, .aac, .opus, .ogg, .wav or .pcm; .m4a and .m4b are refused).
+/// Blocks until the file is written. The API key is read from
+/// `OPENAI_API_KEY`. Returns one of the `SAYIT_*` codes.
+///
+/// # Safety
+///
+/// `text`, `voice` and `output_path` must each be null or point to a
+/// NUL-terminated string that stays valid for the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn sayit_speak(
+    text: *const c_char,
+    voice: *const c_char,
+    speed: f32,
+    output_path: *const c_char,
+) -> c_int {
+    let (Some(text), Some(voice), Some(output_path)) =
+        (str_arg(text), str_arg(voice), str_arg(output_path))
+    else {
+        return SAYIT_INVALID_ARGUMENT;
+    };
+    // A panic mustn't unwind into the caller's C frames
+    std::panic::catch_unwind(|| speak(text, voice, speed, output_path))
+        .unwrap_or(SAYIT_REQUEST_FAILED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_arguments_are_refused_before_any_request() {
+        let text = c"Hello";
+        let path = c"out.mp3";
+        unsafe {
+            assert_eq!(
+                sayit_speak(text.as_ptr(), std::ptr::null(), 1.0, path.as_ptr()),
+                SAYIT_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                sayit_speak(text.as_ptr(), c"robot".as_ptr(), 1.0, path.as_ptr()),
+                SAYIT_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                sayit_speak(text.as_ptr(), c"nova".as_ptr(), 9.0, path.as_ptr()),
+                SAYIT_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                sayit_speak(c" ".as_ptr(), c"nova".as_ptr(), 0.0, path.as_ptr()),
+                SAYIT_INVALID_ARGUMENT
+            );
+        }
+        assert_eq!(format_for_path("note.WAV"), Some(("pcm", true)));
+        assert_eq!(format_for_path("note"), Some(("mp3", false)));
+        assert_eq!(format_for_path("note.m4a"), None);
+    }
+}
@@ -0,0 +1,155 @@
+// `sayit grpc-serve`: the SpeechService of proto/sayit.proto. Synthesize
+// streams a request's audio back a chunk at a time, each chunk sent as soon
+// as it and every chunk before it have arrived from the API, so a client can
+// start playing long before the whole text is read.
+
+use crate::serve::{self, Defaults, Failure, Server, SpeakRequest};
+use crate::{expected_content_type, ResponseFormat, Voice};
+use axum::http::StatusCode;
+use clap::ValueEnum;
+use reqwest::Client;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("sayit");
+}
+
+use proto::speech_service_server::{SpeechService, SpeechServiceServer};
+use proto::{SynthesizeRequest, SynthesizeResponse};
+
+// Chunks buffered ahead of a slow client
+const STREAM_BUFFER: usize = 4;
+
+struct Service {
+    server: Arc<Server>,
+}
+
+fn status(failure: Failure) -> Status {
+    match failure.status {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(failure.message),
+        _ => Status::unavailable(failure.message),
+    }
+}
+
+// An empty field means the server's default
+fn choice<T: ValueEnum>(value: &str, what: &str) -> Result<Option<T>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    T::from_str(value, true)
+        .map(Some)
+        .map_err(|_| format!("unknown {} {:?}", what, value))
+}
+
+fn speak_request(request: SynthesizeRequest) -> Result<SpeakRequest, String> {
+    Ok(SpeakRequest {
+        voice: choice::<Voice>(&request.voice, "voice")?,
+        format: choice::<ResponseFormat>(&request.format, "format")?,
+        speed: (request.speed != 0.0).then_some(request.speed),
+        text: request.text,
+    })
+}
+
+#[tonic::async_trait]
+impl SpeechService for Service {
+    type SynthesizeStream = ReceiverStream<Result<SynthesizeResponse, Status>>;
+
+    async fn synthesize(
+        &self,
+        request: Request<SynthesizeRequest>,
+    ) -> Result<Response<Self::SynthesizeStream>, Status> {
+        let request = speak_request(request.into_inner()).map_err(Status::invalid_argument)?;
+        let (settings, pending) = serve::start(&self.server, request).map_err(status)?;
+        let content_type = expected_content_type(settings.audio_format);
+
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            for (index, pending) in pending.into_iter().enumerate() {
+                let message = serve::finish(&server, index, pending)
+                    .await
+                    .map(|audio| SynthesizeResponse {
+                        index: index as u32,
+                        audio: audio.to_vec(),
+                        content_type: content_type.to_string(),
+                    })
+                    .map_err(status);
+                let failed = message.is_err();
+                // The client hung up, or has everything it will get
+                if sender.send(message).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+pub async fn run(bind: &str, port: u16, client: Client, defaults: Defaults) -> Result<(), String> {
+    let listener = serve::tcp_listener(bind, port).await?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    eprintln!("Listening for gRPC on {}", address);
+
+    let service = Service {
+        server: Arc::new(Server::new(client, defaults, None)),
+    };
+    serve::notify_ready();
+    tonic::transport::Server::builder()
+        .add_service(SpeechServiceServer::new(service))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::speech_service_client::SpeechServiceClient;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn bad_requests_are_refused_before_anything_is_fetched() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = Service {
+            server: Arc::new(Server::new(
+                Client::new(),
+                Defaults {
+                    voice: Voice::Alloy,
+                    format: ResponseFormat::Mp3,
+                    speed: 1.0,
+                    model: "tts-1",
+                    show_api_errors: false,
+                },
+                None,
+            )),
+        };
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(SpeechServiceServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let mut client = SpeechServiceClient::connect(format!("http://{}", address))
+            .await
+            .unwrap();
+        let request = |text: &str, voice: &str, speed: f32| SynthesizeRequest {
+            text: text.to_string(),
+            voice: voice.to_string(),
+            format: String::new(),
+            speed,
+        };
+        for (request, message) in [
+            (request("Hi", "robot", 0.0), "unknown voice"),
+            (request("Hi", "", 9.0), "outside"),
+            (request("  ", "Nova", 0.0), "no text"),
+        ] {
+            let error = client.synthesize(request).await.unwrap_err();
+            assert_eq!(error.code(), Code::InvalidArgument);
+            assert!(error.message().contains(message), "{}", error.message());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// The --post-cmd hook, a command run once for each audio file a run
+// produces once the file is complete, the --after-play hook, run once when
+// everything is played and written, and the --before-fetch filter the input
+// text is piped through.
+//
+// The command goes to the shell (`sh -c` on Unix, `cmd /C` on Windows), so
+// pipes, redirections and `&&` work as usual. `{file}`, `{format}` and
+// `{duration}` are replaced by values quoted for that shell, so they always
+// arrive as single arguments and must not be quoted again. --after-play gets
+// its values as environment variables instead.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Duration;
+
+// Quote `value` as one shell word
+#[cfg(unix)]
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(windows)]
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+// The command line for one file. The duration is in seconds, or empty when
+// it's unknown. The command is scanned once, so a value that happens to
+// contain a placeholder isn't substituted into again; other braces are left
+// for the shell.
+pub fn render(command: &str, file: &str, format: &str, duration: Option<Duration>) -> String {
+    let duration = duration.map_or(String::new(), |duration| {
+        format!("{:.3}", duration.as_secs_f64())
+    });
+    let values = [
+        ("{file}", file),
+        ("{format}", format),
+        ("{duration}", &duration),
+    ];
+
+    let mut rendered = String::new();
+    let mut rest = command;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values
+            .iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder))
+        {
+            Some((placeholder, value)) => {
+                rendered.push_str(&quote(value));
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// Pass a child's output through to stderr line by line, labelled
+fn relay(output: impl Read + Send + 'static, label: &'static str) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            eprintln!("{}: {}", label, line);
+        }
+    })
+}
+
+#[cfg(unix)]
+fn shell(command_line: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line);
+    command
+}
+
+#[cfg(windows)]
+fn shell(command_line: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(command_line);
+    command
+}
+
+// Run a command line with sayit's environment plus `env`, relaying what it
+// prints under `label`
+pub fn run(
+    command_line: &str,
+    label: &'static str,
+    env: &[(&str, String)],
+) -> io::Result<ExitStatus> {
+    let mut child = shell(command_line)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let relays = [
+        child.stdout.take().map(|output| relay(output, label)),
+        child.stderr.take().map(|output| relay(output, label)),
+    ];
+    let status = child.wait()?;
+    for relay in relays.into_iter().flatten() {
+        let _ = relay.join();
+    }
+    Ok(status)
+}
+
+// Pipe `text` through a command line and return what it prints; what it
+// prints to stderr is relayed
+pub fn filter(command_line: &str, text: &str) -> Result<String, String> {
+    let mut child = shell(command_line)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let relay = child
+        .stderr
+        .take()
+        .map(|output| relay(output, "before-fetch"));
+    // Written from another thread, so a command that prints as it reads
+    // can't fill the stdout pipe while sayit is still writing
+    let writer = child.stdin.take().map(|mut stdin| {
+        let text = text.to_string();
+        thread::spawn(move || stdin.write_all(text.as_bytes()))
+    });
+
+    let mut output = Vec::new();
+    let read = child
+        .stdout
+        .take()
+        .map_or(Ok(0), |mut stdout| stdout.read_to_end(&mut output));
+    let status = child.wait().map_err(|e| e.to_string())?;
+    if let Some(relay) = relay {
+        let _ = relay.join();
+    }
+    if !status.success() {
+        return Err(format!("it exited with {}", status));
+    }
+    read.map_err(|e| e.to_string())?;
+    // A command that exits without reading everything is fine
+    if let Some(Ok(Err(e))) = writer.map(|writer| writer.join()) {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            return Err(format!("couldn't send it the text: {}", e));
+        }
+    }
+    String::from_utf8(output).map_err(|_| "its output isn't valid UTF-8".to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_arrive_as_single_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("it's a $file.mp3");
+        std::fs::write(&file, b"").unwrap();
+        let file = file.to_str().unwrap();
+
+        let command = render(
+            "test -f {file} && test {format} = mp3 && test {duration} = 1.500",
+            file,
+            "mp3",
+            Some(Duration::from_millis(1_500)),
+        );
+        assert!(run(&command, "post-cmd", &[]).unwrap().success());
+
+        let unknown = render("test -z {duration}", file, "mp3", None);
+        assert!(run(&unknown, "post-cmd", &[]).unwrap().success());
+        let failing = run("echo to stderr >&2; exit 3", "post-cmd", &[]);
+        assert_eq!(failing.unwrap().code(), Some(3));
+        let env = [("SAYIT_CHUNKS", "12".to_string())];
+        assert!(run("test \"$SAYIT_CHUNKS\" = 12", "after-play", &env)
+            .unwrap()
+            .success());
+    }
+
+    #[test]
+    fn placeholders_in_the_values_are_left_alone() {
+        let command = render(
+            "mv {file} \"${DIR}/\"{format}",
+            "a{format}.mp3",
+            "mp3",
+            None,
+        );
+        assert_eq!(command, "mv 'a{format}.mp3' \"${DIR}/\"'mp3'");
+    }
+
+    #[test]
+    fn filters_rewrite_the_text_or_fail() {
+        let text = "Mr. Smith met Mr. Jones.\n".repeat(10_000);
+        let filtered = filter("sed 's/Mr\\./Mister/g'", &text).unwrap();
+        assert_eq!(filtered, "Mister Smith met Mister Jones.\n".repeat(10_000));
+        assert_eq!(filter("head -c 2", "Hello").unwrap(), "He");
+        assert!(filter("cat; exit 1", "Hello")
+            .unwrap_err()
+            .contains("exited"));
+    }
+}
@@ -0,0 +1,147 @@
+// Logging to the systemd journal (--log-target journald, with the systemd
+// feature on Linux). Records are sent over journald's native protocol, so
+// key-value pairs attached to a log call (`chunk_index = 3; "..."`) become
+// fields such as CHUNK_INDEX that `journalctl CHUNK_INDEX=3` can search.
+// RUST_LOG filters records just as it does on stderr.
+
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use std::os::unix::net::UnixDatagram;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+pub struct Journal {
+    filter: env_logger::Logger,
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    pub fn connect(filter: env_logger::Logger) -> std::io::Result<Journal> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(Journal { filter, socket })
+    }
+
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.filter.filter());
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+// syslog priorities
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Journal field names are upper case letters, digits and underscores, and
+// can't start with an underscore or a digit
+fn field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+    if name.is_empty() {
+        "FIELD".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// One field in the native protocol: `NAME=value\n`, or for values with a
+// newline in them, the name, a newline, the length as 64-bit little endian
+// and the raw value
+fn push_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        push_field(self.0, &field_name(key.as_str()), &value.to_string());
+        Ok(())
+    }
+}
+
+fn encode(record: &Record) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    push_field(&mut datagram, "MESSAGE", &record.args().to_string());
+    push_field(
+        &mut datagram,
+        "PRIORITY",
+        &priority(record.level()).to_string(),
+    );
+    push_field(&mut datagram, "SYSLOG_IDENTIFIER", "sayit");
+    push_field(&mut datagram, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        push_field(&mut datagram, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        push_field(&mut datagram, "CODE_LINE", &line.to_string());
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut datagram));
+    datagram
+}
+
+impl Log for Journal {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.matches(record) {
+            // There's nowhere left to report a lost record
+            let _ = self.socket.send(&encode(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_values_become_journal_fields() {
+        let kvs: &[(&str, Value)] = &[
+            ("chunk_index", Value::from(3)),
+            ("latency_ms", Value::from(250)),
+        ];
+        let record = Record::builder()
+            .args(format_args!("first line\nsecond line"))
+            .level(Level::Warn)
+            .target("sayit")
+            .key_values(&kvs)
+            .build();
+        let datagram = encode(&record);
+
+        let mut message = b"MESSAGE\n".to_vec();
+        message.extend_from_slice(&22u64.to_le_bytes());
+        message.extend_from_slice(b"first line\nsecond line\n");
+        assert!(datagram.starts_with(&message));
+        let text = String::from_utf8_lossy(&datagram);
+        assert!(text.contains("\nPRIORITY=4\n"));
+        assert!(text.contains("\nCHUNK_INDEX=3\n"));
+        assert!(text.ends_with("\nLATENCY_MS=250\n"));
+        assert_eq!(field_name("2nd-try"), "ND_TRY");
+    }
+}
@@ -0,0 +1,128 @@
+// A symlink that always points at the newest output (--latest-link). The
+// link is swapped in with a rename, so scripts following it never find it
+// missing or half made.
+
+use clap::ValueEnum;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// What the link points at when a run writes more than one file
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum LinkTo {
+    // The first file written
+    First,
+    // The directory holding them all
+    Dir,
+}
+
+// The absolute path the link should point at for `paths`
+pub fn target(paths: &[String], to: LinkTo) -> Option<PathBuf> {
+    let paths: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path)))
+        .collect();
+    match paths.as_slice() {
+        [] => None,
+        [path] => Some(path.clone()),
+        [first, ..] if to == LinkTo::First => Some(first.clone()),
+        _ => Some(crate::copy_path::common_dir(&paths)),
+    }
+}
+
+// Whether `link` may be replaced: a symlink always may, a file only with
+// --force, and a real directory never
+pub fn check(link: &Path, force: bool) -> Result<(), String> {
+    let Ok(metadata) = std::fs::symlink_metadata(link) else {
+        return Ok(());
+    };
+    if metadata.file_type().is_symlink() {
+        Ok(())
+    } else if metadata.is_dir() {
+        Err(format!("{} is a directory", link.display()))
+    } else if force {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} exists and isn't a symlink; pass --force to replace it",
+            link.display()
+        ))
+    }
+}
+
+// Point `link` at `target`, through a temporary link renamed over it
+pub fn update(link: &Path, target: &Path, force: bool) -> Result<(), String> {
+    check(link, force)?;
+    let name = link
+        .file_name()
+        .map_or("latest".into(), |name| name.to_string_lossy());
+    let temporary = link.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let _ = std::fs::remove_file(&temporary);
+    let made = make_link(target, &temporary).and_then(|_| std::fs::rename(&temporary, link));
+    made.map_err(|e| {
+        let _ = std::fs::remove_file(&temporary);
+        e.to_string()
+    })
+}
+
+#[cfg(unix)]
+fn make_link(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// Symlinks on Windows need Developer Mode or admin rights; without them the
+// file itself is copied instead, and a directory can't be linked at all
+#[cfg(windows)]
+fn make_link(target: &Path, link: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+
+    if target.is_dir() {
+        return symlink_dir(target, link);
+    }
+    symlink_file(target, link).or_else(|e| {
+        log::info!("Can't create a symlink ({}); copying the file instead", e);
+        std::fs::copy(target, link).map(|_| ())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn the_link_is_replaced_but_files_are_kept_without_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("latest.mp3");
+        let first = dir.path().join("one.mp3");
+        let second = dir.path().join("two.mp3");
+        std::fs::write(&first, b"one").unwrap();
+        std::fs::write(&second, b"two").unwrap();
+
+        update(&link, &first, false).unwrap();
+        update(&link, &second, false).unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), second);
+
+        let file = dir.path().join("notes.mp3");
+        std::fs::write(&file, b"keep me").unwrap();
+        assert!(update(&file, &second, false)
+            .unwrap_err()
+            .contains("--force"));
+        assert_eq!(std::fs::read(&file).unwrap(), b"keep me");
+        update(&file, &second, true).unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"two");
+    }
+
+    #[test]
+    fn multi_file_runs_link_the_first_file_or_the_directory() {
+        let paths = [
+            "/audio/book.part01.mp3".to_string(),
+            "/audio/book.part02.mp3".to_string(),
+        ];
+        assert_eq!(
+            target(&paths, LinkTo::First).unwrap(),
+            Path::new("/audio/book.part01.mp3")
+        );
+        assert_eq!(target(&paths, LinkTo::Dir).unwrap(), Path::new("/audio"));
+        assert!(target(&[], LinkTo::First).is_none());
+    }
+}
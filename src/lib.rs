@@ -0,0 +1,19 @@
+// sayit as a library, for other languages: a C interface (the `ffi`
+// feature, see include/sayit.h) and a WebAssembly one for browsers (the
+// `wasm` feature). The command line tool in main.rs doesn't use it.
+
+#[cfg(any(feature = "ffi", feature = "wasm"))]
+mod chunking;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+// Shared with the command line tool, which uses all of it
+#[cfg(feature = "ffi")]
+#[allow(dead_code)]
+mod merge;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(any(feature = "ffi", feature = "wasm"))]
+const SPEECH_ENDPOINT: &str = "https://api.openai.com/v1/audio/speech";
+#[cfg(any(feature = "ffi", feature = "wasm"))]
+const VOICES: [&str; 6] = ["alloy", "echo", "fable", "onyx", "nova", "shimmer"];
@@ -0,0 +1,168 @@
+// How many speech requests are in flight at once. The limit starts at the
+// ceiling (--concurrency) and adapts AIMD-style: a 429 halves it, and every
+// `limit` requests that succeed in a row raise it by one, so a run settles
+// just under whatever rate the API allows without any tuning.
+
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+pub const DEFAULT_CEILING: usize = 8;
+
+static LIMITER: OnceLock<Limiter> = OnceLock::new();
+
+// Set the ceiling for the run; only the first call has any effect
+pub fn init(ceiling: usize) {
+    let _ = LIMITER.set(Limiter::new(ceiling));
+}
+
+fn limiter() -> &'static Limiter {
+    LIMITER.get_or_init(|| Limiter::new(DEFAULT_CEILING))
+}
+
+// Wait for a turn to send a request
+pub async fn acquire() -> Permit<'static> {
+    limiter().acquire().await
+}
+
+struct State {
+    limit: usize,
+    // Successes since the limit last changed
+    successes: usize,
+    // Permits to retire as they're handed back, after the limit was cut
+    // below the number in flight
+    owed: usize,
+    // Bumped on every cut, so the 429s of requests sent under an old limit
+    // don't cut it again
+    generation: u64,
+}
+
+pub struct Limiter {
+    ceiling: usize,
+    permits: Semaphore,
+    state: Mutex<State>,
+}
+
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+    permit: Option<SemaphorePermit<'a>>,
+    generation: u64,
+}
+
+impl Limiter {
+    pub fn new(ceiling: usize) -> Limiter {
+        let ceiling = ceiling.max(1);
+        Limiter {
+            ceiling,
+            permits: Semaphore::new(ceiling),
+            state: Mutex::new(State {
+                limit: ceiling,
+                successes: 0,
+                owed: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        // The semaphore is never closed
+        let permit = self.permits.acquire().await.ok();
+        Permit {
+            limiter: self,
+            permit,
+            generation: self.state.lock().unwrap().generation,
+        }
+    }
+
+    #[cfg(test)]
+    fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+}
+
+impl Permit<'_> {
+    // The request went through: creep the limit back up towards the ceiling
+    pub fn succeeded(self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.successes += 1;
+        if state.successes < state.limit || state.limit == self.limiter.ceiling {
+            return;
+        }
+        state.successes = 0;
+        state.limit += 1;
+        if state.owed > 0 {
+            state.owed -= 1;
+        } else {
+            self.limiter.permits.add_permits(1);
+        }
+        log::debug!("Raised concurrency to {}", state.limit);
+    }
+
+    // The API answered 429: halve the limit, once per generation
+    pub fn rate_limited(self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if self.generation != state.generation || state.limit == 1 {
+            return;
+        }
+        let limit = (state.limit / 2).max(1);
+        state.owed += state.limit - limit;
+        state.limit = limit;
+        // Retire idle permits now rather than waiting for them to be used
+        while state.owed > 0 {
+            let Ok(idle) = self.limiter.permits.try_acquire() else {
+                break;
+            };
+            idle.forget();
+            state.owed -= 1;
+        }
+        state.successes = 0;
+        state.generation += 1;
+        log::debug!("Rate limited; lowered concurrency to {}", limit);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+        let mut state = self.limiter.state.lock().unwrap();
+        if state.owed > 0 {
+            state.owed -= 1;
+            permit.forget();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rate_limits_halve_the_limit_and_successes_raise_it() {
+        let limiter = Limiter::new(8);
+        let mut permits = Vec::new();
+        for _ in 0..8 {
+            permits.push(limiter.acquire().await);
+        }
+        assert_eq!(limiter.permits.available_permits(), 0);
+
+        // Several 429s from the same burst only count once
+        permits.pop().unwrap().rate_limited();
+        permits.pop().unwrap().rate_limited();
+        assert_eq!(limiter.limit(), 4);
+        // Two of the six still in flight are retired as they come back
+        permits.clear();
+        assert_eq!(limiter.permits.available_permits(), 4);
+
+        for _ in 0..4 {
+            limiter.acquire().await.succeeded();
+        }
+        assert_eq!(limiter.limit(), 5);
+        assert_eq!(limiter.permits.available_permits(), 5);
+
+        let permit = limiter.acquire().await;
+        permit.rate_limited();
+        assert_eq!(limiter.limit(), 2);
+        assert_eq!(limiter.permits.available_permits(), 2);
+    }
+}
@@ -0,0 +1,559 @@
+// M4B audiobooks: the API's ADTS AAC chunks remuxed into a single MP4
+// container with a chapter marker at every heading. .m4a files are the same
+// container without the chapters.
+//
+// Chapters are written twice, as a Nero `chpl` list and as a QuickTime
+// chapter text track, since players each read one or the other.
+
+use crate::tags::Tags;
+use crate::AudioChunk;
+use tokio::sync::mpsc;
+
+// AAC-LC always codes 1024 samples per frame
+const SAMPLES_PER_FRAME: u64 = 1024;
+
+const ADTS_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+// What the ADTS headers say about the stream, needed for the `esds` box
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct AacConfig {
+    object_type: u8,
+    sample_rate_index: u8,
+    channels: u8,
+}
+
+impl AacConfig {
+    fn sample_rate(&self) -> u32 {
+        ADTS_SAMPLE_RATES[usize::from(self.sample_rate_index)]
+    }
+
+    // The two-byte AudioSpecificConfig
+    fn audio_specific_config(&self) -> [u8; 2] {
+        let bits = (u16::from(self.object_type) << 11)
+            | (u16::from(self.sample_rate_index) << 7)
+            | (u16::from(self.channels) << 3);
+        bits.to_be_bytes()
+    }
+}
+
+// Split an ADTS stream into raw AAC frames
+fn parse_adts(bytes: &[u8]) -> Result<(AacConfig, Vec<&[u8]>), String> {
+    let mut frames = Vec::new();
+    let mut config = None;
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let header = bytes
+            .get(pos..pos + 7)
+            .ok_or("truncated ADTS header".to_string())?;
+        if header[0] != 0xff || header[1] & 0xf0 != 0xf0 {
+            return Err(format!("no ADTS sync word at byte {}", pos));
+        }
+        let header_len = if header[1] & 0x01 == 0 { 9 } else { 7 };
+        let frame_config = AacConfig {
+            object_type: (header[2] >> 6) + 1,
+            sample_rate_index: (header[2] >> 2) & 0x0f,
+            channels: ((header[2] & 0x01) << 2) | (header[3] >> 6),
+        };
+        if usize::from(frame_config.sample_rate_index) >= ADTS_SAMPLE_RATES.len() {
+            return Err("invalid ADTS sample rate".to_string());
+        }
+        if *config.get_or_insert(frame_config) != frame_config {
+            return Err("the AAC stream changes format part way through".to_string());
+        }
+        if header[6] & 0x03 != 0 {
+            return Err("ADTS frames holding several AAC frames aren't supported".to_string());
+        }
+        let frame_len = (usize::from(header[3] & 0x03) << 11)
+            | (usize::from(header[4]) << 3)
+            | usize::from(header[5] >> 5);
+        let frame = bytes
+            .get(pos + header_len..pos + frame_len)
+            .ok_or("truncated ADTS frame".to_string())?;
+        frames.push(frame);
+        pos += frame_len;
+    }
+
+    let config = config.ok_or("no AAC audio".to_string())?;
+    Ok((config, frames))
+}
+
+// Chapter start times in 100ns units (what `chpl` uses): a chapter starts
+// with its chunk, so it begins after everything before that chunk.
+// `chunk_samples` is the duration of every chunk in samples at `sample_rate`.
+fn chapter_starts(
+    chapters: &[(usize, String)],
+    chunk_samples: &[u64],
+    sample_rate: u32,
+) -> Vec<(u64, String)> {
+    let mut starts: Vec<(u64, String)> = chapters
+        .iter()
+        .map(|(chunk, title)| {
+            let samples: u64 = chunk_samples.iter().take(*chunk).sum();
+            (samples * 10_000_000 / u64::from(sample_rate), title.clone())
+        })
+        .collect();
+
+    // Players expect the first chapter to start at the very beginning
+    if starts.first().is_some_and(|(start, _)| *start > 0) {
+        starts.insert(0, (0, "Introduction".to_string()));
+    }
+    starts
+}
+
+fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut body = ((u32::from(version) << 24) | flags).to_be_bytes().to_vec();
+    body.extend_from_slice(payload);
+    mp4_box(kind, &body)
+}
+
+fn container(kind: &[u8; 4], children: &[Vec<u8>]) -> Vec<u8> {
+    mp4_box(kind, &children.concat())
+}
+
+fn u16s(values: &[u16]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+const IDENTITY_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+const AUDIO_TRACK: u32 = 1;
+const CHAPTER_TRACK: u32 = 2;
+const MOVIE_TIMESCALE: u32 = 1000;
+
+fn mvhd(duration_ms: u32, next_track: u32) -> Vec<u8> {
+    let mut payload = u32s(&[0, 0, MOVIE_TIMESCALE, duration_ms, 0x10000]);
+    payload.extend(u16s(&[0x100, 0]));
+    payload.extend(u32s(&[0, 0]));
+    payload.extend(u32s(&IDENTITY_MATRIX));
+    payload.extend(u32s(&[0; 6]));
+    payload.extend(u32s(&[next_track]));
+    full_box(b"mvhd", 0, 0, &payload)
+}
+
+fn tkhd(track: u32, flags: u32, duration_ms: u32, volume: u16) -> Vec<u8> {
+    let mut payload = u32s(&[0, 0, track, 0, duration_ms, 0, 0]);
+    payload.extend(u16s(&[0, 0, volume, 0]));
+    payload.extend(u32s(&IDENTITY_MATRIX));
+    payload.extend(u32s(&[0, 0]));
+    full_box(b"tkhd", 0, flags, &payload)
+}
+
+fn mdhd(timescale: u32, duration: u32) -> Vec<u8> {
+    let mut payload = u32s(&[0, 0, timescale, duration]);
+    // ISO 639-2 "und", packed as three 5-bit letters
+    payload.extend(u16s(&[0x55c4, 0]));
+    full_box(b"mdhd", 0, 0, &payload)
+}
+
+fn hdlr(handler: &[u8; 4], name: &str) -> Vec<u8> {
+    let mut payload = u32s(&[0]);
+    payload.extend_from_slice(handler);
+    payload.extend(u32s(&[0, 0, 0]));
+    payload.extend_from_slice(name.as_bytes());
+    payload.push(0);
+    full_box(b"hdlr", 0, 0, &payload)
+}
+
+fn dinf() -> Vec<u8> {
+    let url = full_box(b"url ", 0, 1, &[]);
+    let mut dref = u32s(&[1]);
+    dref.extend(url);
+    container(b"dinf", &[full_box(b"dref", 0, 0, &dref)])
+}
+
+// A sample table with every sample in one chunk at `offset`
+fn stbl(sample_entry: Vec<u8>, durations: &[u32], sizes: &[u32], offset: u32) -> Vec<u8> {
+    let mut stsd = u32s(&[1]);
+    stsd.extend(sample_entry);
+
+    // Run-length encode the sample durations
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &duration in durations {
+        match runs.last_mut() {
+            Some((count, last)) if *last == duration => *count += 1,
+            _ => runs.push((1, duration)),
+        }
+    }
+    let mut stts = u32s(&[runs.len() as u32]);
+    for (count, duration) in runs {
+        stts.extend(u32s(&[count, duration]));
+    }
+
+    let mut stsz = u32s(&[0, sizes.len() as u32]);
+    stsz.extend(u32s(sizes));
+
+    container(
+        b"stbl",
+        &[
+            full_box(b"stsd", 0, 0, &stsd),
+            full_box(b"stts", 0, 0, &stts),
+            full_box(b"stsc", 0, 0, &u32s(&[1, 1, sizes.len() as u32, 1])),
+            full_box(b"stsz", 0, 0, &stsz),
+            full_box(b"stco", 0, 0, &u32s(&[1, offset])),
+        ],
+    )
+}
+
+fn mp4a(config: AacConfig) -> Vec<u8> {
+    let asc = config.audio_specific_config();
+    let mut decoder_specific = vec![0x05, asc.len() as u8];
+    decoder_specific.extend_from_slice(&asc);
+
+    // Object type 0x40 is MPEG-4 audio; stream type 5 (audio) with the
+    // reserved bit set
+    let mut decoder_config = vec![0x04, 0, 0x40, 0x15, 0, 0, 0];
+    decoder_config.extend(u32s(&[0, 0]));
+    decoder_config.extend(decoder_specific);
+    decoder_config[1] = (decoder_config.len() - 2) as u8;
+
+    let mut es = vec![0x03, 0, 0, 0, 0];
+    es.extend(decoder_config);
+    es.extend_from_slice(&[0x06, 0x01, 0x02]);
+    es[1] = (es.len() - 2) as u8;
+
+    let mut payload = vec![0; 6];
+    payload.extend(u16s(&[1]));
+    payload.extend(u32s(&[0, 0]));
+    payload.extend(u16s(&[u16::from(config.channels), 16, 0, 0]));
+    payload.extend(u32s(&[config.sample_rate() << 16]));
+    payload.extend(full_box(b"esds", 0, 0, &es));
+    mp4_box(b"mp4a", &payload)
+}
+
+fn tx3g() -> Vec<u8> {
+    let mut payload = vec![0; 6];
+    payload.extend(u16s(&[1]));
+    payload.extend(u32s(&[0])); // display flags
+    payload.extend_from_slice(&[0x01, 0xff]); // centred, at the bottom
+    payload.extend_from_slice(&[0, 0, 0, 0]); // transparent background
+    payload.extend(u16s(&[0, 0, 0, 0])); // default text box
+    payload.extend(u16s(&[0, 0, 1])); // style record: chars 0-0, font 1
+    payload.extend_from_slice(&[0, 0x12, 0xff, 0xff, 0xff, 0xff]);
+    let mut ftab = u16s(&[1, 1]);
+    ftab.push(5);
+    ftab.extend_from_slice(b"Serif");
+    payload.extend(mp4_box(b"ftab", &ftab));
+    mp4_box(b"tx3g", &payload)
+}
+
+// Nero chapter list
+fn chpl(chapters: &[(u64, String)]) -> Vec<u8> {
+    let mut payload = u32s(&[0]);
+    payload.push(chapters.len().min(255) as u8);
+    for (start, title) in chapters.iter().take(255) {
+        payload.extend_from_slice(&start.to_be_bytes());
+        let title = truncate(title, 255);
+        payload.push(title.len() as u8);
+        payload.extend_from_slice(title.as_bytes());
+    }
+    full_box(b"chpl", 1, 0, &payload)
+}
+
+fn truncate(text: &str, max_len: usize) -> &str {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// iTunes-style metadata, where the cover lives
+fn meta(tags: Option<&Tags>, cover: Option<&[u8]>) -> Option<Vec<u8>> {
+    let item = |kind: &[u8; 4], data_type: u32, value: &[u8]| {
+        let mut data = u32s(&[data_type, 0]);
+        data.extend_from_slice(value);
+        mp4_box(kind, &mp4_box(b"data", &data))
+    };
+    const UTF8: u32 = 1;
+
+    let mut items = Vec::new();
+    if let Some(tags) = tags {
+        let fields = [
+            (b"\xa9nam", &tags.title),
+            (b"\xa9ART", &tags.artist),
+            (b"\xa9alb", &tags.album),
+            (b"\xa9cmt", &tags.comment),
+        ];
+        for (kind, value) in fields {
+            if let Some(value) = value {
+                items.push(item(kind, UTF8, value.as_bytes()));
+            }
+        }
+    }
+    if let Some(cover) = cover {
+        let image_type = if cover.starts_with(b"\x89PNG") {
+            14
+        } else {
+            13
+        };
+        items.push(item(b"covr", image_type, cover));
+    }
+    if items.is_empty() {
+        return None;
+    }
+
+    let mut hdlr = hdlr(b"mdir", "");
+    // The handler's first reserved field carries the "appl" manufacturer code
+    hdlr[20..24].copy_from_slice(b"appl");
+    Some(full_box(
+        b"meta",
+        0,
+        0,
+        &[hdlr, container(b"ilst", &items)].concat(),
+    ))
+}
+
+// Everything about the book besides its audio
+#[derive(Default)]
+pub struct Audiobook {
+    // (chunk index, title) of each chapter's first chunk
+    pub chapters: Vec<(usize, String)>,
+    pub tags: Option<Tags>,
+    pub cover: Option<Vec<u8>>,
+}
+
+// Remux ADTS chunks (in order) into an M4B file
+pub fn mux(chunks: &[&[u8]], book: &Audiobook) -> Result<Vec<u8>, String> {
+    let mut config = None;
+    let mut frames = Vec::new();
+    let mut chunk_samples = Vec::new();
+    for chunk in chunks {
+        let (chunk_config, chunk_frames) = parse_adts(chunk)?;
+        if *config.get_or_insert(chunk_config) != chunk_config {
+            return Err("the chunks were encoded with different AAC settings".to_string());
+        }
+        chunk_samples.push(chunk_frames.len() as u64 * SAMPLES_PER_FRAME);
+        frames.extend(chunk_frames);
+    }
+    let config = config.ok_or("no audio to write".to_string())?;
+    let sample_rate = config.sample_rate();
+
+    let total_samples: u64 = chunk_samples.iter().sum();
+    let duration_ms = u32::try_from(total_samples * 1000 / u64::from(sample_rate))
+        .map_err(|_| "the audio is too long for an M4B file".to_string())?;
+    let audio_duration =
+        u32::try_from(total_samples).map_err(|_| "the audio is too long".to_string())?;
+
+    let chapters = chapter_starts(&book.chapters, &chunk_samples, sample_rate);
+    // Chapter text samples: a length-prefixed title lasting until the next one
+    let mut chapter_samples = Vec::new();
+    let mut chapter_durations = Vec::new();
+    for (i, (start, title)) in chapters.iter().enumerate() {
+        let start_ms = start / 10_000;
+        let end_ms = chapters
+            .get(i + 1)
+            .map_or(u64::from(duration_ms), |(next, _)| next / 10_000);
+        let title = truncate(title, u16::MAX as usize);
+        let mut sample = (title.len() as u16).to_be_bytes().to_vec();
+        sample.extend_from_slice(title.as_bytes());
+        chapter_samples.push(sample);
+        chapter_durations.push(end_ms.saturating_sub(start_ms) as u32);
+    }
+
+    let audio_sizes: Vec<u32> = frames.iter().map(|frame| frame.len() as u32).collect();
+    let audio_len: usize = frames.iter().map(|frame| frame.len()).sum();
+    let chapter_sizes: Vec<u32> = chapter_samples.iter().map(|s| s.len() as u32).collect();
+
+    let ftyp = mp4_box(b"ftyp", b"M4B \0\0\0\0M4B M4A mp42isom");
+    let build_moov = |data_offset: u32| {
+        let mut audio_trak = vec![tkhd(AUDIO_TRACK, 0x3, duration_ms, 0x100)];
+        if !chapters.is_empty() {
+            let chap = mp4_box(b"chap", &u32s(&[CHAPTER_TRACK]));
+            audio_trak.push(mp4_box(b"tref", &chap));
+        }
+        audio_trak.push(container(
+            b"mdia",
+            &[
+                mdhd(sample_rate, audio_duration),
+                hdlr(b"soun", "SoundHandler"),
+                container(
+                    b"minf",
+                    &[
+                        full_box(b"smhd", 0, 0, &[0; 4]),
+                        dinf(),
+                        stbl(
+                            mp4a(config),
+                            &vec![SAMPLES_PER_FRAME as u32; frames.len()],
+                            &audio_sizes,
+                            data_offset,
+                        ),
+                    ],
+                ),
+            ],
+        ));
+
+        let mut moov = vec![
+            mvhd(duration_ms, CHAPTER_TRACK + 1),
+            container(b"trak", &audio_trak),
+        ];
+        if !chapters.is_empty() {
+            // Present but not enabled, so players don't show it as subtitles
+            moov.push(container(
+                b"trak",
+                &[
+                    tkhd(CHAPTER_TRACK, 0x2, duration_ms, 0),
+                    container(
+                        b"mdia",
+                        &[
+                            mdhd(MOVIE_TIMESCALE, duration_ms),
+                            hdlr(b"text", "ChapterHandler"),
+                            container(
+                                b"minf",
+                                &[
+                                    full_box(b"nmhd", 0, 0, &[]),
+                                    dinf(),
+                                    stbl(
+                                        tx3g(),
+                                        &chapter_durations,
+                                        &chapter_sizes,
+                                        data_offset + audio_len as u32,
+                                    ),
+                                ],
+                            ),
+                        ],
+                    ),
+                ],
+            ));
+        }
+
+        let mut udta = Vec::new();
+        if !chapters.is_empty() {
+            udta.push(chpl(&chapters));
+        }
+        if let Some(meta) = meta(book.tags.as_ref(), book.cover.as_deref()) {
+            udta.push(meta);
+        }
+        if !udta.is_empty() {
+            moov.push(container(b"udta", &udta));
+        }
+        container(b"moov", &moov)
+    };
+
+    // The moov box comes first so players can start before reading it all;
+    // its size doesn't depend on the offset it records
+    let moov_len = build_moov(0).len();
+    let data_offset = ftyp.len() + moov_len + 8;
+    let mdat_len = 8 + audio_len + chapter_sizes.iter().sum::<u32>() as usize;
+    let data_offset = u32::try_from(data_offset + mdat_len)
+        .map(|_| data_offset as u32)
+        .map_err(|_| "the audio is too large for an M4B file (over 4 GiB)".to_string())?;
+
+    let mut out = ftyp;
+    out.extend(build_moov(data_offset));
+    out.extend_from_slice(&(mdat_len as u32).to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    for frame in frames {
+        out.extend_from_slice(frame);
+    }
+    for sample in chapter_samples {
+        out.extend(sample);
+    }
+    Ok(out)
+}
+
+// Collect every chunk, then remux them into `file_path` in chunk order
+pub async fn audio_to_m4b(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    file_path: String,
+    book: Audiobook,
+) {
+    let mut chunks = Vec::new();
+    while let Some(chunk) = audio_rx.recv().await {
+        chunks.push(chunk);
+    }
+    chunks.sort_by_key(|chunk| chunk.index);
+
+    let bytes: Vec<&[u8]> = chunks.iter().map(|chunk| &chunk.bytes[..]).collect();
+    let result = mux(&bytes, &book)
+        .and_then(|m4b| std::fs::write(&file_path, m4b).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        eprintln!("Failed to write {}: {}", file_path, e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An ADTS frame of 24 kHz mono AAC-LC around `payload`
+    fn adts_frame(payload: &[u8]) -> Vec<u8> {
+        let len = payload.len() + 7;
+        let mut frame = vec![
+            0xff,
+            0xf1,
+            0x40 | (6 << 2),
+            0x40 | (len >> 11) as u8,
+            (len >> 3) as u8,
+            ((len & 0x7) << 5) as u8 | 0x1f,
+            0xfc,
+        ];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn chapters_start_where_their_chunk_starts() {
+        let chapters = vec![(1, "One".to_string()), (3, "Two".to_string())];
+        // Chunks of 1s, 2s and 0.5s at 24 kHz
+        let starts = chapter_starts(&chapters, &[24000, 48000, 12000], 24000);
+
+        assert_eq!(
+            starts,
+            vec![
+                (0, "Introduction".to_string()),
+                (10_000_000, "One".to_string()),
+                (35_000_000, "Two".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn muxed_file_holds_every_frame_and_the_chapters() {
+        let first = [adts_frame(&[1; 10]), adts_frame(&[2; 20])].concat();
+        let second = adts_frame(&[3; 30]);
+        let book = Audiobook {
+            chapters: vec![(0, "Beginnings".to_string()), (1, "Endings".to_string())],
+            ..Default::default()
+        };
+
+        let m4b = mux(&[&first, &second], &book).unwrap();
+
+        let (config, frames) = parse_adts(&first).unwrap();
+        assert_eq!(config.sample_rate(), 24000);
+        assert_eq!(config.audio_specific_config(), [0x13, 0x08]);
+        assert_eq!(frames, [&[1; 10][..], &[2; 20][..]]);
+
+        assert_eq!(&m4b[4..12], b"ftypM4B ");
+        let find = |needle: &[u8]| m4b.windows(needle.len()).position(|w| w == needle);
+        let mdat = find(b"mdat").unwrap();
+        assert_eq!(&m4b[mdat + 4..mdat + 14], &[1; 10]);
+        assert!(find(b"chpl").unwrap() < mdat);
+        assert!(find(b"Beginnings").unwrap() < mdat);
+        // The second chapter starts after two frames: 2048 / 24000 s
+        let endings = find(b"\x07Endings").unwrap();
+        let start = u64::from_be_bytes(m4b[endings - 8..endings].try_into().unwrap());
+        assert_eq!(start, 2048 * 10_000_000 / 24000);
+        assert!(find(b"\x00\x07Endings").unwrap() > mdat);
+    }
+}
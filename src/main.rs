@@ -1,51 +1,735 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use bytes::Bytes;
-use clap::{CommandFactory, Parser, ValueEnum};
+use chunking::split_input;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use clipboard::{ClipboardContext, ClipboardProvider};
-use env_logger;
-use log;
-use reqwest::Client;
+use encoding_rs::{Encoding, UTF_8};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use regex::{Regex, RegexSet};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+use rodio::buffer::SamplesBuffer;
 use rodio::{source::Source, Decoder, OutputStream, Sink};
+use serde::Deserialize;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, Cursor, Read, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use summary::FetchOutcome;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+mod archive;
+mod breaker;
+mod chapters;
+mod chunking;
+mod clipboard_format;
+mod completions;
+mod config;
+mod copy_path;
+mod dump_chunks;
+mod epub;
+mod grpc;
+mod hooks;
+#[cfg(all(feature = "systemd", target_os = "linux"))]
+mod journal;
+mod latest_link;
+mod limiter;
+mod m4b;
+mod manifest;
+mod manpage;
+mod memory;
+mod merge;
+mod merge_dir;
+mod notebook;
+mod playlist;
+mod preprocess;
+mod preview;
+mod progress;
+mod pronounce;
+mod resample;
+mod selftest;
+mod serve;
+mod split;
+mod sse;
+mod subtitles;
+mod summary;
+mod tags;
+mod template;
+mod transcript;
+mod upload;
+mod voices;
 
 #[derive(Parser)]
 #[command(version = "0.1", about = "Text to speech utility", long_about = None)]
+#[command(group(clap::ArgGroup::new("output_target").args(["output_file", "stdout"])))]
+#[command(group(clap::ArgGroup::new("input_source").args(["input_file", "clipboard", "use_stdin", "man"])))]
+#[command(group(clap::ArgGroup::new("unicode_form").args(["unicode_nfc", "unicode_nfkc", "unicode_nfkd"])))]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     // optional input file to operate on
     input_file: Option<String>,
 
-    // Sets a custom config file
+    // Write the audio to FILE (`-` for stdout); see --config for the config file
     #[arg(short, long, value_name = "FILE")]
     output_file: Option<String>,
 
+    // Write the audio to stdout (same as `-o -`)
+    #[arg(long)]
+    stdout: bool,
+
     // Set a format option
     #[arg(short = 'f', long, value_name = "FORMAT")]
     format: Option<ResponseFormat>,
 
+    // Print every format with a note on what it's good for, and exit
+    #[arg(long)]
+    list_formats: bool,
+
+    // Write the man pages (sayit.1 and sayit-config.5) into DIR and exit; for
+    // packagers, so not shown in --help
+    #[arg(long, value_name = "DIR", hide = true)]
+    generate_manpage: Option<String>,
+
+    // Run every check a real run makes up to the first API request, report
+    // "configuration OK" or the problem, and exit without fetching anything
+    #[arg(long)]
+    validate: bool,
+
+    // Read defaults from FILE instead of the usual config file
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+
+    // Ignore the config file, for runs that don't depend on this machine
+    #[arg(long, conflicts_with = "config")]
+    no_config: bool,
+
+    // Use the config file's [profile.NAME] section over the rest of it
+    #[arg(long, value_name = "NAME", conflicts_with = "no_config")]
+    profile: Option<String>,
+
     // Set a voice
     #[arg(short = 'v', long, value_name = "VOICE")]
     voice: Option<Voice>,
 
     // Set a speed (0.25 - 4.0)
-    #[arg(short = 's', long)]
+    #[arg(short = 's', long, value_parser = parse_speed)]
     speed: Option<f32>,
 
+    // Read text between [[speed:1.3]] and [[/speed]] markers in the input at
+    // that speed instead
+    #[arg(long)]
+    speed_per_segment: bool,
+
+    // Aim for roughly N words per minute by picking the nearest speed
+    #[arg(long, value_name = "N", conflicts_with = "speed", value_parser = clap::value_parser!(u32).range(1..))]
+    target_wpm: Option<u32>,
+
     // Boolean HD flag
     #[arg(long)]
     hd: bool,
 
+    // The model to read with, over the config file's
+    #[arg(long, value_name = "MODEL", value_parser = config::MODELS, conflicts_with = "hd")]
+    model: Option<String>,
+
     // Boolean clipboard flag (reads whatever is currently pasted in the clipboard.)
     #[arg(short = 'c', long)]
     clipboard: bool,
 
+    // Read the clipboard as a particular type; HTML is read as plain prose
+    // and URI lists as one path or URI per line. Implies --clipboard.
+    #[arg(long, value_name = "FORMAT", conflicts_with_all = ["input_file", "use_stdin", "man"])]
+    read_clipboard_format: Option<clipboard_format::ClipboardFormat>,
+
     // Read from stdin
     #[arg(short = 'd', long)]
     use_stdin: bool,
+
+    // Read the man page for COMMAND
+    #[arg(long, value_name = "COMMAND")]
+    man: Option<String>,
+
+    // Skip the first N characters of the input
+    #[arg(long, value_name = "CHARS")]
+    offset: Option<usize>,
+
+    // Read only N characters of the input (after --offset)
+    #[arg(long, value_name = "CHARS")]
+    length: Option<usize>,
+
+    // Character encoding of the input file or stdin, e.g. latin1 or
+    // windows-1252 (default utf-8; invalid bytes are replaced with a warning)
+    #[arg(long, value_name = "ENC", value_parser = parse_encoding)]
+    input_encoding: Option<&'static Encoding>,
+
+    // Play the chunks in a random order (flash-card style listening)
+    #[arg(long)]
+    shuffle_chunks: bool,
+
+    // Seed for --shuffle-chunks, to reproduce a previous order
+    #[arg(long, value_name = "N", requires = "shuffle_chunks")]
+    seed: Option<u64>,
+
+    // Read the chunks in reverse order, for right-to-left documents
+    #[arg(long, conflicts_with = "shuffle_chunks")]
+    rtl: bool,
+
+    // Write each chunk to its own file, e.g. "part-{index:03}-{voice}.{ext}"
+    // (placeholders: index, voice, model, ext, input, chapter)
+    #[arg(long, value_name = "TEMPLATE", conflicts_with = "output_file")]
+    output_template: Option<String>,
+
+    // Write each chunk to DIR/chunk_000.<ext>, chunk_001.<ext>, ... instead of
+    // one merged file
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output_file", "output_template"])]
+    split_output_dir: Option<String>,
+
+    // Stitch DIR/chunk_*.<ext> (as written by --split-output-dir, perhaps
+    // since edited) into the output file, in order, and exit
+    #[arg(long, value_name = "DIR", requires = "output_target", conflicts_with_all = ["input_source", "output_template", "split_output_dir", "output_dir"])]
+    merge: Option<String>,
+
+    // Directory for every file the run generates (per-chunk files, the
+    // playlist); created if missing. Without --output-template each chunk is
+    // written as <input>_000.<ext>, or chunk_000.<ext> without an input file.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output_target", "split_output_dir"])]
+    output_dir: Option<String>,
+
+    // With a .ipynb input file, also read the code cells (only the markdown
+    // cells are read by default)
+    #[arg(long)]
+    read_code_cells: bool,
+
+    // With a .ipynb input file, also read code cell output (printed text and
+    // plain-text results)
+    #[arg(long)]
+    read_cell_outputs: bool,
+
+    // Read the input file as an EPUB book (implied by a .epub extension):
+    // its chapters in reading order, titled from the table of contents. With
+    // --output-dir each chapter is saved as a file of its own.
+    #[arg(long, requires = "input_file")]
+    epub: bool,
+
+    // Normalize the input to Unicode NFC before any other preprocessing
+    // (text pasted from macOS or PDFs is often decomposed)
+    #[arg(long)]
+    unicode_nfc: bool,
+
+    // Normalize the input to Unicode NFKC (also folds compatibility forms
+    // such as ligatures and full-width letters)
+    #[arg(long)]
+    unicode_nfkc: bool,
+
+    // Normalize the input to Unicode NFKD
+    #[arg(long)]
+    unicode_nfkd: bool,
+
+    // Read AsciiDoc as prose: headings become titles, formatting markers are
+    // dropped and source blocks are announced instead of read out
+    #[arg(long)]
+    strip_asciidoc: bool,
+
+    // Read reStructuredText as prose: underlined headings become titles,
+    // admonitions are announced, and code blocks and other directives are
+    // skipped
+    #[arg(long, conflicts_with = "strip_asciidoc")]
+    strip_rst: bool,
+
+    // Also write an extended M3U playlist of the per-chunk files or parts,
+    // e.g. book.m3u8 (needs --output-template, --split-output-dir or
+    // --split-every)
+    #[arg(long, value_name = "FILE")]
+    playlist: Option<String>,
+
+    // Leave out input lines matching REGEX (repeatable; any match skips)
+    #[arg(long, value_name = "REGEX")]
+    skip_pattern: Vec<String>,
+
+    // Leave out blocks from a line matching REGEX through a line matching
+    // --skip-block-end
+    #[arg(long, value_name = "REGEX", requires = "skip_block_end")]
+    skip_block_start: Option<String>,
+
+    // End of a block started by --skip-block-start
+    #[arg(long, value_name = "REGEX", requires = "skip_block_start")]
+    skip_block_end: Option<String>,
+
+    // Replace regex matches before reading, e.g. --replace '\bNASA\b' 'NASA the
+    // space agency' (repeatable, applied in order; `$1` refers to groups)
+    #[arg(long, num_args = 2, value_names = ["PATTERN", "REPLACEMENT"])]
+    replace: Vec<String>,
+
+    // Say terms the way a dictionary file gives them, one `WRITTEN ->
+    // SPOKEN` entry per line (e.g. SQL -> "sequel"), matching whole words
+    #[arg(long, value_name = "PATH")]
+    pronounce_file: Option<String>,
+
+    // Match --pronounce-file terms regardless of case
+    #[arg(long, requires = "pronounce_file")]
+    pronounce_ignore_case: bool,
+
+    // Spoken before the input, as a segment of its own, e.g. an intro
+    #[arg(long, value_name = "TEXT")]
+    prefix: Option<String>,
+
+    // Spoken after the input, as a segment of its own, e.g. an outro
+    #[arg(long, value_name = "TEXT")]
+    suffix: Option<String>,
+
+    // Announce "Chapter N: <title>" (in HD) at each heading line
+    #[arg(long)]
+    chapter_announce: bool,
+
+    // Regex matching heading lines for --chapter-announce; a `title` capture
+    // group, if present, is used as the chapter title
+    #[arg(long, value_name = "REGEX", requires = "chapter_announce")]
+    chapter_pattern: Option<String>,
+
+    // Wrap --format pcm output in a WAV header (implied by a .wav output file)
+    #[arg(long, requires = "output_target")]
+    wav: bool,
+
+    // Resample pcm and WAV output, and what's played, to HZ (the API's is
+    // 24000)
+    #[arg(long, visible_alias = "wav-rate", value_name = "HZ", value_parser = clap::value_parser!(u32).range(8000..=192000))]
+    rate: Option<u32>,
+
+    // Write pcm and WAV output with BITS-bit samples (the API's are 16)
+    #[arg(long, value_name = "16|24", value_parser = parse_wav_bits)]
+    wav_bits: Option<u16>,
+
+    // Convert pcm and WAV output, and what's played, to mono or stereo
+    #[arg(long, value_name = "1|2", value_parser = clap::value_parser!(u16).range(1..=2))]
+    channels: Option<u16>,
+
+    // Silence to put between chunks when they're assembled into the output
+    // file; only pcm, WAV and FLAC, whose chunks are joined as samples
+    #[arg(long, value_name = "MS", default_value_t = 0, conflicts_with_all = ["base64_output", "output_template", "split_output_dir", "output_dir", "split_every", "split_output_by_size", "merge"])]
+    join_gap_ms: u64,
+
+    // Write the audio as base64 text to the output file, or stdout without one
+    #[arg(long, conflicts_with_all = ["play", "wav", "output_template", "split_output_dir", "output_dir"])]
+    base64_output: bool,
+
+    // Encode each chunk separately, one per line
+    #[arg(long, requires = "base64_output")]
+    base64_lines: bool,
+
+    // Prefix the base64 audio with a `data:<mime>;base64,` URI scheme
+    #[arg(long, requires = "base64_output")]
+    data_uri: bool,
+
+    // Print the end-of-run summary as JSON; with --base64-output, also emit
+    // one JSON object per chunk with the audio in an `audio` field
+    #[arg(long)]
+    json: bool,
+
+    // Don't print the end-of-run summary
+    #[arg(short, long)]
+    quiet: bool,
+
+    // Write a JSON manifest describing each chunk: the text it reads, how it
+    // was synthesized, and where its audio is
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<String>,
+
+    // Write the text exactly as it's sent for synthesis, after all
+    // preprocessing, with a "--- chunk N ---" line before each chunk
+    #[arg(long, value_name = "PATH")]
+    save_text: Option<String>,
+
+    // Also write each chunk's response, untouched, to DIR/chunk_000.<ext>
+    // as it arrives, to tell API problems from merging and decoding ones
+    #[arg(long, value_name = "DIR")]
+    dump_chunks_dir: Option<String>,
+
+    // Write approximate subtitles for the audio, one cue per sentence (SRT or
+    // WebVTT, by extension)
+    #[arg(long, value_name = "FILE")]
+    subtitles: Option<String>,
+
+    // Report progress on stderr as JSON lines: chunk-started,
+    // chunk-completed, chunk-failed, chunk-cancelled and done events
+    #[arg(long)]
+    progress_json: bool,
+
+    // Send log records to stderr or, in builds with the systemd feature, to
+    // the systemd journal with fields such as CHUNK_INDEX and LATENCY_MS
+    #[arg(long, value_name = "TARGET", default_value = "stderr")]
+    log_target: LogTarget,
+
+    // Print the full (pretty-printed) error response when a request fails
+    #[arg(long)]
+    show_api_errors: bool,
+
+    // Ask for the audio as server-sent events (stream_format "sse"), logging
+    // the metadata that comes with it (RUST_LOG=debug); models or servers
+    // without SSE get plain responses instead
+    #[arg(long)]
+    sse: bool,
+
+    // The OpenAI organization to bill requests to (OpenAI-Organization)
+    #[arg(long, value_name = "ID", env = "OPENAI_ORG", value_parser = parse_header_value)]
+    org: Option<HeaderValue>,
+
+    // The OpenAI project to bill requests to (OpenAI-Project)
+    #[arg(long, value_name = "ID", env = "OPENAI_PROJECT", value_parser = parse_header_value)]
+    project: Option<HeaderValue>,
+
+    // The User-Agent to send with API requests
+    #[arg(long, value_name = "STRING", value_parser = parse_header_value)]
+    user_agent: Option<HeaderValue>,
+
+    // An extra header to send with every API request, as "Name: Value"
+    // (repeatable)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    // Don't send OPENAI_API_KEY as "Authorization: Bearer", for compatible
+    // APIs that authenticate some other way (with --header, which may then
+    // set Authorization itself); the key isn't needed at all
+    #[arg(long)]
+    no_auth: bool,
+
+    // Overwrite existing output files, and write the output even if --format
+    // contradicts the file extension
+    #[arg(long)]
+    force: bool,
+
+    // Add to the end of an existing output file instead of refusing to touch
+    // it; the file must already be in the same format (not .m4a or .m4b, and base64
+    // only per chunk)
+    #[arg(long, requires = "output_file", conflicts_with = "force")]
+    append: bool,
+
+    // Write the output file in place rather than into `<file>.part` and
+    // renaming it once complete (for filesystems without a working rename)
+    #[arg(long)]
+    no_atomic: bool,
+
+    // Like make: skip the run when the output file is newer than the input
+    // file, and otherwise overwrite the stale output without --force
+    #[arg(long, requires_all = ["input_file", "output_file"], conflicts_with_all = ["append", "archive"])]
+    since_modified: bool,
+
+    // Stop once this many bytes of audio have been produced, finishing the
+    // chunk that reaches the limit (for short previews)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_output_bytes: Option<u64>,
+
+    // Most requests to the API in flight at once. Fewer are sent while it
+    // answers 429, ramping back up as requests succeed.
+    #[arg(long, visible_alias = "jobs", value_name = "N", default_value_t = limiter::DEFAULT_CEILING, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    concurrency: usize,
+
+    // Most chunks held between the fetches and the output or player at each
+    // step; more keeps a slow disk from stalling the fetches, fewer holds
+    // less audio in memory at once
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_BUFFER_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    buffer_size: usize,
+
+    // Hold back requests while MB of fetched audio is waiting to be written
+    // or played, so a large document can't run the machine out of memory
+    #[arg(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(1..))]
+    max_memory: Option<u64>,
+
+    // Give up on the rest of the run once more than PERCENT of the last ten
+    // chunks have failed, as during an outage; 100 never gives up
+    #[arg(long, value_name = "PERCENT", default_value_t = breaker::DEFAULT_MAX_FAILURE_RATE, value_parser = clap::value_parser!(u8).range(1..=100))]
+    max_failure_rate: u8,
+
+    // Save the output file as numbered parts (book.part01.mp3, ...) of at
+    // most DURATION each, e.g. 30m, splitting between chunks; with
+    // --chapter-announce every chapter also starts a new part
+    #[arg(long, value_name = "DURATION", requires = "output_file", conflicts_with_all = ["append", "base64_output"], value_parser = parse_split_duration)]
+    split_every: Option<Duration>,
+
+    // Save the output file as numbered files (book_001.mp3, ...) of at most
+    // BYTES each (e.g. 50MB), splitting between chunks, plus a playlist of
+    // them (book.m3u8 unless --playlist names one)
+    #[arg(long, value_name = "BYTES", requires = "output_file", conflicts_with_all = ["append", "base64_output"], value_parser = parse_byte_size)]
+    split_output_by_size: Option<u64>,
+
+    // Package every file the run writes (audio, playlist, manifest,
+    // subtitles, transcript) into one zip file, with the audio under audio/
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["append", "upload", "stdout"])]
+    archive: Option<String>,
+
+    // Pipe the input text through COMMAND in the shell and read what it
+    // prints instead, before any other preprocessing
+    #[arg(long, value_name = "COMMAND")]
+    before_fetch: Option<String>,
+
+    // Run COMMAND through the shell for each audio file written, once it's
+    // complete; {file}, {format} and {duration} are replaced by quoted values
+    #[arg(long, value_name = "COMMAND")]
+    post_cmd: Option<String>,
+
+    // Exit with an error if --post-cmd fails for any file
+    #[arg(long, requires = "post_cmd")]
+    post_cmd_strict: bool,
+
+    // Run COMMAND through the shell once everything has been played and
+    // written, with $SAYIT_OUTPUT, $SAYIT_CHUNKS and $SAYIT_VOICE set
+    #[arg(long, value_name = "COMMAND")]
+    after_play: Option<String>,
+
+    // Exit with an error if --after-play fails
+    #[arg(long, requires = "after_play")]
+    after_play_fail: bool,
+
+    // Put the absolute path of the output on the clipboard once the run
+    // succeeds; when several files are written, copy them one per line
+    // (list, the default) or, with --copy-path=dir, the directory holding them
+    #[arg(long, value_name = "WHAT", num_args = 0..=1, require_equals = true, default_missing_value = "list")]
+    copy_path: Option<copy_path::CopyPath>,
+
+    // Once the run succeeds, point a symlink at PATH to the output, replacing
+    // the previous link; a file already at PATH is only replaced with --force
+    #[arg(long, value_name = "PATH")]
+    latest_link: Option<String>,
+
+    // What --latest-link points at when several files are written
+    #[arg(
+        long,
+        value_name = "WHAT",
+        default_value = "first",
+        requires = "latest_link"
+    )]
+    latest_link_to: latest_link::LinkTo,
+
+    // PUT the finished output file to URL, such as a WebDAV share or a
+    // presigned S3 URL, and print where it ended up
+    #[arg(long, value_name = "URL", requires = "output_file", conflicts_with_all = ["split_every", "split_output_by_size", "base64_output"], value_parser = upload::parse_url)]
+    upload: Option<reqwest::Url>,
+
+    // A header to send with --upload, as "Name: Value", e.g.
+    // "Authorization: Bearer TOKEN" (repeatable)
+    #[arg(long, value_name = "NAME: VALUE", requires = "upload", value_parser = parse_header)]
+    upload_header: Vec<(HeaderName, HeaderValue)>,
+
+    // Create missing parent directories of the output file(s)
+    #[arg(long)]
+    mkdirs: bool,
+
+    // Cover image (JPEG or PNG) for .m4a and .m4b output
+    #[arg(long, value_name = "IMAGE", requires = "output_file")]
+    cover: Option<String>,
+
+    // Title tag for saved audio (defaults to the input file name)
+    #[arg(long)]
+    title: Option<String>,
+
+    // Album tag for saved audio
+    #[arg(long)]
+    album: Option<String>,
+
+    // Artist tag for saved audio (defaults to the voice name)
+    #[arg(long)]
+    artist: Option<String>,
+
+    // Don't write ID3/Vorbis comment tags into saved audio
+    #[arg(long, conflicts_with_all = ["title", "album", "artist"])]
+    no_tags: bool,
+
+    // Never play audio; an output file must be given (useful on CI machines)
+    #[arg(long, visible_alias = "no-playback", conflicts_with = "play")]
+    no_play: bool,
+
+    // Never open or even look for an audio device, for containers and other
+    // headless machines; needs an output, or --json to print the audio as
+    // JSON on stdout
+    #[arg(long, conflicts_with = "play")]
+    no_audio: bool,
+
+    // Play the audio even when writing it to an output file
+    #[arg(long)]
+    play: bool,
+}
+
+// The command line as it's parsed: `speak` and `save` are the bare command
+// under a name, so they take every one of its flags (see config::parse_cli)
+pub fn cli_command() -> clap::Command {
+    let command = Cli::command();
+    let args: Vec<clap::Arg> = command.get_arguments().cloned().collect();
+    let groups: Vec<clap::ArgGroup> = command.get_groups().cloned().collect();
+    let with_run_flags = |sub: clap::Command| sub.args(args.clone()).groups(groups.clone());
+    command
+        .mut_subcommand("speak", with_run_flags)
+        .mut_subcommand("save", with_run_flags)
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Read the input aloud, or save it with -o (the same as no subcommand)")]
+    Speak,
+    #[command(about = "Save the input's audio rather than playing it; needs an output")]
+    Save,
+    #[command(subcommand, about = "Manage the config file")]
+    Config(ConfigCommand),
+    #[command(about = "List the config file's profiles and the settings each one uses")]
+    Profiles,
+    #[command(
+        about = "Print a shell completion script",
+        after_help = completions::INSTALL
+    )]
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: clap_complete::Shell,
+    },
+    #[command(subcommand, about = "Show the available voices")]
+    Voices(VoicesCommand),
+    #[command(
+        about = "Hear a short sample in one voice or each voice in turn, cached for next time"
+    )]
+    Preview {
+        // The voice to hear; the voice a run would use by default
+        #[arg(long, value_name = "VOICE")]
+        voice: Option<Voice>,
+
+        // Hear every voice, one after another
+        #[arg(long, conflicts_with = "voice")]
+        all: bool,
+
+        // Read this instead of the default phrase
+        #[arg(long, value_name = "STRING")]
+        text: Option<String>,
+
+        #[arg(long)]
+        hd: bool,
+
+        // Write <voice>.mp3 for each voice into DIR instead of playing them
+        #[arg(long, value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+
+        // Overwrite existing files in --output-dir
+        #[arg(long, requires = "output_dir")]
+        force: bool,
+
+        // Only say what the preview would cost
+        #[arg(long)]
+        estimate: bool,
+    },
+    #[command(
+        about = "Time a few real (cheap) API requests at each concurrency level and recommend a --concurrency"
+    )]
+    Selftest {
+        // Don't ask before making the requests
+        #[arg(long)]
+        yes: bool,
+    },
+    #[command(about = "Serve text to speech over HTTP at /speak")]
+    Serve {
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+
+        // Address to listen on; only this machine can connect by default
+        #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1")]
+        bind: String,
+
+        // Require "Authorization: Bearer <TOKEN>" on every request
+        #[arg(long, value_name = "TOKEN", env = "SAYIT_AUTH_TOKEN")]
+        auth_token: Option<String>,
+
+        // Listen on a Unix domain socket at PATH instead of HTTP; requests
+        // are one line of JSON, answered with the audio (see `sayit client`)
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["port", "bind", "auth_token"])]
+        socket: Option<String>,
+    },
+    #[command(about = "Serve text to speech over gRPC, streaming the audio as it arrives")]
+    GrpcServe {
+        #[arg(long, default_value_t = 50051)]
+        port: u16,
+
+        // Address to listen on; only this machine can connect by default
+        #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1")]
+        bind: String,
+    },
+    #[command(about = "Send text to a `sayit serve --socket` server and play or save the audio")]
+    Client {
+        #[arg(long, value_name = "PATH")]
+        socket: String,
+
+        // The text to read; stdin is read if it's left out
+        #[arg(long, value_name = "STRING")]
+        text: Option<String>,
+
+        // Left to the server's defaults unless given
+        #[arg(short = 'v', long, value_name = "VOICE")]
+        voice: Option<Voice>,
+
+        #[arg(short = 'f', long, value_name = "FORMAT")]
+        format: Option<ResponseFormat>,
+
+        #[arg(short = 's', long, value_parser = parse_speed)]
+        speed: Option<f32>,
+
+        // Save the audio instead of playing it
+        #[arg(short, long, value_name = "FILE")]
+        output_file: Option<String>,
+
+        // Overwrite the output file if it exists
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    #[command(about = "Interactively write a config file with your preferred defaults")]
+    Init,
+    #[command(about = "Check the config file and API key, and show the settings in effect")]
+    Validate,
+}
+
+#[derive(Subcommand)]
+enum VoicesCommand {
+    #[command(about = "List every voice with a short description")]
+    List {
+        // Print the list as JSON
+        #[arg(long)]
+        json: bool,
+
+        // Play a short sample of each voice in turn
+        #[arg(long)]
+        sample: bool,
+    },
+    #[command(about = "Play a sample phrase in one voice")]
+    Sample {
+        voice: Voice,
+
+        // Read this instead of the default phrase
+        #[arg(long, value_name = "STRING")]
+        text: Option<String>,
+
+        #[arg(short = 'f', long, value_name = "FORMAT")]
+        format: Option<ResponseFormat>,
+
+        #[arg(long)]
+        hd: bool,
+
+        // Also save the sample
+        #[arg(short, long, value_name = "FILE")]
+        output_file: Option<String>,
+
+        // Overwrite the output file if it exists
+        #[arg(long)]
+        force: bool,
+    },
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum ResponseFormat {
     Opus,
     Aac,
@@ -54,7 +738,91 @@ enum ResponseFormat {
     Mp3,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+impl ResponseFormat {
+    fn api_name(self) -> &'static str {
+        match self {
+            ResponseFormat::Opus => "opus",
+            ResponseFormat::Aac => "aac",
+            ResponseFormat::Flac => "flac",
+            ResponseFormat::Pcm => "pcm",
+            ResponseFormat::Mp3 => "mp3",
+        }
+    }
+
+    // The format's tradeoffs, for --list-formats
+    fn note(self) -> &'static str {
+        match self {
+            ResponseFormat::Opus => "smallest files; good for voice and streaming",
+            ResponseFormat::Aac => "compact and plays well on phones; used for .m4b audiobooks",
+            ResponseFormat::Flac => "lossless, so the largest compressed files",
+            ResponseFormat::Pcm => {
+                "raw 24kHz 16-bit mono, lowest latency; saved as .wav or with --wav to be playable"
+            }
+            ResponseFormat::Mp3 => "widely compatible (the default)",
+        }
+    }
+}
+
+fn render_formats() -> String {
+    ResponseFormat::value_variants()
+        .iter()
+        .map(|format| format!("{:<4}  {}", format.api_name(), format.note()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// The format implied by an output file's extension (.wav means PCM in a WAV
+// header, see --wav)
+fn format_for_extension(path: &str) -> Option<ResponseFormat> {
+    let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "mp3" => Some(ResponseFormat::Mp3),
+        "flac" => Some(ResponseFormat::Flac),
+        "aac" | "m4a" | "m4b" => Some(ResponseFormat::Aac),
+        "opus" | "ogg" => Some(ResponseFormat::Opus),
+        "wav" | "pcm" | "raw" => Some(ResponseFormat::Pcm),
+        _ => None,
+    }
+}
+
+// Whether `path` is an MP4 file (.m4a, .m4b), which holds the AAC chunks
+// remuxed rather than as the API sends them
+fn is_mp4_path(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".m4a") || path.ends_with(".m4b")
+}
+
+// Pick the response format from --format and the output file's extension
+fn resolve_format(
+    requested: Option<ResponseFormat>,
+    output_file: Option<&str>,
+    force: bool,
+) -> Result<ResponseFormat, String> {
+    let implied = output_file.map(|path| (path, format_for_extension(path)));
+
+    match (requested, implied) {
+        (Some(format), Some((path, Some(implied)))) if format != implied && !force => Err(format!(
+            "--format {} contradicts the extension of {} (which implies {}); \
+             pass --force to write it anyway",
+            format.api_name(),
+            path,
+            implied.api_name()
+        )),
+        (Some(format), _) => Ok(format),
+        (None, Some((_, Some(implied)))) => Ok(implied),
+        (None, Some((path, None))) => {
+            log::warn!(
+                "Can't tell the format from the extension of {}; using mp3",
+                path
+            );
+            Ok(ResponseFormat::Mp3)
+        }
+        (None, None) => Ok(ResponseFormat::Mp3),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Voice {
     Alloy,
     Echo,
@@ -64,189 +832,3856 @@ enum Voice {
     Shimmer,
 }
 
-// Helper to split input text into manageable chunks
-fn split_input(input_text: &str, max_length: usize) -> Vec<String> {
-    let mut chunks = Vec::new();
-    let mut current_chunk = Vec::new();
-    let mut current_length = 0;
-
-    for word in input_text.split_whitespace() {
-        if current_length + word.len() + 1 > max_length {
-            chunks.push(current_chunk.join(" "));
-            current_chunk = vec![word.to_string()];
-            current_length = word.len();
-        } else {
-            current_chunk.push(word.to_string());
-            current_length += word.len() + 1; // +1 for space
+impl Voice {
+    fn name(self) -> &'static str {
+        match self {
+            Voice::Alloy => "alloy",
+            Voice::Echo => "echo",
+            Voice::Fable => "fable",
+            Voice::Onyx => "onyx",
+            Voice::Nova => "nova",
+            Voice::Shimmer => "shimmer",
         }
     }
+}
 
-    if !current_chunk.is_empty() {
-        chunks.push(current_chunk.join(" "));
-    }
+// Settings shared by every speech request in a run
+#[derive(Copy, Clone)]
+struct SpeechSettings {
+    audio_format: &'static str,
+    reading_voice: &'static str,
+    tts_model: &'static str,
+    speed: f32,
+    show_api_errors: bool,
+}
 
-    chunks
+// A piece of input text together with the settings to synthesize it with
+#[derive(Clone)]
+struct TextChunk {
+    text: String,
+    settings: SpeechSettings,
+    // Title of the chapter this chunk announces, if it starts one
+    chapter: Option<String>,
 }
 
-async fn fetch_and_process_audio(
-    text: &str,
+// The default for --buffer-size
+const DEFAULT_BUFFER_SIZE: usize = 32;
+
+// A synthesized chunk together with the MIME type the server reported for it
+#[derive(Clone)]
+struct AudioChunk {
     index: usize,
-    client: &Client,
-    audio_tx: mpsc::Sender<(usize, Bytes)>,
-    audio_format: &str,
-    reading_voice: &str,
-    tts_model: &str,
-    speed: f32,
-) {
-    log::info!("Fetching audio for chunk {}: {}", index, text);
-
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .expect("Expected an API key for OpenAI in the environment variables");
-
-    let response = client
-        .post("https://api.openai.com/v1/audio/speech")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({
-            "model": tts_model,
-            "voice": reading_voice,
-            "input": text,
-            "response_format": audio_format,
-            "speed": speed,
-        }))
-        .send()
-        .await;
+    bytes: Bytes,
+    content_type: Option<String>,
+    // How long the chunk plays for, measured the first time it's needed and
+    // shared by every clone
+    duration: Arc<OnceLock<Duration>>,
+    // Counts toward --max-memory until every clone is dropped
+    held: Arc<memory::Held>,
+}
 
-    match response {
-        Ok(resp) => {
-            if let Ok(bytes) = resp.bytes().await {
-                let _ = audio_tx.send((index, bytes)).await;
-            }
+// Holds chunks that arrive out of order and releases them strictly by index
+struct ChunkReorderBuffer {
+    pending: HashMap<usize, AudioChunk>,
+    next_index: usize,
+}
+
+impl ChunkReorderBuffer {
+    fn new() -> Self {
+        ChunkReorderBuffer {
+            pending: HashMap::new(),
+            next_index: 0,
         }
-        Err(e) => println!("Failed to process audio for text: {}\nError: {:?}", text, e),
     }
-}
 
-async fn play_audio_from_queue(mut audio_rx: mpsc::Receiver<(usize, Bytes)>) {
-    tokio::task::spawn_blocking(move || {
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let mut buffer = HashMap::new();
-        let mut next_index = 0;
+    fn insert(&mut self, chunk: AudioChunk) {
+        self.pending.insert(chunk.index, chunk);
+    }
 
-        while let Some((index, bytes)) = audio_rx.blocking_recv() {
-            buffer.insert(index, bytes);
-            while let Some(bytes) = buffer.remove(&next_index) {
-                let cursor = Cursor::new(bytes);
-                if let Ok(source) = Decoder::new_mp3(cursor) {
-                    let sink = Sink::try_new(&stream_handle).unwrap();
-                    sink.append(source.convert_samples::<f32>());
-                    sink.sleep_until_end();
-                }
-                next_index += 1;
-            }
-        }
-    })
-    .await
-    .unwrap();
+    // Next chunk in sequence, if it has arrived
+    fn pop_ready(&mut self) -> Option<AudioChunk> {
+        let chunk = self.pending.remove(&self.next_index)?;
+        self.next_index += 1;
+        Some(chunk)
+    }
 }
 
-async fn audio_to_output_file(mut audio_rx: mpsc::Receiver<(usize, Bytes)>, file_path: String) {
-    let mut output_file = File::create(file_path).unwrap();
-    while let Some((_index, bytes)) = audio_rx.recv().await {
-        output_file.write_all(&bytes).unwrap();
+// The Content-Type the API documents for each response format
+fn expected_content_type(audio_format: &str) -> &'static str {
+    match audio_format {
+        "opus" => "audio/opus",
+        "aac" => "audio/aac",
+        "flac" => "audio/flac",
+        "wav" => "audio/wav",
+        "pcm" => "audio/pcm",
+        _ => "audio/mpeg",
     }
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+// Roughly how many words per minute the voices read at speed 1.0
+const NATURAL_WPM: f32 = 150.0;
 
-    let cli = Cli::parse();
+// The API speed closest to reading `target_wpm` words per minute, rounded to
+// two decimals and clamped to the supported range
+fn speed_for_wpm(target_wpm: u32) -> f32 {
+    let speed = (target_wpm as f32 / NATURAL_WPM * 100.0).round() / 100.0;
+    speed.clamp(config::MIN_SPEED, config::MAX_SPEED)
+}
 
-    let input_text = if cli.use_stdin {
-        let mut buffer = String::new();
-        io::stdin()
-            .read_to_string(&mut buffer)
-            .expect("Failed to read from stdin");
-        buffer
-    } else if cli.clipboard {
-        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-        ctx.get_contents().unwrap_or_else(|_| {
-            eprintln!("Failed to access clipboard contents.");
-            std::process::exit(1);
-        })
-    } else if let Some(file) = cli.input_file {
-        std::fs::read_to_string(file.clone()).unwrap_or_else(|_| {
-            eprintln!("Failed to read file: {}", file);
-            std::process::exit(1);
-        })
-    } else {
-        Cli::command().error(
-            clap::error::ErrorKind::MissingRequiredArgument,
-            "No input source specified.",
-        ).exit();
-    };
+fn parse_speed(value: &str) -> Result<f32, String> {
+    let speed: f32 = value
+        .parse()
+        .map_err(|_| format!("{:?} is not a number", value))?;
+    config::check_speed(speed)
+}
 
-    let output_file_format = match cli.format {
-        Some(ResponseFormat::Opus) => "opus",
-        Some(ResponseFormat::Aac) => "aac",
-        Some(ResponseFormat::Flac) => "flac",
-        Some(ResponseFormat::Pcm) => "pcm",
-        Some(ResponseFormat::Mp3) => "mp3",
-        _ => "mp3",
-    };
+fn parse_wav_bits(value: &str) -> Result<u16, String> {
+    match value {
+        "16" => Ok(16),
+        "24" => Ok(24),
+        _ => Err(format!("{} bits isn't supported; use 16 or 24", value)),
+    }
+}
+
+fn parse_split_duration(value: &str) -> Result<Duration, String> {
+    match humantime::parse_duration(value) {
+        Ok(duration) if duration.is_zero() => Err("the duration must be positive".to_string()),
+        Ok(duration) => Ok(duration),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-    let reading_voice = match cli.voice {
-        Some(Voice::Echo) => "echo",
-        Some(Voice::Onyx) => "onyx",
-        Some(Voice::Nova) => "nova",
-        Some(Voice::Alloy) => "alloy",
-        Some(Voice::Fable) => "fable",
-        Some(Voice::Shimmer) => "shimmer",
-        _ => "alloy",
+// A size in bytes, optionally with a decimal (KB, MB, GB) or binary (KiB,
+// MiB, GiB) unit
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("{:?} doesn't start with a number of bytes", value))?;
+    let scale: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "unknown unit {:?} (use KB, MB, GB, KiB, MiB or GiB)",
+                unit.trim()
+            ))
+        }
     };
+    match number.checked_mul(scale) {
+        Some(0) => Err("the size must be positive".to_string()),
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("{} is too big", value)),
+    }
+}
 
-    let speed = cli.speed.unwrap_or(1.0); // Default speed
-    let tts_model = if cli.hd { "tts-1-hd" } else { "tts-1" };
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| {
+        format!(
+            "unknown encoding {:?} (try utf-8, latin1, windows-1252, shift_jis, ...)",
+            label
+        )
+    })
+}
 
-    let client = Client::new();
-    let (audio_tx, audio_rx) = mpsc::channel::<(usize, Bytes)>(32);
-    let chunks = split_input(&input_text, 4096);
+fn parse_header_value(value: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(value).map_err(|_| format!("{:?} isn't a valid header value", value))
+}
 
-    let handles: Vec<_> = chunks
-        .iter()
-        .enumerate()
-        .map(|(index, text)| {
-            let audio_tx = audio_tx.clone();
-            let client = client.clone();
-            let text = text.clone();
-            let format = output_file_format;
-            tokio::spawn(async move {
-                fetch_and_process_audio(
-                    &text,
-                    index,
-                    &client,
-                    audio_tx,
-                    &format,
-                    reading_voice,
-                    tts_model,
-                    speed,
-                )
-                .await
-            })
-        })
-        .collect();
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Name: Value\", got {:?}", header))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("{:?} isn't a valid header name", name.trim()))?;
+    Ok((name, parse_header_value(value.trim())?))
+}
 
-    if let Some(output_file) = cli.output_file {
-        tokio::spawn(audio_to_output_file(audio_rx, output_file));
-    } else {
-        tokio::spawn(play_audio_from_queue(audio_rx));
+fn build_client(
+    user_agent: Option<HeaderValue>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+) -> Client {
+    let mut default_headers = HeaderMap::new();
+    for (name, value) in headers {
+        // Headers set on the request itself win over the client's defaults
+        let sets_it_itself =
+            name == CONTENT_TYPE || (name == AUTHORIZATION && !NO_AUTH.load(Ordering::Relaxed));
+        if sets_it_itself {
+            log::warn!("Ignoring --header {}: sayit sets it itself", name);
+            continue;
+        }
+        default_headers.append(name, value);
     }
 
-    for handle in handles {
-        let _ = handle.await;
+    let mut builder = Client::builder().default_headers(default_headers);
+    if let Some(user_agent) = user_agent {
+        builder = builder.user_agent(user_agent);
     }
+    builder.build().unwrap_or_else(|e| {
+        eprintln!("Failed to set up the HTTP client: {}", e);
+        std::process::exit(1);
+    })
+}
 
-    // Drop the sender to close the channel and end the playback loop
-    drop(audio_tx);
+// The client for a run, with the headers asked for on the command line
+fn client_for(cli: &Cli) -> Client {
+    let mut headers = cli.headers.clone();
+    let account_headers = [
+        ("openai-organization", &cli.org),
+        ("openai-project", &cli.project),
+    ];
+    for (name, value) in account_headers {
+        if let Some(value) = value {
+            headers.push((HeaderName::from_static(name), value.clone()));
+        }
+    }
+    build_client(cli.user_agent.clone(), headers)
+}
+
+// The man page for `command` as plain text
+fn read_man_page(command: &str) -> Result<String, String> {
+    // GROFF_NO_SGR keeps bold and underline as backspace overstrikes, which
+    // strip_overstrike knows how to remove, rather than escape sequences
+    let output = std::process::Command::new("man")
+        .args(["-P", "cat", command])
+        .env("GROFF_NO_SGR", "1")
+        .output()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => "the man utility isn't installed".to_string(),
+            _ => format!("failed to run man: {}", e),
+        })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim() {
+            "" => format!("man exited with {}", output.status),
+            message => message.to_string(),
+        });
+    }
+    Ok(preprocess::strip_overstrike(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+// Decode input text to UTF-8. A byte order mark overrides `encoding`, and
+// bytes that don't decode are replaced rather than aborting the run.
+fn decode_input(bytes: &[u8], encoding: &'static Encoding, source: &str) -> String {
+    let (text, used, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        log::warn!(
+            "{} isn't valid {}; undecodable bytes were replaced (see --input-encoding)",
+            source,
+            used.name()
+        );
+    }
+    text.into_owned()
+}
+
+// Compile a regex given on the command line, exiting with a message naming the
+// flag if it is invalid
+fn regex_arg(flag: &str, pattern: &str) -> Regex {
+    Regex::new(pattern).unwrap_or_else(|e| {
+        eprintln!("Invalid {}: {}", flag, e);
+        std::process::exit(1);
+    })
+}
+
+const SPEECH_ENDPOINT: &str = "https://api.openai.com/v1/audio/speech";
+
+async fn fetch_and_process_audio(
+    text: &str,
+    index: usize,
+    client: &Client,
+    audio_tx: mpsc::Sender<AudioChunk>,
+    settings: SpeechSettings,
+) -> FetchOutcome {
+    // Once every consumer is gone (stdout closed, playback failed) the audio
+    // would be paid for and thrown away, so stop waiting on the API; dropping
+    // the request future aborts it mid-flight. The same goes for the rest of
+    // a run the API keeps failing.
+    progress::emit(progress::Event::ChunkStarted { index });
+    let outcome = tokio::select! {
+        biased;
+        _ = audio_tx.closed() => {
+            log::info!("Cancelled chunk {}: the output was closed", index);
+            progress::emit(progress::Event::ChunkCancelled { index });
+            FetchOutcome::Cancelled
+        }
+        _ = breaker::tripped() => {
+            log::info!("Cancelled chunk {}: too many chunks failed", index);
+            progress::emit(progress::Event::ChunkCancelled { index });
+            FetchOutcome::Cancelled
+        }
+        chunk = async {
+            memory::room(index).await;
+            fetch_audio(text, index, client, settings).await
+        } => {
+            if let Some(chunk) = &chunk {
+                dump_chunks::write(chunk, settings.audio_format);
+            }
+            let chunk = match chunk {
+                Some(chunk) if settings.audio_format == "pcm" => {
+                    tokio::task::spawn_blocking(move || convert_chunk(chunk))
+                        .await
+                        .ok()
+                        .flatten()
+                }
+                chunk => chunk,
+            };
+            match chunk {
+                Some(mut chunk) => {
+                    chunk.held = memory::hold(chunk.bytes.len());
+                    if progress::enabled() {
+                        let decoded = chunk.clone();
+                        let audio_format = settings.audio_format;
+                        let duration = tokio::task::spawn_blocking(move || {
+                            decoded_duration(&decoded, audio_format)
+                        })
+                        .await
+                        .ok()
+                        .flatten();
+                        progress::emit(progress::Event::ChunkCompleted {
+                            index,
+                            bytes: chunk.bytes.len(),
+                            duration,
+                        });
+                    }
+                    breaker::record(true);
+                    let _ = audio_tx.send(chunk).await;
+                    FetchOutcome::Fetched
+                }
+                None => {
+                    breaker::record(false);
+                    progress::emit(progress::Event::ChunkFailed { index });
+                    FetchOutcome::Failed
+                }
+            }
+        }
+    };
+    memory::finished(index);
+    outcome
+}
+
+// A pcm chunk converted to the sample rate, channel count and sample size
+// asked for with --rate (--wav-rate), --channels and --wav-bits
+fn convert_chunk(chunk: AudioChunk) -> Option<AudioChunk> {
+    if resample::output_format() == merge::API_PCM_FORMAT {
+        return Some(chunk);
+    }
+    match resample::convert_pcm(&chunk.bytes) {
+        Ok(bytes) => Some(AudioChunk {
+            bytes: Bytes::from(bytes),
+            duration: Arc::default(),
+            ..chunk
+        }),
+        Err(e) => {
+            log::error!("Unable to convert chunk {}: {}", chunk.index, e);
+            None
+        }
+    }
+}
+
+// How many times a chunk whose response was cut off is requested again
+const REREQUESTS: u32 = 2;
+
+// How many times a rate limited chunk is tried again before it fails
+const RATE_LIMIT_RETRIES: u32 = 6;
+
+// How long to wait after a 429: what the API asks for in Retry-After, or a
+// delay that doubles with each retry
+fn retry_after(response: &reqwest::Response, retries: u32) -> Duration {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(|seconds| Duration::from_secs_f64(seconds.min(60.0)))
+        .unwrap_or(Duration::from_millis(500 << retries.min(6)))
+}
+
+// The whole body of a response, or why it ended early: a dropped connection
+// can end the stream before Content-Length bytes have arrived
+async fn read_complete_body(response: reqwest::Response) -> Result<Bytes, String> {
+    let expected = response.content_length();
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    match expected {
+        Some(expected) if (bytes.len() as u64) < expected => Err(format!(
+            "only {} of {} bytes arrived",
+            bytes.len(),
+            expected
+        )),
+        _ => Ok(bytes),
+    }
+}
+
+async fn fetch_audio(
+    text: &str,
+    index: usize,
+    client: &Client,
+    settings: SpeechSettings,
+) -> Option<AudioChunk> {
+    log::info!(
+        chunk_index = index,
+        voice = settings.reading_voice,
+        model = settings.tts_model;
+        "Fetching audio for chunk {}: {}",
+        index,
+        text
+    );
+
+    let api_key = (!NO_AUTH.load(Ordering::Relaxed)).then(|| {
+        std::env::var("OPENAI_API_KEY")
+            .expect("Expected an API key for OpenAI in the environment variables")
+    });
+
+    let mut rerequests = 0;
+    let mut rate_limited = 0;
+    loop {
+        let permit = limiter::acquire().await;
+        let sent = Instant::now();
+        let mut request = client.post(SPEECH_ENDPOINT);
+        if let Some(api_key) = &api_key {
+            request = request.header("Authorization", format!("Bearer {}", api_key));
+        }
+        let asked_for_sse = sse::enabled();
+        let mut body = serde_json::json!({
+            "model": settings.tts_model,
+            "voice": settings.reading_voice,
+            "input": text,
+            "response_format": settings.audio_format,
+            "speed": settings.speed,
+        });
+        if asked_for_sse {
+            body["stream_format"] = "sse".into();
+        }
+        let response = request
+            .header("Content-Type", "application/json")
+            .json(&body)
+            .send()
+            .await;
+        if let Ok(resp) = &response {
+            log_response_headers(index, resp.headers());
+        }
+
+        match response {
+            Ok(resp) if !resp.status().is_success() => {
+                let status = resp.status();
+                let delay = retry_after(&resp, rate_limited);
+                let body = resp.text().await.unwrap_or_default();
+                // An exhausted quota answers 429 too, but waiting won't help
+                if status == StatusCode::TOO_MANY_REQUESTS
+                    && !body.contains("insufficient_quota")
+                    && rate_limited < RATE_LIMIT_RETRIES
+                {
+                    permit.rate_limited();
+                    rate_limited += 1;
+                    log::warn!(
+                        "Chunk {} was rate limited; retrying in {:.1}s",
+                        index,
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                if asked_for_sse && status == StatusCode::BAD_REQUEST && sse::refused(&body) {
+                    sse::unsupported(&format!("the API answered {}", status));
+                    continue;
+                }
+                report_api_error(
+                    index,
+                    status,
+                    &body,
+                    api_key.as_deref().unwrap_or_default(),
+                    settings.show_api_errors,
+                );
+                return None;
+            }
+            Ok(resp) => {
+                let content_type = resp
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| {
+                        value
+                            .split(';')
+                            .next()
+                            .unwrap_or(value)
+                            .trim()
+                            .to_lowercase()
+                    });
+
+                let expected = expected_content_type(settings.audio_format);
+                let is_sse = content_type.as_deref() == Some(sse::CONTENT_TYPE);
+                if asked_for_sse && !is_sse {
+                    sse::unsupported("the API answered with plain audio");
+                }
+                if let Some(mime) = content_type.as_deref().filter(|_| !is_sse) {
+                    if mime != expected {
+                        log::warn!(
+                            "Chunk {} was requested as {} but the server returned {}",
+                            index,
+                            expected,
+                            mime
+                        );
+                    }
+                }
+
+                // Partial audio is thrown away rather than decoded, and the
+                // chunk asked for again from the start
+                let body = read_complete_body(resp).await;
+                let body = if is_sse {
+                    body.and_then(|body| sse::parse(&body)).map(|speech| {
+                        for event in &speech.metadata {
+                            log::debug!(chunk_index = index; "Chunk {} event: {}", index, event);
+                        }
+                        Bytes::from(speech.audio)
+                    })
+                } else {
+                    body
+                };
+                // The events carry audio in the format asked for
+                let content_type = if is_sse {
+                    Some(expected.to_string())
+                } else {
+                    content_type
+                };
+                match body {
+                    Ok(bytes) => {
+                        permit.succeeded();
+                        let latency = sent.elapsed().as_millis() as u64;
+                        log::info!(
+                            chunk_index = index,
+                            voice = settings.reading_voice,
+                            model = settings.tts_model,
+                            latency_ms = latency;
+                            "Fetched chunk {} ({} bytes) in {}ms",
+                            index,
+                            bytes.len(),
+                            latency
+                        );
+                        return Some(AudioChunk {
+                            index,
+                            bytes,
+                            content_type,
+                            duration: Arc::default(),
+                            held: Arc::default(),
+                        });
+                    }
+                    Err(reason) if rerequests < REREQUESTS => {
+                        rerequests += 1;
+                        log::warn!(
+                            "The response for chunk {} was cut off ({}); requesting it again",
+                            index,
+                            reason
+                        );
+                    }
+                    Err(reason) => {
+                        log::error!(
+                            "The response for chunk {} was cut off ({}) {} times; giving up",
+                            index,
+                            reason,
+                            rerequests + 1
+                        );
+                        return None;
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to process audio for chunk {}: {}\nError: {:?}",
+                    index,
+                    text,
+                    e
+                );
+                return None;
+            }
+        }
+    }
+}
+
+// The response headers worth quoting when asking OpenAI about a request:
+// its ID, the rate limits and OpenAI's own (processing time, version, ...)
+fn diagnostic_headers(headers: &HeaderMap) -> Vec<(&str, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            name.starts_with("x-request-id")
+                || name.starts_with("x-ratelimit")
+                || name.starts_with("openai-")
+        })
+        .map(|(name, value)| {
+            (
+                name.as_str(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+// The request ID at info, so every chunk's can be found in the log, and the
+// rest of diagnostic_headers at debug
+fn log_response_headers(index: usize, headers: &HeaderMap) {
+    for (name, value) in diagnostic_headers(headers) {
+        if name == "x-request-id" {
+            log::info!(chunk_index = index, request_id = value.as_str(); "Chunk {} has request ID {}", index, value);
+        } else {
+            log::debug!(chunk_index = index; "Chunk {} response header {}: {}", index, name, value);
+        }
+    }
+}
+
+// Log a failed request, pulling the reason out of the API's error JSON
+fn report_api_error(index: usize, status: StatusCode, body: &str, api_key: &str, verbose: bool) {
+    let body = if api_key.is_empty() {
+        body.to_string()
+    } else {
+        body.replace(api_key, "[REDACTED]")
+    };
+    let parsed = serde_json::from_str::<serde_json::Value>(&body).ok();
+
+    if verbose {
+        let pretty = parsed
+            .as_ref()
+            .and_then(|value| serde_json::to_string_pretty(value).ok())
+            .unwrap_or(body);
+        log::error!("Chunk {} failed with {}:\n{}", index, status, pretty);
+    } else {
+        let message = parsed
+            .as_ref()
+            .and_then(|value| value["error"]["message"].as_str())
+            .unwrap_or("no error message in the response");
+        log::error!(
+            "Chunk {} failed with {}: {} (use --show-api-errors for the full response)",
+            index,
+            status,
+            message
+        );
+    }
+}
+
+// The MIME type a chunk is decoded as: the one the server reported, or the
+// requested format's when it didn't send one
+fn chunk_mime<'a>(chunk: &'a AudioChunk, audio_format: &str) -> &'a str {
+    chunk
+        .content_type
+        .as_deref()
+        .unwrap_or_else(|| expected_content_type(audio_format))
+}
+
+fn decode_chunk(
+    chunk: &AudioChunk,
+    audio_format: &str,
+) -> Option<Box<dyn Source<Item = f32> + Send>> {
+    let mime = chunk_mime(chunk, audio_format);
+    match decode(&chunk.bytes, mime) {
+        Ok(source) => Some(source),
+        Err(e) => {
+            log::error!("Unable to decode chunk {} ({}): {}", chunk.index, mime, e);
+            None
+        }
+    }
+}
+
+fn decode(bytes: &Bytes, mime: &str) -> Result<Box<dyn Source<Item = f32> + Send>, String> {
+    if mime == "audio/pcm" {
+        let format = resample::output_format();
+        let samples = merge::samples_in(bytes, format).map_err(|e| e.to_string())?;
+        let buffer = SamplesBuffer::new(format.channels, format.sample_rate, samples);
+        return Ok(Box::new(buffer.convert_samples::<f32>()));
+    }
+
+    let cursor = Cursor::new(bytes.clone());
+    let decoded = match mime {
+        "audio/mpeg" | "audio/mp3" => Decoder::new_mp3(cursor),
+        "audio/flac" | "audio/x-flac" => Decoder::new_flac(cursor),
+        "audio/wav" | "audio/wave" | "audio/x-wav" => Decoder::new_wav(cursor),
+        "audio/aac" => Decoder::new_aac(cursor),
+        "audio/ogg" | "audio/vorbis" => Decoder::new_vorbis(cursor),
+        _ => Decoder::new(cursor),
+    };
+    match decoded {
+        Ok(source) => Ok(Box::new(source.convert_samples::<f32>())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn append_chunk_to_sink(sink: &Sink, chunk: AudioChunk, audio_format: &str) {
+    if let Some(source) = decode_chunk(&chunk, audio_format) {
+        sink.append(source);
+    }
+}
+
+// How long `bytes` of the MIME type `mime` play for, found by decoding every
+// sample: nothing is played, so no output device is needed. Audio that can't
+// be decoded measures zero.
+fn measure_duration(bytes: &Bytes, mime: &str) -> Duration {
+    let source = match decode(bytes, mime) {
+        Ok(source) => source,
+        Err(e) => {
+            log::debug!("Unable to measure {} audio: {}", mime, e);
+            return Duration::ZERO;
+        }
+    };
+    let samples_per_second = u64::from(source.channels()) * u64::from(source.sample_rate());
+    if samples_per_second == 0 {
+        return Duration::ZERO;
+    }
+    let samples = source.count() as f64;
+    Duration::from_secs_f64(samples / samples_per_second as f64)
+}
+
+// A chunk's measured duration, decoding it only the first time; None if it
+// couldn't be measured
+fn decoded_duration(chunk: &AudioChunk, audio_format: &str) -> Option<Duration> {
+    let duration = *chunk
+        .duration
+        .get_or_init(|| measure_duration(&chunk.bytes, chunk_mime(chunk, audio_format)));
+    Some(duration).filter(|duration| !duration.is_zero())
+}
+
+// The title of the chapter each chunk belongs to (empty before the first)
+fn chapter_titles(chunks: &[TextChunk]) -> Vec<String> {
+    let mut chapter = "";
+    chunks
+        .iter()
+        .map(|chunk| {
+            if let Some(title) = &chunk.chapter {
+                chapter = title;
+            }
+            chapter.to_string()
+        })
+        .collect()
+}
+
+// Playlist titles for each chunk: the chapter it belongs to, or failing that
+// the input file's name, numbered
+fn playlist_titles(chunks: &[TextChunk], input_name: Option<&str>) -> Vec<String> {
+    let source = input_name.unwrap_or("Part");
+    chapter_titles(chunks)
+        .into_iter()
+        .enumerate()
+        .map(|(index, chapter)| {
+            if chapter.is_empty() {
+                format!("{} {}", source, index + 1)
+            } else {
+                chapter
+            }
+        })
+        .collect()
+}
+
+// Create the output directory if needed and make sure files can be written
+// there before any requests are made
+fn prepare_output_dir(dir: &str) -> Result<(), String> {
+    if VALIDATING.load(Ordering::Relaxed) && !Path::new(dir).exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir).map_err(|e| format!("failed to create {}: {}", dir, e))?;
+    let probe = Path::new(dir).join(".sayit-write-test");
+    File::create(&probe)
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {}", dir, e))
+}
+
+// Pass chunks through unchanged while adding up their decoded durations
+#[derive(Default)]
+struct Measured {
+    bytes: u64,
+    duration: Duration,
+    // Per chunk, for --manifest
+    durations: HashMap<usize, Duration>,
+}
+
+fn measure_chunks(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    audio_format: &'static str,
+    buffer_size: usize,
+) -> (mpsc::Receiver<AudioChunk>, JoinHandle<Measured>) {
+    let (measured_tx, measured_rx) = mpsc::channel::<AudioChunk>(buffer_size);
+
+    let total = tokio::spawn(async move {
+        let mut total = Measured::default();
+        loop {
+            let chunk = tokio::select! {
+                chunk = audio_rx.recv() => chunk,
+                _ = measured_tx.closed() => break,
+            };
+            let Some(chunk) = chunk else { break };
+            let decoded = chunk.clone();
+            let duration =
+                tokio::task::spawn_blocking(move || decoded_duration(&decoded, audio_format))
+                    .await
+                    .ok()
+                    .flatten();
+            total.bytes += chunk.bytes.len() as u64;
+            if let Some(duration) = duration {
+                total.duration += duration;
+                total.durations.insert(chunk.index, duration);
+            }
+            if measured_tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        total
+    });
+
+    (measured_rx, total)
+}
+
+const NO_AUDIO_DEVICE: &str =
+    "No audio output device available; specify -o to write to a file instead";
+
+// Set by --no-audio: no device is opened, not even to check for one
+static NO_AUDIO: AtomicBool = AtomicBool::new(false);
+
+// Set by --no-auth: requests go without the API key
+static NO_AUTH: AtomicBool = AtomicBool::new(false);
+
+// Set by --validate: paths are checked, but no directory is created
+static VALIDATING: AtomicBool = AtomicBool::new(false);
+
+// Exit unless there's an API key to send, or none is needed
+fn require_api_key() {
+    if !NO_AUTH.load(Ordering::Relaxed)
+        && std::env::var_os("OPENAI_API_KEY").is_none_or(|key| key.is_empty())
+    {
+        eprintln!("OPENAI_API_KEY isn't set (pass --no-auth if the API doesn't need it)");
+        std::process::exit(1);
+    }
+}
+
+// Check for an output device before spending anything on requests
+fn audio_device_available() -> Result<(), String> {
+    if NO_AUDIO.load(Ordering::Relaxed) {
+        return Err("audio is turned off by --no-audio".to_string());
+    }
+    OutputStream::try_default()
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+// Docker and similar containers rarely have a sound device
+fn in_container() -> bool {
+    std::env::var_os("DOCKER").is_some()
+        || Path::new("/.dockerenv").exists()
+        || std::fs::read_to_string("/proc/1/cgroup").is_ok_and(|cgroup| {
+            ["docker", "kubepods", "containerd", "libpod", "lxc"]
+                .iter()
+                .any(|name| cgroup.contains(name))
+        })
+}
+
+async fn play_audio_from_queue(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    audio_format: &'static str,
+) {
+    tokio::task::spawn_blocking(move || {
+        // Returning drops the receiver, which cancels the remaining fetches
+        // unless the audio is also being saved
+        let (_stream, stream_handle) = match OutputStream::try_default() {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{} ({})", NO_AUDIO_DEVICE, e);
+                return;
+            }
+        };
+        let mut buffer = ChunkReorderBuffer::new();
+
+        while let Some(chunk) = audio_rx.blocking_recv() {
+            buffer.insert(chunk);
+            while let Some(chunk) = buffer.pop_ready() {
+                let sink = match Sink::try_new(&stream_handle) {
+                    Ok(sink) => sink,
+                    Err(e) => {
+                        eprintln!("Audio playback failed: {}", e);
+                        return;
+                    }
+                };
+                append_chunk_to_sink(&sink, chunk, audio_format);
+                sink.sleep_until_end();
+            }
+        }
+    })
+    .await
+    .unwrap();
+}
+
+// Where log records go (--log-target)
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum LogTarget {
+    Stderr,
+    Journald,
+}
+
+fn init_logging(target: LogTarget) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    match target {
+        LogTarget::Stderr => builder.init(),
+        LogTarget::Journald => log_to_journal(builder.build()),
+    }
+}
+
+#[cfg(all(feature = "systemd", target_os = "linux"))]
+fn log_to_journal(filter: env_logger::Logger) {
+    let installed = journal::Journal::connect(filter)
+        .map_err(|e| e.to_string())
+        .and_then(|journal| journal.install().map_err(|e| e.to_string()));
+    if let Err(e) = installed {
+        eprintln!("Failed to log to the systemd journal: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(all(feature = "systemd", target_os = "linux")))]
+fn log_to_journal(_: env_logger::Logger) {
+    eprintln!(
+        "--log-target journald needs sayit built with the systemd feature, which is Linux only"
+    );
+    std::process::exit(1);
+}
+
+// What `sayit serve` and `sayit grpc-serve` use for fields a request leaves
+// out. Either needs an API key up front rather than on the first request.
+fn server_defaults(cli: &Cli) -> serve::Defaults {
+    require_api_key();
+    let config = load_config();
+    serve::Defaults {
+        voice: cli.voice.or(config.voice).unwrap_or(Voice::Alloy),
+        format: cli.format.or(config.format).unwrap_or(ResponseFormat::Mp3),
+        speed: cli.speed.or(config.speed).unwrap_or(1.0),
+        model: config::tts_model(cli.hd, cli.model.as_deref(), &config),
+        show_api_errors: cli.show_api_errors,
+    }
+}
+
+#[cfg(unix)]
+async fn serve_socket(path: &str, client: Client, defaults: serve::Defaults) -> Result<(), String> {
+    serve::run_socket(Path::new(path), client, defaults).await
+}
+
+#[cfg(not(unix))]
+async fn serve_socket(_: &str, _: Client, _: serve::Defaults) -> Result<(), String> {
+    Err("Unix domain sockets aren't available on this platform".to_string())
+}
+
+#[cfg(unix)]
+async fn request_from_socket(path: &str, request: &serde_json::Value) -> Result<Vec<u8>, String> {
+    serve::request_socket(Path::new(path), request).await
+}
+
+#[cfg(not(unix))]
+async fn request_from_socket(_: &str, _: &serde_json::Value) -> Result<Vec<u8>, String> {
+    Err("Unix domain sockets aren't available on this platform".to_string())
+}
+
+fn load_config() -> config::Config {
+    config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid config file {}", e);
+        std::process::exit(1);
+    })
+}
+
+// Play a single chunk through to the end
+async fn play_chunk(chunk: AudioChunk, audio_format: &'static str) {
+    tokio::task::spawn_blocking(move || {
+        let Ok((_stream, stream_handle)) = OutputStream::try_default() else {
+            return;
+        };
+        match Sink::try_new(&stream_handle) {
+            Ok(sink) => {
+                append_chunk_to_sink(&sink, chunk, audio_format);
+                sink.sleep_until_end();
+            }
+            Err(e) => eprintln!("Audio playback failed: {}", e),
+        }
+    })
+    .await
+    .unwrap();
+}
+
+// `sayit voices list --sample`: fetch a short introduction from each voice
+// and play them one after another
+async fn play_voice_samples(cli: &Cli) {
+    require_api_key();
+    if let Err(e) = audio_device_available() {
+        eprintln!("{} ({})", NO_AUDIO_DEVICE, e);
+        std::process::exit(1);
+    }
+    let config = load_config();
+    let client = client_for(cli);
+
+    for (index, info) in voices::VOICES.iter().enumerate() {
+        let settings = SpeechSettings {
+            audio_format: "mp3",
+            reading_voice: info.voice.name(),
+            tts_model: config::tts_model(cli.hd, cli.model.as_deref(), &config),
+            speed: 1.0,
+            show_api_errors: cli.show_api_errors,
+        };
+        let text = voices::sample_text(info.voice);
+        let Some(chunk) = fetch_audio(&text, index, &client, settings).await else {
+            continue;
+        };
+        eprintln!("Playing {}", info.voice.name());
+        play_chunk(chunk, "mp3").await;
+    }
+}
+
+// `sayit selftest`: read selftest::PHRASE ROUNDS times from each request
+// slot at every level up to --concurrency, and print how it went
+async fn run_selftest(cli: &Cli, yes: bool) {
+    require_api_key();
+    let levels: Vec<usize> = selftest::LEVELS
+        .into_iter()
+        .filter(|level| *level <= cli.concurrency)
+        .collect();
+    let config = load_config();
+    let settings = SpeechSettings {
+        audio_format: "mp3",
+        reading_voice: cli.voice.or(config.voice).unwrap_or(Voice::Alloy).name(),
+        tts_model: config::tts_model(cli.hd, cli.model.as_deref(), &config),
+        speed: 1.0,
+        show_api_errors: cli.show_api_errors,
+    };
+    match selftest::confirm(&levels, settings.tts_model, yes) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let client = client_for(cli);
+
+    let mut results = Vec::new();
+    for concurrency in levels {
+        eprintln!("Sending {} request(s) at a time...", concurrency);
+        let started = Instant::now();
+        let slots: Vec<_> = (0..concurrency)
+            .map(|slot| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let (mut latencies, mut failed) = (Vec::new(), 0);
+                    for round in 0..selftest::ROUNDS {
+                        let sent = Instant::now();
+                        let index = slot * selftest::ROUNDS + round;
+                        match fetch_audio(selftest::PHRASE, index, &client, settings).await {
+                            Some(_) => latencies.push(sent.elapsed()),
+                            None => failed += 1,
+                        }
+                    }
+                    (latencies, failed)
+                })
+            })
+            .collect();
+        let mut level = selftest::Level {
+            concurrency,
+            latencies: Vec::new(),
+            failed: 0,
+            elapsed: Duration::ZERO,
+        };
+        for slot in slots {
+            let (latencies, failed) = slot.await.unwrap_or((Vec::new(), selftest::ROUNDS));
+            level.latencies.extend(latencies);
+            level.failed += failed;
+        }
+        level.elapsed = started.elapsed();
+        results.push(level);
+    }
+    println!("{}", selftest::render(&results));
+}
+
+// The options of `sayit preview`
+struct Preview<'a> {
+    voices: Vec<Voice>,
+    text: &'a str,
+    hd: bool,
+    output_dir: Option<&'a Path>,
+    force: bool,
+    estimate: bool,
+}
+
+// `sayit preview`: each voice says its name and then reads the sample, or
+// with --output-dir just the sample is saved as <voice>.mp3. Clips come from
+// the cache when they can, and what the rest cost is stated first.
+async fn run_preview(cli: &Cli, preview: Preview<'_>) {
+    let config = load_config();
+    let model = config::tts_model(preview.hd, cli.model.as_deref(), &config);
+    let mut clips = Vec::new();
+    for &voice in &preview.voices {
+        if preview.output_dir.is_none() {
+            let text = voices::sample_text(voice);
+            clips.push(preview::Clip { voice, text });
+        }
+        let text = preview.text.to_string();
+        clips.push(preview::Clip { voice, text });
+    }
+    let cache_dir = preview::cache_dir();
+    let cache_path = |clip: &preview::Clip| {
+        cache_dir
+            .as_deref()
+            .map(|dir| preview::cache_path(dir, model, clip))
+    };
+    let uncached: Vec<&preview::Clip> = clips
+        .iter()
+        .filter(|clip| {
+            cache_path(clip)
+                .and_then(|path| preview::cached(&path))
+                .is_none()
+        })
+        .collect();
+    let estimate = preview::estimate(&uncached, clips.len() - uncached.len(), model);
+    if preview.estimate {
+        println!("{}", estimate);
+        return;
+    }
+    eprintln!("{}", estimate);
+
+    if !uncached.is_empty() {
+        require_api_key();
+    }
+    match preview.output_dir {
+        Some(dir) => {
+            for voice in &preview.voices {
+                let path = dir.join(format!("{}.mp3", voice.name()));
+                if let Err(e) =
+                    check_output_path(&path.to_string_lossy(), preview.force, false, false)
+                {
+                    eprintln!("Refusing to write output: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                eprintln!("Failed to create {}: {}", dir.display(), e);
+                std::process::exit(1);
+            }
+        }
+        None => {
+            if let Err(e) = audio_device_available() {
+                eprintln!("{} ({})", NO_AUDIO_DEVICE, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let client = client_for(cli);
+    for (index, clip) in clips.iter().enumerate() {
+        let path = cache_path(clip);
+        let bytes = match path.as_deref().and_then(preview::cached) {
+            Some(bytes) => Bytes::from(bytes),
+            None => {
+                let settings = SpeechSettings {
+                    audio_format: "mp3",
+                    reading_voice: clip.voice.name(),
+                    tts_model: model,
+                    speed: 1.0,
+                    show_api_errors: cli.show_api_errors,
+                };
+                let Some(chunk) = fetch_audio(&clip.text, index, &client, settings).await else {
+                    continue;
+                };
+                if let Some(path) = &path {
+                    preview::store(path, &chunk.bytes);
+                }
+                chunk.bytes
+            }
+        };
+        if let Some(dir) = preview.output_dir {
+            let path = dir.join(format!("{}.mp3", clip.voice.name()));
+            if let Err(e) = playlist::write_atomically(&path, &bytes) {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+            eprintln!("Wrote {}", path.display());
+            continue;
+        }
+        if index == 0 || clips[index - 1].voice != clip.voice {
+            eprintln!("Playing {}", clip.voice.name());
+        }
+        let chunk = AudioChunk {
+            index,
+            bytes,
+            content_type: None,
+            duration: Arc::default(),
+            held: Arc::default(),
+        };
+        play_chunk(chunk, "mp3").await;
+    }
+}
+
+// The options of `sayit voices sample`
+struct Audition<'a> {
+    voice: Voice,
+    format: Option<ResponseFormat>,
+    hd: bool,
+    output_file: Option<&'a str>,
+    force: bool,
+}
+
+// `sayit voices sample`: read `text` in one voice, play it and optionally
+// save it
+async fn play_voice_sample(cli: &Cli, text: &str, audition: Audition<'_>) {
+    require_api_key();
+    let format = resolve_format(audition.format, audition.output_file, audition.force)
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    let mp4_output = audition.output_file.is_some_and(is_mp4_path);
+    if mp4_output && format != ResponseFormat::Aac {
+        eprintln!("MP4 files (.m4a, .m4b) hold AAC audio; use --format aac or leave --format out");
+        std::process::exit(1);
+    }
+    if let Some(path) = audition.output_file {
+        if let Err(e) = check_output_path(path, audition.force, false, false) {
+            eprintln!("Refusing to write output: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let plays_audio = match audio_device_available() {
+        Ok(()) => true,
+        Err(e) if audition.output_file.is_some() => {
+            log::warn!(
+                "No audio output device available ({}); only saving the sample",
+                e
+            );
+            false
+        }
+        Err(e) => {
+            eprintln!("{} ({})", NO_AUDIO_DEVICE, e);
+            std::process::exit(1);
+        }
+    };
+
+    let config = load_config();
+    let audio_format = format.api_name();
+    let settings = SpeechSettings {
+        audio_format,
+        reading_voice: audition.voice.name(),
+        tts_model: config::tts_model(audition.hd, cli.model.as_deref(), &config),
+        speed: config.speed.unwrap_or(1.0),
+        show_api_errors: cli.show_api_errors,
+    };
+    let Some(chunk) = fetch_audio(text, 0, &client_for(cli), settings).await else {
+        std::process::exit(1);
+    };
+
+    if let Some(path) = audition.output_file {
+        let wrap_pcm_in_wav = audio_format == "pcm" && path.to_lowercase().ends_with(".wav");
+        let contents = if mp4_output {
+            m4b::mux(&[&chunk.bytes], &m4b::Audiobook::default()).unwrap_or_else(|e| {
+                eprintln!("Failed to write {}: {}", path, e);
+                std::process::exit(1);
+            })
+        } else if wrap_pcm_in_wav {
+            let data_len = u32::try_from(chunk.bytes.len()).unwrap_or(u32::MAX);
+            [
+                merge::wav_header(merge::API_PCM_FORMAT, data_len),
+                chunk.bytes.to_vec(),
+            ]
+            .concat()
+        } else {
+            chunk.bytes.to_vec()
+        };
+        if let Err(e) = playlist::write_atomically(Path::new(path), &contents) {
+            eprintln!("Failed to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if plays_audio {
+        play_chunk(chunk, audio_format).await;
+    }
+}
+
+// How --base64-output lays out the encoded audio
+#[derive(Copy, Clone)]
+struct Base64Options {
+    lines: bool,
+    data_uri: bool,
+    json: bool,
+}
+
+impl Base64Options {
+    // Whether each chunk is encoded on its own rather than as one stream
+    fn per_chunk(&self) -> bool {
+        self.lines || self.json
+    }
+}
+
+async fn audio_to_base64(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    file_path: Option<String>,
+    audio_format: &'static str,
+    options: Base64Options,
+    append: bool,
+) {
+    let file_path = file_path.unwrap_or_else(|| STDOUT_PATH.to_string());
+    let mut output = open_output(&file_path, append);
+    let mut buffer = ChunkReorderBuffer::new();
+    let mut stream = Vec::new();
+
+    while let Some(chunk) = audio_rx.recv().await {
+        buffer.insert(chunk);
+        while let Some(chunk) = buffer.pop_ready() {
+            if !options.per_chunk() {
+                stream.push(chunk.bytes.to_vec());
+                continue;
+            }
+
+            let mime = chunk_mime(&chunk, audio_format);
+            let mut encoded = BASE64.encode(&chunk.bytes);
+            if options.data_uri {
+                encoded = format!("data:{};base64,{}", mime, encoded);
+            }
+            if options.json {
+                encoded = serde_json::json!({
+                    "index": chunk.index,
+                    "mime": mime,
+                    "audio": encoded,
+                })
+                .to_string();
+            }
+            encoded.push('\n');
+            if !write_output(&mut output, encoded.as_bytes(), &file_path) {
+                return;
+            }
+        }
+    }
+
+    if !options.per_chunk() {
+        // A single base64 string has to encode one well-formed stream
+        let merged = merge::merge_chunks(audio_format, &stream).unwrap_or_else(|e| {
+            eprintln!("Failed to merge audio for base64 output: {}", e);
+            std::process::exit(1);
+        });
+        let mut encoded = BASE64.encode(merged);
+        if options.data_uri {
+            encoded = format!(
+                "data:{};base64,{}",
+                expected_content_type(audio_format),
+                encoded
+            );
+        }
+        if !write_output(&mut output, encoded.as_bytes(), &file_path) {
+            return;
+        }
+    }
+
+    flush_output(&mut output, &file_path);
+}
+
+// Duplicate every chunk onto two receivers so it can be played and saved
+fn tee_chunks(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    buffer_size: usize,
+) -> (mpsc::Receiver<AudioChunk>, mpsc::Receiver<AudioChunk>) {
+    let (first_tx, first_rx) = mpsc::channel::<AudioChunk>(buffer_size);
+    let (second_tx, second_rx) = mpsc::channel::<AudioChunk>(buffer_size);
+
+    // Keep feeding whichever side is still listening, and hang up on the
+    // fetches once neither is
+    tokio::spawn(async move {
+        let both_closed = async {
+            first_tx.closed().await;
+            second_tx.closed().await;
+        };
+        tokio::pin!(both_closed);
+        loop {
+            let chunk = tokio::select! {
+                chunk = audio_rx.recv() => chunk,
+                _ = &mut both_closed => break,
+            };
+            let Some(chunk) = chunk else { break };
+            let _ = first_tx.send(chunk.clone()).await;
+            let _ = second_tx.send(chunk).await;
+        }
+    });
+
+    (first_rx, second_rx)
+}
+
+// Pass chunks on in order until `max_bytes` of audio have gone through, then
+// hang up on the remaining fetches. `expected_chunks` is lowered to the
+// number of chunks kept, so the output isn't reported as incomplete.
+fn limit_chunks(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    max_bytes: u64,
+    expected_chunks: Arc<AtomicUsize>,
+    buffer_size: usize,
+) -> mpsc::Receiver<AudioChunk> {
+    let (limited_tx, limited_rx) = mpsc::channel::<AudioChunk>(buffer_size);
+
+    tokio::spawn(async move {
+        let mut buffer = ChunkReorderBuffer::new();
+        let mut total = 0;
+        while let Some(chunk) = audio_rx.recv().await {
+            buffer.insert(chunk);
+            while let Some(chunk) = buffer.pop_ready() {
+                total += chunk.bytes.len() as u64;
+                let index = chunk.index;
+                if limited_tx.send(chunk).await.is_err() {
+                    return;
+                }
+                if total >= max_bytes && index + 1 < expected_chunks.load(Ordering::SeqCst) {
+                    log::warn!(
+                        "Output truncated at {} bytes after chunk {} (--max-output-bytes {})",
+                        total,
+                        index,
+                        max_bytes
+                    );
+                    expected_chunks.store(index + 1, Ordering::SeqCst);
+                    // Dropping the receiver cancels the fetches still running
+                    return;
+                }
+            }
+        }
+    });
+
+    limited_rx
+}
+
+// Whether `path` exists and was modified after `than`, for --since-modified;
+// an input that can't be checked counts as changed
+fn is_newer(path: &str, than: &str) -> bool {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|meta| meta.modified());
+    match (modified(path), modified(than)) {
+        (Ok(output), Ok(input)) => output > input,
+        _ => false,
+    }
+}
+
+// Where "-" as an output path sends the audio
+const STDOUT_PATH: &str = "-";
+
+// `--merge DIR`: write the chunk files in DIR to `output_file` as one file
+fn merge_chunk_files(dir: &Path, output_file: &str, force: bool, mkdirs: bool) {
+    let (format, paths) = merge_dir::find(dir).unwrap_or_else(|e| {
+        eprintln!("Nothing to merge: {}", e);
+        std::process::exit(1);
+    });
+    // WAV and raw PCM share a response format but not a file layout
+    let chunk_file = format!("chunk.{}", format);
+    let same_format = format_for_extension(output_file) == format_for_extension(&chunk_file)
+        && output_file.to_lowercase().ends_with(".wav") == (format == "wav");
+    if !same_format && !force {
+        eprintln!(
+            "The chunks are {} but {} has a different extension; \
+             pass --force to write it anyway",
+            format, output_file
+        );
+        std::process::exit(1);
+    }
+    if is_mp4_path(output_file) && format != "aac" {
+        eprintln!(
+            "MP4 files (.m4a, .m4b) hold AAC audio, but the chunks are {}",
+            format
+        );
+        std::process::exit(1);
+    }
+    if let Err(e) = check_output_path(output_file, force, false, mkdirs) {
+        eprintln!("Refusing to write output: {}", e);
+        std::process::exit(1);
+    }
+
+    let merged = merge_dir::merge(format, &paths).unwrap_or_else(|e| {
+        eprintln!("Failed to merge the chunks in {}: {}", dir.display(), e);
+        std::process::exit(1);
+    });
+    let merged = if is_mp4_path(output_file) {
+        m4b::mux(&[&merged], &m4b::Audiobook::default()).unwrap_or_else(|e| {
+            eprintln!("Failed to merge the chunks in {}: {}", dir.display(), e);
+            std::process::exit(1);
+        })
+    } else {
+        merged
+    };
+    let written = if output_file == STDOUT_PATH {
+        io::stdout().write_all(&merged)
+    } else {
+        playlist::write_atomically(Path::new(output_file), &merged)
+    };
+    if let Err(e) = written {
+        eprintln!("Failed to write {}: {}", output_file, e);
+        std::process::exit(1);
+    }
+    log::info!("Merged {} chunk files into {}", paths.len(), output_file);
+}
+
+// Make sure writing to `path` later won't clobber anything or fail for lack
+// of a directory, so a refused run doesn't cost any API requests
+fn check_output_path(path: &str, force: bool, append: bool, mkdirs: bool) -> Result<(), String> {
+    if path == STDOUT_PATH {
+        return Ok(());
+    }
+    let path = Path::new(path);
+
+    if let Some(parent) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if !parent.is_dir() {
+            if !mkdirs {
+                return Err(format!(
+                    "directory {} does not exist; pass --mkdirs to create it",
+                    parent.display()
+                ));
+            }
+            if !VALIDATING.load(Ordering::Relaxed) {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+            }
+        }
+    }
+
+    if path.exists() && !force && !append {
+        return Err(format!(
+            "{} already exists; pass --force to overwrite it",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+fn open_output(file_path: &str, append: bool) -> Box<dyn Write + Send> {
+    if file_path == STDOUT_PATH {
+        return Box::new(io::stdout());
+    }
+    let opened = if append {
+        OpenOptions::new().append(true).create(true).open(file_path)
+    } else {
+        File::create(file_path)
+    };
+    match opened {
+        Ok(file) => Box::new(file),
+        Err(e) => {
+            eprintln!("Failed to create {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// How audio_to_output_file puts its bytes on disk
+#[derive(Clone, Copy, PartialEq)]
+enum WriteMode {
+    // Straight into the file: stdout, or --no-atomic
+    Direct,
+    // Onto the end of the existing file (--append)
+    Append,
+    // Into `<file>.part`, renamed over the file once every chunk is in, so a
+    // file under the real name is always complete
+    Atomic,
+}
+
+// How audio_to_output_file lays chunks out in the file
+#[derive(Clone, Copy, Default)]
+struct Layout {
+    // PCM goes behind a WAV header
+    wrap_pcm_in_wav: bool,
+    // Silence between chunks (--join-gap-ms)
+    join_gap: Duration,
+}
+
+fn part_path(file_path: &str) -> String {
+    format!("{}.part", file_path)
+}
+
+// Returns false once whoever was reading stdout has gone away, in which case
+// the caller should stop so the remaining fetches are cancelled
+fn write_output(output: &mut dyn Write, bytes: &[u8], file_path: &str) -> bool {
+    match output.write_all(bytes) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            log::info!("{} was closed; stopping", file_path);
+            false
+        }
+        Err(e) => {
+            eprintln!("Failed to write {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn flush_output(output: &mut dyn Write, file_path: &str) {
+    if let Err(e) = output.flush() {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("Failed to write {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn audio_to_output_file(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    file_path: String,
+    audio_format: &'static str,
+    layout: Layout,
+    mode: WriteMode,
+    tags: Option<tags::Tags>,
+    // How many chunks make up the whole output; --max-output-bytes lowers it
+    expected_chunks: Arc<AtomicUsize>,
+) -> Vec<manifest::Span> {
+    let Layout {
+        wrap_pcm_in_wav,
+        join_gap,
+    } = layout;
+    // Formats with whole-stream headers are held back and stitched at the
+    // end; appending to one means remuxing the existing file with the new
+    // chunks, which then goes through a .part file like an atomic write
+    let mut held_back = Vec::new();
+    let remux = mode == WriteMode::Append && merge::needs_merge(audio_format);
+    if remux {
+        match std::fs::read(&file_path) {
+            Ok(existing) if !existing.is_empty() => held_back.push(existing),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("Failed to read {} to append to it: {}", file_path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let write_path = if mode == WriteMode::Atomic || remux {
+        part_path(&file_path)
+    } else {
+        file_path.clone()
+    };
+    let mut output = open_output(&write_path, mode == WriteMode::Append && !remux);
+    let mut buffer = ChunkReorderBuffer::new();
+    let mut data_len: u64 = 0;
+    // Where each chunk landed in the file, for --manifest
+    let mut spans = Vec::new();
+    let mut left_out = 0;
+    let mut position = match mode {
+        WriteMode::Append if !remux => std::fs::metadata(&write_path).map_or(0, |meta| meta.len()),
+        _ => 0,
+    };
+
+    // PCM is headerless, so it streams straight through behind a placeholder
+    // header whose lengths are patched once the stream has finished. Stdout
+    // can't be patched, so there the placeholder claims the maximum length,
+    // as streaming WAV writers conventionally do. Appending carries on from
+    // the header already there (main checked it's one of ours).
+    if wrap_pcm_in_wav && position >= 44 {
+        data_len = position - 44;
+    } else if wrap_pcm_in_wav {
+        let placeholder = if file_path == STDOUT_PATH {
+            u32::MAX
+        } else {
+            0
+        };
+        let header = merge::wav_header(resample::output_format(), placeholder);
+        if !write_output(&mut output, &header, &write_path) {
+            return spans;
+        }
+        position += header.len() as u64;
+    }
+    // Whether a gap goes before the next chunk: there's audio before it,
+    // including an existing file being appended to
+    let mut follows_audio = position > if wrap_pcm_in_wav { 44 } else { 0 };
+    let gap = merge::silence(resample::output_format(), join_gap);
+
+    while let Some(chunk) = audio_rx.recv().await {
+        buffer.insert(chunk);
+        while let Some(chunk) = buffer.pop_ready() {
+            if merge::needs_merge(audio_format) {
+                held_back.push(chunk.bytes.to_vec());
+            } else {
+                // A torn sample would misalign everything written after it
+                if audio_format == "pcm" {
+                    if let Err(e) = merge::check_pcm_in(&chunk.bytes, resample::output_format()) {
+                        log::error!("Leaving out chunk {}: {}", chunk.index, e);
+                        left_out += 1;
+                        continue;
+                    }
+                }
+                if audio_format == "pcm" && follows_audio && !gap.is_empty() {
+                    if !write_output(&mut output, &gap, &write_path) {
+                        return spans;
+                    }
+                    position += gap.len() as u64;
+                    data_len += gap.len() as u64;
+                }
+                if !write_output(&mut output, &chunk.bytes, &write_path) {
+                    return spans;
+                }
+                follows_audio = true;
+                let length = chunk.bytes.len() as u64;
+                spans.push(manifest::Span {
+                    index: chunk.index,
+                    offset: position,
+                    length,
+                });
+                position += length;
+                data_len += length;
+            }
+        }
+    }
+
+    if !held_back.is_empty() {
+        let merged =
+            merge::merge_chunks_apart(audio_format, &held_back, join_gap).unwrap_or_else(|e| {
+                eprintln!("Failed to merge audio into {}: {}", file_path, e);
+                std::process::exit(1);
+            });
+        if !write_output(&mut output, &merged, &write_path) {
+            return spans;
+        }
+    }
+
+    flush_output(&mut output, &write_path);
+    drop(output);
+
+    if wrap_pcm_in_wav && file_path != STDOUT_PATH {
+        let data_len = u32::try_from(data_len).unwrap_or_else(|_| {
+            eprintln!("{} is too long for a WAV file (over 4 GiB)", write_path);
+            std::process::exit(1);
+        });
+        let patched = OpenOptions::new()
+            .write(true)
+            .open(&write_path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&merge::wav_header(resample::output_format(), data_len))
+            });
+        if let Err(e) = patched {
+            eprintln!("Failed to finish the WAV header of {}: {}", write_path, e);
+            std::process::exit(1);
+        }
+    }
+
+    // A failed chunk holds back everything after it
+    let written = buffer.next_index - left_out;
+    let chunk_count = expected_chunks.load(Ordering::SeqCst);
+    if written < chunk_count {
+        if mode == WriteMode::Atomic {
+            eprintln!(
+                "Only {} of {} chunks were written; the partial audio was left in {}",
+                written, chunk_count, write_path
+            );
+            std::process::exit(1);
+        }
+        log::warn!(
+            "Only {} of {} chunks were written to {}",
+            written,
+            chunk_count,
+            write_path
+        );
+    }
+
+    // Tags go in once the audio is final, so stdout never gets any
+    if let Some(tags) = tags.filter(|_| file_path != STDOUT_PATH) {
+        if let Err(e) = tags::write_tags(Path::new(&write_path), audio_format, &tags) {
+            log::warn!("Failed to tag {}: {}", write_path, e);
+        }
+    }
+
+    if mode == WriteMode::Atomic || remux {
+        if let Err(e) = std::fs::rename(&write_path, &file_path) {
+            eprintln!(
+                "Failed to move {} into place as {}: {}",
+                write_path, file_path, e
+            );
+            std::process::exit(1);
+        }
+    }
+
+    spans
+}
+
+#[tokio::main]
+async fn main() {
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+    let (mut cli, config_warnings) = config::parse_cli();
+    init_logging(cli.log_target);
+    for warning in &config_warnings {
+        log::warn!("{}", warning);
+    }
+    NO_AUDIO.store(cli.no_audio, Ordering::Relaxed);
+    NO_AUTH.store(cli.no_auth, Ordering::Relaxed);
+    VALIDATING.store(cli.validate, Ordering::Relaxed);
+    sse::init(cli.sse);
+    limiter::init(cli.concurrency);
+    memory::init(cli.max_memory);
+    breaker::init(cli.max_failure_rate);
+    match cli.command {
+        Some(Command::Config(ConfigCommand::Init)) => {
+            if let Err(e) = config::init() {
+                eprintln!("Failed to write the config file: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Config(ConfigCommand::Validate)) => {
+            std::process::exit(if config::validate(cli.no_auth) { 0 } else { 1 });
+        }
+        Some(Command::Completions { shell }) => {
+            completions::print(shell);
+            return;
+        }
+        Some(Command::Profiles) => {
+            if let Err(e) = config::list_profiles() {
+                eprintln!("Invalid config file {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Voices(VoicesCommand::List { json, sample })) => {
+            let default = cli.voice.or(load_config().voice).unwrap_or(Voice::Alloy);
+            if json {
+                println!("{}", voices::to_json(&voices::VOICES, default));
+            } else {
+                println!("{}", voices::render_table(&voices::VOICES, default));
+            }
+            if sample {
+                play_voice_samples(&cli).await;
+            }
+            return;
+        }
+        Some(Command::Voices(VoicesCommand::Sample {
+            voice,
+            ref text,
+            format,
+            hd,
+            ref output_file,
+            force,
+        })) => {
+            let text = text.as_deref().unwrap_or(voices::SAMPLE_PHRASE);
+            let audition = Audition {
+                voice,
+                format,
+                hd,
+                output_file: output_file.as_deref(),
+                force,
+            };
+            play_voice_sample(&cli, text, audition).await;
+            return;
+        }
+        Some(Command::Preview {
+            voice,
+            all,
+            ref text,
+            hd,
+            ref output_dir,
+            force,
+            estimate,
+        }) => {
+            let default = cli.voice.or(load_config().voice).unwrap_or(Voice::Alloy);
+            let preview = Preview {
+                voices: if all {
+                    voices::VOICES.iter().map(|info| info.voice).collect()
+                } else {
+                    vec![voice.unwrap_or(default)]
+                },
+                text: text.as_deref().unwrap_or(voices::SAMPLE_PHRASE),
+                hd,
+                output_dir: output_dir.as_deref(),
+                force,
+                estimate,
+            };
+            run_preview(&cli, preview).await;
+            return;
+        }
+        Some(Command::Selftest { yes }) => {
+            run_selftest(&cli, yes).await;
+            return;
+        }
+        Some(Command::Serve {
+            port,
+            ref bind,
+            ref auth_token,
+            ref socket,
+        }) => {
+            let defaults = server_defaults(&cli);
+            let client = client_for(&cli);
+            let served = match socket {
+                Some(path) => serve_socket(path, client, defaults).await,
+                None => serve::run(bind, port, client, defaults, auth_token.clone()).await,
+            };
+            if let Err(e) = served {
+                eprintln!("Failed to serve: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::GrpcServe { port, ref bind }) => {
+            let defaults = server_defaults(&cli);
+            if let Err(e) = grpc::run(bind, port, client_for(&cli), defaults).await {
+                eprintln!("Failed to serve: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Client {
+            ref socket,
+            ref text,
+            voice,
+            format,
+            speed,
+            ref output_file,
+            force,
+        }) => {
+            let text = match text {
+                Some(text) => text.clone(),
+                None => {
+                    let mut buffer = Vec::new();
+                    io::stdin()
+                        .read_to_end(&mut buffer)
+                        .expect("Failed to read from stdin");
+                    decode_input(&buffer, UTF_8, "stdin")
+                }
+            };
+            let request = serde_json::json!({
+                "text": text,
+                "voice": voice.map(Voice::name),
+                "format": format.map(ResponseFormat::api_name),
+                "speed": speed,
+            });
+            let audio = request_from_socket(socket, &request)
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Request failed: {}", e);
+                    std::process::exit(1);
+                });
+            match output_file {
+                Some(path) => {
+                    let written = check_output_path(path, force, false, false).and_then(|_| {
+                        playlist::write_atomically(Path::new(path), &audio)
+                            .map_err(|e| e.to_string())
+                    });
+                    if let Err(e) = written {
+                        eprintln!("Failed to write {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                }
+                None => {
+                    if let Err(e) = audio_device_available() {
+                        eprintln!("{} ({})", NO_AUDIO_DEVICE, e);
+                        std::process::exit(1);
+                    }
+                    // Raw PCM is the one format without a signature
+                    let audio_format = format
+                        .map(ResponseFormat::api_name)
+                        .or_else(|| merge::sniff_format(&audio))
+                        .unwrap_or("pcm");
+                    let chunk = AudioChunk {
+                        index: 0,
+                        bytes: Bytes::from(audio),
+                        content_type: None,
+                        duration: Arc::default(),
+                        held: Arc::default(),
+                    };
+                    play_chunk(chunk, audio_format).await;
+                }
+            }
+            return;
+        }
+        Some(Command::Speak | Command::Save) | None => {}
+    }
+    if cli.list_formats {
+        println!("{}", render_formats());
+        return;
+    }
+    if let Some(dir) = &cli.generate_manpage {
+        match manpage::write_to(Path::new(dir)) {
+            Ok(paths) => {
+                for path in paths {
+                    println!("{}", path.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to write the man pages to {}: {}", dir, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if cli.progress_json {
+        progress::enable();
+    }
+    if cli.stdout {
+        cli.output_file = Some(STDOUT_PATH.to_string());
+    }
+    let to_stdout = cli.output_file.as_deref() == Some(STDOUT_PATH);
+    if to_stdout && cli.play {
+        eprintln!("Can't play audio while writing it to stdout");
+        std::process::exit(1);
+    }
+    if to_stdout && cli.upload.is_some() {
+        eprintln!("Can't upload audio written to stdout; give --output-file a path");
+        std::process::exit(1);
+    }
+
+    if let (Some(dir), Some(output_file)) = (&cli.merge, &cli.output_file) {
+        merge_chunk_files(Path::new(dir), output_file, cli.force, cli.mkdirs);
+        return;
+    }
+
+    // With --archive every file is written into a staging directory laid out
+    // like the archive, which is zipped at the end
+    let archive = cli.archive.clone().map(|path| {
+        if to_stdout {
+            eprintln!("Can't archive audio written to stdout");
+            std::process::exit(1);
+        }
+        if cli.output_file.is_none()
+            && cli.output_template.is_none()
+            && cli.split_output_dir.is_none()
+            && cli.output_dir.is_none()
+        {
+            eprintln!(
+                "--archive needs audio files to pack: give --output-file, --output-template, \
+                 --split-output-dir or --output-dir"
+            );
+            std::process::exit(1);
+        }
+        if let Err(e) = check_output_path(&path, cli.force, false, cli.mkdirs) {
+            eprintln!("Refusing to write the archive: {}", e);
+            std::process::exit(1);
+        }
+        let path = std::path::absolute(&path).unwrap_or_else(|_| path.into());
+        let staging = archive::staging_dir(&path);
+        let audio_dir = staging.join(archive::AUDIO_DIR);
+        let audio_dir = audio_dir.to_string_lossy().into_owned();
+
+        let in_audio = |path: &String| archive::staged(&staging, archive::AUDIO_DIR, path);
+        let at_top = |path: &String| archive::staged(&staging, "", path);
+        cli.output_file = cli.output_file.as_ref().map(in_audio);
+        cli.output_template = match (&cli.output_template, &cli.output_dir) {
+            (Some(template), None) => {
+                let name = Path::new(template).file_name().unwrap_or_default();
+                Some(template::in_dir(&audio_dir, &name.to_string_lossy()))
+            }
+            (template, _) => template.clone(),
+        };
+        cli.output_dir = cli.output_dir.as_ref().map(|_| audio_dir.clone());
+        cli.split_output_dir = cli.split_output_dir.as_ref().map(|_| audio_dir.clone());
+        // Absolute, so they aren't put in --output-dir as well
+        cli.playlist = cli.playlist.as_ref().map(at_top);
+        cli.manifest = cli.manifest.as_ref().map(at_top);
+        cli.subtitles = cli.subtitles.as_ref().map(at_top);
+        cli.save_text = cli.save_text.as_ref().map(at_top);
+        (path, staging)
+    });
+    // The staging directory is only created once the run is about to fetch,
+    // so nothing before then has to clean it up; being new, nothing in it
+    // needs checking either
+    let is_staged = |path: &str| {
+        archive
+            .as_ref()
+            .is_some_and(|(_, staging)| Path::new(path).starts_with(staging))
+    };
+    if to_stdout && !cli.base64_output && io::stdout().is_terminal() {
+        eprintln!(
+            "Refusing to write binary audio to a terminal; redirect stdout to a file or pipe"
+        );
+        std::process::exit(1);
+    }
+
+    let config = load_config();
+
+    let input_encoding = cli.input_encoding.unwrap_or(UTF_8);
+    let epub_input = cli.epub
+        || cli
+            .input_file
+            .as_deref()
+            .is_some_and(|file| file.to_lowercase().ends_with(".epub"));
+    let input_text = if cli.use_stdin {
+        let mut buffer = Vec::new();
+        io::stdin()
+            .read_to_end(&mut buffer)
+            .expect("Failed to read from stdin");
+        decode_input(&buffer, input_encoding, "stdin")
+    } else if let Some(format) = cli.read_clipboard_format {
+        clipboard_format::read(format).unwrap_or_else(|e| {
+            eprintln!("Failed to access clipboard contents: {}", e);
+            std::process::exit(1);
+        })
+    } else if cli.clipboard {
+        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
+        ctx.get_contents().unwrap_or_else(|_| {
+            eprintln!("Failed to access clipboard contents.");
+            std::process::exit(1);
+        })
+    } else if let Some(command) = &cli.man {
+        read_man_page(command).unwrap_or_else(|e| {
+            eprintln!("Failed to read the man page for {}: {}", command, e);
+            std::process::exit(1);
+        })
+    } else if let Some(file) = &cli.input_file {
+        let bytes = std::fs::read(file).unwrap_or_else(|e| {
+            eprintln!("Failed to read file {}: {}", file, e);
+            std::process::exit(1);
+        });
+        if epub_input {
+            let sections = epub::read_chapters(&bytes).unwrap_or_else(|e| {
+                eprintln!("Failed to read EPUB {}: {}", file, e);
+                std::process::exit(1);
+            });
+            epub::to_text(&sections)
+        } else if file.to_lowercase().ends_with(".ipynb") {
+            let text = decode_input(&bytes, input_encoding, file);
+            let options = notebook::NotebookOptions {
+                code_cells: cli.read_code_cells,
+                outputs: cli.read_cell_outputs,
+            };
+            notebook::extract_text(&text, options).unwrap_or_else(|e| {
+                eprintln!("Failed to read notebook {}: {}", file, e);
+                std::process::exit(1);
+            })
+        } else {
+            decode_input(&bytes, input_encoding, file)
+        }
+    } else if let Ok(text) = std::env::var("SAYIT_TEXT") {
+        // Lowest priority source, handy for `SAYIT_TEXT="$(cmd)" sayit`
+        log::debug!("Reading input from the SAYIT_TEXT environment variable");
+        text
+    } else {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "No input source specified.",
+            )
+            .exit();
+    };
+
+    // The hook may do anything, so --validate leaves it out
+    let input_text = match cli.before_fetch.as_ref().filter(|_| !cli.validate) {
+        Some(command) => {
+            log::info!("Running --before-fetch: {}", command);
+            hooks::filter(command, &input_text).unwrap_or_else(|e| {
+                eprintln!("--before-fetch failed: {}", e);
+                std::process::exit(1);
+            })
+        }
+        None => input_text,
+    };
+
+    let input_text = if cli.offset.is_some() || cli.length.is_some() {
+        let offset = cli.offset.unwrap_or(0);
+        let (slice, range, total) = preprocess::slice_chars(&input_text, offset, cli.length)
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid --offset/--length: {}", e);
+                std::process::exit(1);
+            });
+        eprintln!(
+            "Reading characters {}..{} of {}",
+            range.start, range.end, total
+        );
+        slice.to_string()
+    } else {
+        input_text
+    };
+
+    let normalization = if cli.unicode_nfc {
+        Some(preprocess::Normalization::Nfc)
+    } else if cli.unicode_nfkc {
+        Some(preprocess::Normalization::Nfkc)
+    } else if cli.unicode_nfkd {
+        Some(preprocess::Normalization::Nfkd)
+    } else {
+        None
+    };
+    let input_text = match normalization {
+        Some(form) => preprocess::normalize(&input_text, form),
+        None => input_text,
+    };
+
+    let skip_rules = preprocess::SkipRules {
+        lines: RegexSet::new(&cli.skip_pattern).unwrap_or_else(|e| {
+            eprintln!("Invalid --skip-pattern: {}", e);
+            std::process::exit(1);
+        }),
+        block: cli
+            .skip_block_start
+            .as_deref()
+            .zip(cli.skip_block_end.as_deref())
+            .map(|(start, end)| {
+                (
+                    regex_arg("--skip-block-start", start),
+                    regex_arg("--skip-block-end", end),
+                )
+            }),
+    };
+    let input_text = if skip_rules.is_empty() {
+        input_text
+    } else {
+        preprocess::skip_lines(&input_text, &skip_rules)
+    };
+
+    let input_text = if cli.strip_asciidoc {
+        preprocess::strip_asciidoc(&input_text)
+    } else if cli.strip_rst {
+        preprocess::strip_rst(&input_text)
+    } else {
+        input_text
+    };
+
+    let replacements: Vec<(Regex, String)> = cli
+        .replace
+        .chunks(2)
+        .map(|pair| (regex_arg("--replace", &pair[0]), pair[1].clone()))
+        .collect();
+    let input_text = preprocess::apply_replacements(&input_text, &replacements);
+    let input_text = match &cli.pronounce_file {
+        Some(path) => {
+            let source = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("Failed to read {}: {}", path, e);
+                std::process::exit(1);
+            });
+            let dictionary = pronounce::Dictionary::parse(&source, cli.pronounce_ignore_case)
+                .unwrap_or_else(|e| {
+                    eprintln!("Invalid --pronounce-file {}: {}", path, e);
+                    std::process::exit(1);
+                });
+            dictionary.apply(&input_text)
+        }
+        None => input_text,
+    };
+
+    // A configured format gives way to one implied by the output file name
+    let implied_format = cli.output_file.as_deref().and_then(format_for_extension);
+    let requested_format = match cli.format {
+        None if cli.wav => Some(ResponseFormat::Pcm),
+        None if implied_format.is_none() => config.format,
+        format => format,
+    };
+    let output_file_format = match resolve_format(
+        requested_format,
+        cli.output_file
+            .as_deref()
+            .filter(|path| *path != STDOUT_PATH),
+        cli.force,
+    ) {
+        Ok(format) => format.api_name(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if cli.wav && output_file_format != "pcm" {
+        eprintln!("--wav only applies to --format pcm");
+        std::process::exit(1);
+    }
+    if (cli.rate.is_some() || cli.channels.is_some() || cli.wav_bits.is_some())
+        && output_file_format != "pcm"
+    {
+        eprintln!(
+            "--rate, --channels and --wav-bits only apply to pcm and WAV output (--format pcm, \
+             or a .wav output file), not {}",
+            output_file_format
+        );
+        std::process::exit(1);
+    }
+    resample::init(cli.rate, cli.channels, cli.wav_bits);
+    if cli.join_gap_ms > 0 && !matches!(output_file_format, "pcm" | "flac") {
+        eprintln!(
+            "--join-gap-ms only applies to pcm, WAV and FLAC output, not {}; other formats \
+             are joined as the API sends them",
+            output_file_format
+        );
+        std::process::exit(1);
+    }
+
+    // An EPUB read into --output-dir is saved a chapter per file: the parts
+    // of <input>.<ext> in that directory, split at every chapter
+    let chapter_files = epub_input && cli.output_dir.is_some() && cli.output_template.is_none();
+    if let Some(dir) = cli.output_dir.clone().filter(|_| chapter_files) {
+        let prepared = if is_staged(&dir) {
+            Ok(())
+        } else {
+            prepare_output_dir(&dir)
+        };
+        if let Err(e) = prepared {
+            eprintln!("Unusable --output-dir: {}", e);
+            std::process::exit(1);
+        }
+        let stem = cli
+            .input_file
+            .as_deref()
+            .and_then(|file| Path::new(file).file_stem())
+            .map_or("book".to_string(), |stem| {
+                template::sanitize_file_name(&stem.to_string_lossy())
+            });
+        let ext = if cli.wav { "wav" } else { output_file_format };
+        let path = Path::new(&dir).join(format!("{}.{}", stem, ext));
+        cli.output_file = Some(path.to_string_lossy().into_owned());
+    }
+
+    let saves_audio = cli.output_file.is_some()
+        || cli.output_template.is_some()
+        || cli.split_output_dir.is_some()
+        || cli.output_dir.is_some();
+    if matches!(cli.command, Some(Command::Save)) && !saves_audio && !cli.base64_output {
+        let mut command = cli_command();
+        command.build();
+        let save = command.find_subcommand_mut("save").unwrap();
+        save.error(
+            clap::error::ErrorKind::MissingRequiredArgument,
+            "sayit save requires --output-file, --stdout, --output-template, \
+                 --split-output-dir, --output-dir or --base64-output",
+        )
+        .exit();
+    }
+    if cli.no_play && !saves_audio {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--no-play requires --output-file, --output-template, --split-output-dir \
+                 or --output-dir",
+            )
+            .exit();
+    }
+    if cli.no_audio && !saves_audio && !cli.base64_output {
+        if !cli.json {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--no-audio requires --output-file, --output-template, --split-output-dir, \
+                     --output-dir or --json",
+                )
+                .exit();
+        }
+        // With nowhere else for the audio to go, it's printed as JSON
+        cli.base64_output = true;
+    } else if !saves_audio && !cli.base64_output && !cli.no_audio && in_container() {
+        log::warn!(
+            "sayit seems to be running in a container, which usually has no audio device; \
+             write the audio to a file with -o, or pass --no-audio"
+        );
+    }
+
+    // .m4a and .m4b output is the AAC chunks remuxed into an MP4 file, which
+    // for .m4b is an audiobook with chapters
+    let mp4_output = !cli.base64_output && cli.output_file.as_deref().is_some_and(is_mp4_path);
+    let m4b_output = mp4_output
+        && cli
+            .output_file
+            .as_deref()
+            .is_some_and(|path| path.to_lowercase().ends_with(".m4b"));
+    if mp4_output && output_file_format != "aac" {
+        eprintln!("MP4 files (.m4a, .m4b) hold AAC audio; use --format aac or leave --format out");
+        std::process::exit(1);
+    }
+    // An audiobook's chapters already divide it, so they win over --split-every
+    // and --split-output-by-size
+    let split_limits = (cli.split_every.is_some() || cli.split_output_by_size.is_some())
+        .then_some(split::Limits {
+            max_duration: cli.split_every.unwrap_or(Duration::MAX),
+            max_bytes: cli.split_output_by_size,
+        })
+        .filter(|_| {
+            if m4b_output {
+                log::warn!(
+                    "Ignoring --split-every and --split-output-by-size: .m4b output is divided \
+                     into chapters instead"
+                );
+            } else if mp4_output {
+                log::warn!(
+                    "Ignoring --split-every and --split-output-by-size: .m4a output is always \
+                     one file"
+                );
+            }
+            !mp4_output
+        });
+    let split_limits = if chapter_files {
+        Some(split::Limits {
+            max_duration: Duration::MAX,
+            max_bytes: None,
+        })
+    } else {
+        split_limits
+    };
+    if split_limits.is_some() && cli.output_file.as_deref() == Some(STDOUT_PATH) {
+        eprintln!("Can't split audio written to stdout into parts");
+        std::process::exit(1);
+    }
+    let cover = cli.cover.as_deref().map(|path| {
+        if !mp4_output {
+            eprintln!("--cover only applies to .m4a and .m4b output");
+            std::process::exit(1);
+        }
+        std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read cover image {}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+
+    let wrap_pcm_in_wav = output_file_format == "pcm"
+        && (cli.wav
+            || cli
+                .output_file
+                .as_deref()
+                .is_some_and(|path| path.to_lowercase().ends_with(".wav")));
+
+    let reading_voice = cli.voice.or(config.voice).unwrap_or(Voice::Alloy).name();
+
+    let speed = match cli.target_wpm {
+        Some(target_wpm) => {
+            let speed = speed_for_wpm(target_wpm);
+            if config::check_speed(target_wpm as f32 / NATURAL_WPM).is_err() {
+                log::warn!(
+                    "{} words per minute is beyond the supported speeds; using speed {}",
+                    target_wpm,
+                    speed
+                );
+            } else {
+                log::info!(
+                    "Using speed {} to approximate {} words per minute",
+                    speed,
+                    target_wpm
+                );
+            }
+            speed
+        }
+        None => cli.speed.or(config.speed).unwrap_or(1.0), // Default speed
+    };
+    let tts_model = config::tts_model(cli.hd, cli.model.as_deref(), &config);
+
+    let settings = SpeechSettings {
+        audio_format: output_file_format,
+        reading_voice,
+        tts_model,
+        speed,
+        show_api_errors: cli.show_api_errors,
+    };
+
+    let input_name = cli
+        .input_file
+        .as_deref()
+        .and_then(|file| Path::new(file).file_stem())
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .or_else(|| cli.man.clone());
+    let mut template_values = template::TemplateValues {
+        voice: reading_voice,
+        model: tts_model,
+        ext: output_file_format,
+        input: input_name.clone().unwrap_or_default(),
+        chapters: Vec::new(),
+    };
+    if let Some(output_template) = &cli.output_template {
+        if let Err(e) = template::validate(output_template, &template_values) {
+            eprintln!("Invalid --output-template: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let client = client_for(&cli);
+    let (audio_tx, audio_rx) = mpsc::channel::<AudioChunk>(cli.buffer_size);
+    // The chunks of a stretch of the input, each segment of speed markup
+    // read at its own speed
+    let body_chunks = |text: &str| -> Vec<TextChunk> {
+        let segments = if cli.speed_per_segment {
+            let (segments, warnings) = preprocess::speed_segments(text);
+            for warning in warnings {
+                log::warn!("Speed markup, {}", warning);
+            }
+            segments
+        } else {
+            vec![preprocess::SpeedSegment {
+                text: text.to_string(),
+                speed: None,
+            }]
+        };
+        segments
+            .into_iter()
+            .flat_map(|segment| {
+                let settings = SpeechSettings {
+                    speed: segment.speed.unwrap_or(settings.speed),
+                    ..settings
+                };
+                split_input(&segment.text, 4096)
+                    .into_iter()
+                    .map(move |text| TextChunk {
+                        text,
+                        settings,
+                        chapter: None,
+                    })
+            })
+            .collect()
+    };
+    // EPUB chapters always divide the input; other headings only with
+    // --chapter-announce
+    let chapter_pattern = match cli.chapter_pattern.as_deref() {
+        Some(pattern) if cli.chapter_announce => Some(pattern),
+        _ if epub_input => Some(epub::HEADING_PATTERN),
+        _ if cli.chapter_announce => Some(chapters::DEFAULT_CHAPTER_PATTERN),
+        _ => None,
+    };
+    let mut chunks: Vec<TextChunk> = if let Some(pattern) = chapter_pattern {
+        let pattern = regex_arg("--chapter-pattern", pattern);
+        // Announcements are read in HD so they stand out from the body
+        let announcement_settings = SpeechSettings {
+            tts_model: "tts-1-hd",
+            ..settings
+        };
+
+        let mut chunks = Vec::new();
+        let mut chapter = 0;
+        for section in chapters::split_sections(&input_text, &pattern) {
+            let mut body = body_chunks(&section.body);
+            if let Some(title) = section.title {
+                chapter += 1;
+                if cli.chapter_announce {
+                    chunks.push(TextChunk {
+                        text: chapters::announcement(chapter, &title),
+                        settings: announcement_settings,
+                        chapter: Some(title),
+                    });
+                } else if let Some(first) = body.first_mut() {
+                    first.chapter = Some(title);
+                }
+            }
+            chunks.extend(body);
+        }
+        chunks
+    } else {
+        body_chunks(&input_text)
+    };
+
+    if preprocess::contains_rtl(&input_text) && !cli.rtl {
+        log::warn!(
+            "The input contains right-to-left text, but chunking only understands \
+             left-to-right sentence boundaries; check the result carefully (--rtl \
+             reverses the chunk order)"
+        );
+    }
+    if cli.rtl {
+        chunks.reverse();
+    }
+
+    if cli.shuffle_chunks {
+        let seed = cli.seed.unwrap_or_else(rand::random);
+        eprintln!("Shuffling chunks with seed {}", seed);
+        chunks.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
+
+    // The intro and outro stay put whatever order the body is read in, and
+    // get chunks of their own so there's a pause around them
+    let segment = |text: &str| {
+        split_input(text, 4096).into_iter().map(|text| TextChunk {
+            text,
+            settings,
+            chapter: None,
+        })
+    };
+    if let Some(prefix) = &cli.prefix {
+        chunks.splice(0..0, segment(prefix));
+    }
+    if let Some(suffix) = &cli.suffix {
+        chunks.extend(segment(suffix));
+    }
+
+    // Where the transcript goes (--save-text), if anywhere
+    let save_text = cli.save_text.clone().or_else(|| {
+        cli.output_file
+            .as_deref()
+            .filter(|path| *path != STDOUT_PATH && config.save_text == Some(true))
+            .map(transcript::path_for_output)
+    });
+
+    let base64_options = Base64Options {
+        lines: cli.base64_lines,
+        data_uri: cli.data_uri,
+        json: cli.json,
+    };
+    template_values.chapters = chapter_titles(&chunks);
+    let output_template = if let Some(dir) = &cli.split_output_dir {
+        let created = if cli.validate || is_staged(dir) {
+            Ok(())
+        } else {
+            std::fs::create_dir_all(dir)
+        };
+        if let Err(e) = created {
+            eprintln!("Failed to create {}: {}", dir, e);
+            std::process::exit(1);
+        }
+        Some(template::split_dir_template(dir, chunks.len()))
+    } else if let Some(dir) = cli.output_dir.as_ref().filter(|_| !chapter_files) {
+        let prepared = if is_staged(dir) {
+            Ok(())
+        } else {
+            prepare_output_dir(dir)
+        };
+        if let Err(e) = prepared {
+            eprintln!("Unusable --output-dir: {}", e);
+            std::process::exit(1);
+        }
+        let file_template = cli.output_template.clone().unwrap_or_else(|| {
+            let prefix = input_name
+                .as_deref()
+                .map_or("chunk".to_string(), template::sanitize_file_name)
+                .replace('{', "{{")
+                .replace('}', "}}");
+            template::numbered_template(&prefix, chunks.len())
+        });
+        Some(template::in_dir(dir, &file_template))
+    } else {
+        cli.output_template.clone()
+    };
+    // Relative playlist paths are generated files like any other
+    let playlist_path = match (&cli.output_dir, &cli.playlist) {
+        (Some(dir), Some(playlist)) => {
+            Some(Path::new(dir).join(playlist).to_string_lossy().into_owned())
+        }
+        (_, playlist) => playlist.clone(),
+    };
+    // Parts by size always come with a playlist to keep them in order
+    let playlist_path = playlist_path.or_else(|| {
+        let output_file = cli.output_file.as_ref()?;
+        split_limits?.max_bytes?;
+        Some(
+            Path::new(output_file)
+                .with_extension("m3u8")
+                .to_string_lossy()
+                .into_owned(),
+        )
+    });
+
+    // Frame streams are appended to as they are, WAV by patching its header
+    // and FLAC and Opus by remuxing; base64 only splits into whole chunks
+    // per line, and an MP4 file's sample tables can't be extended
+    if cli.append && cli.base64_output && !base64_options.per_chunk() {
+        eprintln!("--append with --base64-output needs --base64-lines or --json");
+        std::process::exit(1);
+    }
+    if cli.append && mp4_output {
+        eprintln!("--append can't add to an .m4a or .m4b file");
+        std::process::exit(1);
+    }
+    let appends_audio = cli.append && !cli.base64_output;
+    if let Some(path) = cli.output_file.as_deref().filter(|_| appends_audio) {
+        if let Ok(mut file) = File::open(path) {
+            let mut head = Vec::new();
+            let file_len = file.metadata().map_or(0, |meta| meta.len());
+            let checked = (&mut file)
+                .take(64)
+                .read_to_end(&mut head)
+                .map_err(|e| e.to_string())
+                .and_then(|_| {
+                    merge::check_append(
+                        &head,
+                        file_len,
+                        output_file_format,
+                        wrap_pcm_in_wav,
+                        resample::output_format(),
+                    )
+                });
+            if let Err(e) = checked {
+                eprintln!("Can't append to {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let subtitle_output = cli.subtitles.as_ref().map(|path| {
+        let format = subtitles::SubtitleFormat::for_path(path).unwrap_or_else(|e| {
+            eprintln!("Invalid --subtitles: {}", e);
+            std::process::exit(1);
+        });
+        (path.clone(), format)
+    });
+
+    if playlist_path.is_some() && output_template.is_none() && split_limits.is_none() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--playlist requires --output-template, --split-output-dir, --output-dir, \
+                 --split-every or --split-output-by-size",
+            )
+            .exit();
+    }
+
+    let chunk_files: Vec<String> = match &output_template {
+        // The template was validated above, or built by split_dir_template
+        Some(output_template) => (0..chunks.len())
+            .map(|index| template::render(output_template, index, &template_values).unwrap())
+            .collect(),
+        None => Vec::new(),
+    };
+    // Later parts are checked as they're started
+    let output_file_path = match split_limits {
+        Some(limits) => cli
+            .output_file
+            .as_deref()
+            .map(|path| limits.part_path(path, 1)),
+        None => cli.output_file.clone(),
+    };
+    let output_paths: Vec<String> = chunk_files
+        .iter()
+        .cloned()
+        .chain(output_file_path.clone())
+        .chain(playlist_path.clone())
+        .chain(cli.manifest.clone())
+        .chain(cli.subtitles.clone())
+        .filter(|path| path != STDOUT_PATH)
+        .collect();
+    // An output older than its input is stale, along with everything else
+    // the run that wrote it left
+    let mut overwrite = cli.force;
+    if let (true, Some(input), Some(output)) =
+        (cli.since_modified, &cli.input_file, &output_file_path)
+    {
+        // A run that would be skipped still has its configuration checked
+        if is_newer(output, input) && !cli.force && !cli.validate {
+            let incremental = summary::Incremental {
+                regenerated: 0,
+                skipped: 1,
+            };
+            if !cli.quiet && cli.json {
+                eprintln!(
+                    "{}",
+                    serde_json::json!({ "incremental": incremental.to_json() })
+                );
+            } else if !cli.quiet {
+                eprintln!(
+                    "{} ({} is newer than {}; pass --force to regenerate it)",
+                    incremental.render(),
+                    output,
+                    input
+                );
+            }
+            return;
+        }
+        overwrite = true;
+    }
+    for path in output_paths.iter().filter(|path| !is_staged(path)) {
+        if let Err(e) = check_output_path(path, overwrite, cli.append, cli.mkdirs) {
+            eprintln!("Refusing to write output: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(link) = &cli.latest_link {
+        if let Err(e) = latest_link::check(Path::new(link), cli.force) {
+            eprintln!("Refusing to update --latest-link: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let mut plays_audio = !cli.no_audio && !cli.base64_output && (cli.play || !saves_audio);
+    if plays_audio {
+        if let Err(e) = audio_device_available() {
+            if !saves_audio {
+                eprintln!("{} ({})", NO_AUDIO_DEVICE, e);
+                std::process::exit(1);
+            }
+            log::warn!(
+                "No audio output device available ({}); only saving the audio",
+                e
+            );
+            plays_audio = false;
+        }
+    }
+
+    if let Some(dir) = &cli.dump_chunks_dir {
+        if let Err(e) = prepare_output_dir(dir) {
+            eprintln!("Unusable --dump-chunks-dir: {}", e);
+            std::process::exit(1);
+        }
+        if !cli.validate {
+            dump_chunks::init(dir);
+        }
+    }
+
+    if cli.validate {
+        require_api_key();
+        println!(
+            "configuration OK: {} chunk{}, voice {}, model {}, speed {}, format {}",
+            chunks.len(),
+            if chunks.len() == 1 { "" } else { "s" },
+            settings.reading_voice,
+            settings.tts_model,
+            settings.speed,
+            output_file_format
+        );
+        std::process::exit(0);
+    }
+
+    if let Some((_, staging)) = &archive {
+        let audio_dir = staging.join(archive::AUDIO_DIR);
+        if let Err(e) = std::fs::create_dir_all(&audio_dir) {
+            eprintln!("Failed to create {}: {}", audio_dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+    // Written before anything is fetched, so it's there even if the run fails
+    if let Some(path) = save_text.as_ref() {
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        let rendered = transcript::render(&texts);
+        if let Err(e) = playlist::write_atomically(Path::new(path), rendered.as_bytes()) {
+            eprintln!("Failed to write {}: {}", path, e);
+        }
+    }
+
+    let handles: Vec<_> = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let audio_tx = audio_tx.clone();
+            let client = client.clone();
+            let chunk = chunk.clone();
+            tokio::spawn(async move {
+                fetch_and_process_audio(&chunk.text, index, &client, audio_tx, chunk.settings).await
+            })
+        })
+        .collect();
+
+    // Decoding everything just to report the length and pace is only worth
+    // it when the report will be seen
+    let words: usize = chunks
+        .iter()
+        .map(|chunk| chunk.text.split_whitespace().count())
+        .sum();
+    let characters: usize = chunks.iter().map(|chunk| chunk.text.chars().count()).sum();
+    let chunk_count = chunks.len();
+    let expected_chunks = Arc::new(AtomicUsize::new(chunk_count));
+    let audio_rx = match cli.max_output_bytes {
+        Some(max_bytes) => limit_chunks(
+            audio_rx,
+            max_bytes,
+            expected_chunks.clone(),
+            cli.buffer_size,
+        ),
+        None => audio_rx,
+    };
+    let output_file = cli.output_file.clone();
+    let needs_durations = cli.manifest.is_some()
+        || subtitle_output.is_some()
+        || cli
+            .post_cmd
+            .as_deref()
+            .is_some_and(|command| command.contains("{duration}"));
+    let (audio_rx, measured) =
+        if !cli.quiet || needs_durations || log::log_enabled!(log::Level::Info) {
+            let (audio_rx, measured) =
+                measure_chunks(audio_rx, output_file_format, cli.buffer_size);
+            (audio_rx, Some(measured))
+        } else {
+            (audio_rx, None)
+        };
+
+    let (save_rx, play_rx) = if plays_audio && (saves_audio || cli.base64_output) {
+        let (save_rx, play_rx) = tee_chunks(audio_rx, cli.buffer_size);
+        (Some(save_rx), Some(play_rx))
+    } else if plays_audio {
+        (None, Some(audio_rx))
+    } else {
+        (Some(audio_rx), None)
+    };
+
+    let tags = (!cli.no_tags).then(|| tags::Tags {
+        title: cli.title.clone().or_else(|| input_name.clone()),
+        artist: cli
+            .artist
+            .clone()
+            .or_else(|| Some(reading_voice.to_string())),
+        album: cli.album.clone(),
+        comment: Some(format!(
+            "Read by {} ({}) at speed {}",
+            reading_voice, tts_model, speed
+        )),
+        track: None,
+    });
+
+    let mut consumers = Vec::new();
+    let mut written_spans = None;
+    let mut written_parts = None;
+    if let Some(save_rx) = save_rx {
+        consumers.push(if cli.base64_output {
+            tokio::spawn(audio_to_base64(
+                save_rx,
+                cli.output_file,
+                output_file_format,
+                base64_options,
+                cli.append,
+            ))
+        } else if let Some(output_template) = output_template {
+            let first_words = chunks
+                .iter()
+                .map(|chunk| chunk.text.split(' ').take(8).collect::<Vec<_>>().join(" "))
+                .collect();
+            let playlist = playlist_path.clone().map(|path| playlist::Playlist {
+                path,
+                titles: playlist_titles(&chunks, input_name.as_deref()),
+            });
+            tokio::spawn(template::audio_to_template_files(
+                save_rx,
+                output_template,
+                template_values,
+                first_words,
+                tags,
+                playlist,
+            ))
+        } else if mp4_output {
+            // Only an audiobook gets chapter markers
+            let chapters = chunks
+                .iter()
+                .enumerate()
+                .filter(|_| m4b_output)
+                .filter_map(|(index, chunk)| Some((index, chunk.chapter.clone()?)))
+                .collect();
+            tokio::spawn(m4b::audio_to_m4b(
+                save_rx,
+                cli.output_file.unwrap(),
+                m4b::Audiobook {
+                    chapters,
+                    tags,
+                    cover,
+                },
+            ))
+        } else if let Some(limits) = split_limits {
+            let chapter_starts = chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| chunk.chapter.is_some())
+                .map(|(index, _)| index)
+                .collect();
+            let writer = split::audio_to_parts(
+                save_rx,
+                cli.output_file.clone().unwrap(),
+                output_file_format,
+                wrap_pcm_in_wav,
+                tags,
+                split::SplitOptions {
+                    limits,
+                    chapter_starts,
+                    force: overwrite,
+                    buffer_size: cli.buffer_size,
+                },
+                expected_chunks,
+            );
+            let (parts_tx, parts_rx) = tokio::sync::oneshot::channel();
+            written_parts = Some(parts_rx);
+            tokio::spawn(async move {
+                let _ = parts_tx.send(writer.await);
+            })
+        } else {
+            // saves_audio without a template means an output file was given
+            let mode = if cli.append {
+                WriteMode::Append
+            } else if cli.no_atomic || to_stdout {
+                WriteMode::Direct
+            } else {
+                WriteMode::Atomic
+            };
+            let writer = audio_to_output_file(
+                save_rx,
+                cli.output_file.unwrap(),
+                output_file_format,
+                Layout {
+                    wrap_pcm_in_wav,
+                    join_gap: Duration::from_millis(cli.join_gap_ms),
+                },
+                mode,
+                tags,
+                expected_chunks,
+            );
+            let (spans_tx, spans_rx) = tokio::sync::oneshot::channel();
+            written_spans = Some(spans_rx);
+            tokio::spawn(async move {
+                let _ = spans_tx.send(writer.await);
+            })
+        });
+    }
+    if let Some(play_rx) = play_rx {
+        consumers.push(tokio::spawn(play_audio_from_queue(
+            play_rx,
+            output_file_format,
+        )));
+    }
+
+    let mut outcomes = Vec::with_capacity(chunk_count);
+    for handle in handles {
+        outcomes.push(handle.await.unwrap_or(FetchOutcome::Failed));
+    }
+
+    // Drop the sender to close the channel and end the playback loop
+    drop(audio_tx);
+
+    // Wait for the consumers to flush everything they buffered
+    for consumer in consumers {
+        let _ = consumer.await;
+    }
+
+    let failed: Vec<usize> = (0..outcomes.len())
+        .filter(|&index| outcomes[index] == FetchOutcome::Failed)
+        .collect();
+    progress::emit(progress::Event::Done {
+        chunks: chunk_count,
+        failed: &failed,
+    });
+
+    let measured = match measured {
+        Some(measured) => measured.await.unwrap_or_default(),
+        None => Measured::default(),
+    };
+    let duration = measured.duration;
+    if !duration.is_zero() {
+        log::info!(
+            "Read {} words in {:.1}s of audio: about {:.0} words per minute",
+            words,
+            duration.as_secs_f64(),
+            words as f64 / (duration.as_secs_f64() / 60.0)
+        );
+    }
+
+    let parts = match written_parts {
+        Some(parts) => parts.await.unwrap_or_default(),
+        None => Vec::new(),
+    };
+    if let Some(path) = playlist_path.as_ref().filter(|_| split_limits.is_some()) {
+        let source = input_name.as_deref().unwrap_or("Part");
+        let entries: Vec<playlist::Entry> = parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| playlist::Entry {
+                path: part.path.clone(),
+                title: format!("{} {}", source, i + 1),
+                duration: Some(part.duration),
+            })
+            .collect();
+        let m3u = playlist::render(&entries, Path::new(path));
+        if let Err(e) = playlist::write_atomically(Path::new(path), m3u.as_bytes()) {
+            eprintln!("Failed to write {}: {}", path, e);
+        }
+    }
+
+    if let Some((path, format)) = &subtitle_output {
+        let timed: Vec<(&str, Option<Duration>)> = chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| (chunk.text.as_str(), measured.durations.get(&index).copied()))
+            .collect();
+        let cues = subtitles::cues(&timed, Duration::from_millis(cli.join_gap_ms));
+        let rendered = subtitles::render(&cues, *format);
+        if let Err(e) = playlist::write_atomically(Path::new(path), rendered.as_bytes()) {
+            eprintln!("Failed to write {}: {}", path, e);
+        }
+    }
+
+    if let Some(manifest_path) = &cli.manifest {
+        let part_files: HashMap<usize, &str> = parts
+            .iter()
+            .flat_map(|part| {
+                part.spans
+                    .iter()
+                    .map(|span| (span.index, part.path.as_str()))
+            })
+            .collect();
+        let spans: HashMap<usize, manifest::Span> = match written_spans {
+            Some(spans) => spans.await.unwrap_or_default(),
+            None => Vec::new(),
+        }
+        .into_iter()
+        .map(|span| (span.index, span))
+        .collect();
+        // Files are referred to by their place in the archive, if there is one
+        let manifest_path_of = |path: &str| match &archive {
+            Some((_, staging)) => archive::internal_path(staging, path),
+            None => path.to_string(),
+        };
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        let entries: Vec<manifest::ChunkEntry> = manifest::locate_chunks(&input_text, &texts)
+            .into_iter()
+            .enumerate()
+            .map(|(index, characters)| {
+                let settings = chunks[index].settings;
+                let location = if outcomes[index] != FetchOutcome::Fetched {
+                    manifest::Location::Unknown
+                } else if let Some(file) = chunk_files.get(index) {
+                    manifest::Location::File(manifest_path_of(file))
+                } else if let Some(file) = part_files.get(&index) {
+                    manifest::Location::File(manifest_path_of(file))
+                } else if let Some(span) = spans.get(&index) {
+                    manifest::Location::Span {
+                        offset: span.offset,
+                        length: span.length,
+                    }
+                } else {
+                    manifest::Location::Unknown
+                };
+                manifest::ChunkEntry {
+                    index,
+                    text: texts[index],
+                    characters,
+                    voice: settings.reading_voice,
+                    model: settings.tts_model,
+                    speed: settings.speed,
+                    location,
+                    duration: measured.durations.get(&index).copied(),
+                    outcome: outcomes[index],
+                }
+            })
+            .collect();
+        let output = output_file
+            .as_deref()
+            .filter(|path| *path != STDOUT_PATH)
+            .map(manifest_path_of);
+        let run = manifest::Run {
+            provider: "openai",
+            endpoint: SPEECH_ENDPOINT,
+            parameters: serde_json::json!({
+                "input": cli.input_file,
+                "offset": cli.offset,
+                "length": cli.length,
+                "format": output_file_format,
+                "voice": reading_voice,
+                "model": tts_model,
+                "speed": speed,
+            }),
+            output: output.as_deref(),
+            started: started_at,
+            finished: SystemTime::now(),
+        };
+        let json = serde_json::to_string_pretty(&manifest::render(&run, &entries)).unwrap();
+        if let Err(e) = playlist::write_atomically(Path::new(manifest_path), json.as_bytes()) {
+            eprintln!("Failed to write {}: {}", manifest_path, e);
+        }
+    }
+
+    // What the written files hold, for --post-cmd and --upload
+    let file_format = if wrap_pcm_in_wav {
+        "wav"
+    } else if m4b_output {
+        "m4b"
+    } else if mp4_output {
+        "m4a"
+    } else {
+        output_file_format
+    };
+    // Every audio file the run produced, in order
+    let produced: Vec<(String, Option<Duration>)> = if !parts.is_empty() {
+        parts
+            .iter()
+            .map(|part| (part.path.clone(), Some(part.duration)))
+            .collect()
+    } else if !chunk_files.is_empty() {
+        chunk_files
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| outcomes[*index] == FetchOutcome::Fetched)
+            .map(|(index, path)| (path.clone(), measured.durations.get(&index).copied()))
+            .collect()
+    } else {
+        output_file
+            .clone()
+            .filter(|path| path != STDOUT_PATH && !cli.base64_output)
+            .map(|path| (path, Some(duration).filter(|duration| !duration.is_zero())))
+            .into_iter()
+            .collect()
+    };
+    let mut post_cmd_failed = false;
+    if let Some(post_cmd) = &cli.post_cmd {
+        for (path, duration) in &produced {
+            if !Path::new(path).exists() {
+                continue;
+            }
+            let command_line = hooks::render(post_cmd, path, file_format, *duration);
+            log::info!("Running --post-cmd: {}", command_line);
+            match hooks::run(&command_line, "post-cmd", &[]) {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    log::warn!("--post-cmd failed for {} ({})", path, status);
+                    post_cmd_failed = true;
+                }
+                Err(e) => {
+                    log::warn!("Failed to run --post-cmd for {}: {}", path, e);
+                    post_cmd_failed = true;
+                }
+            }
+        }
+    }
+
+    let mut archive_failed = false;
+    if let Some((path, staging)) = &archive {
+        match archive::write(path, staging) {
+            Ok(files) => {
+                log::info!("Archived {} files in {}", files, path.display());
+                if let Err(e) = std::fs::remove_dir_all(staging) {
+                    log::warn!("Failed to remove {}: {}", staging.display(), e);
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to write {}: {}; the files are still in {}",
+                    path.display(),
+                    e,
+                    staging.display()
+                );
+                archive_failed = true;
+            }
+        }
+    }
+
+    let mut upload_failed = false;
+    if let (Some(url), Some(path)) = (&cli.upload, &output_file) {
+        let result = if !failed.is_empty() {
+            Err(format!(
+                "{} chunks failed, so the audio is incomplete",
+                failed.len()
+            ))
+        } else {
+            let client = build_client(cli.user_agent.clone(), Vec::new());
+            let content_type = upload::content_type(file_format);
+            upload::put_file(
+                &client,
+                url,
+                Path::new(path),
+                content_type,
+                &cli.upload_header,
+            )
+            .await
+        };
+        match result {
+            Ok(uploaded) => println!("{}", uploaded),
+            Err(e) => {
+                eprintln!(
+                    "Failed to upload to {}: {}; the audio is still at {}",
+                    url, e, path
+                );
+                upload_failed = true;
+            }
+        }
+    }
+
+    // What --copy-path and --latest-link point at; an archive stands in for
+    // the files packed into it
+    let finished: Vec<String> = match &archive {
+        Some((path, _)) => vec![path.to_string_lossy().into_owned()],
+        None => produced
+            .into_iter()
+            .map(|(path, _)| path)
+            .filter(|path| Path::new(path).exists())
+            .collect(),
+    };
+    let succeeded = failed.is_empty() && !archive_failed;
+    if let Some(mode) = cli.copy_path {
+        if !succeeded {
+            log::warn!("Not copying the output path: the run didn't succeed");
+        } else if let Some(text) = copy_path::text(&finished, mode) {
+            copy_path::copy(&text);
+        } else {
+            log::warn!("Not copying the output path: no file was written");
+        }
+    }
+
+    let mut latest_link_failed = false;
+    if let Some(link) = &cli.latest_link {
+        match latest_link::target(&finished, cli.latest_link_to) {
+            _ if !succeeded => {
+                log::warn!("Not updating {}: the run didn't succeed", link);
+            }
+            Some(target) => {
+                if let Err(e) = latest_link::update(Path::new(link), &target, cli.force) {
+                    eprintln!("Failed to update {}: {}", link, e);
+                    latest_link_failed = true;
+                }
+            }
+            None => log::warn!("Not updating {}: no file was written", link),
+        }
+    }
+
+    let mut after_play_failed = false;
+    if let Some(after_play) = &cli.after_play {
+        // One file, or the directory holding them all
+        let output = latest_link::target(&finished, latest_link::LinkTo::Dir)
+            .map_or(String::new(), |path| path.to_string_lossy().into_owned());
+        let env = [
+            ("SAYIT_OUTPUT", output),
+            ("SAYIT_CHUNKS", chunk_count.to_string()),
+            ("SAYIT_VOICE", reading_voice.to_string()),
+        ];
+        log::info!("Running --after-play: {}", after_play);
+        match hooks::run(after_play, "after-play", &env) {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                log::warn!("--after-play failed ({})", status);
+                after_play_failed = true;
+            }
+            Err(e) => {
+                log::warn!("Failed to run --after-play: {}", e);
+                after_play_failed = true;
+            }
+        }
+    }
+
+    if !cli.quiet {
+        let part_outputs = parts
+            .iter()
+            .map(|part| (part.path.clone(), Some(part.duration)));
+        let other_outputs = output_paths
+            .into_iter()
+            .filter(|path| split_limits.is_none() || Some(path) != output_file_path.as_ref())
+            .map(|path| (path, None));
+        let archive_output = archive
+            .iter()
+            .map(|(path, _)| (path.to_string_lossy().into_owned(), None));
+        let outputs = part_outputs
+            .chain(other_outputs)
+            .chain(archive_output)
+            .filter_map(|(path, duration)| {
+                let bytes = std::fs::metadata(&path).ok()?.len();
+                Some(summary::Output {
+                    path,
+                    bytes,
+                    duration,
+                })
+            })
+            .collect();
+        let summary = summary::Summary {
+            characters,
+            outcomes,
+            audio_bytes: measured.bytes,
+            duration: Some(duration).filter(|duration| !duration.is_zero()),
+            elapsed: started.elapsed(),
+            outputs,
+            incremental: cli.since_modified.then_some(summary::Incremental {
+                regenerated: 1,
+                skipped: 0,
+            }),
+        };
+        if cli.json {
+            eprintln!("{}", summary.to_json());
+        } else {
+            eprintln!("{}", summary.render());
+        }
+    }
+    if breaker::is_tripped()
+        || (post_cmd_failed && cli.post_cmd_strict)
+        || (after_play_failed && cli.after_play_fail)
+        || archive_failed
+        || upload_failed
+        || latest_link_failed
+    {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(index: usize, bytes: &'static [u8]) -> AudioChunk {
+        AudioChunk {
+            index,
+            bytes: Bytes::from_static(bytes),
+            content_type: None,
+            duration: Arc::default(),
+            held: Arc::default(),
+        }
+    }
+
+    fn assert_within_limit(chunks: &[String], max_length: usize) {
+        for chunk in chunks {
+            assert!(!chunk.is_empty(), "empty chunk in {:?}", chunks);
+            assert!(
+                chunk.len() <= max_length,
+                "chunk of {} bytes exceeds {}: {:?}",
+                chunk.len(),
+                max_length,
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn split_input_empty_string_has_no_chunks() {
+        assert!(split_input("", 10).is_empty());
+        assert!(split_input("   \n\t ", 10).is_empty());
+    }
+
+    #[test]
+    fn split_input_blank_lines_never_make_a_chunk() {
+        // A run of blank lines where a chunk ends is dropped with the rest of
+        // the whitespace rather than sent as a request of its own
+        let text = "one\n\n\n   \n\t\n\ntwo\n\n\n\nthree";
+        assert_eq!(split_input(text, 3), vec!["one", "two", "thr", "ee"]);
+        assert_eq!(split_input(text, 100), vec!["one two three"]);
+    }
+
+    #[test]
+    fn split_input_single_word() {
+        assert_eq!(split_input("hello", 10), vec!["hello"]);
+    }
+
+    #[test]
+    fn split_input_words_exactly_at_the_boundary() {
+        // "aaaa bbbb" is exactly 9 bytes and must fit in one chunk
+        assert_eq!(split_input("aaaa bbbb", 9), vec!["aaaa bbbb"]);
+        assert_eq!(split_input("aaaa bbbb cccc", 9), vec!["aaaa bbbb", "cccc"]);
+    }
+
+    #[test]
+    fn split_input_oversized_single_word_is_broken_up() {
+        let chunks = split_input(&"x".repeat(25), 10);
+        assert_eq!(chunks.len(), 3);
+        assert_within_limit(&chunks, 10);
+        assert_eq!(chunks.concat(), "x".repeat(25));
+    }
+
+    #[test]
+    fn split_input_multibyte_characters_stay_intact() {
+        let text = "héllo wörld ñandú 日本語のテキスト";
+        let chunks = split_input(text, 8);
+        assert_within_limit(&chunks, 8);
+        assert_eq!(chunks.concat().replace(' ', ""), text.replace(' ', ""));
+    }
+
+    #[test]
+    fn split_input_trailing_whitespace_is_dropped() {
+        assert_eq!(split_input("one two  \n\n", 100), vec!["one two"]);
+    }
+
+    #[test]
+    fn split_input_long_text_respects_the_limit() {
+        let text = "lorem ipsum dolor sit amet ".repeat(500);
+        let chunks = split_input(&text, 4096);
+        assert_eq!(chunks.len(), 4);
+        assert_within_limit(&chunks, 4096);
+    }
+
+    #[tokio::test]
+    async fn cut_off_responses_are_detected() {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let bodies: [&[u8]; 2] = [b"abc", b"abcdefghij"];
+            for body in bodies {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut request).await;
+                let head = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\n";
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(body).await.unwrap();
+                // Dropping the socket ends the stream, early for the first
+            }
+        });
+
+        let client = Client::new();
+        let cut_off = client.get(&url).send().await.unwrap();
+        assert!(read_complete_body(cut_off).await.is_err());
+        let whole = client.get(&url).send().await.unwrap();
+        assert_eq!(read_complete_body(whole).await.unwrap(), &b"abcdefghij"[..]);
+    }
+
+    #[test]
+    fn only_one_input_source_is_accepted() {
+        let conflicts = [
+            &["sayit", "-c", "notes.txt"][..],
+            &["sayit", "-d", "-c"],
+            &["sayit", "--man", "ls", "-d"],
+            &["sayit", "--read-clipboard-format", "text/html", "notes.txt"],
+        ];
+        for args in conflicts {
+            let err = Cli::try_parse_from(args).err().unwrap();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
+        assert!(
+            Cli::try_parse_from(["sayit", "-c", "--read-clipboard-format", "text/html"]).is_ok()
+        );
+    }
+
+    #[test]
+    fn copy_path_only_takes_a_value_after_an_equals_sign() {
+        let cli = Cli::try_parse_from(["sayit", "--copy-path", "notes.txt"]).unwrap();
+        assert_eq!(cli.copy_path, Some(copy_path::CopyPath::List));
+        assert_eq!(cli.input_file.as_deref(), Some("notes.txt"));
+        let cli = Cli::try_parse_from(["sayit", "--copy-path=dir", "notes.txt"]).unwrap();
+        assert_eq!(cli.copy_path, Some(copy_path::CopyPath::Dir));
+    }
+
+    #[test]
+    fn every_format_is_listed_with_a_note() {
+        let listing = render_formats();
+        assert_eq!(
+            listing.lines().count(),
+            ResponseFormat::value_variants().len()
+        );
+        assert!(listing.contains("mp3   widely compatible"));
+        assert!(listing.lines().all(|line| line.len() > "flac  ".len()));
+    }
+
+    #[test]
+    fn format_is_inferred_from_each_extension() {
+        let cases = [
+            ("out.mp3", ResponseFormat::Mp3),
+            ("out.flac", ResponseFormat::Flac),
+            ("out.aac", ResponseFormat::Aac),
+            ("out.m4a", ResponseFormat::Aac),
+            ("out.opus", ResponseFormat::Opus),
+            ("out.ogg", ResponseFormat::Opus),
+            ("out.wav", ResponseFormat::Pcm),
+            ("OUT.WAV", ResponseFormat::Pcm),
+            ("out.pcm", ResponseFormat::Pcm),
+        ];
+        for (path, format) in cases {
+            assert_eq!(
+                resolve_format(None, Some(path), false),
+                Ok(format),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_or_missing_extension_falls_back_to_mp3() {
+        assert_eq!(
+            resolve_format(None, Some("out.xyz"), false),
+            Ok(ResponseFormat::Mp3)
+        );
+        assert_eq!(
+            resolve_format(None, Some("out"), false),
+            Ok(ResponseFormat::Mp3)
+        );
+        assert_eq!(resolve_format(None, None, false), Ok(ResponseFormat::Mp3));
+    }
+
+    #[test]
+    fn explicit_format_conflicting_with_extension_needs_force() {
+        let flac = Some(ResponseFormat::Flac);
+        assert!(resolve_format(flac, Some("out.mp3"), false).is_err());
+        assert_eq!(
+            resolve_format(flac, Some("out.mp3"), true),
+            Ok(ResponseFormat::Flac)
+        );
+        assert_eq!(
+            resolve_format(flac, Some("out.flac"), false),
+            Ok(ResponseFormat::Flac)
+        );
+        assert_eq!(
+            resolve_format(flac, Some("out.bin"), false),
+            Ok(ResponseFormat::Flac)
+        );
+    }
+
+    #[tokio::test]
+    async fn output_file_is_written_in_chunk_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.mp3");
+        let (audio_tx, audio_rx) = mpsc::channel::<AudioChunk>(8);
+        let writer = tokio::spawn(audio_to_output_file(
+            audio_rx,
+            path.to_string_lossy().into_owned(),
+            "mp3",
+            Layout::default(),
+            WriteMode::Atomic,
+            None,
+            Arc::new(AtomicUsize::new(3)),
+        ));
+
+        // Simulate fetches completing out of order: chunk 2 finishes first
+        audio_tx.send(chunk(2, b"chunk2")).await.unwrap();
+        audio_tx.send(chunk(0, b"chunk0")).await.unwrap();
+        audio_tx.send(chunk(1, b"chunk1")).await.unwrap();
+        drop(audio_tx);
+        let spans = writer.await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"chunk0chunk1chunk2");
+        assert_eq!(
+            spans[2],
+            manifest::Span {
+                index: 2,
+                offset: 12,
+                length: 6
+            }
+        );
+        // The .part file was renamed into place
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn pcm_output_gets_a_patched_wav_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        let (audio_tx, audio_rx) = mpsc::channel::<AudioChunk>(8);
+        let writer = tokio::spawn(audio_to_output_file(
+            audio_rx,
+            path.to_string_lossy().into_owned(),
+            "pcm",
+            Layout {
+                wrap_pcm_in_wav: true,
+                ..Layout::default()
+            },
+            WriteMode::Direct,
+            None,
+            Arc::new(AtomicUsize::new(2)),
+        ));
+
+        audio_tx.send(chunk(1, &[3, 0, 4, 0])).await.unwrap();
+        audio_tx.send(chunk(0, &[1, 0, 2, 0])).await.unwrap();
+        drop(audio_tx);
+        writer.await.unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(
+            written[..44],
+            merge::wav_header(merge::API_PCM_FORMAT, 8)[..]
+        );
+        assert_eq!(written[44..], [1, 0, 2, 0, 3, 0, 4, 0]);
+    }
+
+    #[tokio::test]
+    async fn appending_to_a_wav_file_extends_its_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("today.wav");
+        let mut existing = merge::wav_header(merge::API_PCM_FORMAT, 4);
+        existing.extend_from_slice(&[1, 0, 2, 0]);
+        std::fs::write(&path, existing).unwrap();
+
+        let (audio_tx, audio_rx) = mpsc::channel::<AudioChunk>(8);
+        let writer = tokio::spawn(audio_to_output_file(
+            audio_rx,
+            path.to_string_lossy().into_owned(),
+            "pcm",
+            Layout {
+                wrap_pcm_in_wav: true,
+                ..Layout::default()
+            },
+            WriteMode::Append,
+            None,
+            Arc::new(AtomicUsize::new(1)),
+        ));
+        audio_tx.send(chunk(0, &[3, 0, 4, 0])).await.unwrap();
+        drop(audio_tx);
+        writer.await.unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(
+            written[..44],
+            merge::wav_header(merge::API_PCM_FORMAT, 8)[..]
+        );
+        assert_eq!(written[44..], [1, 0, 2, 0, 3, 0, 4, 0]);
+    }
+
+    #[tokio::test]
+    async fn join_gap_goes_between_chunks_and_after_appended_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gaps.pcm");
+        std::fs::write(&path, [1, 0]).unwrap();
+
+        let (audio_tx, audio_rx) = mpsc::channel::<AudioChunk>(8);
+        let writer = tokio::spawn(audio_to_output_file(
+            audio_rx,
+            path.to_string_lossy().into_owned(),
+            "pcm",
+            Layout {
+                join_gap: Duration::from_micros(125),
+                ..Layout::default()
+            },
+            WriteMode::Append,
+            None,
+            Arc::new(AtomicUsize::new(2)),
+        ));
+        audio_tx.send(chunk(0, &[2, 0])).await.unwrap();
+        audio_tx.send(chunk(1, &[3, 0])).await.unwrap();
+        drop(audio_tx);
+        writer.await.unwrap();
+
+        // 125µs at 24 kHz is three samples
+        let silence = [0; 6];
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(
+            written,
+            [&[1, 0][..], &silence, &[2, 0], &silence, &[3, 0]].concat()
+        );
+    }
+
+    #[test]
+    fn pcm_survives_playback_conversion_and_wav_round_trip() {
+        let original: Vec<i16> = vec![0, 1, -1, 12345, -12345, i16::MAX, i16::MIN];
+        let bytes: Vec<u8> = original.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let pcm = AudioChunk {
+            index: 0,
+            bytes: Bytes::from(bytes.clone()),
+            content_type: None,
+            duration: Arc::default(),
+            held: Arc::default(),
+        };
+
+        // PCM bytes -> SamplesBuffer, as played
+        let source = decode_chunk(&pcm, "pcm").unwrap();
+        assert_eq!(source.sample_rate(), merge::API_PCM_FORMAT.sample_rate);
+        let played: Vec<i16> = source.convert_samples::<i16>().collect();
+        assert_eq!(played, original);
+
+        // ... -> WAV, as saved -> decoded again
+        let played_bytes: Vec<u8> = played.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let wav = [
+            merge::wav_header(merge::API_PCM_FORMAT, played_bytes.len() as u32),
+            played_bytes,
+        ]
+        .concat();
+        let decoded: Vec<i16> = Decoder::new_wav(Cursor::new(wav)).unwrap().collect();
+        assert_eq!(decoded, original);
+
+        // A torn trailing sample is refused rather than dropped
+        let torn = AudioChunk {
+            bytes: Bytes::from(bytes[..bytes.len() - 1].to_vec()),
+            ..pcm
+        };
+        assert!(decode_chunk(&torn, "pcm").is_none());
+    }
+
+    #[tokio::test]
+    async fn fetches_see_the_channel_close_once_every_consumer_is_gone() {
+        let (audio_tx, audio_rx) = mpsc::channel::<AudioChunk>(8);
+        let (save_rx, mut play_rx) = tee_chunks(audio_rx, 8);
+
+        // One side going away leaves the other fed
+        drop(save_rx);
+        audio_tx.send(chunk(0, b"chunk0")).await.unwrap();
+        assert_eq!(play_rx.recv().await.unwrap().bytes, &b"chunk0"[..]);
+        assert!(!audio_tx.is_closed());
+
+        drop(play_rx);
+        tokio::time::timeout(std::time::Duration::from_secs(5), audio_tx.closed())
+            .await
+            .expect("the tee should hang up once both sides are closed");
+    }
+
+    #[tokio::test]
+    async fn output_stops_after_the_chunk_that_reaches_the_limit() {
+        let (audio_tx, audio_rx) = mpsc::channel::<AudioChunk>(8);
+        let expected_chunks = Arc::new(AtomicUsize::new(4));
+        let mut limited_rx = limit_chunks(audio_rx, 10, expected_chunks.clone(), 8);
+
+        audio_tx.send(chunk(1, b"chunk1")).await.unwrap();
+        audio_tx.send(chunk(0, b"chunk0")).await.unwrap();
+        assert_eq!(limited_rx.recv().await.unwrap().index, 0);
+        assert_eq!(limited_rx.recv().await.unwrap().index, 1);
+        assert!(limited_rx.recv().await.is_none());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), audio_tx.closed())
+            .await
+            .expect("the remaining fetches should be cancelled");
+        assert_eq!(expected_chunks.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn existing_outputs_and_missing_directories_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("old.mp3");
+        std::fs::write(&existing, b"keep me").unwrap();
+        let existing = existing.to_str().unwrap();
+        let nested = dir.path().join("a/b/new.mp3");
+        let nested = nested.to_str().unwrap();
+
+        let err = check_output_path(existing, false, false, false).unwrap_err();
+        assert!(err.contains(existing) && err.contains("--force"));
+        assert!(check_output_path(existing, true, false, false).is_ok());
+        assert!(check_output_path(existing, false, true, false).is_ok());
+        assert!(check_output_path(STDOUT_PATH, false, false, false).is_ok());
+
+        assert!(check_output_path(nested, false, false, false)
+            .unwrap_err()
+            .contains("--mkdirs"));
+        assert!(check_output_path(nested, false, false, true).is_ok());
+        assert!(dir.path().join("a/b").is_dir());
+        assert_eq!(
+            std::fs::read(dir.path().join("old.mp3")).unwrap(),
+            b"keep me"
+        );
+    }
+
+    #[test]
+    fn byte_sizes_take_decimal_and_binary_units() {
+        assert_eq!(parse_byte_size("1200"), Ok(1200));
+        assert_eq!(parse_byte_size("50MB"), Ok(50_000_000));
+        assert_eq!(parse_byte_size("2 MiB"), Ok(2 << 20));
+        assert!(parse_byte_size("0").is_err());
+        assert!(parse_byte_size("10 parsecs").is_err());
+    }
+
+    #[test]
+    fn target_wpm_maps_to_the_nearest_supported_speed() {
+        assert_eq!(speed_for_wpm(150), 1.0);
+        assert_eq!(speed_for_wpm(180), 1.2);
+        assert_eq!(speed_for_wpm(100), 0.67);
+        assert_eq!(speed_for_wpm(10), config::MIN_SPEED);
+        assert_eq!(speed_for_wpm(1000), config::MAX_SPEED);
+    }
+
+    #[test]
+    fn pcm_duration_comes_from_the_sample_count() {
+        // Half a second of 24 kHz mono 16-bit silence
+        let pcm = chunk(0, &[0; 24000]);
+        assert_eq!(
+            measure_duration(&pcm.bytes, "audio/pcm"),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            decoded_duration(&pcm.clone(), "pcm"),
+            Some(Duration::from_millis(500))
+        );
+        // Clones share the measurement, so it isn't decoded again
+        assert_eq!(pcm.duration.get(), Some(&Duration::from_millis(500)));
+        assert_eq!(
+            measure_duration(&Bytes::from_static(b"junk"), "audio/mpeg"),
+            Duration::ZERO
+        );
+        // The reported type wins over the requested format
+        let reported = AudioChunk {
+            content_type: Some("audio/pcm".to_string()),
+            ..chunk(1, &[0; 24000])
+        };
+        assert_eq!(
+            decoded_duration(&reported, "mp3"),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn input_is_decoded_from_the_named_encoding() {
+        let latin1 = b"caf\xe9 cr\xe8me";
+        let encoding = parse_encoding("latin1").unwrap();
+        assert_eq!(decode_input(latin1, encoding, "test"), "café crème");
+        assert_eq!(
+            decode_input(latin1, UTF_8, "test"),
+            "caf\u{FFFD} cr\u{FFFD}me"
+        );
+        assert!(parse_encoding("klingon").is_err());
+    }
+
+    #[test]
+    fn headers_are_parsed_and_malformed_ones_rejected() {
+        let (name, value) = parse_header("X-Request-Source:  nightly-build ").unwrap();
+        assert_eq!(name, "x-request-source");
+        assert_eq!(value, "nightly-build");
+
+        assert!(parse_header("X-Missing-Colon").is_err());
+        assert!(parse_header("Bad Name: value").is_err());
+        assert!(parse_header(": value").is_err());
+        assert!(parse_header("X-Newline: a\nb").is_err());
+    }
+
+    #[test]
+    fn only_request_ids_rate_limits_and_openai_headers_are_logged() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("x-request-id", "req_123"),
+            ("x-ratelimit-remaining-requests", "499"),
+            ("openai-processing-ms", "812"),
+            ("content-type", "audio/mpeg"),
+            ("set-cookie", "session=secret"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        let logged = diagnostic_headers(&headers);
+        assert_eq!(logged.len(), 3);
+        assert!(logged.contains(&("x-request-id", "req_123".to_string())));
+        assert!(logged.iter().all(|(name, _)| *name != "set-cookie"));
+    }
 }
@@ -0,0 +1,212 @@
+// A JSON sidecar (--manifest) describing the produced audio chunk by chunk:
+// which part of the text each chunk reads, how it was synthesized and where
+// its audio ended up, for read-along players and other downstream tools.
+
+use crate::summary::FetchOutcome;
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Where a chunk's audio was written within a single output file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub index: usize,
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub enum Location {
+    Span { offset: u64, length: u64 },
+    File(String),
+    // Re-encoded into a merged stream, or not written at all
+    Unknown,
+}
+
+pub struct ChunkEntry<'a> {
+    pub index: usize,
+    pub text: &'a str,
+    // Character range in the preprocessed text; None for generated text
+    // such as --prefix and chapter announcements
+    pub characters: Option<Range<usize>>,
+    pub voice: &'static str,
+    pub model: &'static str,
+    pub speed: f32,
+    pub location: Location,
+    pub duration: Option<Duration>,
+    pub outcome: FetchOutcome,
+}
+
+pub struct Run<'a> {
+    pub provider: &'static str,
+    pub endpoint: &'static str,
+    pub parameters: serde_json::Value,
+    pub output: Option<&'a str>,
+    pub started: SystemTime,
+    pub finished: SystemTime,
+}
+
+fn unix_ms(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis())
+}
+
+pub fn text_hash(text: &str) -> String {
+    let digest = Sha256::digest(text.as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256:{}", hex)
+}
+
+// Byte range of `words` in `source` starting at or after `from`, allowing any
+// whitespace between them
+fn find_words(source: &str, words: &[&str], from: usize) -> Option<Range<usize>> {
+    let first = words.first()?;
+    let mut search_from = from;
+    while let Some(found) = source[search_from..].find(first) {
+        let start = search_from + found;
+        let mut end = start + first.len();
+        let matched = words[1..].iter().all(|word| {
+            let rest = &source[end..];
+            let trimmed = rest.trim_start();
+            if !trimmed.starts_with(word) {
+                return false;
+            }
+            end += rest.len() - trimmed.len() + word.len();
+            true
+        });
+        if matched {
+            return Some(start..end);
+        }
+        search_from = start + first.len();
+    }
+    None
+}
+
+// The character range each chunk's text was taken from. Chunking rejoins
+// words with single spaces, so chunks are matched word by word; each search
+// carries on from the previous match, falling back to the start of the text
+// for reordered (--shuffle-chunks, --rtl) chunks.
+pub fn locate_chunks(source: &str, texts: &[&str]) -> Vec<Option<Range<usize>>> {
+    let mut cursor = 0;
+    texts
+        .iter()
+        .map(|text| {
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let bytes =
+                find_words(source, &words, cursor).or_else(|| find_words(source, &words, 0))?;
+            cursor = bytes.end;
+            let start = source[..bytes.start].chars().count();
+            Some(start..start + source[bytes].chars().count())
+        })
+        .collect()
+}
+
+pub fn render(run: &Run, chunks: &[ChunkEntry]) -> serde_json::Value {
+    let failed: Vec<usize> = chunks
+        .iter()
+        .filter(|chunk| chunk.outcome == FetchOutcome::Failed)
+        .map(|chunk| chunk.index)
+        .collect();
+    let chunks: Vec<serde_json::Value> = chunks
+        .iter()
+        .map(|chunk| {
+            let mut entry = serde_json::json!({
+                "index": chunk.index,
+                "characters": chunk.characters.as_ref().map(|range| {
+                    serde_json::json!({ "start": range.start, "end": range.end })
+                }),
+                "text_hash": text_hash(chunk.text),
+                "voice": chunk.voice,
+                "model": chunk.model,
+                "speed": chunk.speed,
+                "duration_seconds": chunk.duration.map(|duration| duration.as_secs_f64()),
+                "status": match chunk.outcome {
+                    FetchOutcome::Fetched => "fetched",
+                    FetchOutcome::Failed => "failed",
+                    FetchOutcome::Cancelled => "cancelled",
+                },
+            });
+            match &chunk.location {
+                Location::Span { offset, length } => {
+                    entry["offset"] = serde_json::json!(offset);
+                    entry["length"] = serde_json::json!(length);
+                }
+                Location::File(path) => entry["file"] = serde_json::json!(path),
+                Location::Unknown => {}
+            }
+            entry
+        })
+        .collect();
+
+    serde_json::json!({
+        "provider": { "name": run.provider, "endpoint": run.endpoint },
+        "parameters": run.parameters,
+        "output": run.output,
+        "started_at_ms": unix_ms(run.started),
+        "finished_at_ms": unix_ms(run.finished),
+        "failed": failed,
+        "chunks": chunks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_located_in_the_source_by_character() {
+        let source = "Ça va?\nThe  quick\nfox. Ça va?";
+        let texts = ["The quick fox.", "Intro", "Ça va?", "Ça va?"];
+        assert_eq!(
+            locate_chunks(source, &texts),
+            vec![Some(7..22), None, Some(23..29), Some(0..6)]
+        );
+    }
+
+    #[test]
+    fn manifest_records_where_each_chunk_went() {
+        let started = UNIX_EPOCH + Duration::from_millis(1_000);
+        let run = Run {
+            provider: "openai",
+            endpoint: "https://example.com/speech",
+            parameters: serde_json::json!({ "voice": "nova" }),
+            output: Some("out.mp3"),
+            started,
+            finished: started + Duration::from_millis(500),
+        };
+        let chunk = |index, location, outcome| ChunkEntry {
+            index,
+            text: "Hello.",
+            characters: Some(0..6),
+            voice: "nova",
+            model: "tts-1",
+            speed: 1.0,
+            location,
+            duration: None,
+            outcome,
+        };
+        let manifest = render(
+            &run,
+            &[
+                chunk(
+                    0,
+                    Location::Span {
+                        offset: 0,
+                        length: 480,
+                    },
+                    FetchOutcome::Fetched,
+                ),
+                chunk(1, Location::Unknown, FetchOutcome::Failed),
+            ],
+        );
+
+        assert_eq!(manifest["failed"], serde_json::json!([1]));
+        assert_eq!(manifest["finished_at_ms"], 1_500);
+        assert_eq!(manifest["chunks"][0]["length"], 480);
+        assert_eq!(manifest["chunks"][0]["characters"]["end"], 6);
+        assert!(manifest["chunks"][1].get("offset").is_none());
+        assert_eq!(
+            manifest["chunks"][0]["text_hash"],
+            "sha256:2d8bd7d9bb5f85ba643f0110d50cb506a1fe439e769a22503193ea6046bb87f7"
+        );
+    }
+}
@@ -0,0 +1,285 @@
+// Man pages for packagers (the hidden --generate-manpage DIR): sayit.1 for
+// the command and sayit-config.5 for the config file. Both are built from the
+// clap definitions, so every flag, its environment variable and its config
+// key come from the same place as the parser, and nothing depends on the
+// machine or the date, so the pages are reproducible.
+
+use crate::{config, ResponseFormat, Voice};
+use clap::{Arg, Command, ValueEnum};
+use roff::{bold, italic, roman, Inline, Roff};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CONFIG_PATH: &str = "$XDG_CONFIG_HOME/sayit/config.toml";
+
+// Write both pages into `dir`, returning their paths
+pub fn write_to(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let pages = [
+        ("sayit.1", command_page()?),
+        ("sayit-config.5", config_page()),
+    ];
+    let mut written = Vec::new();
+    for (name, page) in pages {
+        let path = dir.join(name);
+        std::fs::write(&path, page)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+fn command() -> Command {
+    let mut command = crate::cli_command();
+    command.build();
+    command
+}
+
+// `--long`, as it appears in roff
+fn flag(arg: &Arg) -> String {
+    format!("--{}", arg.get_long().unwrap_or_default())
+}
+
+fn names<T: ValueEnum>() -> String {
+    T::value_variants()
+        .iter()
+        .filter_map(|value| value.to_possible_value())
+        .map(|value| value.get_name().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// A `.TP` entry: `tag` in bold over `text`
+fn entry(roff: &mut Roff, tag: impl Into<String>, text: impl Into<Vec<Inline>>) {
+    roff.control("TP", []);
+    roff.text([bold(tag)]);
+    roff.text(text);
+}
+
+fn command_page() -> io::Result<Vec<u8>> {
+    let command = command();
+    let mut page = Vec::new();
+    let man = clap_mangen::Man::new(command.clone()).manual("sayit manual");
+    man.render_title(&mut page)?;
+    man.render_name_section(&mut page)?;
+    man.render_synopsis_section(&mut page)?;
+    man.render_options_section(&mut page)?;
+    man.render_subcommands_section(&mut page)?;
+
+    let mut roff = Roff::new();
+    roff.control("SH", ["ENVIRONMENT"]);
+    entry(
+        &mut roff,
+        "OPENAI_API_KEY",
+        [roman(
+            "The OpenAI API key; required for anything that makes requests, unless --no-auth is given.",
+        )],
+    );
+    entry(
+        &mut roff,
+        "SAYIT_TEXT",
+        [roman("Text to read when no other input source is given.")],
+    );
+    let subcommand_args = command
+        .get_subcommands()
+        .flat_map(|sub| sub.get_arguments());
+    for arg in command.get_arguments().chain(subcommand_args) {
+        if let Some(name) = arg.get_env() {
+            entry(
+                &mut roff,
+                name.to_string_lossy(),
+                [roman("Default for "), bold(flag(arg)), roman(".")],
+            );
+        }
+    }
+    roff.control("PP", []);
+    roff.text([roman(
+        "Every other flag can also be set from SAYIT_ and its long name (or an alias) in \
+         capitals, with _ for -. Switches take 1/0, true/false, yes/no or on/off. These come between the config \
+         file and the command line, and a variable is ignored when its flag, or one that \
+         conflicts with it, is given:",
+    )]);
+    for arg in config::flags(&command) {
+        for name in config::env_names(arg) {
+            entry(
+                &mut roff,
+                name,
+                [roman("Sets "), bold(flag(arg)), roman(".")],
+            );
+        }
+    }
+
+    roff.control("SH", ["FILES"]);
+    entry(
+        &mut roff,
+        CONFIG_PATH,
+        [
+            roman("Defaults for every flag (~/.config/sayit/config.toml without XDG_CONFIG_HOME); see "),
+            bold("sayit-config"),
+            roman("(5)."),
+        ],
+    );
+
+    roff.control("SH", ["EXIT STATUS"]);
+    entry(
+        &mut roff,
+        "0",
+        [roman(
+            "Success, including --validate finding nothing wrong and --since-modified finding \
+             the output up to date.",
+        )],
+    );
+    entry(
+        &mut roff,
+        "1",
+        [roman(
+            "Failure: a bad input, output or config file, a run --max-failure-rate gave up on, \
+             a failed --archive, --upload or --latest-link, or a failed hook under \
+             --post-cmd-strict or --after-play-fail.",
+        )],
+    );
+    entry(
+        &mut roff,
+        "2",
+        [roman("The command line couldn't be parsed.")],
+    );
+
+    roff.control("SH", ["SEE ALSO"]);
+    roff.text([bold("sayit-config"), roman("(5)")]);
+    roff.to_writer(&mut page)?;
+    man.render_version_section(&mut page)?;
+    Ok(page)
+}
+
+fn config_page() -> Vec<u8> {
+    let command = command();
+    let mut roff = Roff::new();
+    roff.control("TH", ["sayit-config", "5", "", "", "sayit manual"]);
+    roff.control("SH", ["NAME"]);
+    roff.text([roman("sayit-config - defaults for sayit")]);
+
+    roff.control("SH", ["DESCRIPTION"]);
+    roff.text([
+        roman("sayit reads defaults from the TOML file "),
+        italic(CONFIG_PATH),
+        roman(
+            " (~/.config/sayit/config.toml without XDG_CONFIG_HOME), or the file given with \
+             --config; --no-config ignores it. Command line flags win over SAYIT_* environment \
+             variables, which win over the file. Unknown keys are warned about and ignored. \
+             sayit config init writes the file interactively and sayit config validate checks it.",
+        ),
+    ]);
+
+    roff.control("SH", ["SETTINGS"]);
+    entry(
+        &mut roff,
+        "voice",
+        [roman(format!("One of {}.", names::<Voice>()))],
+    );
+    entry(
+        &mut roff,
+        "speed",
+        [roman(format!(
+            "From {} to {}.",
+            config::MIN_SPEED,
+            config::MAX_SPEED
+        ))],
+    );
+    entry(
+        &mut roff,
+        "format",
+        [roman(format!("One of {}.", names::<ResponseFormat>()))],
+    );
+    entry(
+        &mut roff,
+        "model",
+        [roman(format!("One of {}.", config::MODELS.join(", ")))],
+    );
+    entry(
+        &mut roff,
+        "save_text",
+        [roman("true to save the text read next to the output file.")],
+    );
+
+    roff.control("SH", ["FLAGS"]);
+    roff.text([roman(
+        "Any other flag is given a default by its long name with _ for -. Switches take true or \
+         false, flags that take two values a list of two, and repeatable flags a list:",
+    )]);
+    for arg in config::flags(&command) {
+        let key = arg.get_id().as_str();
+        if config::SETTINGS.contains(&key) || config::NOT_CONFIGURABLE.contains(&key) {
+            continue;
+        }
+        let value = if !arg.get_action().takes_values() {
+            "true or false".to_string()
+        } else {
+            let value_names = arg
+                .get_value_names()
+                .map(|names| {
+                    names
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_else(|| vec![key.to_uppercase()]);
+            value_names.join(" ")
+        };
+        entry(
+            &mut roff,
+            key,
+            [roman(format!("{} (for {})", value, flag(arg)))],
+        );
+    }
+    roff.control("PP", []);
+    roff.text([roman(format!(
+        "{} can only be given on the command line.",
+        config::NOT_CONFIGURABLE
+            .iter()
+            .map(|key| format!("--{}", key.replace('_', "-")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))]);
+
+    roff.control("SH", ["PROFILES"]);
+    roff.text([roman(
+        "A [profile.NAME] table holds more of the same keys, used over the rest of the file \
+         with --profile NAME. sayit profiles lists them.",
+    )]);
+
+    roff.control("SH", ["EXAMPLE"]);
+    roff.control("nf", []);
+    for line in [
+        "voice = \"onyx\"",
+        "concurrency = 4",
+        "replace = [[\"colour\", \"color\"]]",
+        "",
+        "[profile.audiobook]",
+        "format = \"flac\"",
+        "chapter_announce = true",
+    ] {
+        roff.text([roman(line)]);
+    }
+    roff.control("fi", []);
+
+    roff.control("SH", ["SEE ALSO"]);
+    roff.text([bold("sayit"), roman("(1)")]);
+    roff.render().into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_cover_flags_and_are_reproducible() {
+        let page = String::from_utf8(command_page().unwrap()).unwrap();
+        assert_eq!(page.as_bytes(), command_page().unwrap());
+        assert!(page.contains(r"\-\-wav\-bits"));
+        assert!(page.contains("SAYIT_CONCURRENCY"));
+        assert!(!page.contains("generate"));
+
+        let config = String::from_utf8(config_page()).unwrap();
+        assert!(config.contains("max_failure_rate"));
+        assert!(!config.contains("no_config"));
+    }
+}
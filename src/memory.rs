@@ -0,0 +1,125 @@
+// Capping the audio held in memory at once (--max-memory). A fetched chunk
+// counts from when it arrives until its last copy is dropped, having been
+// written or played, whether it sits in a channel or a reorder buffer. While
+// the cap is reached, chunks wait before being requested. The earliest chunk
+// not yet fetched never waits, since the output may be holding on to the rest
+// until it arrives.
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::watch;
+
+static CAP: OnceLock<u64> = OnceLock::new();
+static STATE: OnceLock<watch::Sender<State>> = OnceLock::new();
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+// Set the cap in megabytes for the run; without one nothing is counted
+pub fn init(max_memory_mb: Option<u64>) {
+    if let Some(mb) = max_memory_mb {
+        let _ = CAP.set(mb.saturating_mul(1024 * 1024));
+    }
+}
+
+fn state() -> &'static watch::Sender<State> {
+    STATE.get_or_init(|| watch::Sender::new(State::default()))
+}
+
+#[derive(Default)]
+struct State {
+    // Bytes of audio held
+    held: u64,
+    // How many chunks from the start have finished fetching (or given up),
+    // and the ones past those that have
+    finished_before: usize,
+    finished_after: BTreeSet<usize>,
+}
+
+impl State {
+    fn finish(&mut self, index: usize) {
+        self.finished_after.insert(index);
+        while self.finished_after.remove(&self.finished_before) {
+            self.finished_before += 1;
+        }
+    }
+
+    fn may_fetch(&self, index: usize, cap: u64) -> bool {
+        self.held < cap || index <= self.finished_before
+    }
+}
+
+// Wait until chunk `index` may be requested
+pub async fn room(index: usize) {
+    let Some(&cap) = CAP.get() else {
+        return;
+    };
+    let mut state = state().subscribe();
+    if state.borrow().may_fetch(index, cap) {
+        return;
+    }
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!(
+            "{} MB of audio is waiting to be written or played (see --max-memory); \
+             holding back requests until some of it is",
+            cap / (1024 * 1024)
+        );
+    }
+    log::debug!("Chunk {} is waiting for memory", index);
+    let _ = state.wait_for(|state| state.may_fetch(index, cap)).await;
+}
+
+// Note that chunk `index` is done fetching, however it went
+pub fn finished(index: usize) {
+    if CAP.get().is_some() {
+        state().send_modify(|state| state.finish(index));
+    }
+}
+
+// A chunk's share of the cap, given back when it's dropped
+#[derive(Default)]
+pub struct Held(u64);
+
+pub fn hold(bytes: usize) -> Arc<Held> {
+    if CAP.get().is_none() {
+        return Arc::default();
+    }
+    let bytes = bytes as u64;
+    state().send_modify(|state| state.held += bytes);
+    Arc::new(Held(bytes))
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        if self.0 > 0 {
+            state().send_modify(|state| state.held = state.held.saturating_sub(self.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_earliest_unfetched_chunk_may_always_be_fetched() {
+        let mut state = State {
+            held: 100,
+            ..State::default()
+        };
+        assert!(state.may_fetch(0, 100));
+        assert!(!state.may_fetch(1, 100));
+
+        // Chunk 2 arriving first doesn't let chunk 3 through
+        state.finish(2);
+        assert!(!state.may_fetch(3, 100));
+        state.finish(0);
+        assert!(state.may_fetch(1, 100));
+        state.finish(1);
+        assert_eq!(state.finished_before, 3);
+        assert!(state.may_fetch(3, 100));
+        assert!(!state.may_fetch(4, 100));
+
+        state.held = 99;
+        assert!(state.may_fetch(4, 100));
+    }
+}
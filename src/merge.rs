@@ -0,0 +1,801 @@
+// Stitching of per-chunk API responses into a single well-formed file.
+//
+// MP3, ADTS AAC and raw PCM are frame/sample streams, so plain concatenation
+// is already valid for them. WAV and FLAC carry a header describing the whole
+// stream, so their chunks are unpacked and rewritten as one stream here, and
+// each Ogg Opus chunk is a logical stream of its own, so their packets are
+// re-chained into one.
+
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use std::io::{self, Cursor};
+use std::time::Duration;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Whether chunks in this format have to be merged rather than concatenated
+pub fn needs_merge(audio_format: &str) -> bool {
+    matches!(audio_format, "wav" | "flac" | "opus")
+}
+
+pub fn merge_chunks(audio_format: &str, chunks: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    merge_chunks_apart(audio_format, chunks, Duration::ZERO)
+}
+
+// merge_chunks with `gap` of silence between chunks (--join-gap-ms). Only
+// WAV and FLAC are decoded to samples here, so the other formats are joined
+// without one.
+pub fn merge_chunks_apart(
+    audio_format: &str,
+    chunks: &[Vec<u8>],
+    gap: Duration,
+) -> io::Result<Vec<u8>> {
+    match audio_format {
+        "wav" => merge_wav(chunks, gap),
+        "flac" => merge_flac(chunks, gap),
+        "opus" => merge_opus(chunks),
+        _ => Ok(chunks.concat()),
+    }
+}
+
+// How many frames of `format` play for `gap`
+fn gap_frames(format: StreamFormat, gap: Duration) -> usize {
+    (u128::from(format.sample_rate) * gap.as_nanos() / 1_000_000_000) as usize
+}
+
+// `gap` of silence as PCM in `format`
+pub fn silence(format: StreamFormat, gap: Duration) -> Vec<u8> {
+    let frame_len = usize::from(format.channels * format.bits_per_sample / 8);
+    vec![0; gap_frames(format, gap) * frame_len]
+}
+
+// Parameters shared by every chunk of a PCM-based stream
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+}
+
+// Raw `pcm` responses from the API are 24kHz mono signed 16-bit little-endian
+pub const API_PCM_FORMAT: StreamFormat = StreamFormat {
+    sample_rate: 24000,
+    channels: 1,
+    bits_per_sample: 16,
+};
+
+// The samples of a raw API `pcm` response, which are signed 16-bit
+// little-endian whatever the host's byte order. A trailing odd byte means the
+// response was cut short mid-sample; dropping it would shift every sample
+// after it in a concatenated stream, so it is an error.
+pub fn pcm_samples(bytes: &[u8]) -> io::Result<Vec<i16>> {
+    samples_in(bytes, API_PCM_FORMAT)
+}
+
+// The samples of PCM in `format`, as 16-bit; 24-bit samples lose their low
+// byte, which is plenty for playback and measuring
+pub fn samples_in(bytes: &[u8], format: StreamFormat) -> io::Result<Vec<i16>> {
+    check_pcm_in(bytes, format)?;
+    let sample_bytes = usize::from(format.bits_per_sample / 8);
+    Ok(bytes
+        .chunks_exact(sample_bytes)
+        .map(|sample| i16::from_le_bytes([sample[sample_bytes - 2], sample[sample_bytes - 1]]))
+        .collect())
+}
+
+pub fn check_pcm(bytes: &[u8]) -> io::Result<()> {
+    check_pcm_in(bytes, API_PCM_FORMAT)
+}
+
+pub fn check_pcm_in(bytes: &[u8], format: StreamFormat) -> io::Result<()> {
+    let sample_bytes = usize::from(format.bits_per_sample / 8);
+    match bytes.len() % sample_bytes {
+        0 => Ok(()),
+        extra => Err(invalid_data(format!(
+            "PCM data ends with an incomplete sample ({} of {} bytes)",
+            extra, sample_bytes
+        ))),
+    }
+}
+
+// Canonical 44-byte RIFF/WAVE header for `data_len` bytes of integer PCM
+pub fn wav_header(format: StreamFormat, data_len: u32) -> Vec<u8> {
+    let block_align = format.channels * format.bits_per_sample / 8;
+    let byte_rate = format.sample_rate * u32::from(block_align);
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&data_len.saturating_add(36).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // integer PCM
+    header.extend_from_slice(&format.channels.to_le_bytes());
+    header.extend_from_slice(&format.sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&format.bits_per_sample.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+// Split a WAV file into its format and the bytes of its data chunk. Streamed
+// responses may carry a placeholder data length, so the data chunk is clamped
+// to whatever is actually present.
+fn parse_wav(bytes: &[u8]) -> io::Result<(StreamFormat, &[u8])> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid_data("chunk is not a RIFF/WAVE file".to_string()));
+    }
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let body = pos + 8;
+
+        if id == b"fmt " && body + 16 <= bytes.len() {
+            format = Some(StreamFormat {
+                channels: u16::from_le_bytes([bytes[body + 2], bytes[body + 3]]),
+                sample_rate: u32::from_le_bytes(bytes[body + 4..body + 8].try_into().unwrap()),
+                bits_per_sample: u16::from_le_bytes([bytes[body + 14], bytes[body + 15]]),
+            });
+        } else if id == b"data" {
+            let format = format
+                .ok_or_else(|| invalid_data("WAV data chunk before fmt chunk".to_string()))?;
+            let end = body.saturating_add(len).min(bytes.len());
+            return Ok((format, &bytes[body..end]));
+        }
+
+        pos = body.saturating_add(len + (len & 1));
+    }
+
+    Err(invalid_data("WAV chunk has no data section".to_string()))
+}
+
+// The container or stream format the start of a file is in, as far as its
+// magic bytes tell; raw PCM has none, so it can't be recognised
+pub fn sniff_format(head: &[u8]) -> Option<&'static str> {
+    if head.starts_with(b"fLaC") {
+        Some("flac")
+    } else if head.starts_with(b"OggS") {
+        Some("opus")
+    } else if head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == b"WAVE" {
+        Some("wav")
+    } else if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        Some("m4a")
+    } else if head.starts_with(b"ID3") {
+        Some("mp3")
+    } else if head.len() >= 2 && head[0] == 0xff && head[1] & 0xe0 == 0xe0 {
+        // ADTS and MPEG audio share the sync word; ADTS has layer bits 00
+        if head[1] & 0x06 == 0 {
+            Some("aac")
+        } else {
+            Some("mp3")
+        }
+    } else {
+        None
+    }
+}
+
+// Check that audio in `audio_format` can go onto the end of an existing file
+// that starts with `head` and is `file_len` bytes long, rather than turning
+// it into a mix of two formats. WAV output is only appended to when it was
+// written by sayit: the same PCM format, and the data running to the end.
+pub fn check_append(
+    head: &[u8],
+    file_len: u64,
+    audio_format: &str,
+    wrap_pcm_in_wav: bool,
+    pcm_format: StreamFormat,
+) -> Result<(), String> {
+    if file_len == 0 {
+        return Ok(());
+    }
+    let found = sniff_format(head);
+    let expected = if wrap_pcm_in_wav {
+        Some("wav")
+    } else if audio_format == "pcm" {
+        None
+    } else {
+        Some(audio_format)
+    };
+    if found != expected {
+        return Err(format!(
+            "it holds {} audio, not {}",
+            found.unwrap_or("raw pcm or unrecognised"),
+            expected.unwrap_or("raw pcm")
+        ));
+    }
+
+    if wrap_pcm_in_wav {
+        // Everything but the lengths, which sayit patches in at the end
+        let canonical = wav_header(pcm_format, 0);
+        if file_len < 44 || head.len() < 44 || head[8..36] != canonical[8..36] {
+            return Err(format!(
+                "only WAV files of {} Hz {} 16-bit PCM written by sayit can be appended to",
+                pcm_format.sample_rate,
+                if pcm_format.channels == 1 {
+                    "mono"
+                } else {
+                    "stereo"
+                }
+            ));
+        }
+        if head[36..40] != *b"data" {
+            return Err("the WAV file has extra chunks before its audio".to_string());
+        }
+    }
+    let header_len = if wrap_pcm_in_wav { 44 } else { 0 };
+    let frame_len = u64::from(pcm_format.channels * pcm_format.bits_per_sample / 8);
+    if audio_format == "pcm" && !(file_len - header_len).is_multiple_of(frame_len) {
+        return Err("it ends partway through a sample".to_string());
+    }
+    Ok(())
+}
+
+fn merge_wav(chunks: &[Vec<u8>], gap: Duration) -> io::Result<Vec<u8>> {
+    let mut format = None;
+    let mut data = Vec::new();
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let (chunk_format, samples) = parse_wav(chunk)?;
+        match format {
+            None => format = Some(chunk_format),
+            Some(first) if first != chunk_format => {
+                return Err(invalid_data(format!(
+                    "chunk {} is {:?} but chunk 0 is {:?}",
+                    index, chunk_format, first
+                )))
+            }
+            Some(_) => {}
+        }
+        if index > 0 {
+            data.extend(silence(chunk_format, gap));
+        }
+        data.extend_from_slice(samples);
+    }
+
+    let format = format.ok_or_else(|| invalid_data("no WAV chunks to merge".to_string()))?;
+    let data_len = u32::try_from(data.len())
+        .map_err(|_| invalid_data("merged WAV data exceeds 4 GiB".to_string()))?;
+
+    let mut merged = wav_header(format, data_len);
+    merged.extend_from_slice(&data);
+    Ok(merged)
+}
+
+fn merge_flac(chunks: &[Vec<u8>], gap: Duration) -> io::Result<Vec<u8>> {
+    let mut format = None;
+    let mut samples = Vec::new();
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let mut reader = claxon::FlacReader::new(Cursor::new(chunk))
+            .map_err(|e| invalid_data(format!("chunk {} is not valid FLAC: {}", index, e)))?;
+        let info = reader.streaminfo();
+        let chunk_format = StreamFormat {
+            sample_rate: info.sample_rate,
+            channels: info.channels as u16,
+            bits_per_sample: info.bits_per_sample as u16,
+        };
+        match format {
+            None => format = Some(chunk_format),
+            Some(first) if first != chunk_format => {
+                return Err(invalid_data(format!(
+                    "chunk {} is {:?} but chunk 0 is {:?}",
+                    index, chunk_format, first
+                )))
+            }
+            Some(_) => {}
+        }
+
+        if index > 0 {
+            let gap_samples = gap_frames(chunk_format, gap) * usize::from(chunk_format.channels);
+            samples.extend(std::iter::repeat_n(0, gap_samples));
+        }
+        for sample in reader.samples() {
+            samples.push(sample.map_err(|e| {
+                invalid_data(format!("failed to decode FLAC chunk {}: {}", index, e))
+            })?);
+        }
+    }
+
+    let format = format.ok_or_else(|| invalid_data("no FLAC chunks to merge".to_string()))?;
+    Ok(encode_flac(format, &samples))
+}
+
+// The fields of an OpusHead packet that every chunk has to agree on: the
+// channel count and mapping (bytes 9 and 18 on) and the input sample rate
+fn opus_stream_layout(head: &[u8]) -> io::Result<(u8, u32, &[u8])> {
+    if head.len() < 19 || !head.starts_with(b"OpusHead") {
+        return Err(invalid_data("chunk is not an Ogg Opus stream".to_string()));
+    }
+    let sample_rate = u32::from_le_bytes(head[12..16].try_into().unwrap());
+    Ok((head[9], sample_rate, &head[18..]))
+}
+
+// The samples an OpusHead says to drop from the start of the stream
+fn opus_pre_skip(head: &[u8]) -> u64 {
+    u64::from(u16::from_le_bytes([head[10], head[11]]))
+}
+
+// Re-chain Ogg Opus chunks into one logical stream: the first chunk's
+// OpusHead and OpusTags headers are kept, later chunks' headers are dropped,
+// and their audio pages move onto the first chunk's serial number with
+// granule positions carried on from where the previous chunk ended. Only the
+// first chunk's pre-skip is still skipped, so a later chunk's granules are
+// re-based without its own.
+fn merge_opus(chunks: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    let mut writer = PacketWriter::new(Vec::new());
+    let mut serial = None;
+    let mut layout = None;
+    let mut granule_offset = 0;
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let mut reader = PacketReader::new(Cursor::new(chunk));
+        let mut packets = Vec::new();
+        while let Some(packet) = reader
+            .read_packet()
+            .map_err(|e| invalid_data(format!("chunk {}: {}", index, e)))?
+        {
+            packets.push(packet);
+        }
+
+        let head = packets
+            .first()
+            .ok_or_else(|| invalid_data(format!("chunk {} is empty", index)))?;
+        let chunk_layout = opus_stream_layout(&head.data)?;
+        match layout {
+            None => layout = Some((chunk_layout.0, chunk_layout.1, chunk_layout.2.to_vec())),
+            Some((channels, sample_rate, ref mapping))
+                if (channels, sample_rate, mapping.as_slice()) != chunk_layout =>
+            {
+                return Err(invalid_data(format!(
+                    "chunk {} has a different channel layout from chunk 0",
+                    index
+                )));
+            }
+            Some(_) => {}
+        }
+        let serial = *serial.get_or_insert(head.stream_serial());
+        let pre_skip = if index > 0 {
+            opus_pre_skip(&head.data)
+        } else {
+            0
+        };
+
+        let is_last_chunk = index + 1 == chunks.len();
+        let mut chunk_end = 0;
+        for (number, packet) in packets.iter().enumerate() {
+            let is_header = number < 2;
+            if is_header && index > 0 {
+                continue;
+            }
+            let granule = if is_header {
+                0
+            } else {
+                let chunk_granule = packet.absgp_page().saturating_sub(pre_skip);
+                chunk_end = chunk_end.max(chunk_granule);
+                granule_offset + chunk_granule
+            };
+            // The header packets must each end their own page
+            let end_info = if packet.last_in_stream() && is_last_chunk {
+                PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() || packet.last_in_stream() || is_header {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            writer.write_packet(packet.data.clone().into(), serial, end_info, granule)?;
+        }
+        granule_offset += chunk_end;
+    }
+
+    Ok(writer.into_inner())
+}
+
+// Minimal FLAC encoder: fixed-size blocks, independent channels, each subframe
+// stored with a second-order fixed predictor and Rice-coded residual, or
+// verbatim when that turns out smaller.
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+pub fn encode_flac(format: StreamFormat, interleaved: &[i32]) -> Vec<u8> {
+    let channels = usize::from(format.channels);
+    let bps = u32::from(format.bits_per_sample);
+    let total_frames = interleaved.len() / channels;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"fLaC");
+
+    // STREAMINFO, the only (and therefore last) metadata block
+    let mut info = BitWriter::new();
+    info.write(1, 1);
+    info.write(0, 7);
+    info.write(34, 24);
+    info.write(FLAC_BLOCK_SIZE as u64, 16);
+    info.write(FLAC_BLOCK_SIZE as u64, 16);
+    info.write(0, 24); // min frame size unknown
+    info.write(0, 24); // max frame size unknown
+    info.write(u64::from(format.sample_rate), 20);
+    info.write(channels as u64 - 1, 3);
+    info.write(u64::from(bps) - 1, 5);
+    info.write(total_frames as u64, 36);
+    for _ in 0..16 {
+        info.write(0, 8); // MD5 unknown
+    }
+    out.extend_from_slice(&info.finish());
+
+    for (frame_number, block) in interleaved.chunks(FLAC_BLOCK_SIZE * channels).enumerate() {
+        let block_len = block.len() / channels;
+        let mut frame = BitWriter::new();
+
+        frame.write(0b11_1111_1111_1110, 14);
+        frame.write(0, 1);
+        frame.write(0, 1); // fixed block size stream
+        if block_len == FLAC_BLOCK_SIZE {
+            frame.write(0b1100, 4);
+        } else {
+            frame.write(0b0111, 4); // 16-bit (size - 1) follows the header
+        }
+        frame.write(0b0000, 4); // sample rate from STREAMINFO
+        frame.write(channels as u64 - 1, 4);
+        frame.write(sample_size_code(bps), 3);
+        frame.write(0, 1);
+        frame.write_utf8(frame_number as u64);
+        if block_len != FLAC_BLOCK_SIZE {
+            frame.write(block_len as u64 - 1, 16);
+        }
+        let crc = crc8(frame.bytes());
+        frame.write(u64::from(crc), 8);
+
+        for channel in 0..channels {
+            let samples: Vec<i32> = block
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect();
+            write_subframe(&mut frame, &samples, bps);
+        }
+
+        let mut bytes = frame.finish();
+        let crc = crc16(&bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        out.extend_from_slice(&bytes);
+    }
+
+    out
+}
+
+fn sample_size_code(bps: u32) -> u64 {
+    match bps {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    }
+}
+
+fn write_subframe(frame: &mut BitWriter, samples: &[i32], bps: u32) {
+    let verbatim_bits = samples.len() as u64 * u64::from(bps);
+
+    if samples.len() > 2 {
+        let residual: Vec<i64> = (2..samples.len())
+            .map(|i| {
+                i64::from(samples[i]) - 2 * i64::from(samples[i - 1]) + i64::from(samples[i - 2])
+            })
+            .collect();
+        let (rice_param, residual_bits) = best_rice_parameter(&residual);
+        let fixed_bits = 2 * u64::from(bps) + 2 + 4 + 4 + residual_bits;
+
+        if fixed_bits < verbatim_bits {
+            frame.write(0, 1);
+            frame.write(0b001010, 6); // FIXED, order 2
+            frame.write(0, 1);
+            for &warmup in &samples[..2] {
+                frame.write_signed(i64::from(warmup), bps);
+            }
+            frame.write(0b00, 2); // Rice, 4-bit parameters
+            frame.write(0, 4); // single partition
+            frame.write(u64::from(rice_param), 4);
+            for &value in &residual {
+                frame.write_rice(value, rice_param);
+            }
+            return;
+        }
+    }
+
+    frame.write(0, 1);
+    frame.write(0b000001, 6); // VERBATIM
+    frame.write(0, 1);
+    for &sample in samples {
+        frame.write_signed(i64::from(sample), bps);
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Rice parameter (0-14) giving the smallest residual, with its size in bits
+fn best_rice_parameter(residual: &[i64]) -> (u32, u64) {
+    (0..15)
+        .map(|k| {
+            let bits: u64 = residual
+                .iter()
+                .map(|&r| (zigzag(r) >> k) + 1 + u64::from(k))
+                .sum();
+            (k, bits)
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u64, width: u32) {
+        for shift in (0..width).rev() {
+            self.acc = (self.acc << 1) | ((value >> shift) & 1);
+            self.bits += 1;
+            if self.bits == 8 {
+                self.bytes.push(self.acc as u8);
+                self.acc = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, width: u32) {
+        self.write(value as u64 & ((1u64 << width) - 1), width);
+    }
+
+    fn write_rice(&mut self, value: i64, param: u32) {
+        let folded = zigzag(value);
+        for _ in 0..(folded >> param) {
+            self.write(0, 1);
+        }
+        self.write(1, 1);
+        self.write(folded, param);
+    }
+
+    // FLAC's UTF-8-like variable length integer coding
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        let mut extra = 1;
+        while value >= 1u64 << (6 + 5 * extra) {
+            extra += 1;
+        }
+        let prefix = (0xFFu64 << (7 - extra)) & 0xFF;
+        self.write(prefix | (value >> (6 * extra)), 8);
+        for i in (0..extra).rev() {
+            self.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Pad to a byte boundary and return the buffer
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.write(0, pad);
+        }
+        self.bytes
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEECH: StreamFormat = StreamFormat {
+        sample_rate: 24000,
+        channels: 1,
+        bits_per_sample: 16,
+    };
+
+    fn tone(len: usize, phase: f32) -> Vec<i32> {
+        (0..len)
+            .map(|i| ((i as f32 * 0.05 + phase).sin() * 12000.0) as i32)
+            .collect()
+    }
+
+    #[test]
+    fn three_flac_chunks_merge_into_one_stream_with_the_total_duration() {
+        // Long enough to need multi-byte frame numbers (more than 128 blocks)
+        let lengths = [24000 * 25, 10000, 5000];
+        let originals: Vec<Vec<i32>> = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| tone(len, i as f32))
+            .collect();
+        let chunks: Vec<Vec<u8>> = originals.iter().map(|s| encode_flac(SPEECH, s)).collect();
+
+        let merged = merge_chunks("flac", &chunks).unwrap();
+
+        let mut reader = claxon::FlacReader::new(Cursor::new(&merged)).unwrap();
+        let total: u64 = lengths.iter().sum::<usize>() as u64;
+        assert_eq!(reader.streaminfo().samples, Some(total));
+        let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+        assert_eq!(decoded, originals.concat());
+    }
+
+    #[test]
+    fn wav_chunks_merge_under_a_single_header() {
+        let first = [wav_header(SPEECH, 4), vec![1, 0, 2, 0]].concat();
+        // Streamed WAV responses may claim a larger data length than they carry
+        let second = [wav_header(SPEECH, u32::MAX), vec![3, 0]].concat();
+
+        let merged = merge_chunks("wav", &[first.clone(), second.clone()]).unwrap();
+
+        assert_eq!(merged[..44], wav_header(SPEECH, 6)[..]);
+        assert_eq!(merged[44..], [1, 0, 2, 0, 3, 0]);
+
+        // A millisecond at 24 kHz is 24 samples of silence between them
+        let apart = merge_chunks_apart("wav", &[first, second], Duration::from_millis(1)).unwrap();
+        assert_eq!(apart[..44], wav_header(SPEECH, 54)[..]);
+        assert_eq!(apart[44..48], [1, 0, 2, 0]);
+        assert!(apart[48..96].iter().all(|&byte| byte == 0));
+        assert_eq!(apart[96..], [3, 0]);
+    }
+
+    #[test]
+    fn pcm_is_little_endian_and_whole_samples_only() {
+        assert_eq!(
+            pcm_samples(&[0x01, 0x00, 0xff, 0x7f, 0x00, 0x80]).unwrap(),
+            [1, i16::MAX, i16::MIN]
+        );
+        let truncated = pcm_samples(&[0x01, 0x00, 0xff]).unwrap_err();
+        assert_eq!(truncated.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn opus_chunk(serial: u32, channels: u8, granules: &[u64]) -> Vec<u8> {
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(channels);
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&24000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        let tags = b"OpusTags\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+
+        let mut writer = PacketWriter::new(Vec::new());
+        let end_page = PacketWriteEndInfo::EndPage;
+        writer
+            .write_packet(head.into(), serial, end_page, 0)
+            .unwrap();
+        writer
+            .write_packet(tags.into(), serial, end_page, 0)
+            .unwrap();
+        for (i, &granule) in granules.iter().enumerate() {
+            let end_info = if i + 1 == granules.len() {
+                PacketWriteEndInfo::EndStream
+            } else {
+                end_page
+            };
+            writer
+                .write_packet(vec![0xf8, i as u8].into(), serial, end_info, granule)
+                .unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn opus_chunks_are_rechained_into_one_logical_stream() {
+        let chunks = [
+            opus_chunk(11, 1, &[960, 1272]),
+            opus_chunk(22, 1, &[960, 1920]),
+        ];
+
+        let merged = merge_chunks("opus", &chunks).unwrap();
+
+        let mut reader = PacketReader::new(Cursor::new(merged));
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        assert!(packets.iter().all(|p| p.stream_serial() == 11));
+        assert!(packets[0].data.starts_with(b"OpusHead"));
+        assert!(packets[1].data.starts_with(b"OpusTags"));
+        let audio: Vec<(u8, u64)> = packets[2..]
+            .iter()
+            .map(|p| (p.data[1], p.absgp_page()))
+            .collect();
+        assert_eq!(audio, [(0, 960), (1, 1272), (0, 1920), (1, 2880)]);
+        // Each chunk plays for its last granule less its pre-skip of 312,
+        // and so does the merged stream
+        let played = packets.last().unwrap().absgp_page() - 312;
+        assert_eq!(played, (1272 - 312) + (1920 - 312));
+        assert!(packets.last().unwrap().last_in_stream());
+        assert!(packets[..packets.len() - 1]
+            .iter()
+            .all(|p| !p.last_in_stream()));
+
+        let stereo = opus_chunk(33, 2, &[960]);
+        assert!(merge_chunks("opus", &[chunks[0].clone(), stereo]).is_err());
+    }
+
+    #[test]
+    fn appending_checks_the_existing_format() {
+        let wav = wav_header(API_PCM_FORMAT, 4);
+        assert_eq!(sniff_format(&wav), Some("wav"));
+        assert_eq!(sniff_format(b"ID3\x04"), Some("mp3"));
+        assert_eq!(sniff_format(&[0xff, 0xfb, 0x90]), Some("mp3"));
+        assert_eq!(sniff_format(&[0xff, 0xf1, 0x50]), Some("aac"));
+        assert_eq!(sniff_format(&[0, 0, 1, 0]), None);
+
+        assert!(check_append(&wav, 48, "pcm", true, API_PCM_FORMAT).is_ok());
+        assert!(check_append(&[0xff, 0xfb], 1000, "mp3", false, API_PCM_FORMAT).is_ok());
+        assert!(check_append(b"", 0, "flac", false, API_PCM_FORMAT).is_ok());
+        assert!(check_append(&[0, 0, 1, 0], 4, "pcm", false, API_PCM_FORMAT).is_ok());
+
+        let err = check_append(&[0xff, 0xfb], 1000, "aac", false, API_PCM_FORMAT).unwrap_err();
+        assert_eq!(err, "it holds mp3 audio, not aac");
+        assert!(check_append(&wav, 48, "pcm", false, API_PCM_FORMAT).is_err());
+        assert!(check_append(&[0, 0, 1], 3, "pcm", false, API_PCM_FORMAT).is_err());
+        let stereo = wav_header(
+            StreamFormat {
+                channels: 2,
+                ..API_PCM_FORMAT
+            },
+            4,
+        );
+        assert!(check_append(&stereo, 48, "pcm", true, API_PCM_FORMAT).is_err());
+        let stereo_format = StreamFormat {
+            channels: 2,
+            ..API_PCM_FORMAT
+        };
+        assert!(check_append(&stereo, 48, "pcm", true, stereo_format).is_ok());
+        assert!(check_append(&[0, 0, 1, 0, 2, 0], 6, "pcm", false, stereo_format).is_err());
+    }
+}
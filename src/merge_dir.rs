@@ -0,0 +1,122 @@
+// Putting a directory of chunk files back together (--merge DIR), the
+// inverse of --split-output-dir: after editing some of the chunks in another
+// program, `chunk_*.<ext>` are read in order and stitched like the chunks of
+// a normal run.
+
+use crate::merge;
+use std::path::{Path, PathBuf};
+
+// The formats chunk files can be in, by extension
+const FORMATS: [&str; 6] = ["mp3", "opus", "aac", "flac", "wav", "pcm"];
+
+// The number in a chunk file's name, if it is one
+fn chunk_number(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.strip_prefix("chunk_")?.parse().ok()
+}
+
+// The chunk files in `dir` in order, and the format they're all in
+pub fn find(dir: &Path) -> Result<(&'static str, Vec<PathBuf>), String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut chunks: Vec<(u64, PathBuf)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| Some((chunk_number(&path)?, path)))
+        .collect();
+    chunks.sort();
+
+    let Some((_, first)) = chunks.first() else {
+        return Err(format!("no chunk_*.<ext> files in {}", dir.display()));
+    };
+    let extension = |path: &Path| {
+        path.extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    };
+    let first_extension = extension(first);
+    let Some(format) = FORMATS
+        .into_iter()
+        .find(|format| *format == first_extension)
+    else {
+        return Err(format!(
+            "{} isn't in a format chunks come in ({})",
+            first.display(),
+            FORMATS.join(", ")
+        ));
+    };
+    if let Some((_, odd)) = chunks
+        .iter()
+        .find(|(_, path)| extension(path) != first_extension)
+    {
+        return Err(format!(
+            "{} and {} are in different formats",
+            first.display(),
+            odd.display()
+        ));
+    }
+    Ok((format, chunks.into_iter().map(|(_, path)| path).collect()))
+}
+
+// An ID3v2 tag at the start of an MP3, as written on each chunk file; left
+// in, every chunk's tag would end up in the middle of the merged stream
+fn id3_len(bytes: &[u8]) -> usize {
+    if bytes.len() < 10 || !bytes.starts_with(b"ID3") {
+        return 0;
+    }
+    // The size is stored as four 7-bit bytes, plus a footer if flagged
+    let size = bytes[6..10]
+        .iter()
+        .fold(0usize, |size, byte| (size << 7) | usize::from(byte & 0x7f));
+    let footer = if bytes[5] & 0x10 != 0 { 10 } else { 0 };
+    (10 + size + footer).min(bytes.len())
+}
+
+// The chunk files at `paths`, in `format`, as one file
+pub fn merge(format: &str, paths: &[PathBuf]) -> Result<Vec<u8>, String> {
+    let mut chunks = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match format {
+            "mp3" => {
+                bytes.drain(..id3_len(&bytes));
+            }
+            "pcm" => merge::check_pcm(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?,
+            _ => {}
+        }
+        chunks.push(bytes);
+    }
+    merge::merge_chunks(format, &chunks).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_found_in_numeric_order_without_their_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let tag = [b"ID3".as_slice(), &[4, 0, 0, 0, 0, 0, 2], b"xy"].concat();
+        for (name, audio) in [
+            ("chunk_10.mp3", b"\xff\xfbten".as_slice()),
+            ("chunk_9.mp3", b"\xff\xfbnine"),
+            ("chunk_0.mp3", b"\xff\xfbzero"),
+        ] {
+            std::fs::write(dir.path().join(name), [tag.as_slice(), audio].concat()).unwrap();
+        }
+        std::fs::write(dir.path().join("index.tsv"), "chunk\tfile\n").unwrap();
+
+        let (format, paths) = find(dir.path()).unwrap();
+        assert_eq!(format, "mp3");
+        let names: Vec<_> = paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["chunk_0.mp3", "chunk_9.mp3", "chunk_10.mp3"]);
+        assert_eq!(
+            merge(format, &paths).unwrap(),
+            b"\xff\xfbzero\xff\xfbnine\xff\xfbten"
+        );
+
+        std::fs::write(dir.path().join("chunk_11.wav"), b"").unwrap();
+        assert!(find(dir.path()).unwrap_err().contains("different formats"));
+    }
+}
@@ -0,0 +1,99 @@
+// Reading Jupyter notebooks (`.ipynb`): the prose is in the markdown cells,
+// with code and its output read only on request.
+
+use serde_json::Value;
+
+#[derive(Copy, Clone, Default)]
+pub struct NotebookOptions {
+    pub code_cells: bool,
+    pub outputs: bool,
+}
+
+// Cell sources and output text may be one string or a list of lines
+fn joined(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(lines) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+// Text of a code cell's outputs: printed streams and plain-text results
+fn output_text(output: &Value) -> String {
+    match output["output_type"].as_str() {
+        Some("stream") => joined(&output["text"]),
+        Some("execute_result") | Some("display_data") => joined(&output["data"]["text/plain"]),
+        _ => String::new(),
+    }
+}
+
+// The notebook's text in cell order, with cells separated by blank lines
+pub fn extract_text(json: &str, options: NotebookOptions) -> Result<String, String> {
+    let notebook: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let cells = notebook["cells"]
+        .as_array()
+        .ok_or("no \"cells\" list; is this a Jupyter notebook?")?;
+
+    let mut parts = Vec::new();
+    for cell in cells {
+        match cell["cell_type"].as_str() {
+            Some("markdown") => parts.push(joined(&cell["source"])),
+            Some("code") => {
+                if options.code_cells {
+                    parts.push(joined(&cell["source"]));
+                }
+                if options.outputs {
+                    let outputs = cell["outputs"].as_array().map_or(&[][..], Vec::as_slice);
+                    parts.extend(outputs.iter().map(output_text));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let parts: Vec<&str> = parts
+        .iter()
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .collect();
+    Ok(parts.join("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTEBOOK: &str = r##"{
+        "cells": [
+            {"cell_type": "markdown", "source": ["# Results\n", "The mean is below."]},
+            {"cell_type": "code", "source": "print(mean)",
+             "outputs": [
+                {"output_type": "stream", "name": "stdout", "text": ["4.2\n"]},
+                {"output_type": "display_data", "data": {"image/png": "...", "text/plain": ["<Figure>"]}}
+             ]},
+            {"cell_type": "raw", "source": "ignored"},
+            {"cell_type": "markdown", "source": "That's all."}
+        ],
+        "nbformat": 4
+    }"##;
+
+    #[test]
+    fn markdown_cells_are_read_and_code_is_optional() {
+        assert_eq!(
+            extract_text(NOTEBOOK, NotebookOptions::default()).unwrap(),
+            "# Results\nThe mean is below.\n\nThat's all."
+        );
+
+        let everything = NotebookOptions {
+            code_cells: true,
+            outputs: true,
+        };
+        assert_eq!(
+            extract_text(NOTEBOOK, everything).unwrap(),
+            "# Results\nThe mean is below.\n\nprint(mean)\n\n4.2\n\n<Figure>\n\nThat's all."
+        );
+
+        assert!(extract_text("{\"cells\": 3}", everything).is_err());
+        assert!(extract_text("not json", everything).is_err());
+    }
+}
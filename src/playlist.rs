@@ -0,0 +1,121 @@
+// Extended M3U playlists listing the files a run produced, in order.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+// Where to write the playlist, and what to call each chunk's entry
+pub struct Playlist {
+    pub path: String,
+    pub titles: Vec<String>,
+}
+
+pub struct Entry {
+    pub path: String,
+    pub title: String,
+    pub duration: Option<Duration>,
+}
+
+pub fn render(entries: &[Entry], playlist_path: &Path) -> String {
+    let base = playlist_path.parent().unwrap_or(Path::new(""));
+    let mut m3u = String::from("#EXTM3U\n");
+    for entry in entries {
+        let seconds = entry
+            .duration
+            .map_or(-1, |duration| duration.as_secs_f64().round() as i64);
+        m3u.push_str(&format!("#EXTINF:{},{}\n", seconds, entry.title));
+        m3u.push_str(&relative_to(Path::new(&entry.path), base));
+        m3u.push('\n');
+    }
+    m3u
+}
+
+// `path` as seen from `base`, so the playlist still works if the directory
+// is moved; falls back to the path as given if they share no root
+fn relative_to(path: &Path, base: &Path) -> String {
+    let absolute = |path: &Path| -> Option<PathBuf> {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let dir = dir.unwrap_or(Path::new(".")).canonicalize().ok()?;
+        Some(dir.join(path.file_name()?))
+    };
+    let (Some(path_abs), Ok(base_abs)) = (absolute(path), base_or_cwd(base).canonicalize()) else {
+        return path.to_string_lossy().into_owned();
+    };
+
+    let path_parts: Vec<Component> = path_abs.components().collect();
+    let base_parts: Vec<Component> = base_abs.components().collect();
+    let common = path_parts
+        .iter()
+        .zip(&base_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path.to_string_lossy().into_owned();
+    }
+
+    let mut parts: Vec<String> = vec!["..".to_string(); base_parts.len() - common];
+    parts.extend(
+        path_parts[common..]
+            .iter()
+            .map(|part| part.as_os_str().to_string_lossy().into_owned()),
+    );
+    parts.join("/")
+}
+
+fn base_or_cwd(base: &Path) -> &Path {
+    if base.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        base
+    }
+}
+
+// Write to a temporary file next to `path` and rename it into place, so
+// nothing ever sees a half-written file
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(&temp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_path);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_relative_to_the_playlist() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("audio")).unwrap();
+        std::fs::create_dir(dir.path().join("lists")).unwrap();
+        let chunk = dir.path().join("audio/chunk_000.mp3");
+        std::fs::write(&chunk, b"").unwrap();
+
+        let entries = [Entry {
+            path: chunk.to_string_lossy().into_owned(),
+            title: "Beginnings".to_string(),
+            duration: Some(Duration::from_millis(61_600)),
+        }];
+        let playlist = dir.path().join("lists/book.m3u8");
+
+        assert_eq!(
+            render(&entries, &playlist),
+            "#EXTM3U\n#EXTINF:62,Beginnings\n../audio/chunk_000.mp3\n"
+        );
+
+        write_atomically(&playlist, b"#EXTM3U\n").unwrap();
+        assert_eq!(std::fs::read(&playlist).unwrap(), b"#EXTM3U\n");
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("lists")).unwrap().count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,613 @@
+// Text clean-up passes applied to the input before it is chunked.
+
+use regex::{Regex, RegexSet};
+use std::ops::Range;
+use unicode_bidi::{bidi_class, BidiClass};
+use unicode_normalization::UnicodeNormalization;
+
+// Unicode normalization forms offered by --unicode-nfc and friends
+#[derive(Copy, Clone, Debug)]
+pub enum Normalization {
+    Nfc,
+    Nfkc,
+    Nfkd,
+}
+
+pub fn normalize(text: &str, form: Normalization) -> String {
+    match form {
+        Normalization::Nfc => text.nfc().collect(),
+        Normalization::Nfkc => text.nfkc().collect(),
+        Normalization::Nfkd => text.nfkd().collect(),
+    }
+}
+
+// Whether the text contains right-to-left script (Arabic, Hebrew, Persian,
+// ...), which the sentence-boundary chunking doesn't know how to handle
+pub fn contains_rtl(text: &str) -> bool {
+    text.chars()
+        .any(|c| matches!(bidi_class(c), BidiClass::R | BidiClass::AL))
+}
+
+// Lines to leave out of the spoken text: any line matching one of `lines`,
+// and every block from a line matching the start pattern through the next
+// line matching the end pattern (inclusive)
+pub struct SkipRules {
+    pub lines: RegexSet,
+    pub block: Option<(Regex, Regex)>,
+}
+
+impl SkipRules {
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.block.is_none()
+    }
+}
+
+pub fn skip_lines(text: &str, rules: &SkipRules) -> String {
+    let mut kept = String::with_capacity(text.len());
+    let mut in_block = false;
+
+    for line in text.lines() {
+        if let Some((start, end)) = &rules.block {
+            if in_block {
+                in_block = !end.is_match(line);
+                continue;
+            }
+            if start.is_match(line) {
+                in_block = !end.is_match(line);
+                continue;
+            }
+        }
+        if rules.lines.is_match(line) {
+            continue;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+
+    kept
+}
+
+// Turn AsciiDoc markup into plain prose: headings are read as titles,
+// inline formatting markers and cross-reference targets are dropped, and
+// `----` listing blocks are announced rather than read out
+pub fn strip_asciidoc(text: &str) -> String {
+    let heading = Regex::new(r"^(=+)\s+(.*\S)\s*$").unwrap();
+    let source_attribute = Regex::new(r"^\[source(?:,\s*([\w+#-]+))?.*\]\s*$").unwrap();
+    let attribute_entry = Regex::new(r"^:[\w-]+!?:").unwrap();
+    let inline = [
+        (Regex::new(r"<<[^,>]+,\s*([^>]+)>>").unwrap(), "$1"),
+        (Regex::new(r"<<([^,>]+)>>").unwrap(), "$1"),
+        (Regex::new(r"\*\*([^*]+)\*\*").unwrap(), "$1"),
+        (Regex::new(r"\*([^*\s][^*]*)\*").unwrap(), "$1"),
+        (Regex::new(r"__([^_]+)__").unwrap(), "$1"),
+        (Regex::new(r"\b_([^_\s][^_]*)_\b").unwrap(), "$1"),
+        (Regex::new(r"`+([^`]+)`+").unwrap(), "$1"),
+    ];
+
+    let mut spoken = String::with_capacity(text.len());
+    let mut language = None;
+    let mut in_listing = false;
+
+    for line in text.lines() {
+        if line.trim_end() == "----" {
+            if !in_listing {
+                match language.take() {
+                    Some(language) => spoken.push_str(&format!("Code block in {}.\n", language)),
+                    None => spoken.push_str("Code block.\n"),
+                }
+            }
+            in_listing = !in_listing;
+            continue;
+        }
+        if in_listing || attribute_entry.is_match(line) {
+            continue;
+        }
+        if let Some(captures) = source_attribute.captures(line) {
+            language = Some(captures.get(1).map_or("code", |m| m.as_str()).to_string());
+            continue;
+        }
+        // An attribute line only describes the block that follows it
+        language = None;
+
+        let mut line = match heading.captures(line) {
+            Some(captures) if &captures[1] == "=" => captures[2].to_string(),
+            Some(captures) => format!("{}:", &captures[2]),
+            None => line.to_string(),
+        };
+        for (pattern, replacement) in &inline {
+            line = pattern.replace_all(&line, *replacement).into_owned();
+        }
+        spoken.push_str(&line);
+        spoken.push('\n');
+    }
+
+    spoken
+}
+
+const RST_ADMONITIONS: [&str; 11] = [
+    "attention",
+    "caution",
+    "danger",
+    "error",
+    "hint",
+    "important",
+    "note",
+    "seealso",
+    "tip",
+    "todo",
+    "warning",
+];
+
+// Turn reStructuredText into plain prose: underlined headings are read as
+// titles, admonitions are announced by name and read, code blocks are
+// announced and skipped, other directives and comments are dropped, and
+// inline markup loses its markers
+pub fn strip_rst(text: &str) -> String {
+    let directive = Regex::new(r"^\.\.\s+([\w-]+)::\s*(.*)$").unwrap();
+    let option = Regex::new(r"^:[\w-]+:").unwrap();
+    let inline = [
+        (
+            Regex::new(r":[\w:-]+:`([^`<]*?)\s*(?:<[^>]*>)?`").unwrap(),
+            "$1",
+        ),
+        (Regex::new(r"`([^`<]*?)\s*<[^>]*>`__?").unwrap(), "$1"),
+        (Regex::new(r"`([^`]+)`__?").unwrap(), "$1"),
+        (Regex::new(r"``([^`]+)``").unwrap(), "$1"),
+        (Regex::new(r"\*\*([^*]+)\*\*").unwrap(), "$1"),
+        (Regex::new(r"\*([^*\s][^*]*)\*").unwrap(), "$1"),
+        (Regex::new(r"`([^`]+)`").unwrap(), "$1"),
+        (Regex::new(r"\|([^|\s][^|]*)\|").unwrap(), "$1"),
+    ];
+    let is_adornment = |line: &str| {
+        let line = line.trim_end();
+        line.len() >= 2
+            && line.chars().all(|c| "=-~^\"'`#*+:.".contains(c))
+            && line.chars().all(|c| line.starts_with(c))
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+    let mut spoken = String::with_capacity(text.len());
+    let mut i = 0;
+
+    // Skip the indented body of a directive or literal block starting at
+    // line `i`, returning the first line after it
+    let block_end = |mut i: usize| {
+        while i < lines.len() && (lines[i].trim().is_empty() || lines[i].starts_with([' ', '\t'])) {
+            i += 1;
+        }
+        i
+    };
+
+    while i < lines.len() {
+        let line = lines[i];
+
+        // An overline is dropped; the underline below the title does the work
+        if is_adornment(line) && lines.get(i + 2).is_some_and(|under| is_adornment(under)) {
+            i += 1;
+            continue;
+        }
+        if !line.trim().is_empty()
+            && lines.get(i + 1).is_some_and(|under| {
+                is_adornment(under) && under.trim_end().len() >= line.trim().chars().count()
+            })
+        {
+            spoken.push_str(&format!("{}:\n", line.trim()));
+            i += 2;
+            continue;
+        }
+
+        if let Some(captures) = directive.captures(line) {
+            let name = captures[1].to_lowercase();
+            let argument = captures[2].trim();
+            let body_start = i + 1;
+            i = block_end(body_start);
+
+            if RST_ADMONITIONS.contains(&name.as_str()) {
+                spoken.push_str(&format!("{}{}.\n", name[..1].to_uppercase(), &name[1..]));
+                if !argument.is_empty() {
+                    spoken.push_str(argument);
+                    spoken.push('\n');
+                }
+                for body_line in &lines[body_start..i] {
+                    let body_line = body_line.trim();
+                    if !option.is_match(body_line) {
+                        spoken.push_str(body_line);
+                        spoken.push('\n');
+                    }
+                }
+            } else if matches!(name.as_str(), "code" | "code-block" | "sourcecode") {
+                match argument {
+                    "" => spoken.push_str("Code block.\n"),
+                    language => spoken.push_str(&format!("Code block in {}.\n", language)),
+                }
+            }
+            continue;
+        }
+        // Comments and hyperlink targets
+        if line.starts_with("..") {
+            i = block_end(i + 1);
+            continue;
+        }
+
+        // A paragraph ending in `::` introduces an indented literal block
+        let mut text = line.to_string();
+        let mut literal_follows = false;
+        if let Some(stripped) = line.trim_end().strip_suffix("::") {
+            literal_follows = true;
+            text = if stripped.trim().is_empty() {
+                String::new()
+            } else if stripped.ends_with(' ') {
+                stripped.trim_end().to_string()
+            } else {
+                format!("{}:", stripped)
+            };
+        }
+
+        for (pattern, replacement) in &inline {
+            text = pattern.replace_all(&text, *replacement).into_owned();
+        }
+        if !(text.is_empty() && literal_follows) {
+            spoken.push_str(&text);
+            spoken.push('\n');
+        }
+        i += 1;
+
+        if literal_follows {
+            spoken.push_str("Code block.\n");
+            i = block_end(i);
+        }
+    }
+
+    spoken
+}
+
+// Turn HTML into plain prose: scripts, styles and comments are dropped,
+// block elements become line breaks, other tags are removed and character
+// references are decoded
+pub fn strip_html(html: &str) -> String {
+    let hidden = Regex::new(
+        r"(?is)<!--.*?-->|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<head\b.*?</head\s*>",
+    )
+    .unwrap();
+    let block = Regex::new(
+        r"(?i)</?(?:address|article|aside|blockquote|br|dd|div|dl|dt|figcaption|footer|h[1-6]|header|hr|li|main|nav|ol|p|pre|section|table|td|th|tr|ul)\b[^>]*>",
+    )
+    .unwrap();
+    let tag = Regex::new(r"<[^>]*>").unwrap();
+    let blank_lines = Regex::new(r"\n{3,}").unwrap();
+
+    let text = hidden.replace_all(html, "");
+    let text = block.replace_all(&text, "\n");
+    let text = tag.replace_all(&text, "");
+    let text = decode_entities(&text);
+
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+    blank_lines
+        .replace_all(lines.join("\n").trim(), "\n\n")
+        .into_owned()
+}
+
+fn decode_entities(text: &str) -> String {
+    let entity = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+    entity
+        .replace_all(text, |captures: &regex::Captures| {
+            let name = &captures[1];
+            let decoded = match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                "mdash" => Some('—'),
+                "ndash" => Some('–'),
+                "hellip" => Some('…'),
+                "lsquo" => Some('‘'),
+                "rsquo" => Some('’'),
+                "ldquo" => Some('“'),
+                "rdquo" => Some('”'),
+                _ => {
+                    let code = match name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => name.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                    };
+                    code.and_then(char::from_u32)
+                }
+            };
+            decoded.map_or_else(|| captures[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+// The `length` characters (or the rest) starting `offset` characters in,
+// along with the character range taken and the text's length in characters
+pub fn slice_chars(
+    text: &str,
+    offset: usize,
+    length: Option<usize>,
+) -> Result<(&str, Range<usize>, usize), String> {
+    let total = text.chars().count();
+    let end = match length {
+        Some(length) => offset.saturating_add(length),
+        None => total,
+    };
+    if offset > total || end > total {
+        return Err(format!(
+            "characters {}..{} are out of range; the input has {}",
+            offset, end, total
+        ));
+    }
+
+    // Byte positions of the character boundaries, with the end of the text
+    let byte_at = |chars: usize| {
+        text.char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .nth(chars)
+            .unwrap()
+    };
+    Ok((&text[byte_at(offset)..byte_at(end)], offset..end, total))
+}
+
+// Drop the backspace overstrikes terminals use for bold (`X\bX`) and
+// underline (`_\bX`), keeping the character that was struck last
+pub fn strip_overstrike(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\u{8}' {
+            plain.pop();
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+// Apply regex substitutions in order; replacements may refer to capture
+// groups as `$1` or `${name}`
+pub fn apply_replacements(text: &str, replacements: &[(Regex, String)]) -> String {
+    let mut text = text.to_string();
+    for (pattern, replacement) in replacements {
+        text = pattern
+            .replace_all(&text, replacement.as_str())
+            .into_owned();
+    }
+    text
+}
+
+// A run of text read at its own speed (--speed-per-segment); None means the
+// run's normal speed
+#[derive(Debug, PartialEq)]
+pub struct SpeedSegment {
+    pub text: String,
+    pub speed: Option<f32>,
+}
+
+// Split text at `[[speed:1.3]]...[[/speed]]` markers. Segments don't nest;
+// markers that are malformed, out of range or unbalanced are left in the
+// text as they are, with a warning for each.
+pub fn speed_segments(text: &str) -> (Vec<SpeedSegment>, Vec<String>) {
+    let marker = Regex::new(r"\[\[speed:([^\[\]]*)\]\]|\[\[/speed\]\]").unwrap();
+    let markers: Vec<_> = marker.captures_iter(text).collect();
+    let mut segments = Vec::new();
+    let mut warnings = Vec::new();
+    let mut current = String::new();
+    let mut speed = None;
+    let mut last = 0;
+    // The closing marker of a refused opening one stays literal too
+    let mut literal_close = false;
+
+    let mut flush = |current: &mut String, speed: Option<f32>| {
+        if !current.trim().is_empty() {
+            segments.push(SpeedSegment {
+                text: std::mem::take(current),
+                speed,
+            });
+        }
+        current.clear();
+    };
+
+    for (i, captures) in markers.iter().enumerate() {
+        let found = captures.get(0).unwrap();
+        current.push_str(&text[last..found.start()]);
+        last = found.end();
+        let line = text[..found.start()].matches('\n').count() + 1;
+
+        match captures.get(1) {
+            Some(value) => {
+                let closed = markers.get(i + 1).is_some_and(|next| next.get(1).is_none());
+                let parsed = value
+                    .as_str()
+                    .trim()
+                    .parse::<f32>()
+                    .map_err(|e| e.to_string())
+                    .and_then(crate::config::check_speed);
+                match (parsed, closed) {
+                    (Ok(parsed), true) => {
+                        flush(&mut current, None);
+                        speed = Some(parsed);
+                        continue;
+                    }
+                    (Err(e), _) => {
+                        warnings.push(format!("line {}: ignoring {}: {}", line, found.as_str(), e));
+                        literal_close = closed;
+                    }
+                    (Ok(_), false) => warnings.push(format!(
+                        "line {}: ignoring {}: no [[/speed]] follows it",
+                        line,
+                        found.as_str()
+                    )),
+                }
+                current.push_str(found.as_str());
+            }
+            None if speed.is_some() => flush(&mut current, speed.take()),
+            None => {
+                if !std::mem::take(&mut literal_close) {
+                    warnings.push(format!(
+                        "line {}: ignoring [[/speed]] without a [[speed:...]] before it",
+                        line
+                    ));
+                }
+                current.push_str(found.as_str());
+            }
+        }
+    }
+    current.push_str(&text[last..]);
+    flush(&mut current, speed);
+
+    (segments, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_markup_splits_the_text_into_segments() {
+        let text = "Slowly [[speed:0.8]]now faster[[/speed]] and back.\n\
+                    [[speed:fast]]kept[[/speed]] [[/speed]] [[speed:9]] [[speed:1.5]]end";
+        let (segments, warnings) = speed_segments(text);
+        assert_eq!(
+            segments,
+            [
+                SpeedSegment {
+                    text: "Slowly ".to_string(),
+                    speed: None
+                },
+                SpeedSegment {
+                    text: "now faster".to_string(),
+                    speed: Some(0.8)
+                },
+                SpeedSegment {
+                    text: " and back.\n[[speed:fast]]kept[[/speed]] [[/speed]] [[speed:9]] \
+                           [[speed:1.5]]end"
+                        .to_string(),
+                    speed: None
+                },
+            ]
+        );
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].starts_with("line 2: ignoring [[speed:fast]]"));
+        assert!(warnings[3].contains("no [[/speed]] follows it"));
+    }
+
+    #[test]
+    fn html_is_read_as_prose() {
+        let html = "<html><head><title>Ignored</title></head><body>\n\
+                    <h1>Release&nbsp;notes</h1><!-- draft -->\n\
+                    <p>Fixes <b>three</b> bugs &amp; adds <a href=\"#x\">one</a>\n   feature.</p>\
+                    <script>alert(1)</script><ul><li>Faster&#8230;</li><li>Smaller &#x2014; &bogus;</li></ul>\
+                    </body></html>";
+        assert_eq!(
+            strip_html(html),
+            "Release notes\n\nFixes three bugs & adds one\nfeature.\n\nFaster…\n\nSmaller — &bogus;"
+        );
+    }
+
+    #[test]
+    fn slices_count_characters_not_bytes() {
+        let text = "naïve café au lait";
+        assert_eq!(slice_chars(text, 6, Some(4)), Ok(("café", 6..10, 18)));
+        assert_eq!(slice_chars(text, 11, None), Ok(("au lait", 11..18, 18)));
+        assert_eq!(slice_chars(text, 18, None), Ok(("", 18..18, 18)));
+        assert!(slice_chars(text, 19, None).is_err());
+        assert!(slice_chars(text, 10, Some(9)).is_err());
+    }
+
+    #[test]
+    fn overstrikes_are_removed() {
+        assert_eq!(
+            strip_overstrike("N\u{8}NA\u{8}AM\u{8}ME\u{8}E\n  _\u{8}f_\u{8}i_\u{8}l_\u{8}e"),
+            "NAME\n  file"
+        );
+    }
+
+    #[test]
+    fn asciidoc_markup_is_stripped() {
+        let text = "= User Guide\n:toc: left\n\n== Getting Started\n\
+                    Run *sayit* with `--hd`, see <<install,the install notes>> \
+                    or <<usage>>.\n\
+                    [source,rust]\n----\nfn main() {}\n----\nDone.\n";
+
+        assert_eq!(
+            strip_asciidoc(text),
+            "User Guide\n\nGetting Started:\n\
+             Run sayit with --hd, see the install notes or usage.\n\
+             Code block in rust.\nDone.\n"
+        );
+    }
+
+    #[test]
+    fn rst_markup_is_stripped() {
+        let text = "=====\nGuide\n=====\n\n\
+                    Install\n-------\n\
+                    Run **sayit** with ``--hd``; see `the docs <https://x.y>`_ \
+                    and :ref:`usage`.\n\n\
+                    .. note:: Keep your key secret.\n   It bills you.\n\n\
+                    .. code-block:: python\n   :linenos:\n\n   print(1)\n\n\
+                    .. image:: cat.png\n\n\
+                    .. _usage:\n\n\
+                    Example::\n\n    sayit notes.txt\n\nDone.\n";
+
+        assert_eq!(
+            strip_rst(text),
+            "Guide:\n\nInstall:\n\
+             Run sayit with --hd; see the docs and usage.\n\n\
+             Note.\nKeep your key secret.\nIt bills you.\n\n\
+             Code block in python.\n\
+             Example:\nCode block.\nDone.\n"
+        );
+    }
+
+    #[test]
+    fn rtl_scripts_are_detected() {
+        assert!(contains_rtl("Hello שלום"));
+        assert!(contains_rtl("سلام"));
+        assert!(!contains_rtl("Hello, naïve café 你好"));
+    }
+
+    #[test]
+    fn normalize_composes_and_folds() {
+        // "café" with a combining acute accent, then the "ﬁ" ligature
+        let text = "cafe\u{301} \u{FB01}le";
+
+        assert_eq!(normalize(text, Normalization::Nfc), "caf\u{E9} \u{FB01}le");
+        assert_eq!(normalize(text, Normalization::Nfkc), "caf\u{E9} file");
+        assert_eq!(normalize(text, Normalization::Nfkd), "cafe\u{301} file");
+    }
+
+    #[test]
+    fn replacements_apply_in_order_with_capture_groups() {
+        let replacements = vec![
+            (
+                Regex::new(r"\bNASA\b").unwrap(),
+                "NASA the space agency".to_string(),
+            ),
+            (Regex::new(r"(\d+)%").unwrap(), "$1 percent".to_string()),
+            (Regex::new(r"space agency").unwrap(), "agency".to_string()),
+        ];
+
+        assert_eq!(
+            apply_replacements("NASA cut 10% of NASAL sprays", &replacements),
+            "NASA the agency cut 10 percent of NASAL sprays"
+        );
+    }
+
+    #[test]
+    fn skip_lines_drops_matching_lines_and_blocks() {
+        let rules = SkipRules {
+            lines: RegexSet::new([r"^Advertisement$", r"(?i)copyright"]).unwrap(),
+            block: Some((
+                Regex::new(r"^<nav>").unwrap(),
+                Regex::new(r"</nav>$").unwrap(),
+            )),
+        };
+        let text = "Title\nAdvertisement\n<nav>\nHome | About\n</nav>\nBody text.\n\
+                    <nav>inline</nav>\n(c) Copyright 2024\nEnd.";
+
+        assert_eq!(skip_lines(text, &rules), "Title\nBody text.\nEnd.\n");
+    }
+}
@@ -0,0 +1,100 @@
+// `sayit preview`: hearing one voice, or each in turn, read a short sample,
+// so choosing a voice doesn't take full runs. Every clip is cached under
+// `$XDG_CACHE_HOME/sayit/previews` (falling back to `~/.cache`) by its model,
+// voice and text, so hearing it again costs nothing.
+
+use crate::config;
+use crate::playlist;
+use crate::Voice;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+// One piece of audio a preview needs
+pub struct Clip {
+    pub voice: Voice,
+    pub text: String,
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_home.join("sayit").join("previews"))
+}
+
+// Where `clip` read by `model` is cached in `dir`
+pub fn cache_path(dir: &Path, model: &str, clip: &Clip) -> PathBuf {
+    let digest = Sha256::digest(format!("{}\n{}\n{}", model, clip.voice.name(), clip.text));
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    dir.join(format!("{}.mp3", hex))
+}
+
+pub fn cached(path: &Path) -> Option<Vec<u8>> {
+    std::fs::read(path).ok().filter(|bytes| !bytes.is_empty())
+}
+
+// Keep a fetched clip for next time; a cache that can't be written only
+// means paying for the clip again
+pub fn store(path: &Path, bytes: &[u8]) {
+    let stored = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| playlist::write_atomically(path, bytes));
+    if let Err(e) = stored {
+        log::warn!("Unable to cache {}: {}", path.display(), e);
+    }
+}
+
+// What fetching `clips` with `model` costs, in dollars
+pub fn cost(clips: &[&Clip], model: &str) -> f64 {
+    let characters: usize = clips.iter().map(|clip| clip.text.chars().count()).sum();
+    characters as f64 * config::price_per_million(model) / 1_000_000.0
+}
+
+// The line stating what a preview will cost, given the clips not yet cached
+pub fn estimate(uncached: &[&Clip], cached: usize, model: &str) -> String {
+    let characters: usize = uncached.iter().map(|clip| clip.text.chars().count()).sum();
+    let mut line = format!(
+        "{} request(s), {} characters, about ${:.4} with {}",
+        uncached.len(),
+        characters,
+        cost(uncached, model),
+        model
+    );
+    if cached > 0 {
+        line.push_str(&format!("; {} clip(s) already cached", cached));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clips_are_cached_by_model_voice_and_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let clip = |voice, text: &str| Clip {
+            voice,
+            text: text.to_string(),
+        };
+        let nova = clip(Voice::Nova, "Hello there.");
+        let path = cache_path(dir.path(), "tts-1", &nova);
+        assert_eq!(path, cache_path(dir.path(), "tts-1", &nova));
+        assert_ne!(path, cache_path(dir.path(), "tts-1-hd", &nova));
+        assert_ne!(
+            path,
+            cache_path(dir.path(), "tts-1", &clip(Voice::Echo, "Hello there."))
+        );
+
+        assert!(cached(&path).is_none());
+        store(&path, b"\xff\xfbaudio");
+        assert_eq!(cached(&path).unwrap(), b"\xff\xfbaudio");
+
+        assert_eq!(
+            estimate(&[&nova], 1, "tts-1-hd"),
+            "1 request(s), 12 characters, about $0.0004 with tts-1-hd; 1 clip(s) already cached"
+        );
+    }
+}
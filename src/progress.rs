@@ -0,0 +1,116 @@
+// JSON-lines progress events on stderr for editors and GUIs driving sayit
+// (--progress-json). Each event is a single line so readers can parse the
+// stream as it arrives.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub enum Event<'a> {
+    ChunkStarted {
+        index: usize,
+    },
+    ChunkCompleted {
+        index: usize,
+        bytes: usize,
+        // None when the audio couldn't be decoded to measure it
+        duration: Option<Duration>,
+    },
+    ChunkFailed {
+        index: usize,
+    },
+    // Abandoned because every consumer had gone away
+    ChunkCancelled {
+        index: usize,
+    },
+    Done {
+        chunks: usize,
+        failed: &'a [usize],
+    },
+}
+
+impl Event<'_> {
+    fn to_json(&self, timestamp_ms: u128) -> serde_json::Value {
+        let mut json = match self {
+            Event::ChunkStarted { index } => {
+                serde_json::json!({ "event": "chunk-started", "index": index })
+            }
+            Event::ChunkCompleted {
+                index,
+                bytes,
+                duration,
+            } => serde_json::json!({
+                "event": "chunk-completed",
+                "index": index,
+                "bytes": bytes,
+                "duration_seconds": duration.map(|duration| duration.as_secs_f64()),
+            }),
+            Event::ChunkFailed { index } => {
+                serde_json::json!({ "event": "chunk-failed", "index": index })
+            }
+            Event::ChunkCancelled { index } => {
+                serde_json::json!({ "event": "chunk-cancelled", "index": index })
+            }
+            Event::Done { chunks, failed } => {
+                serde_json::json!({ "event": "done", "chunks": chunks, "failed": failed })
+            }
+        };
+        json["timestamp_ms"] = serde_json::json!(timestamp_ms);
+        json
+    }
+}
+
+// Write `event` to stderr, if --progress-json asked for events
+pub fn emit(event: Event) {
+    if !enabled() {
+        return;
+    }
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis());
+    eprintln!("{}", event.to_json(timestamp_ms));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_single_line_json() {
+        let completed = Event::ChunkCompleted {
+            index: 3,
+            bytes: 48_000,
+            duration: Some(Duration::from_millis(2_500)),
+        };
+        let json = completed.to_json(1_700_000_000_000);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "event": "chunk-completed",
+                "index": 3,
+                "bytes": 48_000,
+                "duration_seconds": 2.5,
+                "timestamp_ms": 1_700_000_000_000u64,
+            })
+        );
+        assert!(!json.to_string().contains('\n'));
+
+        let done = Event::Done {
+            chunks: 4,
+            failed: &[1],
+        };
+        assert_eq!(
+            done.to_json(5),
+            serde_json::json!({ "event": "done", "chunks": 4, "failed": [1], "timestamp_ms": 5 })
+        );
+    }
+}
@@ -0,0 +1,145 @@
+// Pronunciation dictionaries (--pronounce-file): terms the voice gets wrong,
+// each mapped to how it should be said. One entry per line:
+//
+//     SQL -> "sequel"
+//     kubectl -> cube control
+//     # comments and blank lines are ignored
+//
+// Terms only match whole words, and longer terms win over shorter ones that
+// overlap them ("SQL Server" before "SQL").
+
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+
+pub struct Dictionary {
+    pattern: Regex,
+    spoken: HashMap<String, String>,
+    ignore_case: bool,
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+// `\b` only means something next to a word character, so terms such as
+// "C++" or ".NET" are bounded on their word side only
+fn bounded(term: &str) -> String {
+    let word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    format!(
+        "{}{}{}",
+        if word(term.chars().next()) { r"\b" } else { "" },
+        regex::escape(term),
+        if word(term.chars().last()) { r"\b" } else { "" }
+    )
+}
+
+impl Dictionary {
+    pub fn parse(source: &str, ignore_case: bool) -> Result<Dictionary, String> {
+        let key = |term: &str| {
+            if ignore_case {
+                term.to_lowercase()
+            } else {
+                term.to_string()
+            }
+        };
+        let mut spoken = HashMap::new();
+        let mut terms = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((written, said)) = line.split_once("->") else {
+                return Err(format!(
+                    "line {}: expected `WRITTEN -> SPOKEN`, found {:?}",
+                    number + 1,
+                    line
+                ));
+            };
+            let written = unquote(written);
+            if written.is_empty() {
+                return Err(format!("line {}: nothing to replace", number + 1));
+            }
+            if spoken
+                .insert(key(written), unquote(said).to_string())
+                .is_none()
+            {
+                terms.push(written.to_string());
+            }
+        }
+
+        terms.sort_by_key(|term| std::cmp::Reverse(term.chars().count()));
+        let alternation: Vec<String> = terms.iter().map(|term| bounded(term)).collect();
+        // An empty dictionary gets a pattern that never matches
+        let pattern = if alternation.is_empty() {
+            r"[^\s\S]".to_string()
+        } else {
+            alternation.join("|")
+        };
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Dictionary {
+            pattern,
+            spoken,
+            ignore_case,
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        self.pattern
+            .replace_all(text, |captures: &regex::Captures| {
+                let written = &captures[0];
+                let key = if self.ignore_case {
+                    written.to_lowercase()
+                } else {
+                    written.to_string()
+                };
+                self.spoken
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or_else(|| written.to_string())
+            })
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DICTIONARY: &str = "# databases\n\
+                              SQL -> \"sequel\"\n\
+                              SQL Server -> sequel server\n\
+                              \n\
+                              kubectl -> cube control\n\
+                              C++ -> C plus plus\n";
+
+    #[test]
+    fn whole_words_are_replaced_longest_first() {
+        let dictionary = Dictionary::parse(DICTIONARY, false).unwrap();
+        assert_eq!(
+            dictionary.apply("Run kubectl against SQL Server, not SQLite or sql. C++!"),
+            "Run cube control against sequel server, not SQLite or sql. C plus plus!"
+        );
+        let dictionary = Dictionary::parse(DICTIONARY, true).unwrap();
+        assert_eq!(
+            dictionary.apply("sql and Kubectl"),
+            "sequel and cube control"
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_reported_by_number() {
+        let error = Dictionary::parse("SQL -> sequel\nkubectl cube control", false)
+            .err()
+            .unwrap();
+        assert!(error.starts_with("line 2:"), "{}", error);
+        assert_eq!(Dictionary::parse("", false).unwrap().apply("SQL"), "SQL");
+    }
+}
@@ -0,0 +1,126 @@
+// Converting the API's 24kHz mono 16-bit PCM to another sample rate,
+// channel count and sample size (--rate, --channels, --wav-bits). Each chunk
+// is resampled by linear interpolation as it arrives, so everything
+// downstream (WAV headers, playback, durations) works from output_format()
+// rather than the API's.
+
+use crate::merge::{self, StreamFormat, API_PCM_FORMAT};
+use std::io;
+use std::sync::OnceLock;
+
+static OUTPUT: OnceLock<StreamFormat> = OnceLock::new();
+
+// Set the format PCM chunks are converted to for the run
+pub fn init(rate: Option<u32>, channels: Option<u16>, bits: Option<u16>) {
+    let _ = OUTPUT.set(StreamFormat {
+        sample_rate: rate.unwrap_or(API_PCM_FORMAT.sample_rate),
+        channels: channels.unwrap_or(API_PCM_FORMAT.channels),
+        bits_per_sample: bits.unwrap_or(API_PCM_FORMAT.bits_per_sample),
+    });
+}
+
+// The format of the PCM the run produces
+pub fn output_format() -> StreamFormat {
+    OUTPUT.get().copied().unwrap_or(API_PCM_FORMAT)
+}
+
+// Interleaved 16-bit `samples` in `from` as interleaved samples in `to`.
+// Channels are mixed down by averaging and up by copying the first.
+pub fn convert(samples: &[i16], from: StreamFormat, to: StreamFormat) -> Vec<i16> {
+    let in_channels = usize::from(from.channels.max(1));
+    let out_channels = usize::from(to.channels.max(1));
+    let frames: Vec<f32> = samples
+        .chunks_exact(in_channels)
+        .map(|frame| frame.iter().map(|&s| f32::from(s)).sum::<f32>() / in_channels as f32)
+        .collect();
+    if frames.is_empty() {
+        return Vec::new();
+    }
+
+    let (from_rate, to_rate) = (u64::from(from.sample_rate), u64::from(to.sample_rate));
+    let out_frames = (frames.len() as u64 * to_rate + from_rate / 2) / from_rate;
+    let step = from_rate as f64 / to_rate as f64;
+    let last = frames.len() - 1;
+    let mut converted = Vec::with_capacity(out_frames as usize * out_channels);
+    for i in 0..out_frames {
+        let position = i as f64 * step;
+        let before = (position as usize).min(last);
+        let after = (before + 1).min(last);
+        let weight = (position - before as f64) as f32;
+        let value = frames[before] + (frames[after] - frames[before]) * weight;
+        let sample = value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        converted.extend(std::iter::repeat_n(sample, out_channels));
+    }
+    converted
+}
+
+// A raw API `pcm` response converted to the run's output format
+pub fn convert_pcm(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let samples = merge::pcm_samples(bytes)?;
+    let output = output_format();
+    Ok(to_bytes(&convert(&samples, API_PCM_FORMAT, output), output))
+}
+
+// Little-endian `samples` at the sample size of `format`, widening 16-bit
+// samples to 24 by padding the low byte
+fn to_bytes(samples: &[i16], format: StreamFormat) -> Vec<u8> {
+    match format.bits_per_sample {
+        24 => samples
+            .iter()
+            .flat_map(|sample| (i32::from(*sample) << 8).to_le_bytes()[..3].to_vec())
+            .collect(),
+        _ => samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_rate: u32, channels: u16) -> StreamFormat {
+        StreamFormat {
+            sample_rate,
+            channels,
+            ..API_PCM_FORMAT
+        }
+    }
+
+    // The bytes of `seconds` of 16-bit audio in `format`
+    fn bytes_for(seconds: usize, format: StreamFormat) -> usize {
+        seconds * format.sample_rate as usize * usize::from(format.channels) * 2
+    }
+
+    #[test]
+    fn converted_audio_keeps_its_duration() {
+        let two_seconds = vec![1000i16; 2 * 24000];
+        for (rate, channels) in [(16000, 1), (48000, 2), (44100, 2), (24000, 2)] {
+            let to = format(rate, channels);
+            let converted = convert(&two_seconds, API_PCM_FORMAT, to);
+            assert_eq!(converted.len() * 2, bytes_for(2, to), "{} Hz", rate);
+            assert!(converted.iter().all(|&sample| sample == 1000));
+        }
+    }
+
+    #[test]
+    fn samples_are_interpolated_and_channels_mixed() {
+        let doubled = convert(&[0, 100], format(1, 1), format(2, 2));
+        assert_eq!(doubled, [0, 0, 50, 50, 100, 100, 100, 100]);
+        let mixed = convert(&[100, 300, -50, 50], format(24000, 2), API_PCM_FORMAT);
+        assert_eq!(mixed, [200, 0]);
+    }
+
+    #[test]
+    fn samples_widen_to_24_bits_and_back() {
+        let wide = StreamFormat {
+            bits_per_sample: 24,
+            ..API_PCM_FORMAT
+        };
+        let bytes = to_bytes(&[1, -2, i16::MAX], wide);
+        assert_eq!(bytes, [0, 1, 0, 0, 0xfe, 0xff, 0, 0xff, 0x7f]);
+        assert_eq!(merge::samples_in(&bytes, wide).unwrap(), [1, -2, i16::MAX]);
+        assert!(merge::samples_in(&bytes[..8], wide).is_err());
+    }
+}
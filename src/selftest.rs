@@ -0,0 +1,132 @@
+// `sayit selftest`: reading a short phrase at a few concurrency levels to
+// see how fast requests come back from this machine and account, and which
+// --concurrency to use. It makes real API requests, so it asks first.
+
+use crate::config;
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::Confirm;
+use std::time::Duration;
+
+pub const PHRASE: &str = "Testing, one, two, three.";
+
+// The concurrency levels tried, each for ROUNDS requests per request slot
+pub const LEVELS: [usize; 4] = [1, 2, 4, 8];
+pub const ROUNDS: usize = 2;
+
+// How one level went
+pub struct Level {
+    pub concurrency: usize,
+    pub latencies: Vec<Duration>,
+    pub failed: usize,
+    pub elapsed: Duration,
+}
+
+impl Level {
+    fn median_latency(&self) -> Option<Duration> {
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        latencies.get(latencies.len() / 2).copied()
+    }
+
+    // Requests that succeeded per second
+    fn throughput(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+// Ask before spending anything on `model`; `yes` skips the question, which
+// can't be asked without a terminal
+pub fn confirm(levels: &[usize], model: &str, yes: bool) -> Result<bool, String> {
+    if yes {
+        return Ok(true);
+    }
+    let requests: usize = levels.iter().map(|level| level * ROUNDS).sum();
+    let characters = requests * PHRASE.len();
+    let prompt = format!(
+        "This makes {} real API requests ({} characters, about ${:.2} with {}). Go ahead?",
+        requests,
+        characters,
+        characters as f64 * config::price_per_million(model) / 1_000_000.0,
+        model
+    );
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .default(false)
+        .interact()
+        .map_err(|e| format!("{} (pass --yes to run without asking)", e))
+}
+
+// The lowest level that gets within a tenth of the best throughput without
+// any failures; more requests at once than that only risks rate limits
+pub fn recommend(levels: &[Level]) -> Option<usize> {
+    let clean: Vec<&Level> = levels
+        .iter()
+        .filter(|level| level.failed == 0 && !level.latencies.is_empty())
+        .collect();
+    let best = clean
+        .iter()
+        .map(|level| level.throughput())
+        .fold(0.0, f64::max);
+    clean
+        .iter()
+        .find(|level| level.throughput() >= best * 0.9)
+        .map(|level| level.concurrency)
+}
+
+pub fn render(levels: &[Level]) -> String {
+    let mut lines = vec![format!(
+        "{:<11}  {:>8}  {:>6}  {:>14}  {:>10}",
+        "CONCURRENCY", "REQUESTS", "FAILED", "MEDIAN LATENCY", "THROUGHPUT"
+    )];
+    for level in levels {
+        let latency = level.median_latency().map_or("-".to_string(), |latency| {
+            format!("{}ms", latency.as_millis())
+        });
+        lines.push(format!(
+            "{:<11}  {:>8}  {:>6}  {:>14}  {:>8.2}/s",
+            level.concurrency,
+            level.latencies.len() + level.failed,
+            level.failed,
+            latency,
+            level.throughput()
+        ));
+    }
+    lines.push(match recommend(levels) {
+        Some(concurrency) => format!("Recommended: --concurrency {}", concurrency),
+        None => "No level got through without failures; check the log above".to_string(),
+    });
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(concurrency: usize, latency_ms: u64, failed: usize, elapsed_ms: u64) -> Level {
+        Level {
+            concurrency,
+            latencies: vec![Duration::from_millis(latency_ms); concurrency * ROUNDS - failed],
+            failed,
+            elapsed: Duration::from_millis(elapsed_ms),
+        }
+    }
+
+    #[test]
+    fn the_lowest_level_near_the_best_throughput_is_recommended() {
+        let levels = [
+            level(1, 500, 0, 1000),
+            level(2, 500, 0, 1000),
+            level(4, 600, 0, 1300),
+            level(8, 900, 0, 2500),
+        ];
+        // 2/s, 4/s, 6.2/s and 6.4/s: 4 is within a tenth of 8
+        assert_eq!(recommend(&levels), Some(4));
+
+        let table = render(&levels);
+        assert!(table.lines().nth(3).unwrap().contains(" 600ms "));
+        assert!(table.ends_with("Recommended: --concurrency 4"));
+
+        let failing = [level(1, 500, 0, 1000), level(2, 300, 1, 300)];
+        assert_eq!(recommend(&failing), Some(1));
+    }
+}
@@ -0,0 +1,526 @@
+// `sayit serve`: a small HTTP server for other programs. POST /speak takes a
+// JSON body and GET /speak the same fields as query parameters; either way
+// the text is chunked, synthesized and stitched into one audio response.
+//
+// With --socket it listens on a Unix domain socket instead: a client writes
+// the same JSON as one line and reads back the audio until EOF, or a single
+// line starting with ERROR_PREFIX if the request failed (`sayit client`).
+//
+// `sayit grpc-serve` (grpc.rs) shares the chunking, fetching and cache here.
+//
+// Under systemd either server can be socket activated: a socket passed in
+// LISTEN_FDS is used instead of binding one, and READY=1 is sent once
+// requests are being accepted.
+
+use crate::{
+    config, expected_content_type, fetch_audio, merge, split_input, AudioChunk, ResponseFormat,
+    SpeechSettings, Voice,
+};
+use axum::extract::{Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use bytes::Bytes;
+use listenfd::ListenFd;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+
+// Synthesized chunks kept for repeated requests, oldest dropped first
+const CACHED_CHUNKS: usize = 256;
+
+// Starts the answer on the socket when a request fails; no audio format
+// begins with it
+#[cfg(unix)]
+const ERROR_PREFIX: &str = "sayit-error: ";
+
+// Longest request line accepted on the socket
+#[cfg(unix)]
+const MAX_REQUEST_LINE: u64 = 16 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct SpeakRequest {
+    pub text: String,
+    pub voice: Option<Voice>,
+    pub format: Option<ResponseFormat>,
+    pub speed: Option<f32>,
+}
+
+// What a request gets for the fields it leaves out
+#[derive(Copy, Clone)]
+pub struct Defaults {
+    pub voice: Voice,
+    pub format: ResponseFormat,
+    pub speed: f32,
+    pub model: &'static str,
+    pub show_api_errors: bool,
+}
+
+// A chunk's text and everything else that decides its audio
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    text: String,
+    voice: &'static str,
+    model: &'static str,
+    format: &'static str,
+    speed_bits: u32,
+}
+
+#[derive(Default)]
+struct Cache {
+    chunks: HashMap<CacheKey, Bytes>,
+    order: VecDeque<CacheKey>,
+}
+
+impl Cache {
+    fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        self.chunks.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: CacheKey, bytes: Bytes) {
+        if self.chunks.insert(key.clone(), bytes).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > CACHED_CHUNKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.chunks.remove(&oldest);
+            }
+        }
+    }
+}
+
+// A chunk of a request, either already at hand or on its way
+pub enum Pending {
+    Cached(Bytes),
+    Fetching(CacheKey, JoinHandle<Option<AudioChunk>>),
+}
+
+pub struct Server {
+    client: Client,
+    defaults: Defaults,
+    auth_token: Option<String>,
+    cache: Mutex<Cache>,
+}
+
+impl Server {
+    pub fn new(client: Client, defaults: Defaults, auth_token: Option<String>) -> Server {
+        Server {
+            client,
+            defaults,
+            auth_token,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+}
+
+// Why a request couldn't be answered, with the HTTP status that says so
+pub struct Failure {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+fn failure(status: StatusCode, message: impl Into<String>) -> Failure {
+    Failure {
+        status,
+        message: message.into(),
+    }
+}
+
+// The socket systemd passed in (LISTEN_FDS) when the server is socket
+// activated, otherwise a new one bound to `bind`:`port`
+pub async fn tcp_listener(bind: &str, port: u16) -> Result<tokio::net::TcpListener, String> {
+    let passed = ListenFd::from_env()
+        .take_tcp_listener(0)
+        .map_err(|e| format!("can't use the socket systemd passed: {}", e))?;
+    match passed {
+        Some(listener) => {
+            log::info!("Using the socket passed by systemd instead of --bind/--port");
+            listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+            tokio::net::TcpListener::from_std(listener).map_err(|e| e.to_string())
+        }
+        None => tokio::net::TcpListener::bind((bind, port))
+            .await
+            .map_err(|e| format!("can't listen on {}:{}: {}", bind, port, e)),
+    }
+}
+
+// Tell systemd the server is accepting connections (Type=notify); nothing
+// happens when it wasn't started by systemd
+pub fn notify_ready() {
+    #[cfg(unix)]
+    if let Err(e) = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]) {
+        log::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+fn router(server: Arc<Server>) -> Router {
+    Router::new()
+        .route("/speak", get(speak_query).post(speak_json))
+        .with_state(server)
+}
+
+pub async fn run(
+    bind: &str,
+    port: u16,
+    client: Client,
+    defaults: Defaults,
+    auth_token: Option<String>,
+) -> Result<(), String> {
+    let listener = tcp_listener(bind, port).await?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    eprintln!("Listening on http://{}/speak", address);
+
+    let server = Arc::new(Server::new(client, defaults, auth_token));
+    notify_ready();
+    axum::serve(listener, router(server))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn speak_query(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Query(request): Query<SpeakRequest>,
+) -> Response {
+    speak(&server, &headers, request).await
+}
+
+async fn speak_json(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Json(request): Json<SpeakRequest>,
+) -> Response {
+    speak(&server, &headers, request).await
+}
+
+fn authorized(server: &Server, headers: &HeaderMap) -> bool {
+    let Some(token) = &server.auth_token else {
+        return true;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token)
+}
+
+async fn speak(server: &Server, headers: &HeaderMap, request: SpeakRequest) -> Response {
+    if !authorized(server, headers) {
+        let message = "missing or wrong bearer token";
+        return (StatusCode::UNAUTHORIZED, message).into_response();
+    }
+    match synthesize(server, request).await {
+        Ok((audio_format, audio)) => {
+            ([(CONTENT_TYPE, expected_content_type(audio_format))], audio).into_response()
+        }
+        Err(failure) => (failure.status, failure.message).into_response(),
+    }
+}
+
+// The audio for a whole request in one piece, and the format it's in
+async fn synthesize(
+    server: &Server,
+    request: SpeakRequest,
+) -> Result<(&'static str, Vec<u8>), Failure> {
+    let (settings, pending) = start(server, request)?;
+    let mut chunks = Vec::with_capacity(pending.len());
+    for (index, pending) in pending.into_iter().enumerate() {
+        chunks.push(finish(server, index, pending).await?.to_vec());
+    }
+
+    let audio = merge::merge_chunks(settings.audio_format, &chunks).map_err(|e| {
+        failure(
+            StatusCode::BAD_GATEWAY,
+            format!("couldn't join the chunks: {}", e),
+        )
+    })?;
+    Ok((settings.audio_format, audio))
+}
+
+// Check a request and start fetching every chunk the cache doesn't have at
+// once. The chunks come back in the order of the text.
+pub fn start(
+    server: &Server,
+    request: SpeakRequest,
+) -> Result<(SpeechSettings, Vec<Pending>), Failure> {
+    let speed = request.speed.unwrap_or(server.defaults.speed);
+    if let Err(e) = config::check_speed(speed) {
+        return Err(failure(StatusCode::BAD_REQUEST, e));
+    }
+    let texts = split_input(&request.text, 4096);
+    if texts.is_empty() {
+        return Err(failure(StatusCode::BAD_REQUEST, "there's no text to read"));
+    }
+
+    let settings = SpeechSettings {
+        audio_format: request.format.unwrap_or(server.defaults.format).api_name(),
+        reading_voice: request.voice.unwrap_or(server.defaults.voice).name(),
+        tts_model: server.defaults.model,
+        speed,
+        show_api_errors: server.defaults.show_api_errors,
+    };
+    log::info!(
+        "Speaking {} chunks as {} {}",
+        texts.len(),
+        settings.reading_voice,
+        settings.audio_format
+    );
+
+    let mut pending = Vec::new();
+    for (index, text) in texts.into_iter().enumerate() {
+        let key = CacheKey {
+            text,
+            voice: settings.reading_voice,
+            model: settings.tts_model,
+            format: settings.audio_format,
+            speed_bits: settings.speed.to_bits(),
+        };
+        let cached = server.cache.lock().unwrap().get(&key);
+        pending.push(match cached {
+            Some(bytes) => Pending::Cached(bytes),
+            None => {
+                let client = server.client.clone();
+                let text = key.text.clone();
+                let fetch =
+                    tokio::spawn(async move { fetch_audio(&text, index, &client, settings).await });
+                Pending::Fetching(key, fetch)
+            }
+        });
+    }
+    Ok((settings, pending))
+}
+
+// Wait for chunk `index` of a request, caching it once it's fetched
+pub async fn finish(server: &Server, index: usize, pending: Pending) -> Result<Bytes, Failure> {
+    match pending {
+        Pending::Cached(bytes) => Ok(bytes),
+        Pending::Fetching(key, fetch) => match fetch.await.ok().flatten() {
+            Some(chunk) => {
+                let mut cache = server.cache.lock().unwrap();
+                cache.insert(key, chunk.bytes.clone());
+                Ok(chunk.bytes)
+            }
+            None => Err(failure(
+                StatusCode::BAD_GATEWAY,
+                format!("chunk {} couldn't be synthesized", index),
+            )),
+        },
+    }
+}
+
+// Serve requests on a Unix domain socket at `path`, which only the current
+// user can connect to. A socket left behind by a previous server is replaced.
+#[cfg(unix)]
+pub async fn run_socket(path: &Path, client: Client, defaults: Defaults) -> Result<(), String> {
+    let listener = unix_listener(path)?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    let listening = address.as_pathname().unwrap_or(path);
+    eprintln!("Listening on {}", listening.display());
+
+    let server = Arc::new(Server::new(client, defaults, None));
+    notify_ready();
+    loop {
+        let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(&server, stream).await {
+                log::warn!("Failed to answer a socket client: {}", e);
+            }
+        });
+    }
+}
+
+// The socket systemd passed in, whose path and permissions are set by the
+// .socket unit, otherwise a new one at `path`
+#[cfg(unix)]
+fn unix_listener(path: &Path) -> Result<UnixListener, String> {
+    let passed = ListenFd::from_env()
+        .take_unix_listener(0)
+        .map_err(|e| format!("can't use the socket systemd passed: {}", e))?;
+    if let Some(listener) = passed {
+        log::info!("Using the socket passed by systemd");
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        return UnixListener::from_std(listener).map_err(|e| e.to_string());
+    }
+
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("{} is in use by another server", path.display()));
+        }
+        std::fs::remove_file(path)
+            .map_err(|e| format!("can't replace {}: {}", path.display(), e))?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("can't listen on {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("can't restrict {}: {}", path.display(), e))?;
+    Ok(listener)
+}
+
+#[cfg(unix)]
+async fn answer(server: &Server, stream: UnixStream) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_REQUEST_LINE))
+        .read_line(&mut line)
+        .await?;
+    let result = match serde_json::from_str::<SpeakRequest>(&line) {
+        Ok(request) => synthesize(server, request).await,
+        Err(e) => Err(failure(
+            StatusCode::BAD_REQUEST,
+            format!("invalid request: {}", e),
+        )),
+    };
+    match result {
+        Ok((_, audio)) => writer.write_all(&audio).await?,
+        Err(failure) => {
+            let message = format!("{}{}\n", ERROR_PREFIX, failure.message);
+            writer.write_all(message.as_bytes()).await?
+        }
+    }
+    writer.shutdown().await
+}
+
+// `sayit client`: send one request to a `sayit serve --socket` server and
+// return the audio it answers with
+#[cfg(unix)]
+pub async fn request_socket(path: &Path, request: &serde_json::Value) -> Result<Vec<u8>, String> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| format!("can't connect to {}: {}", path.display(), e))?;
+    let mut line = request.to_string();
+    line.push('\n');
+    let mut audio = Vec::new();
+    let exchanged = async {
+        stream.write_all(line.as_bytes()).await?;
+        stream.read_to_end(&mut audio).await
+    };
+    exchanged.await.map_err(|e| e.to_string())?;
+
+    if let Some(message) = audio.strip_prefix(ERROR_PREFIX.as_bytes()) {
+        return Err(String::from_utf8_lossy(message).trim_end().to_string());
+    }
+    if audio.is_empty() {
+        return Err("the server closed the connection without answering".to_string());
+    }
+    Ok(audio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: Defaults = Defaults {
+        voice: Voice::Alloy,
+        format: ResponseFormat::Mp3,
+        speed: 1.0,
+        model: "tts-1",
+        show_api_errors: false,
+    };
+
+    async fn spawn_server(auth_token: Option<&str>) -> String {
+        let auth_token = auth_token.map(str::to_string);
+        let server = Arc::new(Server::new(Client::new(), DEFAULTS, auth_token));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(server)).await });
+        format!("http://{}/speak", address)
+    }
+
+    #[tokio::test]
+    async fn requests_are_checked_before_anything_is_fetched() {
+        let url = spawn_server(Some("secret")).await;
+        let client = Client::new();
+
+        let status = |response: reqwest::Response| response.status();
+        let unauthorized = client.get(&url).query(&[("text", "Hi")]).send();
+        assert_eq!(
+            status(unauthorized.await.unwrap()),
+            StatusCode::UNAUTHORIZED
+        );
+        let wrong_token = client
+            .get(&url)
+            .bearer_auth("guess")
+            .query(&[("text", "Hi")]);
+        assert_eq!(
+            status(wrong_token.send().await.unwrap()),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let too_fast = client
+            .post(&url)
+            .bearer_auth("secret")
+            .json(&serde_json::json!({ "text": "Hi", "speed": 9.0 }));
+        assert_eq!(
+            status(too_fast.send().await.unwrap()),
+            StatusCode::BAD_REQUEST
+        );
+        let blank = client
+            .get(&url)
+            .bearer_auth("secret")
+            .query(&[("text", "  "), ("voice", "nova")]);
+        assert_eq!(status(blank.send().await.unwrap()), StatusCode::BAD_REQUEST);
+        let unknown_voice = client
+            .get(&url)
+            .bearer_auth("secret")
+            .query(&[("text", "Hi"), ("voice", "robot")]);
+        assert_eq!(
+            status(unknown_voice.send().await.unwrap()),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn socket_clients_get_errors_instead_of_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sayit.sock");
+        let server_path = path.clone();
+        tokio::spawn(async move { run_socket(&server_path, Client::new(), DEFAULTS).await });
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+
+        let too_fast = serde_json::json!({ "text": "Hi", "speed": 9.0, "voice": null });
+        let error = request_socket(&path, &too_fast).await.unwrap_err();
+        assert!(error.contains("outside"), "{}", error);
+        let error = request_socket(&path, &serde_json::json!({ "voice": "nova" }))
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("invalid request"), "{}", error);
+
+        // A second server doesn't take the socket over
+        let taken = run_socket(&path, Client::new(), DEFAULTS).await;
+        assert!(taken.unwrap_err().contains("in use"));
+    }
+
+    #[test]
+    fn the_cache_drops_its_oldest_chunks() {
+        let key = |text: &str| CacheKey {
+            text: text.to_string(),
+            voice: "alloy",
+            model: "tts-1",
+            format: "mp3",
+            speed_bits: 1.0f32.to_bits(),
+        };
+        let mut cache = Cache::default();
+        for i in 0..=CACHED_CHUNKS {
+            cache.insert(key(&i.to_string()), Bytes::from_static(b"mp3"));
+        }
+        assert!(cache.get(&key("0")).is_none());
+        assert!(cache.get(&key("1")).is_some());
+        assert_eq!(cache.chunks.len(), CACHED_CHUNKS);
+    }
+}
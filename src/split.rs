@@ -0,0 +1,347 @@
+// Saving one output file as numbered parts of limited duration
+// (--split-every), for players that can't cope with very long files, or of
+// limited size (--split-output-by-size), for hosts that limit uploads.
+
+use crate::tags::Tags;
+use crate::{
+    audio_to_output_file, check_output_path, decoded_duration, manifest, AudioChunk,
+    ChunkReorderBuffer, Layout, WriteMode,
+};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// How big a part may get; a chunk bigger than either limit on its own still
+// gets a part to itself
+#[derive(Copy, Clone)]
+pub struct Limits {
+    pub max_duration: Duration,
+    pub max_bytes: Option<u64>,
+}
+
+impl Limits {
+    // Where part `number` of `file_path` goes: parts by size are numbered
+    // like a series of files (book_001.mp3), parts by time like one
+    // recording (book.part01.mp3)
+    pub fn part_path(&self, file_path: &str, number: usize) -> String {
+        if self.max_bytes.is_some() {
+            sequence_path(file_path, number)
+        } else {
+            numbered_path(file_path, number)
+        }
+    }
+}
+
+pub struct SplitOptions {
+    pub limits: Limits,
+    // Chunks that start a chapter, which always start a new part too
+    pub chapter_starts: HashSet<usize>,
+    pub force: bool,
+    // The capacity of each part's chunk channel (--buffer-size)
+    pub buffer_size: usize,
+}
+
+// A finished part, with where each chunk landed in it
+pub struct Part {
+    pub path: String,
+    pub duration: Duration,
+    pub spans: Vec<manifest::Span>,
+}
+
+// `book.mp3` as its `number`th part: `book.part01.mp3`
+pub fn numbered_path(file_path: &str, number: usize) -> String {
+    let path = Path::new(file_path);
+    let suffix = format!("part{:02}", number);
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => format!(
+            "{}.{}.{}",
+            stem.to_string_lossy(),
+            suffix,
+            ext.to_string_lossy()
+        ),
+        (Some(stem), None) => format!("{}.{}", stem.to_string_lossy(), suffix),
+        _ => suffix,
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// `book.mp3` as the `number`th of a series: `book_001.mp3`
+pub fn sequence_path(file_path: &str, number: usize) -> String {
+    let path = Path::new(file_path);
+    let stem = path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{:03}.{}", stem, number, ext.to_string_lossy()),
+        None => format!("{}_{:03}", stem, number),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// A part being written: its chunks are renumbered from 0 and handed to an
+// audio_to_output_file of its own
+struct OpenPart {
+    path: String,
+    tx: mpsc::Sender<AudioChunk>,
+    writer: JoinHandle<Vec<manifest::Span>>,
+    chunk_count: Arc<AtomicUsize>,
+    first_index: usize,
+    chunks: usize,
+    duration: Duration,
+    bytes: u64,
+}
+
+impl OpenPart {
+    async fn finish(self) -> Part {
+        self.chunk_count.store(self.chunks, Ordering::SeqCst);
+        drop(self.tx);
+        let spans = self
+            .writer
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|span| manifest::Span {
+                index: span.index + self.first_index,
+                ..span
+            })
+            .collect();
+        Part {
+            path: self.path,
+            duration: self.duration,
+            spans,
+        }
+    }
+}
+
+// Write chunks in order, starting a new part whenever the next chunk would
+// take the current one past a limit or starts a chapter. Sizes are of the
+// audio (and WAV header), so tags add a little to each part.
+pub async fn audio_to_parts(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    file_path: String,
+    audio_format: &'static str,
+    wrap_pcm_in_wav: bool,
+    tags: Option<Tags>,
+    options: SplitOptions,
+    expected_chunks: Arc<AtomicUsize>,
+) -> Vec<Part> {
+    let mut buffer = ChunkReorderBuffer::new();
+    let mut parts = Vec::new();
+    let mut current: Option<OpenPart> = None;
+
+    while let Some(chunk) = audio_rx.recv().await {
+        buffer.insert(chunk);
+        while let Some(mut chunk) = buffer.pop_ready() {
+            let decoded = chunk.clone();
+            let duration =
+                tokio::task::spawn_blocking(move || decoded_duration(&decoded, audio_format))
+                    .await
+                    .ok()
+                    .flatten()
+                    .unwrap_or_else(|| {
+                        if options.limits.max_duration < Duration::MAX {
+                            log::warn!(
+                                "Couldn't measure chunk {}; it counts as silent for --split-every",
+                                chunk.index
+                            );
+                        }
+                        Duration::ZERO
+                    });
+            let bytes = chunk.bytes.len() as u64;
+
+            let split_here = current.as_ref().is_some_and(|part| {
+                part.chunks > 0
+                    && (part.duration + duration > options.limits.max_duration
+                        || options
+                            .limits
+                            .max_bytes
+                            .is_some_and(|max_bytes| part.bytes + bytes > max_bytes)
+                        || options.chapter_starts.contains(&chunk.index))
+            });
+            if split_here {
+                parts.push(current.take().unwrap().finish().await);
+            }
+
+            let part = current.get_or_insert_with(|| {
+                let path = options.limits.part_path(&file_path, parts.len() + 1);
+                if let Err(e) = check_output_path(&path, options.force, false, false) {
+                    eprintln!("Refusing to write output: {}", e);
+                    std::process::exit(1);
+                }
+                let (tx, rx) = mpsc::channel::<AudioChunk>(options.buffer_size);
+                let chunk_count = Arc::new(AtomicUsize::new(usize::MAX));
+                let writer = tokio::spawn(audio_to_output_file(
+                    rx,
+                    path.clone(),
+                    audio_format,
+                    Layout {
+                        wrap_pcm_in_wav,
+                        ..Layout::default()
+                    },
+                    WriteMode::Atomic,
+                    tags.clone(),
+                    chunk_count.clone(),
+                ));
+                OpenPart {
+                    path,
+                    tx,
+                    writer,
+                    chunk_count,
+                    first_index: chunk.index,
+                    chunks: 0,
+                    duration: Duration::ZERO,
+                    bytes: if wrap_pcm_in_wav { 44 } else { 0 },
+                }
+            });
+            if part.chunks == 0
+                && options
+                    .limits
+                    .max_bytes
+                    .is_some_and(|max| part.bytes + bytes > max)
+            {
+                log::warn!(
+                    "Chunk {} alone is bigger than --split-output-by-size; {} will be too",
+                    chunk.index,
+                    part.path
+                );
+            }
+            chunk.index -= part.first_index;
+            part.chunks += 1;
+            part.duration += duration;
+            part.bytes += bytes;
+            if part.tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    }
+    if let Some(part) = current {
+        parts.push(part.finish().await);
+    }
+
+    // A failed chunk holds back everything after it
+    let chunk_count = expected_chunks.load(Ordering::SeqCst);
+    if buffer.next_index < chunk_count {
+        log::warn!(
+            "Only {} of {} chunks were written to {} parts",
+            buffer.next_index,
+            chunk_count,
+            parts.len()
+        );
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge;
+    use bytes::Bytes;
+
+    // One second of silent raw PCM
+    fn second_of_pcm(index: usize) -> AudioChunk {
+        let format = merge::API_PCM_FORMAT;
+        let bytes = vec![0; format.sample_rate as usize * format.channels as usize * 2];
+        AudioChunk {
+            index,
+            bytes: Bytes::from(bytes),
+            content_type: None,
+            duration: Arc::default(),
+            held: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn parts_are_numbered_before_the_extension() {
+        assert_eq!(numbered_path("out/book.mp3", 1), "out/book.part01.mp3");
+        assert_eq!(numbered_path("book", 12), "book.part12");
+        assert_eq!(numbered_path("a.b.wav", 100), "a.b.part100.wav");
+        assert_eq!(sequence_path("out/book.mp3", 1), "out/book_001.mp3");
+        assert_eq!(sequence_path("book", 1234), "book_1234");
+    }
+
+    #[tokio::test]
+    async fn parts_split_at_chunk_boundaries_and_chapters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("book.pcm");
+        let (audio_tx, audio_rx) = tokio::sync::mpsc::channel::<AudioChunk>(8);
+        let writer = tokio::spawn(audio_to_parts(
+            audio_rx,
+            path.to_string_lossy().into_owned(),
+            "pcm",
+            false,
+            None,
+            SplitOptions {
+                limits: Limits {
+                    max_duration: Duration::from_millis(2_500),
+                    max_bytes: None,
+                },
+                chapter_starts: HashSet::from([3]),
+                force: false,
+                buffer_size: 8,
+            },
+            Arc::new(AtomicUsize::new(4)),
+        ));
+
+        for index in [1, 0, 2, 3] {
+            audio_tx.send(second_of_pcm(index)).await.unwrap();
+        }
+        drop(audio_tx);
+        let parts = writer.await.unwrap();
+
+        let durations: Vec<u64> = parts.iter().map(|part| part.duration.as_secs()).collect();
+        assert_eq!(durations, [2, 1, 1]);
+        assert_eq!(parts[1].spans[0].index, 2);
+        assert_eq!(parts[2].path, numbered_path(&path.to_string_lossy(), 3));
+        let second_of_bytes = second_of_pcm(0).bytes.len() as u64;
+        assert_eq!(
+            std::fs::metadata(&parts[0].path).unwrap().len(),
+            2 * second_of_bytes
+        );
+        // Only the finished parts are left in the directory
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn parts_by_size_stay_under_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("book.pcm").to_string_lossy().into_owned();
+        let second_of_bytes = second_of_pcm(0).bytes.len() as u64;
+        let (audio_tx, audio_rx) = tokio::sync::mpsc::channel::<AudioChunk>(8);
+        let limits = Limits {
+            max_duration: Duration::MAX,
+            max_bytes: Some(2 * second_of_bytes + 1),
+        };
+        let writer = tokio::spawn(audio_to_parts(
+            audio_rx,
+            path.clone(),
+            "pcm",
+            false,
+            None,
+            SplitOptions {
+                limits,
+                chapter_starts: HashSet::new(),
+                force: false,
+                buffer_size: 8,
+            },
+            Arc::new(AtomicUsize::new(5)),
+        ));
+        for index in 0..5 {
+            audio_tx.send(second_of_pcm(index)).await.unwrap();
+        }
+        drop(audio_tx);
+        let parts = writer.await.unwrap();
+
+        let paths: Vec<&str> = parts.iter().map(|part| part.path.as_str()).collect();
+        assert_eq!(paths, [1, 2, 3].map(|number| sequence_path(&path, number)));
+        let sizes: Vec<u64> = parts
+            .iter()
+            .map(|part| std::fs::metadata(&part.path).unwrap().len())
+            .collect();
+        assert_eq!(sizes, [2, 2, 1].map(|seconds| seconds * second_of_bytes));
+    }
+}
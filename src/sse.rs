@@ -0,0 +1,105 @@
+// Asking for speech as server-sent events (--sse, the API's `stream_format:
+// "sse"`). The response is a series of `data: {...}` events: audio deltas in
+// base64, which are put back together into the chunk's audio, and metadata
+// such as the usage reported when the audio is done, which is logged. Models
+// and servers without SSE get a plain response instead, and the rest of the
+// run stops asking.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const CONTENT_TYPE: &str = "text/event-stream";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Stop asking for SSE, warning the first time
+pub fn unsupported(reason: &str) {
+    if ENABLED.swap(false, Ordering::Relaxed) {
+        log::warn!("--sse isn't available ({}); using plain responses", reason);
+    }
+}
+
+// Whether a failed request was refused for asking for SSE
+pub fn refused(body: &str) -> bool {
+    body.contains("stream_format")
+}
+
+// The audio and the other events of an SSE response
+#[derive(Debug, Default)]
+pub struct Speech {
+    pub audio: Vec<u8>,
+    pub metadata: Vec<Value>,
+}
+
+// Read a whole SSE response. One that ends before `speech.audio.done` was
+// cut off like any other short response.
+pub fn parse(body: &[u8]) -> Result<Speech, String> {
+    let body = String::from_utf8_lossy(body).replace("\r\n", "\n");
+    let mut speech = Speech::default();
+    let mut done = false;
+    for event in body.split("\n\n") {
+        let data: Vec<&str> = event
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.strip_prefix(' ').unwrap_or(data))
+            .collect();
+        if data.is_empty() || data == ["[DONE]"] {
+            continue;
+        }
+        let event: Value = serde_json::from_str(&data.join("\n"))
+            .map_err(|e| format!("an event isn't valid JSON: {}", e))?;
+        match event["type"].as_str() {
+            Some("speech.audio.delta") => {
+                let delta = event["audio"].as_str().unwrap_or_default();
+                let audio = BASE64
+                    .decode(delta)
+                    .map_err(|e| format!("an audio delta isn't valid base64: {}", e))?;
+                speech.audio.extend(audio);
+            }
+            Some("error") => {
+                let message = event["error"]["message"].as_str().unwrap_or("no message");
+                return Err(format!("the stream reported an error: {}", message));
+            }
+            kind => {
+                done |= kind == Some("speech.audio.done");
+                speech.metadata.push(event);
+            }
+        }
+    }
+    if !done {
+        return Err("the stream ended before speech.audio.done".to_string());
+    }
+    Ok(speech)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_are_joined_and_metadata_kept() {
+        let body = "data: {\"type\":\"speech.audio.delta\",\"audio\":\"SUQz\"}\r\n\r\n\
+                    data: {\"type\":\"speech.audio.delta\",\"audio\":\"AQI=\"}\n\n\
+                    event: done\n\
+                    data: {\"type\":\"speech.audio.done\",\"usage\":{\"output_tokens\":7}}\n\n";
+        let speech = parse(body.as_bytes()).unwrap();
+        assert_eq!(speech.audio, b"ID3\x01\x02");
+        assert_eq!(speech.metadata.len(), 1);
+        assert_eq!(speech.metadata[0]["usage"]["output_tokens"], 7);
+
+        let cut_off = body.rsplit_once("event:").unwrap().0;
+        assert!(parse(cut_off.as_bytes())
+            .unwrap_err()
+            .contains("ended before"));
+    }
+}
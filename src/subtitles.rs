@@ -0,0 +1,181 @@
+// Approximate subtitles (--subtitles) for the produced audio: one cue per
+// sentence, timed by sharing each chunk's measured duration between its
+// sentences in proportion to their length.
+
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SubtitleFormat {
+    Srt,
+    WebVtt,
+}
+
+impl SubtitleFormat {
+    pub fn for_path(path: &str) -> Result<SubtitleFormat, String> {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+        match ext.as_deref() {
+            Some("srt") => Ok(SubtitleFormat::Srt),
+            Some("vtt") => Ok(SubtitleFormat::WebVtt),
+            _ => Err(format!(
+                "can't tell the subtitle format of {}; use a .srt or .vtt file name",
+                path
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cue {
+    pub start: Duration,
+    pub end: Duration,
+    pub text: String,
+}
+
+// The sentences of a chunk's text; a word ending in . ! ? or … (before any
+// closing quotes or brackets) ends a sentence
+fn sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for word in text.split_whitespace() {
+        current.push(word);
+        let bare = word.trim_end_matches(['"', '\'', ')', ']', '”', '’', '»']);
+        if bare.ends_with(['.', '!', '?', '…']) {
+            sentences.push(current.join(" "));
+            current.clear();
+        }
+    }
+    if !current.is_empty() {
+        sentences.push(current.join(" "));
+    }
+    sentences
+}
+
+// Cues for chunks in playback order, each with its measured duration, and
+// `gap` of silence between chunks (--join-gap-ms). Chunks whose duration is
+// unknown (failed or undecodable) get no cues.
+pub fn cues(chunks: &[(&str, Option<Duration>)], gap: Duration) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut chunk_start = Duration::ZERO;
+    let mut follows_audio = false;
+    for (text, duration) in chunks {
+        let Some(duration) = *duration else {
+            continue;
+        };
+        if follows_audio {
+            chunk_start += gap;
+        }
+        follows_audio = true;
+        let sentences = sentences(text);
+        let total_chars: usize = sentences.iter().map(|s| s.chars().count()).sum();
+        let mut chars_before = 0;
+        for sentence in sentences {
+            let chars = sentence.chars().count();
+            let at = |chars: usize| {
+                let nanos = duration.as_nanos() * chars as u128 / total_chars.max(1) as u128;
+                chunk_start + Duration::from_nanos(nanos as u64)
+            };
+            cues.push(Cue {
+                start: at(chars_before),
+                end: at(chars_before + chars),
+                text: sentence,
+            });
+            chars_before += chars;
+        }
+        chunk_start += duration;
+    }
+    cues
+}
+
+fn timestamp(time: Duration, decimal_separator: char) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        decimal_separator,
+        millis % 1000
+    )
+}
+
+pub fn render(cues: &[Cue], format: SubtitleFormat) -> String {
+    let mut out = String::new();
+    if format == SubtitleFormat::WebVtt {
+        out.push_str("WEBVTT\n\n");
+    }
+    let separator = match format {
+        SubtitleFormat::Srt => ',',
+        SubtitleFormat::WebVtt => '.',
+    };
+    for (i, cue) in cues.iter().enumerate() {
+        if format == SubtitleFormat::Srt {
+            out.push_str(&format!("{}\n", i + 1));
+        }
+        out.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            timestamp(cue.start, separator),
+            timestamp(cue.end, separator),
+            cue.text
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentences_share_their_chunk_duration() {
+        let chunks = [
+            ("Hi there. Bye!", Some(Duration::from_millis(1_300))),
+            ("Lost chunk.", None),
+            ("He said \"stop.\" And", Some(Duration::from_secs(3_600))),
+        ];
+        let cues = cues(&chunks, Duration::ZERO);
+        assert_eq!(
+            cues.iter().map(|cue| cue.text.as_str()).collect::<Vec<_>>(),
+            ["Hi there.", "Bye!", "He said \"stop.\"", "And"]
+        );
+        assert_eq!(cues[0].end, Duration::from_millis(900));
+        assert_eq!(cues[1].end, Duration::from_millis(1_300));
+        assert_eq!(cues[2].start, Duration::from_millis(1_300));
+
+        let srt = render(&cues[..1], SubtitleFormat::Srt);
+        assert_eq!(srt, "1\n00:00:00,000 --> 00:00:00,900\nHi there.\n\n");
+        let vtt = render(&cues[3..], SubtitleFormat::WebVtt);
+        assert_eq!(vtt, "WEBVTT\n\n00:50:01.300 --> 01:00:01.300\nAnd\n\n");
+
+        assert_eq!(
+            SubtitleFormat::for_path("talk.VTT"),
+            Ok(SubtitleFormat::WebVtt)
+        );
+        assert!(SubtitleFormat::for_path("talk.txt").is_err());
+    }
+
+    #[test]
+    fn the_join_gap_goes_between_chunks() {
+        let chunks = [
+            ("One.", Some(Duration::from_secs(1))),
+            ("Lost.", None),
+            ("Two.", Some(Duration::from_secs(2))),
+            ("Three.", Some(Duration::from_secs(1))),
+        ];
+        let starts: Vec<Duration> = cues(&chunks, Duration::from_millis(250))
+            .iter()
+            .map(|cue| cue.start)
+            .collect();
+        assert_eq!(
+            starts,
+            [
+                Duration::ZERO,
+                Duration::from_millis(1_250),
+                Duration::from_millis(3_500)
+            ]
+        );
+    }
+}
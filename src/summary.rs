@@ -0,0 +1,218 @@
+// The report printed to stderr once a run has finished (see --quiet and
+// --json).
+
+use std::time::Duration;
+
+// How a chunk's request ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FetchOutcome {
+    Fetched,
+    Failed,
+    // Abandoned because every consumer had gone away, or too many other
+    // chunks failed
+    Cancelled,
+}
+
+// A file the run wrote
+pub struct Output {
+    pub path: String,
+    pub bytes: u64,
+    // Only known for --split-every parts
+    pub duration: Option<Duration>,
+}
+
+// What --since-modified made of the run's input: regenerated because the
+// output was missing or older, or skipped because it was newer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Incremental {
+    pub regenerated: usize,
+    pub skipped: usize,
+}
+
+impl Incremental {
+    pub fn render(&self) -> String {
+        format!(
+            "Inputs:   {} regenerated, {} skipped",
+            self.regenerated, self.skipped
+        )
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({ "regenerated": self.regenerated, "skipped": self.skipped })
+    }
+}
+
+pub struct Summary {
+    pub characters: usize,
+    pub outcomes: Vec<FetchOutcome>,
+    pub audio_bytes: u64,
+    // None when the audio couldn't be decoded to measure it
+    pub duration: Option<Duration>,
+    pub elapsed: Duration,
+    pub outputs: Vec<Output>,
+    // Only with --since-modified
+    pub incremental: Option<Incremental>,
+}
+
+impl Summary {
+    fn failed(&self) -> Vec<usize> {
+        self.indices_of(FetchOutcome::Failed)
+    }
+
+    fn indices_of(&self, outcome: FetchOutcome) -> Vec<usize> {
+        (0..self.outcomes.len())
+            .filter(|&index| self.outcomes[index] == outcome)
+            .collect()
+    }
+
+    fn requests(&self) -> usize {
+        self.outcomes.len() - self.indices_of(FetchOutcome::Cancelled).len()
+    }
+
+    pub fn render(&self) -> String {
+        let failed = self.failed();
+        let cancelled = self.indices_of(FetchOutcome::Cancelled).len();
+
+        let mut requests = format!("{} made", self.requests());
+        if !failed.is_empty() {
+            let indices: Vec<String> = failed.iter().map(usize::to_string).collect();
+            requests.push_str(&format!(
+                ", {} failed (chunk {})",
+                failed.len(),
+                indices.join(", ")
+            ));
+        }
+        if cancelled > 0 {
+            requests.push_str(&format!(", {} cancelled", cancelled));
+        }
+
+        let mut audio = format_bytes(self.audio_bytes);
+        if let Some(duration) = self.duration {
+            audio.push_str(&format!(", {}", format_duration(duration)));
+        }
+
+        let mut lines = vec![
+            format!(
+                "Input:    {} characters in {} chunks",
+                self.characters,
+                self.outcomes.len()
+            ),
+            format!("Requests: {}", requests),
+            format!("Audio:    {}", audio),
+            format!("Elapsed:  {}", format_duration(self.elapsed)),
+        ];
+        if let Some(incremental) = self.incremental {
+            lines.insert(0, incremental.render());
+        }
+        for (i, output) in self.outputs.iter().enumerate() {
+            let label = if i == 0 { "Wrote:" } else { "" };
+            let mut size = format_bytes(output.bytes);
+            if let Some(duration) = output.duration {
+                size.push_str(&format!(", {}", format_duration(duration)));
+            }
+            lines.push(format!("{:<9} {} ({})", label, output.path, size));
+        }
+        lines.join("\n")
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let outputs: Vec<_> = self
+            .outputs
+            .iter()
+            .map(|output| {
+                serde_json::json!({
+                    "path": output.path,
+                    "bytes": output.bytes,
+                    "audio_seconds": output.duration.map(|duration| duration.as_secs_f64()),
+                })
+            })
+            .collect();
+        let mut json = serde_json::json!({
+            "input_characters": self.characters,
+            "chunks": self.outcomes.len(),
+            "requests": self.requests(),
+            "failed": self.failed(),
+            "cancelled": self.indices_of(FetchOutcome::Cancelled).len(),
+            "audio_bytes": self.audio_bytes,
+            "audio_seconds": self.duration.map(|duration| duration.as_secs_f64()),
+            "elapsed_seconds": self.elapsed.as_secs_f64(),
+            "outputs": outputs,
+        });
+        if let Some(incremental) = self.incremental {
+            json["incremental"] = incremental.to_json();
+        }
+        json
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    if seconds < 60.0 {
+        return format!("{:.1}s", seconds);
+    }
+    let minutes = (seconds / 60.0).floor();
+    format!("{}m {:04.1}s", minutes, seconds - minutes * 60.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_lists_failures_and_outputs() {
+        let summary = Summary {
+            characters: 5210,
+            outcomes: vec![
+                FetchOutcome::Fetched,
+                FetchOutcome::Failed,
+                FetchOutcome::Fetched,
+                FetchOutcome::Failed,
+            ],
+            audio_bytes: 1_572_864,
+            duration: Some(Duration::from_millis(62_400)),
+            elapsed: Duration::from_millis(8_300),
+            outputs: vec![
+                Output {
+                    path: "out/001.mp3".to_string(),
+                    bytes: 786_432,
+                    duration: None,
+                },
+                Output {
+                    path: "out/003.mp3".to_string(),
+                    bytes: 900,
+                    duration: Some(Duration::from_secs(2)),
+                },
+            ],
+            incremental: None,
+        };
+
+        assert_eq!(
+            summary.render(),
+            "Input:    5210 characters in 4 chunks\n\
+             Requests: 4 made, 2 failed (chunk 1, 3)\n\
+             Audio:    1.5 MiB, 1m 02.4s\n\
+             Elapsed:  8.3s\n\
+             Wrote:    out/001.mp3 (768.0 KiB)\n          \
+             out/003.mp3 (900 B, 2.0s)"
+        );
+
+        let json = summary.to_json();
+        assert_eq!(json["failed"], serde_json::json!([1, 3]));
+        assert_eq!(json["outputs"][1]["bytes"], 900);
+        assert_eq!(json["audio_seconds"], 62.4);
+    }
+}
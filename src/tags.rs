@@ -0,0 +1,278 @@
+// Metadata tags for saved audio: ID3v2 for MP3, Vorbis comments for FLAC and
+// Opus. Tags are written into finished files, never into a stream that is
+// still being written.
+
+use id3::frame::Comment;
+use id3::{TagLike, Version};
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use std::io::{self, Cursor};
+use std::path::Path;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub comment: Option<String>,
+    // (track number, total tracks), counted from 1
+    pub track: Option<(u32, u32)>,
+}
+
+impl Tags {
+    // The same tags for the `index`th of `count` files
+    pub fn for_track(&self, index: usize, count: usize) -> Tags {
+        Tags {
+            track: Some((index as u32 + 1, count as u32)),
+            ..self.clone()
+        }
+    }
+
+    fn vorbis_comments(&self) -> Vec<String> {
+        let mut comments = Vec::new();
+        let fields = [
+            ("TITLE", &self.title),
+            ("ARTIST", &self.artist),
+            ("ALBUM", &self.album),
+            ("COMMENT", &self.comment),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                comments.push(format!("{}={}", key, value));
+            }
+        }
+        if let Some((number, total)) = self.track {
+            comments.push(format!("TRACKNUMBER={}", number));
+            comments.push(format!("TRACKTOTAL={}", total));
+        }
+        comments
+    }
+}
+
+// Tag a finished file; formats without a tag format of their own (AAC, WAV,
+// raw PCM) are left alone
+pub fn write_tags(path: &Path, audio_format: &str, tags: &Tags) -> io::Result<()> {
+    match audio_format {
+        "mp3" => write_id3(path, tags),
+        "flac" => {
+            let tagged = tag_flac(&std::fs::read(path)?, tags)?;
+            std::fs::write(path, tagged)
+        }
+        "opus" => {
+            let tagged = tag_opus(&std::fs::read(path)?, tags)?;
+            std::fs::write(path, tagged)
+        }
+        _ => {
+            log::debug!(
+                "Not tagging {}: {} has no tag format",
+                path.display(),
+                audio_format
+            );
+            Ok(())
+        }
+    }
+}
+
+fn write_id3(path: &Path, tags: &Tags) -> io::Result<()> {
+    let mut tag = id3::Tag::new();
+    if let Some(title) = &tags.title {
+        tag.set_title(title.as_str());
+    }
+    if let Some(artist) = &tags.artist {
+        tag.set_artist(artist.as_str());
+    }
+    if let Some(album) = &tags.album {
+        tag.set_album(album.as_str());
+    }
+    if let Some(comment) = &tags.comment {
+        tag.add_frame(Comment {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: comment.clone(),
+        });
+    }
+    if let Some((number, total)) = tags.track {
+        tag.set_track(number);
+        tag.set_total_tracks(total);
+    }
+
+    tag.write_to_path(path, Version::Id3v24)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+// The body shared by FLAC's VORBIS_COMMENT block and Opus's OpusTags packet
+fn vorbis_comment_body(tags: &Tags) -> Vec<u8> {
+    let vendor = concat!("sayit ", env!("CARGO_PKG_VERSION"));
+    let comments = tags.vorbis_comments();
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    body.extend_from_slice(vendor.as_bytes());
+    body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        body.extend_from_slice(comment.as_bytes());
+    }
+    body
+}
+
+const FLAC_VORBIS_COMMENT: u8 = 4;
+
+// Replace any VORBIS_COMMENT metadata block with one holding `tags`
+fn tag_flac(bytes: &[u8], tags: &Tags) -> io::Result<Vec<u8>> {
+    if !bytes.starts_with(b"fLaC") {
+        return Err(invalid_data("not a FLAC file".to_string()));
+    }
+
+    let mut blocks = Vec::new();
+    let mut pos = 4;
+    loop {
+        let header = bytes
+            .get(pos..pos + 4)
+            .ok_or_else(|| invalid_data("truncated FLAC metadata".to_string()))?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = bytes
+            .get(pos + 4..pos + 4 + len)
+            .ok_or_else(|| invalid_data("truncated FLAC metadata".to_string()))?;
+        if block_type != FLAC_VORBIS_COMMENT {
+            blocks.push((block_type, body));
+        }
+        pos += 4 + len;
+        if is_last {
+            break;
+        }
+    }
+
+    // STREAMINFO has to stay first; the comments go straight after it
+    let comment = vorbis_comment_body(tags);
+    blocks.insert(1.min(blocks.len()), (FLAC_VORBIS_COMMENT, &comment));
+
+    let mut tagged = b"fLaC".to_vec();
+    for (i, (block_type, body)) in blocks.iter().enumerate() {
+        let last_flag = if i + 1 == blocks.len() { 0x80 } else { 0 };
+        tagged.push(last_flag | block_type);
+        tagged.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        tagged.extend_from_slice(body);
+    }
+    tagged.extend_from_slice(&bytes[pos..]);
+    Ok(tagged)
+}
+
+// Rewrite an Ogg Opus stream with its OpusTags header packet (always the
+// second packet) replaced; audio pages keep their granule positions
+fn tag_opus(bytes: &[u8], tags: &Tags) -> io::Result<Vec<u8>> {
+    let mut reader = PacketReader::new(Cursor::new(bytes));
+    let mut writer = PacketWriter::new(Vec::new());
+    let mut packet_number = 0;
+
+    while let Some(packet) = reader
+        .read_packet()
+        .map_err(|e| invalid_data(e.to_string()))?
+    {
+        let data = if packet_number == 1 && packet.data.starts_with(b"OpusTags") {
+            let mut data = b"OpusTags".to_vec();
+            data.extend_from_slice(&vorbis_comment_body(tags));
+            data
+        } else {
+            packet.data.clone()
+        };
+        // The header packets must each end their own page
+        let end_info = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() || packet_number < 2 {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        writer.write_packet(
+            data.into_boxed_slice(),
+            packet.stream_serial(),
+            end_info,
+            packet.absgp_page(),
+        )?;
+        packet_number += 1;
+    }
+
+    Ok(writer.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge;
+
+    fn tags() -> Tags {
+        Tags {
+            title: Some("Notes".to_string()),
+            artist: Some("nova".to_string()),
+            album: None,
+            comment: Some("tts-1 at speed 1".to_string()),
+            track: None,
+        }
+        .for_track(1, 3)
+    }
+
+    #[test]
+    fn flac_keeps_its_audio_and_gains_comments() {
+        let samples: Vec<i32> = (0..4800).map(|i| (i % 100) * 50).collect();
+        let flac = merge::encode_flac(merge::API_PCM_FORMAT, &samples);
+
+        let tagged = tag_flac(&flac, &tags()).unwrap();
+        // Tagging twice replaces the block rather than adding another
+        let tagged = tag_flac(&tagged, &tags()).unwrap();
+
+        let mut reader = claxon::FlacReader::new(Cursor::new(&tagged)).unwrap();
+        let comments: Vec<_> = reader.tags().collect();
+        assert_eq!(
+            comments,
+            vec![
+                ("TITLE", "Notes"),
+                ("ARTIST", "nova"),
+                ("COMMENT", "tts-1 at speed 1"),
+                ("TRACKNUMBER", "2"),
+                ("TRACKTOTAL", "3"),
+            ]
+        );
+        let decoded: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn opus_tags_packet_is_replaced() {
+        let mut writer = PacketWriter::new(Vec::new());
+        let head = b"OpusHead\x01\x01\x38\x01\xc0\x5d\x00\x00\x00\x00\x00".to_vec();
+        let empty_tags = b"OpusTags\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        let end_page = PacketWriteEndInfo::EndPage;
+        writer
+            .write_packet(head.clone().into(), 7, end_page, 0)
+            .unwrap();
+        writer
+            .write_packet(empty_tags.into(), 7, end_page, 0)
+            .unwrap();
+        writer
+            .write_packet(
+                vec![0xf8, 1, 2].into(),
+                7,
+                PacketWriteEndInfo::EndStream,
+                960,
+            )
+            .unwrap();
+
+        let tagged = tag_opus(&writer.into_inner(), &tags()).unwrap();
+
+        let mut reader = PacketReader::new(Cursor::new(tagged));
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, head);
+        let comments = reader.read_packet().unwrap().unwrap().data;
+        assert!(comments.starts_with(b"OpusTags"));
+        assert!(comments.windows(11).any(|w| w == b"TITLE=Notes"));
+        let audio = reader.read_packet().unwrap().unwrap();
+        assert_eq!(audio.data, [0xf8, 1, 2]);
+        assert_eq!(audio.absgp_page(), 960);
+        assert!(audio.last_in_stream());
+    }
+}
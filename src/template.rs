@@ -0,0 +1,320 @@
+// Per-chunk output files named from a template such as
+// `part-{index:03}-{voice}.{ext}`.
+
+use crate::playlist::{self, Playlist};
+use crate::tags::{self, Tags};
+use crate::{decoded_duration, merge, AudioChunk};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// Values for the placeholders besides `{index}`
+#[derive(Clone)]
+pub struct TemplateValues {
+    pub voice: &'static str,
+    pub model: &'static str,
+    pub ext: &'static str,
+    // The input file's name without its extension, if there is one
+    pub input: String,
+    // The title of the chapter each chunk belongs to, by chunk index
+    pub chapters: Vec<String>,
+}
+
+// Substitute `{index}`, `{voice}`, `{model}`, `{ext}`, `{input}` and
+// `{chapter}`. Numbers accept a width such as `{index:3}` or, zero-padded,
+// `{index:03}`; `{{` and `}}` are literal braces.
+pub fn render(template: &str, index: usize, values: &TemplateValues) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                rendered.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                rendered.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(format!("unclosed '{{' in template {:?}", template)),
+                    }
+                }
+                let (name, spec) = placeholder
+                    .split_once(':')
+                    .unwrap_or((placeholder.as_str(), ""));
+                rendered.push_str(&substitute(name, spec, index, values)?);
+            }
+            '}' => return Err(format!("unmatched '}}' in template {:?}", template)),
+            c => rendered.push(c),
+        }
+    }
+
+    Ok(rendered)
+}
+
+fn substitute(
+    name: &str,
+    spec: &str,
+    index: usize,
+    values: &TemplateValues,
+) -> Result<String, String> {
+    let value = match name {
+        "index" => index.to_string(),
+        "voice" => values.voice.to_string(),
+        "model" => values.model.to_string(),
+        "ext" => values.ext.to_string(),
+        "input" => sanitize_file_name(&values.input),
+        "chapter" => sanitize_file_name(values.chapters.get(index).map_or("", String::as_str)),
+        _ => return Err(format!("unknown template placeholder {{{}}}", name)),
+    };
+
+    if spec.is_empty() {
+        return Ok(value);
+    }
+
+    let width: usize = spec
+        .parse()
+        .map_err(|_| format!("invalid width {:?} for {{{}}}", spec, name))?;
+    if spec.starts_with('0') {
+        Ok(format!("{:0>width$}", value, width = width))
+    } else {
+        Ok(format!("{:>width$}", value, width = width))
+    }
+}
+
+// Check a template up front so a typo doesn't cost any API requests
+pub fn validate(template: &str, values: &TemplateValues) -> Result<(), String> {
+    if render(template, 0, values)? == render(template, 1, values)? {
+        return Err(format!(
+            "template {:?} must contain {{index}} so every chunk gets its own file",
+            template
+        ));
+    }
+    Ok(())
+}
+
+// Make text (a chapter title, an input file name) safe to use as a file
+// name everywhere, Windows included: no separators, reserved characters,
+// control characters, reserved device names or trailing dots and spaces, and
+// short enough to leave room for the rest of the path
+pub fn sanitize_file_name(name: &str) -> String {
+    const MAX_LEN: usize = 100;
+
+    let mut clean: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    if clean.len() > MAX_LEN {
+        let mut end = MAX_LEN;
+        while !clean.is_char_boundary(end) {
+            end -= 1;
+        }
+        clean.truncate(end);
+    }
+    let clean = clean.trim().trim_end_matches('.').trim_end().to_string();
+
+    let stem = clean.split('.').next().unwrap_or("").to_uppercase();
+    let reserved = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4
+            && (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.ends_with(|c: char| c.is_ascii_digit()));
+    if clean.is_empty() {
+        "untitled".to_string()
+    } else if reserved {
+        format!("_{}", clean)
+    } else {
+        clean
+    }
+}
+
+// `template` placed inside `dir`, whose braces mustn't be read as placeholders
+pub fn in_dir(dir: &str, template: &str) -> String {
+    let dir = dir.replace('{', "{{").replace('}', "}}");
+    Path::new(&dir)
+        .join(template)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Numbered file names `PREFIX_000.ext`, padded to at least three digits and
+// wide enough that the names sort in chunk order
+pub fn numbered_template(prefix: &str, chunk_count: usize) -> String {
+    let width = chunk_count.saturating_sub(1).to_string().len().max(3);
+    format!("{}_{{index:0{}}}.{{ext}}", prefix, width)
+}
+
+// Template for --split-output-dir: `DIR/chunk_000.ext`
+pub fn split_dir_template(dir: &str, chunk_count: usize) -> String {
+    in_dir(dir, &numbered_template("chunk", chunk_count))
+}
+
+// Write each chunk to its own file as it arrives, tagged as one track of the
+// set, then list them in an `index.tsv` (chunk, file, first words) next to
+// the first file and in the playlist, if one was asked for
+pub async fn audio_to_template_files(
+    mut audio_rx: mpsc::Receiver<AudioChunk>,
+    template: String,
+    values: TemplateValues,
+    first_words: Vec<String>,
+    tags: Option<Tags>,
+    playlist: Option<Playlist>,
+) {
+    let mut written = Vec::new();
+
+    while let Some(chunk) = audio_rx.recv().await {
+        // The template was validated before any request was sent
+        let path = render(&template, chunk.index, &values).unwrap();
+        let wrap_pcm_in_wav = values.ext == "pcm" && path.to_lowercase().ends_with(".wav");
+
+        let result = File::create(&path).and_then(|mut file| {
+            if wrap_pcm_in_wav {
+                let data_len = u32::try_from(chunk.bytes.len()).unwrap_or(u32::MAX);
+                file.write_all(&merge::wav_header(
+                    crate::resample::output_format(),
+                    data_len,
+                ))?;
+            }
+            file.write_all(&chunk.bytes)
+        });
+
+        if let (Ok(()), Some(tags)) = (&result, &tags) {
+            let tags = tags.for_track(chunk.index, first_words.len());
+            if let Err(e) = tags::write_tags(Path::new(&path), values.ext, &tags) {
+                log::warn!("Failed to tag {}: {}", path, e);
+            }
+        }
+
+        // Playlist entries carry their length, which takes a decode to find
+        let duration = match (&result, &playlist) {
+            (Ok(()), Some(_)) => decoded_duration(&chunk, values.ext),
+            _ => None,
+        };
+
+        match result {
+            Ok(()) => written.push((chunk.index, path, duration)),
+            Err(e) => eprintln!("Failed to write chunk {} to {}: {}", chunk.index, path, e),
+        }
+    }
+
+    written.sort_by_key(|(index, _, _)| *index);
+    if let Some(playlist) = playlist {
+        write_playlist(&playlist, &written);
+    }
+    if written.is_empty() {
+        return;
+    }
+
+    let index_path = Path::new(&written[0].1)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join("index.tsv");
+    let mut listing = String::from("chunk\tfile\tfirst words\n");
+    for (index, path, _) in &written {
+        let file_name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.clone());
+        let words = first_words.get(*index).map(String::as_str).unwrap_or("");
+        listing.push_str(&format!("{}\t{}\t{}\n", index, file_name, words));
+    }
+
+    if let Err(e) = std::fs::write(&index_path, listing) {
+        eprintln!("Failed to write {}: {}", index_path.display(), e);
+    }
+}
+
+fn write_playlist(playlist: &Playlist, written: &[(usize, String, Option<Duration>)]) {
+    let entries: Vec<playlist::Entry> = written
+        .iter()
+        .map(|(index, path, duration)| playlist::Entry {
+            path: path.clone(),
+            title: playlist.titles.get(*index).cloned().unwrap_or_default(),
+            duration: *duration,
+        })
+        .collect();
+    let playlist_path = Path::new(&playlist.path);
+    let m3u = playlist::render(&entries, playlist_path);
+    if let Err(e) = playlist::write_atomically(playlist_path, m3u.as_bytes()) {
+        eprintln!("Failed to write {}: {}", playlist.path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: TemplateValues = TemplateValues {
+        voice: "nova",
+        model: "tts-1",
+        ext: "mp3",
+        input: String::new(),
+        chapters: Vec::new(),
+    };
+
+    #[test]
+    fn render_substitutes_and_pads() {
+        assert_eq!(
+            render("part-{index:03}-{voice}.{ext}", 7, &VALUES).unwrap(),
+            "part-007-nova.mp3"
+        );
+        assert_eq!(
+            render("{model}/{index}.{ext}", 12, &VALUES).unwrap(),
+            "tts-1/12.mp3"
+        );
+        assert_eq!(render("{{{index:2}}}", 3, &VALUES).unwrap(), "{ 3}");
+    }
+
+    #[test]
+    fn chapter_and_input_names_are_sanitized() {
+        let values = TemplateValues {
+            input: "notes".to_string(),
+            chapters: vec!["Intro".to_string(), "Q&A: who/why?".to_string()],
+            ..VALUES
+        };
+        assert_eq!(
+            render("{input}-{index}-{chapter}.{ext}", 1, &values).unwrap(),
+            "notes-1-Q&A_ who_why_.mp3"
+        );
+
+        assert_eq!(sanitize_file_name("  The End...  "), "The End");
+        assert_eq!(sanitize_file_name("con.txt"), "_con.txt");
+        assert_eq!(sanitize_file_name("COM1"), "_COM1");
+        assert_eq!(sanitize_file_name("Tab\there"), "Tab_here");
+        assert_eq!(sanitize_file_name(""), "untitled");
+        assert_eq!(sanitize_file_name(&"é".repeat(80)).len(), 100);
+    }
+
+    #[test]
+    fn split_dir_names_sort_in_chunk_order() {
+        let template = split_dir_template("out", 12);
+        assert_eq!(render(&template, 7, &VALUES).unwrap(), "out/chunk_007.mp3");
+
+        let template = split_dir_template("{weird}", 1500);
+        assert_eq!(
+            render(&template, 42, &VALUES).unwrap(),
+            "{weird}/chunk_0042.mp3"
+        );
+    }
+
+    #[test]
+    fn validate_rejects_bad_templates() {
+        assert!(validate("part-{index}.{ext}", &VALUES).is_ok());
+        assert!(validate("part.{ext}", &VALUES).is_err());
+        assert!(validate("part-{chapter}.{ext}", &VALUES).is_err());
+        assert!(validate("part-{index.{ext}", &VALUES).is_err());
+        assert!(validate("part-{index:x}.{ext}", &VALUES).is_err());
+    }
+}
@@ -0,0 +1,36 @@
+// The text exactly as it was sent for synthesis (--save-text), after every
+// preprocessing step, with a marker line before each chunk so the audio can
+// be matched back to it.
+
+use std::path::Path;
+
+// `notes.mp3` -> `notes.txt`, for the `save_text = true` config option
+pub fn path_for_output(output_file: &str) -> String {
+    Path::new(output_file)
+        .with_extension("txt")
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn render(chunks: &[&str]) -> String {
+    let mut out = String::new();
+    for (index, text) in chunks.iter().enumerate() {
+        out.push_str(&format!("--- chunk {} ---\n{}\n", index, text));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_marked_by_index() {
+        assert_eq!(
+            render(&["Chapter 1: Intro", "It was a dark night."]),
+            "--- chunk 0 ---\nChapter 1: Intro\n--- chunk 1 ---\nIt was a dark night.\n"
+        );
+        assert_eq!(path_for_output("out/book.part.mp3"), "out/book.part.txt");
+        assert_eq!(path_for_output("book"), "book.txt");
+    }
+}
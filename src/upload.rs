@@ -0,0 +1,184 @@
+// Uploading the finished output file (--upload) with an HTTP PUT, for
+// WebDAV shares, presigned object storage URLs and the like. The file is
+// streamed from disk, so long audiobooks are never held in memory.
+
+use crate::expected_content_type;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use reqwest::{Client, Response, StatusCode, Url};
+use std::path::Path;
+use std::time::Duration;
+
+const ATTEMPTS: u32 = 3;
+
+pub fn parse_url(value: &str) -> Result<Url, String> {
+    let url = Url::parse(value).map_err(|e| format!("{:?} isn't a valid URL: {}", value, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(format!("can't upload over {}; use an http(s) URL", scheme)),
+    }
+}
+
+// The MIME type of a written file; .m4a and .m4b files are MP4 containers
+pub fn content_type(file_format: &str) -> &'static str {
+    match file_format {
+        "m4a" | "m4b" => "audio/mp4",
+        format => expected_content_type(format),
+    }
+}
+
+// Failures worth another attempt: the server being busy or briefly broken
+fn transient(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// Where the file ended up: the Location a server answers with, if any,
+// otherwise the URL that was uploaded to
+fn final_url(response: &Response) -> String {
+    response
+        .headers()
+        .get(LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|location| response.url().join(location).ok())
+        .unwrap_or_else(|| response.url().clone())
+        .to_string()
+}
+
+// PUT the file at `path` to `url`, retrying transient failures with a
+// growing delay. Headers given by the user win over the ones set here.
+pub async fn put_file(
+    client: &Client,
+    url: &Url,
+    path: &Path,
+    content_type: &'static str,
+    extra_headers: &[(HeaderName, HeaderValue)],
+) -> Result<String, String> {
+    let mut attempt = 1;
+    loop {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let length = file
+            .metadata()
+            .await
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?
+            .len();
+
+        // An explicit length stops the body being sent chunked, which
+        // presigned URLs refuse
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+        for (name, value) in extra_headers {
+            headers.insert(name.clone(), value.clone());
+        }
+
+        let failure = match client
+            .put(url.clone())
+            .headers(headers)
+            .body(file)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => return Ok(final_url(&response)),
+            Ok(response) if transient(response.status()) => {
+                format!("the server answered {}", response.status())
+            }
+            Ok(response) => return Err(format!("the server answered {}", response.status())),
+            Err(e) if e.is_builder() => return Err(e.to_string()),
+            Err(e) => e.to_string(),
+        };
+        if attempt == ATTEMPTS {
+            return Err(format!("{} ({} attempts)", failure, ATTEMPTS));
+        }
+        let delay = Duration::from_secs(1 << (attempt - 1));
+        log::warn!(
+            "Upload attempt {} failed: {}; retrying in {}s",
+            attempt,
+            failure,
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::http::HeaderMap as ServerHeaders;
+    use axum::response::IntoResponse;
+    use axum::routing::put;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Received {
+        attempts: usize,
+        body: Vec<u8>,
+        content_type: Option<String>,
+        authorization: Option<String>,
+    }
+
+    // A server that fails the first PUT with 503 and then keeps the body
+    async fn flaky_server() -> (String, Arc<Mutex<Received>>) {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let state = received.clone();
+        let app = axum::Router::new().route(
+            "/{*path}",
+            put(move |headers: ServerHeaders, body: Bytes| async move {
+                let mut received = state.lock().unwrap();
+                received.attempts += 1;
+                if received.attempts == 1 {
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
+                let header = |name| {
+                    headers
+                        .get(name)
+                        .and_then(|value: &HeaderValue| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                received.content_type = header(CONTENT_TYPE);
+                received.authorization = header(reqwest::header::AUTHORIZATION);
+                received.body = body.to_vec();
+                (StatusCode::CREATED, [(LOCATION, "/stored/book.mp3")]).into_response()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{}/inbox/book.mp3", address), received)
+    }
+
+    #[tokio::test]
+    async fn upload_is_retried_and_reports_where_the_file_went() {
+        let (url, received) = flaky_server().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("book.mp3");
+        std::fs::write(&path, b"ID3 audio").unwrap();
+
+        let headers = [(
+            reqwest::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer token"),
+        )];
+        let url = parse_url(&url).unwrap();
+        let stored = put_file(&Client::new(), &url, &path, content_type("mp3"), &headers)
+            .await
+            .unwrap();
+
+        assert!(stored.ends_with("/stored/book.mp3"));
+        let received = received.lock().unwrap();
+        assert_eq!(received.attempts, 2);
+        assert_eq!(received.body, b"ID3 audio");
+        assert_eq!(received.content_type.as_deref(), Some("audio/mpeg"));
+        assert_eq!(received.authorization.as_deref(), Some("Bearer token"));
+    }
+
+    #[test]
+    fn only_http_urls_are_accepted() {
+        assert!(parse_url("https://dav.example.com/audio/").is_ok());
+        assert!(parse_url("ftp://example.com/book.mp3").is_err());
+        assert!(parse_url("book.mp3").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+// The voices each backend offers, for `sayit voices list`. The descriptions
+// and phrases are maintained by hand; add a line here whenever a voice is
+// added.
+
+use crate::Voice;
+
+pub struct VoiceInfo {
+    pub backend: &'static str,
+    pub voice: Voice,
+    pub description: &'static str,
+    // Something to try it out on with `sayit voices sample --text`
+    pub phrase: &'static str,
+}
+
+pub const VOICES: [VoiceInfo; 6] = [
+    VoiceInfo {
+        backend: "openai",
+        voice: Voice::Alloy,
+        description: "Neutral and balanced, a good all-rounder",
+        phrase: "Your meeting with the design team starts in ten minutes.",
+    },
+    VoiceInfo {
+        backend: "openai",
+        voice: Voice::Echo,
+        description: "Calm, measured male voice",
+        phrase: "Take a slow breath in, and let it out as you count to four.",
+    },
+    VoiceInfo {
+        backend: "openai",
+        voice: Voice::Fable,
+        description: "Expressive storyteller with a British accent",
+        phrase: "Once upon a time, in a kingdom by the sea, there lived a lighthouse keeper.",
+    },
+    VoiceInfo {
+        backend: "openai",
+        voice: Voice::Onyx,
+        description: "Deep, authoritative male voice",
+        phrase: "Tonight's top story: the city council has approved the new budget.",
+    },
+    VoiceInfo {
+        backend: "openai",
+        voice: Voice::Nova,
+        description: "Bright, energetic female voice",
+        phrase: "Great news, your order is on its way and arrives tomorrow!",
+    },
+    VoiceInfo {
+        backend: "openai",
+        voice: Voice::Shimmer,
+        description: "Soft, warm female voice",
+        phrase: "Goodnight, little one. The stars are out, and everything is quiet.",
+    },
+];
+
+// What `sayit voices sample` reads without --text
+pub const SAMPLE_PHRASE: &str = "The quick brown fox jumps over the lazy dog.";
+
+// What a voice says about itself with --sample
+pub fn sample_text(voice: Voice) -> String {
+    format!("Hello, I am {}", voice.name())
+}
+
+// The voices as a table, with `default` (the voice a run would use) marked
+pub fn render_table(voices: &[VoiceInfo], default: Voice) -> String {
+    let rows: Vec<[&str; 5]> = voices
+        .iter()
+        .map(|info| {
+            let marker = if info.voice == default { "*" } else { "" };
+            [
+                marker,
+                info.backend,
+                info.voice.name(),
+                info.description,
+                info.phrase,
+            ]
+        })
+        .collect();
+    let header = ["", "BACKEND", "VOICE", "DESCRIPTION", "TRY"];
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .chain([&header])
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut lines = Vec::new();
+    for row in [header].into_iter().chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell))
+            .collect();
+        lines.push(cells.join("  ").trim_end().to_string());
+    }
+    lines.join("\n")
+}
+
+pub fn to_json(voices: &[VoiceInfo], default: Voice) -> serde_json::Value {
+    voices
+        .iter()
+        .map(|info| {
+            serde_json::json!({
+                "backend": info.backend,
+                "name": info.voice.name(),
+                "description": info.description,
+                "phrase": info.phrase,
+                "default": info.voice == default,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+
+    #[test]
+    fn every_voice_is_described() {
+        for voice in Voice::value_variants() {
+            assert!(VOICES.iter().any(|info| info.voice == *voice));
+        }
+
+        let table = render_table(&VOICES[..2], Voice::Echo);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("   BACKEND  VOICE  DESCRIPTION"));
+        assert!(lines[1].starts_with("   openai   alloy  Neutral and balanced"));
+        assert!(lines[2].starts_with("*  openai   echo   Calm, measured male voice"));
+        assert!(lines[2].ends_with("as you count to four."));
+        let json = to_json(&VOICES, Voice::Nova);
+        assert_eq!(json[4]["name"], "nova");
+        assert_eq!(json[4]["default"], true);
+        assert_eq!(json[0]["default"], false);
+        assert_eq!(sample_text(Voice::Onyx), "Hello, I am onyx");
+    }
+}
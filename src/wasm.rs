@@ -0,0 +1,59 @@
+// Text to speech in the browser: `sayitSpeak` for JavaScript, built with
+// `wasm-pack build --target web -- --features wasm`. reqwest makes its
+// requests through the browser's fetch here, and playing the audio is left
+// to the page (see examples/wasm.html).
+
+use crate::{chunking, SPEECH_ENDPOINT, VOICES};
+use wasm_bindgen::prelude::*;
+
+async fn fetch(
+    client: &reqwest::Client,
+    api_key: &str,
+    text: &str,
+    voice: &str,
+) -> Result<Vec<u8>, String> {
+    let response = client
+        .post(SPEECH_ENDPOINT)
+        .bearer_auth(api_key)
+        .json(&serde_json::json!({
+            "model": "tts-1",
+            "voice": voice,
+            "input": text,
+            "response_format": "mp3",
+        }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("the speech request failed with {}", status));
+    }
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    Ok(bytes.to_vec())
+}
+
+// Read `text` with `voice`, resolving to the MP3 audio as a Uint8Array and
+// rejecting with a message if anything goes wrong. The API key comes from
+// the caller, since a page has no environment to read it from. (Exported
+// async functions can't borrow their arguments, hence the Strings.)
+#[wasm_bindgen(js_name = sayitSpeak)]
+pub async fn sayit_speak(text: String, voice: String, api_key: String) -> Result<JsValue, JsValue> {
+    if !VOICES.contains(&voice.as_str()) {
+        return Err(JsValue::from_str(&format!("unknown voice {:?}", voice)));
+    }
+    let chunks = chunking::split_input(&text, 4096);
+    if chunks.is_empty() {
+        return Err(JsValue::from_str("there is no text to read"));
+    }
+
+    // MP3 chunks can simply be concatenated
+    let client = reqwest::Client::new();
+    let mut audio = Vec::new();
+    for chunk in &chunks {
+        let bytes = fetch(&client, &api_key, chunk, &voice)
+            .await
+            .map_err(|e| JsValue::from_str(&e))?;
+        audio.extend_from_slice(&bytes);
+    }
+    Ok(js_sys::Uint8Array::from(audio.as_slice()).into())
+}